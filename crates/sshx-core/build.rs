@@ -1,10 +1,17 @@
-use std::{env, path::PathBuf};
-
+#[cfg(feature = "grpc")]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    use std::{env, path::PathBuf};
+
     let descriptor_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("sshx.bin");
     tonic_build::configure()
         .file_descriptor_set_path(descriptor_path)
         .bytes(["."])
+        .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .type_attribute(".", "#[serde(rename_all = \"camelCase\")]")
+        .message_attribute(".", "#[serde(default)]")
         .compile(&["proto/sshx.proto"], &["proto/"])?;
     Ok(())
 }
+
+#[cfg(not(feature = "grpc"))]
+fn main() {}
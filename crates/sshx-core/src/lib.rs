@@ -1,4 +1,13 @@
 //! The core crate for shared code used in the sshx application.
+//!
+//! Everything except the ID types and the [`protocol`] module is behind a
+//! default feature, so consumers like lightweight viewer clients can depend on
+//! just those without the generated gRPC code. Without default features, this
+//! crate also builds for `wasm32-unknown-unknown`, like `sshx-crypto`:
+//!
+//! - `grpc`: Protocol buffer and gRPC definitions in the `proto` module.
+//! - `logfile`: Log files with rotation, in the `logfile` module.
+//! - `rand`: Random secrets, with `rand_alphanumeric()`.
 
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
@@ -8,17 +17,246 @@ use std::sync::atomic::{AtomicU32, Ordering};
 
 use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "logfile")]
+pub mod logfile;
+pub mod protocol;
+
 /// Protocol buffer and gRPC definitions, automatically generated by Tonic.
+///
+/// Messages also implement [`Serialize`] and [`Deserialize`] with camelCase
+/// field names, so they can be written as JSON by APIs and debugging tools.
+#[cfg(feature = "grpc")]
 #[allow(missing_docs, non_snake_case)]
 #[allow(clippy::derive_partial_eq_without_eq)]
 pub mod proto {
+    use crate::{Sid, Uid};
+
     tonic::include_proto!("sshx");
 
     /// File descriptor set used for gRPC reflection.
     pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("sshx");
+
+    /// Metadata key of a Channel() request, listing the optional features that
+    /// the client supports from [`client_features`], separated by commas.
+    pub const CLIENT_FEATURES_KEY: &str = "sshx-client-features";
+
+    /// Metadata key of a Channel() request, with a random ID that a client
+    /// picks once, so that the server can refuse clients that were replaced.
+    pub const BACKEND_ID_KEY: &str = "sshx-backend-id";
+
+    /// Binary metadata key of a Channel() request from a client that takes
+    /// over as the backend of the session, with its encrypted zeros block.
+    pub const TAKEOVER_KEY: &str = "sshx-takeover-bin";
+
+    /// Returns the message that a client signs in the hello of a Channel()
+    /// request, if it registered a key when opening the session. The
+    /// timestamp is in milliseconds since the Unix epoch.
+    pub fn hello_payload(name: &str, token: &str, timestamp: u64) -> Vec<u8> {
+        format!("sshx-hello\0{name}\0{token}\0{timestamp}").into_bytes()
+    }
+
+    /// Optional features of clients, which servers only use when asked to,
+    /// since older clients do not understand them.
+    pub mod client_features {
+        /// Users joining the session are reported with
+        /// [`ServerMessage::UserJoined`](super::server_update::ServerMessage::UserJoined).
+        pub const USER_JOINED: &str = "userJoined";
+
+        /// Users in the session are tracked with
+        /// [`ServerMessage::UserJoined`](super::server_update::ServerMessage::UserJoined)
+        /// and [`ServerMessage::UserLeft`](super::server_update::ServerMessage::UserLeft),
+        /// starting with the users already present when the channel opens.
+        pub const USER_PRESENCE: &str = "userPresence";
+
+        /// Users in the session are listed periodically with
+        /// [`ServerMessage::Roster`](super::server_update::ServerMessage::Roster),
+        /// including when they joined.
+        pub const ROSTER: &str = "roster";
+    }
+
+    impl TerminalData {
+        /// Returns the ID of the shell.
+        pub fn sid(&self) -> Sid {
+            Sid(self.id)
+        }
+    }
+
+    impl TerminalInput {
+        /// Returns the ID of the shell.
+        pub fn sid(&self) -> Sid {
+            Sid(self.id)
+        }
+    }
+
+    impl TerminalSize {
+        /// Returns the ID of the shell.
+        pub fn sid(&self) -> Sid {
+            Sid(self.id)
+        }
+    }
+
+    impl NewShell {
+        /// Construct a message for a new shell centered at a position.
+        pub fn new(id: Sid, (x, y): (i32, i32)) -> Self {
+            Self { id: id.0, x, y }
+        }
+
+        /// Returns the ID of the shell.
+        pub fn sid(&self) -> Sid {
+            Sid(self.id)
+        }
+
+        /// Returns the position of the shell.
+        pub fn center(&self) -> (i32, i32) {
+            (self.x, self.y)
+        }
+    }
+
+    impl Capabilities {
+        /// Returns whether the server supports an optional feature.
+        pub fn has(&self, feature: &str) -> bool {
+            self.features.iter().any(|f| f == feature)
+        }
+    }
+
+    impl ClosedShell {
+        /// Construct a message for a shell that was closed for a reason.
+        pub fn new(id: Sid, reason: impl Into<String>) -> Self {
+            Self {
+                id: id.0,
+                exit_code: None,
+                signal: None,
+                reason: reason.into(),
+            }
+        }
+
+        /// Returns the ID of the shell.
+        pub fn sid(&self) -> Sid {
+            Sid(self.id)
+        }
+
+        /// Returns the message reporting this shell as closed to a server.
+        ///
+        /// Servers without [`features::SHELL_CLOSED`] only understand the
+        /// deprecated message carrying the shell's ID.
+        ///
+        /// [`features::SHELL_CLOSED`]: crate::protocol::features::SHELL_CLOSED
+        pub fn into_message(self, capabilities: &Capabilities) -> client_update::ClientMessage {
+            if capabilities.has(crate::protocol::features::SHELL_CLOSED) {
+                client_update::ClientMessage::ShellClosed(self)
+            } else {
+                client_update::ClientMessage::ClosedShell(self.id)
+            }
+        }
+    }
+
+    impl From<ClosedShell> for crate::protocol::WsShellClosed {
+        fn from(closed: ClosedShell) -> Self {
+            Self {
+                exit_code: closed.exit_code,
+                signal: closed.signal,
+                reason: closed.reason,
+            }
+        }
+    }
+
+    impl ShellHealth {
+        /// Returns the ID of the shell.
+        pub fn sid(&self) -> Sid {
+            Sid(self.id)
+        }
+    }
+
+    impl From<ShellHealth> for crate::protocol::WsShellHealth {
+        fn from(health: ShellHealth) -> Self {
+            Self {
+                stalled: health.stalled,
+                restarted: health.restarted,
+            }
+        }
+    }
+
+    impl From<crate::protocol::WsStreamKind> for StreamKind {
+        fn from(kind: crate::protocol::WsStreamKind) -> Self {
+            use crate::protocol::WsStreamKind;
+            match kind {
+                WsStreamKind::File => StreamKind::File,
+                WsStreamKind::Port => StreamKind::Port,
+                WsStreamKind::Clipboard => StreamKind::Clipboard,
+                WsStreamKind::Metrics => StreamKind::Metrics,
+            }
+        }
+    }
+
+    impl StreamKind {
+        /// Returns the kind for the web protocol, or `None` if unspecified.
+        pub fn to_ws(self) -> Option<crate::protocol::WsStreamKind> {
+            use crate::protocol::WsStreamKind;
+            match self {
+                StreamKind::Unspecified => None,
+                StreamKind::File => Some(WsStreamKind::File),
+                StreamKind::Port => Some(WsStreamKind::Port),
+                StreamKind::Clipboard => Some(WsStreamKind::Clipboard),
+                StreamKind::Metrics => Some(WsStreamKind::Metrics),
+            }
+        }
+    }
+
+    impl UserJoined {
+        /// Returns the ID of the user.
+        pub fn uid(&self) -> Uid {
+            Uid(self.id)
+        }
+    }
+
+    impl RosterUser {
+        /// Returns the ID of the user.
+        pub fn uid(&self) -> Uid {
+            Uid(self.id)
+        }
+    }
+
+    impl SequenceNumbers {
+        /// Iterate over the active shells and their sequence numbers.
+        pub fn iter(&self) -> impl Iterator<Item = (Sid, u64)> + '_ {
+            self.map.iter().map(|(&id, &seq)| (Sid(id), seq))
+        }
+    }
+
+    impl FromIterator<(Sid, u64)> for SequenceNumbers {
+        fn from_iter<T: IntoIterator<Item = (Sid, u64)>>(iter: T) -> Self {
+            let map = iter.into_iter().map(|(id, seq)| (id.0, seq)).collect();
+            Self { map }
+        }
+    }
+
+    impl InactivityPolicy {
+        /// Keep a session for some time after its client disconnects, or until
+        /// it is closed explicitly if `None`.
+        pub fn keep(duration: Option<std::time::Duration>) -> Self {
+            use inactivity_policy::Policy;
+            let policy = match duration {
+                Some(duration) => {
+                    Policy::KeepSecs(duration.as_secs().try_into().unwrap_or(u32::MAX))
+                }
+                None => Policy::KeepForever(true),
+            };
+            Self {
+                policy: Some(policy),
+            }
+        }
+    }
+
+    impl SerializedSession {
+        /// Returns the next shell and user IDs of the session's counter.
+        pub fn next_ids(&self) -> (Sid, Uid) {
+            (Sid(self.next_sid), Uid(self.next_uid))
+        }
+    }
 }
 
 /// Generate a cryptographically-secure, random alphanumeric value.
+#[cfg(feature = "rand")]
 pub fn rand_alphanumeric(len: usize) -> String {
     use rand::{distributions::Alphanumeric, thread_rng, Rng};
     thread_rng()
@@ -39,6 +277,18 @@ impl Display for Sid {
     }
 }
 
+impl From<u32> for Sid {
+    fn from(id: u32) -> Self {
+        Sid(id)
+    }
+}
+
+impl From<Sid> for u32 {
+    fn from(id: Sid) -> Self {
+        id.0
+    }
+}
+
 /// Unique identifier for a user within the session.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 #[serde(transparent)]
@@ -50,6 +300,18 @@ impl Display for Uid {
     }
 }
 
+impl From<u32> for Uid {
+    fn from(id: u32) -> Self {
+        Uid(id)
+    }
+}
+
+impl From<Uid> for u32 {
+    fn from(id: Uid) -> Self {
+        id.0
+    }
+}
+
 /// A counter for generating unique identifiers.
 #[derive(Debug)]
 pub struct IdCounter {
@@ -0,0 +1,250 @@
+//! Log files with size- and time-based rotation.
+//!
+//! When a log file is rotated, it is compressed with gzip to `<path>.1.gz`,
+//! and older files are shifted up to `<path>.2.gz` and so on, up to a limit.
+//! Compression runs on a background thread, so that logging is not blocked.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::{write::GzEncoder, Compression};
+
+/// How often a log file is rotated, regardless of its size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    /// Only rotate the file when it grows too large.
+    #[default]
+    Never,
+    /// Rotate the file at the start of every hour.
+    Hourly,
+    /// Rotate the file at midnight UTC every day.
+    Daily,
+}
+
+impl Rotation {
+    /// Returns the index of the period that a time falls into.
+    fn period(self, time: SystemTime) -> u64 {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match self {
+            Rotation::Never => 0,
+            Rotation::Hourly => secs / 3600,
+            Rotation::Daily => secs / 86400,
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Rotation::Never),
+            "hourly" => Ok(Rotation::Hourly),
+            "daily" => Ok(Rotation::Daily),
+            _ => Err(format!("expected never, hourly or daily, got {s:?}")),
+        }
+    }
+}
+
+/// Options for writing logs to a rotated file.
+#[derive(Clone, Debug)]
+pub struct LogFileOptions {
+    /// Path of the current log file.
+    pub path: PathBuf,
+
+    /// Rotate the file before it grows larger than this many bytes.
+    pub max_size: Option<u64>,
+
+    /// Rotate the file at the start of each period.
+    pub rotation: Rotation,
+
+    /// Number of compressed old files to keep.
+    pub keep: usize,
+}
+
+/// A log file that is rotated when it grows too large, or periodically.
+///
+/// This implements [`Write`], so it can be used as the writer of a
+/// `tracing_subscriber` layer when wrapped in a mutex.
+pub struct LogFile {
+    options: LogFileOptions,
+    file: File,
+    size: u64,
+    period: u64,
+    /// Compression of the last rotated file, if it was started.
+    compressing: Option<JoinHandle<()>>,
+}
+
+impl LogFile {
+    /// Open a log file for appending, creating it if it does not exist.
+    pub fn open(options: LogFileOptions) -> io::Result<Self> {
+        let file = append(&options)?;
+        let metadata = file.metadata()?;
+        let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+        let mut log_file = Self {
+            size: metadata.len(),
+            period: options.rotation.period(modified),
+            file,
+            options,
+            compressing: None,
+        };
+        // Finish compressing a file that was rotated before the last exit.
+        if suffixed(&log_file.options.path, ".rotated").exists() {
+            log_file.start_compressing();
+        }
+        Ok(log_file)
+    }
+
+    /// Move the current file aside to be compressed, and start a new one.
+    ///
+    /// This runs while logging is blocked, so only renames the file here.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        // Compressing the previous file only takes this long if rotations
+        // happen very quickly, since it started a whole period ago.
+        self.finish_compressing();
+        if self.options.keep > 0 {
+            fs::rename(&self.options.path, suffixed(&self.options.path, ".rotated"))?;
+        } else {
+            fs::remove_file(&self.options.path)?;
+        }
+        self.file = append(&self.options)?;
+        self.size = 0;
+        if self.options.keep > 0 {
+            self.start_compressing();
+        }
+        Ok(())
+    }
+
+    /// Compress the rotated file on a background thread.
+    fn start_compressing(&mut self) {
+        let options = self.options.clone();
+        self.compressing = Some(thread::spawn(move || {
+            if let Err(err) = compress(&options) {
+                eprintln!("failed to compress rotated log file: {err}");
+            }
+        }));
+    }
+
+    /// Wait for the rotated file to be compressed, if it is in progress.
+    fn finish_compressing(&mut self) {
+        if let Some(compressing) = self.compressing.take() {
+            compressing.join().ok();
+        }
+    }
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        self.finish_compressing();
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let period = self.options.rotation.period(SystemTime::now());
+        let too_large = match self.options.max_size {
+            Some(max_size) => self.size > 0 && self.size + buf.len() as u64 > max_size,
+            None => false,
+        };
+        if period != self.period || too_large {
+            // Keep writing to the current file if rotation fails, so that
+            // logs are not lost.
+            if let Err(err) = self.rotate() {
+                eprintln!("failed to rotate log file: {err}");
+            }
+            self.period = period;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn append(options: &LogFileOptions) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&options.path)
+}
+
+/// Returns the path of the log file with a suffix appended.
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    path.into()
+}
+
+/// Shift the compressed files up by one, and compress the rotated file as the
+/// most recent of them.
+fn compress(options: &LogFileOptions) -> io::Result<()> {
+    let archive_path = |n: usize| suffixed(&options.path, &format!(".{n}.gz"));
+    for n in (1..options.keep).rev() {
+        let from = archive_path(n);
+        if from.exists() {
+            fs::rename(from, archive_path(n + 1))?;
+        }
+    }
+    let rotated = suffixed(&options.path, ".rotated");
+    let mut input = File::open(&rotated)?;
+    let output = File::create(archive_path(1))?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(rotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use flate2::read::GzDecoder;
+
+    use super::{LogFile, LogFileOptions, Rotation};
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = std::env::temp_dir().join(format!("sshx-logfile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut file = LogFile::open(LogFileOptions {
+            path: dir.join("sshx.log"),
+            max_size: Some(10),
+            rotation: Rotation::Never,
+            keep: 2,
+        })
+        .unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        // Dropping the file waits for compression to finish.
+        drop(file);
+
+        let read_archive = |n: usize| {
+            let gz = std::fs::File::open(dir.join(format!("sshx.log.{n}.gz"))).unwrap();
+            let mut text = String::new();
+            GzDecoder::new(gz).read_to_string(&mut text).unwrap();
+            text
+        };
+        assert_eq!(
+            std::fs::read_to_string(dir.join("sshx.log")).unwrap(),
+            "fourth\n"
+        );
+        assert_eq!(read_archive(1), "third\n");
+        assert_eq!(read_archive(2), "second\n");
+        assert!(!dir.join("sshx.log.3.gz").exists());
+        assert!(!dir.join("sshx.log.rotated").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
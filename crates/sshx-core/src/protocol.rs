@@ -0,0 +1,500 @@
+//! Serializable types of the real-time protocol between the web server and its
+//! WebSocket clients, such as the browser app.
+//!
+//! Messages are encoded as CBOR by default. WebSocket clients can negotiate the
+//! `sshx-json` subprotocol to exchange JSON text messages instead, where binary
+//! data is represented as an array of bytes.
+
+use std::str::FromStr;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{Sid, Uid};
+
+/// Version of the real-time protocol implemented by the server.
+///
+/// Increment this when making incompatible changes to [`WsServer`] or
+/// [`WsClient`], so that stale clients are told to reload.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version that the server still accepts from clients.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Names of optional features that a server can advertise to its clients, so
+/// that they can detect support without comparing versions.
+pub mod features {
+    /// Closed shells are reported to users with [`WsServer::ShellClosed`].
+    ///
+    /// [`WsServer::ShellClosed`]: super::WsServer::ShellClosed
+    pub const SHELL_CLOSED: &str = "shellClosed";
+
+    /// Auxiliary data streams are relayed between the client and users.
+    pub const STREAMS: &str = "streams";
+
+    /// Terminal data can be subscribed to with the times it was produced, in
+    /// [`WsServer::TimedChunks`].
+    ///
+    /// [`WsServer::TimedChunks`]: super::WsServer::TimedChunks
+    pub const TIMESTAMPS: &str = "timestamps";
+
+    /// Command-line clients can track which users are in the session, with
+    /// [`client_features::USER_PRESENCE`].
+    ///
+    /// [`client_features::USER_PRESENCE`]: crate::proto::client_features::USER_PRESENCE
+    pub const USER_PRESENCE: &str = "userPresence";
+
+    /// Output rates of shells are sent to users with [`WsServer::Throughput`].
+    ///
+    /// [`WsServer::Throughput`]: super::WsServer::Throughput
+    pub const THROUGHPUT: &str = "throughput";
+
+    /// Users have colors and avatars, which are set with
+    /// [`WsClient::SetProfile`].
+    ///
+    /// [`WsClient::SetProfile`]: super::WsClient::SetProfile
+    pub const PROFILES: &str = "profiles";
+
+    /// Users can share the part of the canvas that they are looking at with
+    /// [`WsClient::SetViewport`], so that others can follow them.
+    ///
+    /// [`WsClient::SetViewport`]: super::WsClient::SetViewport
+    pub const VIEWPORTS: &str = "viewports";
+
+    /// Sessions have shared notes, sent with [`WsServer::Notes`] and edited
+    /// with [`WsClient::NotesEdit`].
+    ///
+    /// [`WsServer::Notes`]: super::WsServer::Notes
+    /// [`WsClient::NotesEdit`]: super::WsClient::NotesEdit
+    pub const NOTES: &str = "notes";
+
+    /// Command-line clients can suggest commands and links to users, which
+    /// are sent with [`WsServer::Palette`].
+    ///
+    /// [`WsServer::Palette`]: super::WsServer::Palette
+    pub const PALETTE: &str = "palette";
+
+    /// Command-line clients report shells that stop responding to input, which
+    /// are sent to users with [`WsServer::ShellHealth`].
+    ///
+    /// [`WsServer::ShellHealth`]: super::WsServer::ShellHealth
+    pub const SHELL_HEALTH: &str = "shellHealth";
+
+    /// Command-line clients can share the color scheme of their terminal,
+    /// which is sent with [`WsServer::ColorScheme`].
+    ///
+    /// [`WsServer::ColorScheme`]: super::WsServer::ColorScheme
+    pub const COLOR_SCHEME: &str = "colorScheme";
+
+    /// Command-line clients can share the environment of their shells for
+    /// debugging, which is sent with [`WsServer::Environment`].
+    ///
+    /// [`WsServer::Environment`]: super::WsServer::Environment
+    pub const ENVIRONMENT: &str = "environment";
+
+    /// Sessions are bound to a key pair of the command-line client, which
+    /// signs the hello of every Channel() request, so a stolen token is not
+    /// enough to connect as the client.
+    pub const CLIENT_KEYS: &str = "clientKeys";
+}
+
+/// Codes that the server closes WebSocket connections with, so that frontends
+/// and the headless viewer can tell failures apart without parsing reasons.
+///
+/// Codes from 4000 follow the HTTP status with the same last three digits.
+pub mod close_codes {
+    /// A message was larger than the server accepts.
+    pub const MESSAGE_TOO_LARGE: u16 = 1009;
+
+    /// The encryption key or write password was not correct.
+    pub const UNAUTHORIZED: u16 = 4401;
+
+    /// The join link was not valid for this session.
+    pub const FORBIDDEN: u16 = 4403;
+
+    /// The requested session does not exist.
+    pub const NOT_FOUND: u16 = 4404;
+
+    /// The client did not answer pings in time.
+    pub const TIMEOUT: u16 = 4408;
+
+    /// The join link expired while the user was connected.
+    pub const EXPIRED: u16 = 4410;
+
+    /// The protocol version of the client is not supported, so it should be
+    /// reloaded.
+    pub const UPGRADE_REQUIRED: u16 = 4426;
+
+    /// The client sent too many messages.
+    pub const RATE_LIMITED: u16 = 4429;
+
+    /// The server failed to connect to the session.
+    pub const INTERNAL_ERROR: u16 = 4500;
+
+    /// The server is restarting, so the client should reconnect.
+    pub const UNAVAILABLE: u16 = 4503;
+
+    /// The session used up its bandwidth limit.
+    pub const BANDWIDTH_EXCEEDED: u16 = 4509;
+}
+
+/// Optional features and limits of the server, sent in [`WsServer::Hello`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsCapabilities {
+    /// Names of supported optional features, from [`features`].
+    pub features: Vec<String>,
+    /// Largest message that the server accepts, in bytes.
+    pub max_message_size: u32,
+}
+
+impl WsCapabilities {
+    /// Returns whether the server supports an optional feature.
+    pub fn has(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+fn first_version() -> u32 {
+    1
+}
+
+/// Real-time message conveying the position and size of a terminal.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsWinsize {
+    /// The top-left x-coordinate of the window, offset from origin.
+    pub x: i32,
+    /// The top-left y-coordinate of the window, offset from origin.
+    pub y: i32,
+    /// The number of rows in the window.
+    pub rows: u16,
+    /// The number of columns in the terminal.
+    pub cols: u16,
+}
+
+impl Default for WsWinsize {
+    fn default() -> Self {
+        WsWinsize {
+            x: 0,
+            y: 0,
+            rows: 24,
+            cols: 80,
+        }
+    }
+}
+
+/// Real-time message providing information about a user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsUser {
+    /// The user's display name.
+    pub name: String,
+    /// Live coordinates of the mouse cursor, if available.
+    pub cursor: Option<(i32, i32)>,
+    /// Currently focused terminal window ID.
+    pub focus: Option<Sid>,
+    /// Whether the user has write permissions in the session.
+    pub can_write: bool,
+    /// Index of the user's color in a palette, assigned by the server so that
+    /// it is stable and differs from the other users present when joining.
+    #[serde(default)]
+    pub color: u32,
+    /// Short text shown in place of the user's initials, like an emoji.
+    #[serde(default)]
+    pub avatar: Option<String>,
+    /// Part of the canvas that the user is looking at, if they share it.
+    #[serde(default)]
+    pub viewport: Option<WsViewport>,
+}
+
+/// Real-time message describing the part of the canvas that a user sees.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsViewport {
+    /// The x-coordinate of the canvas at the corner of the view.
+    pub x: i32,
+    /// The y-coordinate of the canvas at the corner of the view.
+    pub y: i32,
+    /// Zoom level of the view, as a percentage.
+    pub zoom: u32,
+}
+
+/// Profile of a user, which they can change with [`WsClient::SetProfile`].
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsProfile {
+    /// The user's display name, left unchanged if empty.
+    pub name: String,
+    /// Short text shown in place of the user's initials, if any.
+    pub avatar: Option<String>,
+}
+
+/// Real-time message describing why a shell was closed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsShellClosed {
+    /// Exit code of the shell process, if it exited.
+    pub exit_code: Option<i32>,
+    /// Signal that terminated the shell process, if any.
+    pub signal: Option<i32>,
+    /// Human-readable reason for closing the shell.
+    pub reason: String,
+}
+
+/// Real-time message describing whether a shell responds to input.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsShellHealth {
+    /// Input has been pending for a while without any output from the shell.
+    pub stalled: bool,
+    /// The client restarted the shell process after it stalled.
+    pub restarted: bool,
+}
+
+/// Shared notes of a session, which are end-to-end encrypted.
+///
+/// The notes are kept as a log of edits, each made by a client on top of an
+/// earlier version. Clients transform concurrent edits against each other in
+/// the order of the log, since the server cannot read them.
+///
+/// The text and each edit are encrypted on stream `0x500000000`, from a
+/// random offset that is prepended to the ciphertext as 8 big-endian bytes.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsNotes {
+    /// Version of the text, which is the number of edits that it includes.
+    pub version: u64,
+    /// Encrypted text of the notes at this version, if it was ever compacted.
+    pub text: Option<Bytes>,
+    /// Encrypted edits after the text, in order.
+    pub edits: Vec<WsNotesEdit>,
+}
+
+/// An encrypted edit to the shared notes of a session.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WsNotesEdit {
+    /// ID of the user who made the edit.
+    pub user: Uid,
+    /// Version of the notes that the edit was made on top of.
+    pub base: u64,
+    /// Encrypted contents of the edit.
+    pub data: Bytes,
+}
+
+/// A command or link suggested to users by the command-line client.
+///
+/// The client sends a JSON list of these, encrypted on stream `0x600000000`,
+/// so that users can paste commands into a shell or open links with a click.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PaletteItem {
+    /// Short description shown to users.
+    pub label: String,
+    /// Command to paste into the focused shell, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    /// URL to open in a new tab, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+/// Color scheme of the command-line client's terminal, so that the web
+/// renderer can default to matching colors.
+///
+/// The client sends this as JSON, encrypted on stream `0x700000000`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ColorScheme {
+    /// Light text on a dark background.
+    Dark,
+    /// Dark text on a light background.
+    Light,
+}
+
+impl ColorScheme {
+    /// Detect the color scheme from the `COLORFGBG` environment variable set
+    /// by some terminals, like `15;0` or `0;default;15`, where the last field
+    /// is the ANSI color index of the background.
+    pub fn from_colorfgbg(value: &str) -> Option<Self> {
+        let background: u8 = value.rsplit(';').next()?.parse().ok()?;
+        match background {
+            0..=6 | 8 => Some(ColorScheme::Dark),
+            7 | 9..=15 => Some(ColorScheme::Light),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ColorScheme {
+    type Err = String;
+
+    /// Parse `dark` or `light`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dark" => Ok(ColorScheme::Dark),
+            "light" => Ok(ColorScheme::Light),
+            _ => Err(format!("expected dark or light, got {s:?}")),
+        }
+    }
+}
+
+/// Environment that shells of the command-line client start with, so that
+/// users can debug why a prompt looks different in sshx.
+///
+/// The client sends this as JSON, encrypted on stream `0x800000000`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ShellEnvironment {
+    /// Program that is run for new shells.
+    pub shell: String,
+    /// Value of `TERM` for new shells.
+    pub term: String,
+    /// Environment variables of new shells, sorted by name. Values that look
+    /// like secrets are redacted.
+    pub vars: Vec<(String, String)>,
+}
+
+/// Kind of data carried by an auxiliary stream, alongside terminal data.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum WsStreamKind {
+    /// Transfer of a file.
+    File,
+    /// Connection to a forwarded network port.
+    Port,
+    /// Contents of a clipboard.
+    Clipboard,
+    /// System metrics from the client.
+    Metrics,
+}
+
+/// A real-time message sent from the server over WebSocket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum WsServer {
+    /// Initial server message, with the user's ID, session metadata, the
+    /// server's protocol version, and its capabilities. Servers from before
+    /// versioning omit the version, which is treated as version 1, and servers
+    /// from before capabilities omit them, which means no optional features.
+    Hello(
+        Uid,
+        String,
+        #[serde(default = "first_version")] u32,
+        #[serde(default)] WsCapabilities,
+    ),
+    /// The user's authentication was invalid.
+    InvalidAuth(),
+    /// A snapshot of all current users in the session.
+    Users(Vec<(Uid, WsUser)>),
+    /// Info about a single user in the session: joined, left, or changed.
+    UserDiff(Uid, Option<WsUser>),
+    /// Notification when the set of open shells has changed.
+    Shells(Vec<(Sid, WsWinsize)>),
+    /// Subscription results, in the form of terminal data chunks.
+    Chunks(Sid, u64, Vec<Bytes>),
+    /// Subscription results like [`Chunks`], with the time that each chunk was
+    /// produced in milliseconds since the Unix epoch, or 0 if unknown.
+    ///
+    /// [`Chunks`]: WsServer::Chunks
+    TimedChunks(Sid, u64, Vec<Bytes>, Vec<u64>),
+    /// Get a chat message tuple `(uid, name, text)` from the room.
+    Hear(Uid, String, String),
+    /// Forward a latency measurement between the server and backend shell.
+    ShellLatency(u64),
+    /// Echo back a timestamp, for the the client's own latency measurement.
+    Pong(u64),
+    /// Alert the client of an application error.
+    Error(String),
+    /// Announcement from the server operator, shown to all users.
+    Notice(String),
+    /// Standing message from the server operator, sent once after [`Hello`]
+    /// when configured, such as a legal notice.
+    ///
+    /// [`Hello`]: WsServer::Hello
+    Banner(String),
+    /// A shell was closed, with its exit status if it exited on its own.
+    ShellClosed(Sid, WsShellClosed),
+    /// Terminal input to a shell was dropped for exceeding the rate limit, with
+    /// the number of milliseconds until more input is accepted.
+    Throttled(Sid, u64),
+    /// Data on an auxiliary stream from the client, with its stream ID, kind,
+    /// and encryption offset.
+    Stream(u32, WsStreamKind, Bytes, u64),
+    /// Periodic output rate of each open shell, in bytes per second over the
+    /// last few seconds, for charting activity.
+    Throughput(Vec<(Sid, u64)>),
+    /// All shared notes of the session, sent after joining and whenever an
+    /// edit from this user was too old to be added.
+    Notes(WsNotes),
+    /// An edit was added to the shared notes, with its version after the edit.
+    NotesEdit(u64, WsNotesEdit),
+    /// Encrypted list of [`PaletteItem`] suggested by the client, with its
+    /// encryption offset. Sent after joining and whenever the list changes.
+    Palette(Bytes, u64),
+    /// The health of a shell changed. Sent after joining for stalled shells.
+    ShellHealth(Sid, WsShellHealth),
+    /// Encrypted [`ColorScheme`] of the client's terminal, sent after joining
+    /// if the client shared one.
+    ColorScheme(Bytes),
+    /// Encrypted [`ShellEnvironment`] of the client, sent after joining if the
+    /// client shared it.
+    Environment(Bytes),
+}
+
+/// A record in a session transcript, which is stored as a CBOR sequence.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum TranscriptRecord {
+    /// First record, with the session name and encrypted zeros block.
+    Header(String, Bytes),
+    /// Retained data of a shell, encrypted just like [`WsServer::Chunks`].
+    Chunks(Sid, u64, Vec<Bytes>),
+    /// Retained data of a shell with the time of each chunk, like
+    /// [`WsServer::TimedChunks`], when requested with the transcript.
+    TimedChunks(Sid, u64, Vec<Bytes>, Vec<u64>),
+}
+
+/// A real-time message sent from the client over WebSocket.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum WsClient {
+    /// Authenticate the user's encryption key by zeros block and write password
+    /// (if provided), with the client's protocol version. Clients from before
+    /// versioning omit the version, which is treated as version 1.
+    Authenticate(Bytes, Option<Bytes>, #[serde(default)] Option<u32>),
+    /// Set the name of the current user.
+    SetName(String),
+    /// Set the name and avatar of the current user.
+    SetProfile(WsProfile),
+    /// Send real-time information about the user's cursor.
+    SetCursor(Option<(i32, i32)>),
+    /// Set the currently focused shell.
+    SetFocus(Option<Sid>),
+    /// Share the part of the canvas in view, or stop sharing it.
+    SetViewport(Option<WsViewport>),
+    /// Create a new shell.
+    Create(i32, i32),
+    /// Close a specific shell.
+    Close(Sid),
+    /// Move a shell window to a new position and focus it.
+    Move(Sid, Option<WsWinsize>),
+    /// Add user data to a given shell.
+    Data(Sid, Bytes, u64),
+    /// Subscribe to a shell, starting at a given chunk index. If the flag is
+    /// set, chunks are sent as [`WsServer::TimedChunks`] with their times.
+    Subscribe(Sid, u64, #[serde(default)] bool),
+    /// Send a a chat message to the room.
+    Chat(String),
+    /// Send a ping to the server, for latency measurement.
+    Ping(u64),
+    /// Send data on an auxiliary stream to the client, with its stream ID,
+    /// kind, and encryption offset.
+    Stream(u32, WsStreamKind, Bytes, u64),
+    /// Add an encrypted edit to the shared notes, on top of a version.
+    NotesEdit(u64, Bytes),
+    /// Replace the edits up to a version of the shared notes with the
+    /// encrypted text at that version, so that the log stays small.
+    NotesCompact(u64, Bytes),
+}
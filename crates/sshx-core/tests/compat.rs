@@ -0,0 +1,198 @@
+//! Compatibility with messages and snapshots serialized by previous releases.
+//!
+//! Each directory in `tests/fixtures` holds samples written by the release it
+//! is named after: CBOR sequences of WebSocket messages, and a session snapshot
+//! as stored in Redis. If a test here fails, the change would break clients or
+//! servers that are still running that release.
+
+use std::fs;
+use std::path::PathBuf;
+
+use prost::Message;
+use serde::{de::DeserializeOwned, Serialize};
+use sshx_core::proto::SerializedSession;
+use sshx_core::protocol::{WsCapabilities, WsClient, WsServer};
+use sshx_core::{Sid, Uid};
+
+fn fixture(version: &str, name: &str) -> Vec<u8> {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(version)
+        .join(name);
+    fs::read(&path).unwrap_or_else(|err| panic!("failed to read {}: {err}", path.display()))
+}
+
+/// Split a CBOR sequence into the encoded bytes of each item.
+fn split_cbor_seq(data: &[u8]) -> Vec<&[u8]> {
+    let mut items = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let start = data.len() - rest.len();
+        let _: ciborium::Value = ciborium::de::from_reader(&mut rest).unwrap();
+        items.push(&data[start..data.len() - rest.len()]);
+    }
+    items
+}
+
+fn to_cbor(value: &impl Serialize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(value, &mut buf).unwrap();
+    buf
+}
+
+/// Decode a message, checking that it is encoded the same way again unless it
+/// has gained fields since the fixture was written.
+fn decode<T: Serialize + DeserializeOwned>(item: &[u8], changed: bool) -> T {
+    let msg: T = ciborium::de::from_reader(item).unwrap();
+    if !changed {
+        assert_eq!(to_cbor(&msg), item, "message is encoded differently");
+    }
+    msg
+}
+
+#[test]
+fn ws_server_v0_3_1() {
+    let data = fixture("v0.3.1", "ws_server.cbor");
+    let items = split_cbor_seq(&data);
+    assert_eq!(items.len(), 10);
+
+    match decode(items[0], true) {
+        WsServer::Hello(user_id, name, version, capabilities) => {
+            assert_eq!(user_id, Uid(1));
+            assert_eq!(name, "user@host");
+            assert_eq!(version, 1);
+            assert_eq!(capabilities, WsCapabilities::default());
+        }
+        msg => panic!("unexpected message: {msg:?}"),
+    }
+    assert!(matches!(decode(items[1], false), WsServer::InvalidAuth()));
+    match decode(items[2], true) {
+        WsServer::Users(users) => {
+            assert_eq!(users.len(), 1);
+            assert_eq!(users[0].0, Uid(1));
+            assert_eq!(users[0].1.name, "alice");
+            assert_eq!(users[0].1.cursor, Some((10, -20)));
+            assert_eq!(users[0].1.focus, Some(Sid(2)));
+            assert!(users[0].1.can_write);
+            assert_eq!((users[0].1.color, &users[0].1.avatar), (0, &None));
+        }
+        msg => panic!("unexpected message: {msg:?}"),
+    }
+    assert!(matches!(
+        decode(items[3], false),
+        WsServer::UserDiff(Uid(2), None)
+    ));
+    match decode(items[4], false) {
+        WsServer::Shells(shells) => {
+            assert_eq!(shells.len(), 1);
+            assert_eq!(shells[0].0, Sid(1));
+            assert_eq!(shells[0].1.x, 10);
+            assert_eq!(shells[0].1.y, -20);
+            assert_eq!((shells[0].1.rows, shells[0].1.cols), (24, 80));
+        }
+        msg => panic!("unexpected message: {msg:?}"),
+    }
+    match decode(items[5], false) {
+        WsServer::Chunks(id, seqnum, chunks) => {
+            assert_eq!((id, seqnum), (Sid(1), 42));
+            assert_eq!(chunks, [&b"\x01\x02\x03"[..], b"\xff"]);
+        }
+        msg => panic!("unexpected message: {msg:?}"),
+    }
+    match decode(items[6], false) {
+        WsServer::Hear(id, name, msg) => {
+            assert_eq!(
+                (id, name.as_str(), msg.as_str()),
+                (Uid(1), "alice", "hello")
+            );
+        }
+        msg => panic!("unexpected message: {msg:?}"),
+    }
+    assert!(matches!(
+        decode(items[7], false),
+        WsServer::ShellLatency(15)
+    ));
+    assert!(matches!(
+        decode(items[8], false),
+        WsServer::Pong(1700000000000)
+    ));
+    assert!(matches!(decode(items[9], false), WsServer::Error(err) if err == "oops"));
+}
+
+#[test]
+fn ws_client_v0_3_1() {
+    let data = fixture("v0.3.1", "ws_client.cbor");
+    let items = split_cbor_seq(&data);
+    assert_eq!(items.len(), 11);
+
+    match decode(items[0], true) {
+        WsClient::Authenticate(zeros, write_zeros, version) => {
+            assert_eq!(zeros, [7; 16][..]);
+            assert_eq!(write_zeros.as_deref(), Some(&[9; 16][..]));
+            assert_eq!(version, None);
+        }
+        msg => panic!("unexpected message: {msg:?}"),
+    }
+    assert!(matches!(decode(items[1], false), WsClient::SetName(name) if name == "bob"));
+    assert!(matches!(
+        decode(items[2], false),
+        WsClient::SetCursor(Some((1, 2)))
+    ));
+    assert!(matches!(
+        decode(items[3], false),
+        WsClient::SetFocus(Some(Sid(1)))
+    ));
+    assert!(matches!(
+        decode(items[4], false),
+        WsClient::Create(100, -50)
+    ));
+    assert!(matches!(decode(items[5], false), WsClient::Close(Sid(3))));
+    match decode(items[6], false) {
+        WsClient::Move(id, Some(winsize)) => {
+            assert_eq!(id, Sid(1));
+            assert_eq!((winsize.x, winsize.y), (10, -20));
+            assert_eq!((winsize.rows, winsize.cols), (24, 80));
+        }
+        msg => panic!("unexpected message: {msg:?}"),
+    }
+    match decode(items[7], false) {
+        WsClient::Data(id, data, offset) => {
+            assert_eq!((id, &data[..], offset), (Sid(1), &b"ls\r"[..], 12345));
+        }
+        msg => panic!("unexpected message: {msg:?}"),
+    }
+    assert!(matches!(
+        decode(items[8], true),
+        WsClient::Subscribe(Sid(1), 7, false)
+    ));
+    assert!(matches!(decode(items[9], false), WsClient::Chat(msg) if msg == "hi"));
+    assert!(matches!(
+        decode(items[10], false),
+        WsClient::Ping(1700000000000)
+    ));
+}
+
+#[test]
+fn serialized_session_v0_3_1() {
+    let data = fixture("v0.3.1", "session.bin");
+    let session = SerializedSession::decode(&*data).unwrap();
+    assert_eq!(
+        session.encode_to_vec(),
+        data,
+        "snapshot is encoded differently"
+    );
+
+    assert_eq!(session.encrypted_zeros, [7; 16][..]);
+    assert_eq!(session.next_ids(), (Sid(2), Uid(3)));
+    assert_eq!(session.name, "user@host");
+    assert_eq!(session.write_password_hash.as_deref(), Some(&[9; 16][..]));
+
+    assert_eq!(session.shells.len(), 1);
+    let shell = &session.shells[&1];
+    assert_eq!(shell.seqnum, 11);
+    assert_eq!(shell.data, [&b"hello"[..], b" world"]);
+    assert_eq!((shell.chunk_offset, shell.byte_offset), (0, 0));
+    assert!(!shell.closed);
+    assert_eq!((shell.winsize_x, shell.winsize_y), (10, -20));
+    assert_eq!((shell.winsize_rows, shell.winsize_cols), (24, 80));
+}
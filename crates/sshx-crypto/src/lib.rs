@@ -1,7 +1,22 @@
 //! Encryption of byte streams based on a random key.
+//!
+//! This crate has no dependencies on the operating system, so it builds for
+//! `wasm32-unknown-unknown` and the web app can use the exact same Argon2 and
+//! AES-CTR code as the command-line client. Together with the ID types and
+//! `protocol` module of `sshx-core` without default features, it makes up the
+//! part of sshx that is shared with the browser.
+//!
+//! With the `wasm` feature, it exports JavaScript bindings. The web app loads
+//! them from `src/lib/wasm`, which is generated by `scripts/build-wasm.sh`.
+
+#![cfg_attr(not(feature = "wasm"), forbid(unsafe_code))]
+#![warn(missing_docs)]
 
 use aes::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
 
+#[cfg(feature = "wasm")]
+mod wasm;
+
 type Aes128Ctr64BE = ctr::Ctr64BE<aes::Aes128>;
 
 // Note: The KDF salt is public, as it needs to be used from the web client. It
@@ -58,9 +73,32 @@ impl Encrypt {
     }
 }
 
+/// Length of the random salt of a write password verifier, in bytes.
+pub const VERIFIER_SALT_LEN: usize = 16;
+
+/// Hash the encrypted zero block of a write password with a random salt, into
+/// the verifier that the server stores for a session.
+///
+/// The zero block is already the output of a slow hash, so this uses lighter
+/// parameters than [`Encrypt::new`]. It keeps the stored verifier from being
+/// usable as a write password, if the server's storage is leaked.
+pub fn hash_verifier(zeros: &[u8], salt: &[u8]) -> Vec<u8> {
+    use argon2::{Algorithm, Argon2, Params, Version};
+    let hasher = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(4096, 1, 1, Some(32)).unwrap(),
+    );
+    let mut hash = vec![0; 32];
+    hasher
+        .hash_password_into(zeros, salt, &mut hash)
+        .expect("failed to hash verifier with argon2");
+    hash
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Encrypt;
+    use super::{hash_verifier, Encrypt, VERIFIER_SALT_LEN};
 
     #[test]
     fn make_encrypt() {
@@ -93,6 +131,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn verifier_depends_on_salt() {
+        let zeros = Encrypt::new("write password").zeros();
+        let hash = hash_verifier(&zeros, &[1; VERIFIER_SALT_LEN]);
+        assert_eq!(hash.len(), 32);
+        assert_eq!(hash, hash_verifier(&zeros, &[1; VERIFIER_SALT_LEN]));
+        assert_ne!(hash, hash_verifier(&zeros, &[2; VERIFIER_SALT_LEN]));
+        assert_ne!(hash, zeros);
+    }
+
     #[test]
     #[should_panic]
     fn zero_stream_num() {
@@ -0,0 +1,29 @@
+//! JavaScript bindings for encryption in the web app.
+
+use wasm_bindgen::prelude::*;
+
+/// Encrypts byte streams using the Argon2 hash of a random key.
+#[wasm_bindgen(js_name = Encrypt)]
+pub struct JsEncrypt(crate::Encrypt);
+
+#[wasm_bindgen(js_class = Encrypt)]
+impl JsEncrypt {
+    /// Construct a new encryptor.
+    #[wasm_bindgen(constructor)]
+    pub fn new(key: &str) -> Self {
+        Self(crate::Encrypt::new(key))
+    }
+
+    /// Get the encrypted zero block.
+    pub fn zeros(&self) -> Vec<u8> {
+        self.0.zeros()
+    }
+
+    /// Encrypt a segment of data from a stream.
+    pub fn segment(&self, stream_num: u64, offset: u64, data: &[u8]) -> Result<Vec<u8>, JsError> {
+        if stream_num == 0 {
+            return Err(JsError::new("stream number must be nonzero"));
+        }
+        Ok(self.0.segment(stream_num, offset, data))
+    }
+}
@@ -0,0 +1,74 @@
+//! Benchmarks for fanning out terminal data from a shell to many subscribers.
+//!
+//! Each iteration adds 1 MiB of data to a shell in chunks of a fixed size,
+//! while every subscriber reads it back with `subscribe_chunks()`.
+
+use std::pin::pin;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_util::StreamExt;
+use sshx_core::Sid;
+use sshx_server::session::{Metadata, Session};
+use tokio::runtime::Runtime;
+
+/// Bytes of terminal data added in each iteration, less than what is stored.
+const TOTAL_BYTES: u64 = 1 << 20;
+
+async fn fanout(subscribers: usize, chunk_size: u64) {
+    let session = Arc::new(Session::new(Metadata {
+        encrypted_zeros: Bytes::new(),
+        name: String::new(),
+        write_password_hash: None,
+        write_password_salt: None,
+        color_scheme: None,
+        environment: None,
+        client_key: None,
+        token_nonce: Bytes::new(),
+    }));
+    session.add_shell(Sid(1), (0, 0)).unwrap();
+
+    let tasks: Vec<_> = (0..subscribers)
+        .map(|_| {
+            let session = Arc::clone(&session);
+            tokio::spawn(async move {
+                let mut chunks = pin!(session.subscribe_chunks(Sid(1), 0));
+                let mut end = 0;
+                while end < TOTAL_BYTES {
+                    let (seqnum, data, _) = chunks.next().await.unwrap();
+                    end = seqnum + data.iter().map(|d| d.len() as u64).sum::<u64>();
+                }
+            })
+        })
+        .collect();
+
+    let chunk = Bytes::from(vec![b'x'; chunk_size as usize]);
+    for seq in (0..TOTAL_BYTES).step_by(chunk_size as usize) {
+        session.add_data(Sid(1), chunk.clone(), seq, 0).unwrap();
+        tokio::task::yield_now().await;
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+fn bench_fanout(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("fanout");
+    group.sample_size(20);
+    for subscribers in [1, 10, 100] {
+        for chunk_size in [256, 4096, 65536] {
+            group.throughput(Throughput::Bytes(TOTAL_BYTES * subscribers as u64));
+            let id = BenchmarkId::new(format!("{subscribers}_subscribers"), chunk_size);
+            group.bench_with_input(id, &chunk_size, |b, &chunk_size| {
+                b.to_async(&runtime)
+                    .iter(|| fanout(subscribers, chunk_size));
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_fanout);
+criterion_main!(benches);
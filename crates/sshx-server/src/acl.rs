@@ -0,0 +1,110 @@
+//! Network access control based on the IP address of incoming connections.
+
+use std::net::IpAddr;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use ipnet::IpNet;
+
+/// Parse a CIDR network, also accepting a bare IP address as a single host.
+pub fn parse_cidr(s: &str) -> Result<IpNet, ipnet::AddrParseError> {
+    match s.parse::<IpAddr>() {
+        Ok(addr) => Ok(addr.into()),
+        Err(_) => s.parse(),
+    }
+}
+
+/// Set of CIDR allow and deny rules applied to remote addresses.
+///
+/// Deny rules take precedence over allow rules. If there are no allow rules,
+/// then every address that is not denied is allowed.
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Construct a new filter from lists of allowed and denied networks.
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>) -> Self {
+        Self { allow, deny }
+    }
+
+    /// Add rules from a file, in addition to the existing rules.
+    ///
+    /// Each non-empty line of the file has the form `allow <cidr>` or
+    /// `deny <cidr>`, and comments start with `#`.
+    pub fn load_file(&mut self, path: &Path) -> Result<()> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read IP rules from {}", path.display()))?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((action, cidr)) = line.split_once(char::is_whitespace) else {
+                bail!(
+                    "{}:{}: expected `allow <cidr>` or `deny <cidr>`",
+                    path.display(),
+                    i + 1
+                );
+            };
+            let net = parse_cidr(cidr.trim())
+                .with_context(|| format!("{}:{}: invalid network", path.display(), i + 1))?;
+            match action {
+                "allow" => self.allow.push(net),
+                "deny" => self.deny.push(net),
+                _ => bail!("{}:{}: unknown action {action:?}", path.display(), i + 1),
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether this filter has any rules.
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Check whether a remote address is allowed to connect.
+    pub fn is_allowed(&self, addr: IpAddr) -> bool {
+        // Dual-stack listeners report IPv4 clients as IPv4-mapped IPv6 addresses.
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+            IpAddr::V4(_) => addr,
+        };
+        if self.deny.iter().any(|net| net.contains(&addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cidr, IpFilter};
+
+    #[test]
+    fn allow_and_deny() {
+        let filter = IpFilter::new(
+            vec![
+                parse_cidr("10.0.0.0/8").unwrap(),
+                parse_cidr("::1").unwrap(),
+            ],
+            vec![parse_cidr("10.1.0.0/16").unwrap()],
+        );
+        assert!(filter.is_allowed("10.2.3.4".parse().unwrap()));
+        assert!(!filter.is_allowed("10.1.3.4".parse().unwrap()));
+        assert!(!filter.is_allowed("192.168.0.1".parse().unwrap()));
+        assert!(filter.is_allowed("::1".parse().unwrap()));
+        assert!(filter.is_allowed("::ffff:10.2.3.4".parse().unwrap()));
+        assert!(!filter.is_allowed("::ffff:10.1.3.4".parse().unwrap()));
+    }
+
+    #[test]
+    fn empty_allows_all() {
+        let filter = IpFilter::default();
+        assert!(filter.is_empty());
+        assert!(filter.is_allowed("1.2.3.4".parse().unwrap()));
+        assert!(filter.is_allowed("2001:db8::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,181 @@
+//! Append-only audit log of security-relevant events on the server.
+
+use std::convert::Infallible;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use parking_lot::Mutex;
+use serde::Serialize;
+use sshx_core::Uid;
+use tracing::error;
+
+use crate::state::mesh::StorageMesh;
+
+/// An event recorded in the audit log.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A new session was created by a command-line client.
+    SessionCreated {
+        /// Name of the session.
+        session: String,
+    },
+    /// A session was closed permanently.
+    SessionClosed {
+        /// Name of the session.
+        session: String,
+        /// Why the session was closed, such as `client` or `expired`.
+        reason: String,
+    },
+    /// A web user authenticated to a session.
+    UserAuthenticated {
+        /// Name of the session.
+        session: String,
+        /// ID of the user within the session.
+        user_id: Uid,
+        /// Whether the user was granted write access.
+        can_write: bool,
+        /// Subject identifier of the viewer, if logged in with OpenID Connect.
+        subject: Option<String>,
+    },
+    /// A web user failed to authenticate to a session.
+    AuthFailed {
+        /// Name of the session.
+        session: String,
+    },
+    /// A key holder downloaded the encrypted transcript of a session.
+    TranscriptDownloaded {
+        /// Name of the session.
+        session: String,
+    },
+    /// An operator took an action through the admin API.
+    AdminAction {
+        /// Short name of the action, like `close_session`.
+        action: String,
+        /// Session that was the target of the action, if any.
+        session: Option<String>,
+    },
+    /// A request to the admin API had a missing or invalid token.
+    AdminAuthFailed,
+    /// A web viewer logged in with the OpenID Connect provider.
+    Login {
+        /// Subject identifier from the provider.
+        subject: String,
+        /// Email address of the user, if provided.
+        email: Option<String>,
+    },
+}
+
+/// The remote client responsible for an audited event.
+///
+/// This is attached to every request by the server, and is empty for events
+/// that are not caused by a client.
+#[derive(Clone, Debug, Default)]
+pub struct Peer {
+    /// IP address of the client, or `None` on a Unix domain socket.
+    pub ip: Option<IpAddr>,
+    /// Subject of the TLS client certificate, if the client presented one.
+    pub cert_subject: Option<String>,
+}
+
+#[axum::async_trait]
+impl<S: Sync> FromRequestParts<S> for Peer {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get::<Peer>().cloned().unwrap_or_default())
+    }
+}
+
+/// A single line of the audit log, as serialized to JSON.
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    /// Milliseconds since the Unix epoch.
+    ts: u64,
+    host: Option<&'a str>,
+    ip: Option<IpAddr>,
+    cert_subject: Option<&'a str>,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+/// Writes audit events as JSON lines to a file, a Redis stream, or both.
+///
+/// If neither destination is configured, events are discarded.
+#[derive(Default)]
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+    stream: Option<(StorageMesh, String)>,
+}
+
+impl AuditLog {
+    /// Create a new audit log with the given destinations.
+    ///
+    /// The file is opened in append mode and created if it does not exist.
+    pub fn new(path: Option<&Path>, stream: Option<(StorageMesh, String)>) -> Result<Self> {
+        let file = match path {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open audit log {}", path.display()))?;
+                Some(Mutex::new(file))
+            }
+            None => None,
+        };
+        Ok(Self { file, stream })
+    }
+
+    /// Returns whether any destination is configured for audit events.
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some() || self.stream.is_some()
+    }
+
+    /// Record an event, with the client that caused it if known.
+    pub fn record(&self, peer: &Peer, event: AuditEvent) {
+        if !self.is_enabled() {
+            return;
+        }
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let host = self.stream.as_ref().and_then(|(mesh, _)| mesh.host());
+        let record = AuditRecord {
+            ts,
+            host: host.as_deref(),
+            ip: peer.ip,
+            cert_subject: peer.cert_subject.as_deref(),
+            event: &event,
+        };
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(err) => {
+                error!(?err, "failed to serialize audit event");
+                return;
+            }
+        };
+
+        if let Some(file) = &self.file {
+            if let Err(err) = writeln!(file.lock(), "{line}") {
+                error!(?err, "failed to write to audit log");
+            }
+        }
+        if let Some((mesh, key)) = &self.stream {
+            let mesh = mesh.clone();
+            let key = key.clone();
+            tokio::spawn(async move {
+                if let Err(err) = mesh.append_stream(&key, &line).await {
+                    error!(?err, "failed to append to audit stream");
+                }
+            });
+        }
+    }
+}
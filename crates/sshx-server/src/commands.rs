@@ -0,0 +1,251 @@
+//! Subcommands for managing a running server from the shell.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context, Result};
+use clap::Subcommand;
+use prometheus::IntCounter;
+use reqwest::{Method, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sshx_server::report::ErrorReporter;
+use sshx_server::state::mesh::StorageMesh;
+
+/// Subcommands of the server binary.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Manage the sessions of a running server.
+    Sessions {
+        /// URL of the running server, instead of the first address that the
+        /// options say it listens on.
+        #[clap(long)]
+        server: Option<String>,
+
+        #[clap(subcommand)]
+        command: SessionsCommand,
+    },
+    /// Show the latency from a running server to the others in its mesh.
+    Mesh {
+        /// URL of the running server, instead of the first address that the
+        /// options say it listens on.
+        #[clap(long)]
+        server: Option<String>,
+    },
+}
+
+/// Subcommands of `sshx-server sessions`.
+#[derive(Subcommand, Debug)]
+pub enum SessionsCommand {
+    /// List open sessions.
+    List,
+    /// Close a session, disconnecting its users and its client.
+    Close {
+        /// Name of the session, from its URL.
+        name: String,
+    },
+    /// Pin a session, so that it does not expire while its client is
+    /// disconnected.
+    Pin {
+        /// Name of the session, from its URL.
+        name: String,
+    },
+    /// Unpin a session, so that it expires like others.
+    Unpin {
+        /// Name of the session, from its URL.
+        name: String,
+    },
+    /// Show statistics about sessions.
+    Stats,
+}
+
+/// Way of reaching running servers to manage their sessions.
+pub enum Target {
+    /// The admin API of one server, at a URL with a bearer token.
+    Api { url: String, token: String },
+    /// The Redis instance shared by a mesh of servers.
+    Redis(StorageMesh),
+}
+
+impl Target {
+    /// Connect to the Redis instance at a URL.
+    pub fn redis(url: &str) -> Result<Self> {
+        let redis_errors = IntCounter::new("redis_errors", "Redis errors")?;
+        let mesh = StorageMesh::new(url, None, redis_errors, ErrorReporter::default())?;
+        Ok(Self::Redis(mesh))
+    }
+}
+
+/// Summary of a session from the admin API.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionSummary {
+    name: String,
+    users: usize,
+    shells: usize,
+    idle_secs: u64,
+}
+
+/// Statistics about a server from the admin API.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Stats {
+    host: Option<String>,
+    uptime_secs: u64,
+    sessions: usize,
+    users: usize,
+    ws_connections: i64,
+}
+
+/// Health of the mesh from the admin API.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MeshHealth {
+    host: Option<String>,
+    peers: Vec<MeshPeer>,
+}
+
+/// Latest results of probing another server from the admin API.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MeshPeer {
+    host: String,
+    latency_ms: Option<f64>,
+    last_ok_secs: Option<u64>,
+    failures: u32,
+    error: Option<String>,
+}
+
+/// Run `sshx-server mesh` through the admin API, printing its results.
+pub async fn run_mesh(url: &str, token: &str) -> Result<()> {
+    let mesh: MeshHealth = get(url, token, "/mesh").await?;
+    let Some(host) = mesh.host else {
+        bail!("the server is not part of a mesh");
+    };
+    println!("host: {host}");
+    println!(
+        "{:<24} {:>9} {:>8} {:>8}",
+        "PEER", "LATENCY", "LAST OK", "FAILURES"
+    );
+    for peer in mesh.peers {
+        let latency = peer
+            .latency_ms
+            .map_or("-".into(), |ms| format!("{ms:.1}ms"));
+        let last_ok = peer
+            .last_ok_secs
+            .map_or("-".into(), |secs| format!("{secs}s"));
+        println!(
+            "{:<24} {:>9} {:>8} {:>8}",
+            peer.host, latency, last_ok, peer.failures
+        );
+        if let Some(error) = peer.error {
+            println!("  {error}");
+        }
+    }
+    Ok(())
+}
+
+/// Run a subcommand of `sshx-server sessions`, printing its results.
+pub async fn run_sessions(target: Target, command: SessionsCommand) -> Result<()> {
+    match (target, command) {
+        (Target::Api { url, token }, SessionsCommand::List) => {
+            let sessions: Vec<SessionSummary> = get(&url, &token, "/sessions").await?;
+            println!(
+                "{:<12} {:>5} {:>6} {:>8}",
+                "NAME", "USERS", "SHELLS", "IDLE"
+            );
+            for s in sessions {
+                let idle = format!("{}s", s.idle_secs);
+                println!("{:<12} {:>5} {:>6} {:>8}", s.name, s.users, s.shells, idle);
+            }
+        }
+        (Target::Api { url, token }, SessionsCommand::Close { name }) => {
+            let path = format!("/sessions/{name}");
+            let resp = api(&url, &token, Method::DELETE, &path).await?;
+            if resp.status() == StatusCode::NOT_FOUND {
+                bail!("session {name} not found, or the admin API is not enabled");
+            }
+            println!("closed session {name}");
+        }
+        (Target::Api { url, token }, SessionsCommand::Pin { name }) => {
+            set_pinned(&url, &token, &name, true).await?;
+            println!("pinned session {name}");
+        }
+        (Target::Api { url, token }, SessionsCommand::Unpin { name }) => {
+            set_pinned(&url, &token, &name, false).await?;
+            println!("unpinned session {name}");
+        }
+        (Target::Api { url, token }, SessionsCommand::Stats) => {
+            let stats: Stats = get(&url, &token, "/stats").await?;
+            if let Some(host) = stats.host {
+                println!("host:           {host}");
+            }
+            println!("uptime:         {}s", stats.uptime_secs);
+            println!("sessions:       {}", stats.sessions);
+            println!("users:          {}", stats.users);
+            println!("ws connections: {}", stats.ws_connections);
+        }
+        (Target::Redis(mesh), SessionsCommand::List) => {
+            let sessions = mesh.list_sessions().await?;
+            println!("{:<12} OWNER", "NAME");
+            for (name, owner) in sessions {
+                println!("{name:<12} {}", owner.as_deref().unwrap_or("-"));
+            }
+        }
+        (Target::Redis(mesh), SessionsCommand::Close { name }) => {
+            mesh.mark_closed(&name).await?;
+            println!("closed session {name}");
+        }
+        (Target::Redis(_), SessionsCommand::Pin { .. } | SessionsCommand::Unpin { .. }) => {
+            bail!("pinning sessions needs the admin API, pass --admin-token");
+        }
+        (Target::Redis(mesh), SessionsCommand::Stats) => {
+            let sessions = mesh.list_sessions().await?;
+            let mut owners = BTreeMap::<&str, usize>::new();
+            for (_, owner) in &sessions {
+                *owners.entry(owner.as_deref().unwrap_or("-")).or_default() += 1;
+            }
+            println!("sessions: {}", sessions.len());
+            for (owner, count) in owners {
+                println!("  {owner}: {count}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Pin or unpin a session through the admin API.
+async fn set_pinned(url: &str, token: &str, name: &str, pinned: bool) -> Result<()> {
+    let method = if pinned { Method::PUT } else { Method::DELETE };
+    let resp = api(url, token, method, &format!("/sessions/{name}/pin")).await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        bail!("session {name} not found, or the admin API is not enabled");
+    }
+    Ok(())
+}
+
+/// Send a request to the admin API and parse the JSON response.
+async fn get<T: DeserializeOwned>(url: &str, token: &str, path: &str) -> Result<T> {
+    let resp = api(url, token, Method::GET, path).await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        bail!("the admin API is not enabled on the server");
+    }
+    Ok(resp.json().await?)
+}
+
+/// Send a request to the admin API, failing on errors other than 404.
+async fn api(url: &str, token: &str, method: Method, path: &str) -> Result<reqwest::Response> {
+    let resp = reqwest::Client::new()
+        .request(method, format!("{url}/api/admin{path}"))
+        .bearer_auth(token)
+        .send()
+        .await
+        .with_context(|| format!("failed to reach the server at {url}"))?;
+    match resp.status() {
+        StatusCode::UNAUTHORIZED => bail!("the server rejected the admin token"),
+        status if status.is_server_error() => {
+            let text = resp.text().await.unwrap_or_default();
+            bail!("the server failed with {status}: {text}")
+        }
+        _ => Ok(resp),
+    }
+}
@@ -0,0 +1,54 @@
+//! Entry points for fuzzing the parsers of untrusted input.
+//!
+//! These are called by the targets in the top-level `fuzz/` directory, and by
+//! regression tests that replay the inputs saved in `fuzz/regressions`. Each
+//! one must not panic, whatever bytes it is given.
+
+use bytes::Bytes;
+use prost::Message;
+use sshx_core::proto::ClientUpdate;
+use tokio::sync::mpsc;
+
+use crate::grpc::handle_update;
+use crate::session::{Metadata, Session};
+use crate::web::protocol::WsClient;
+
+/// Decode a CBOR message from a web client.
+pub fn ws_client(data: &[u8]) {
+    let _: Result<WsClient, _> = ciborium::de::from_reader(data);
+}
+
+/// Decode a sequence of length-delimited client updates, applying each one
+/// to a new session as if it came from the Channel() RPC.
+pub fn client_update(mut data: &[u8]) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let session = Session::new(Metadata {
+            encrypted_zeros: Bytes::new(),
+            name: String::new(),
+            write_password_hash: None,
+            write_password_salt: None,
+            color_scheme: None,
+            environment: None,
+            client_key: None,
+            token_nonce: Bytes::new(),
+        });
+        let (tx, mut rx) = mpsc::channel(16);
+        while let Ok(update) = ClientUpdate::decode_length_delimited(&mut data) {
+            handle_update(&tx, &session, update).await;
+            while rx.try_recv().is_ok() {}
+        }
+        session.snapshot().ok();
+    });
+}
+
+/// Restore a session from a compressed snapshot, then snapshot it again.
+pub fn snapshot(data: &[u8]) {
+    if let Ok(session) = Session::restore(data) {
+        session.retained_chunks();
+        session.snapshot().ok();
+    }
+}
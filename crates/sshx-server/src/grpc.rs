@@ -1,30 +1,75 @@
 //! Defines gRPC routes and application request logic.
 
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use base64::prelude::{Engine as _, BASE64_STANDARD};
-use hmac::Mac;
+use ring::signature::{UnparsedPublicKey, ED25519};
 use sshx_core::proto::{
-    client_update::ClientMessage, server_update::ServerMessage, sshx_service_server::SshxService,
-    ClientUpdate, CloseRequest, CloseResponse, OpenRequest, OpenResponse, ServerUpdate,
+    client_features,
+    client_update::ClientMessage,
+    hello_payload,
+    server_update::ServerMessage,
+    sshx_service_server::{SshxService, SshxServiceServer},
+    Capabilities, ClientUpdate, CloseRequest, CloseResponse, NewShell, OpenRequest, OpenResponse,
+    Roster, RosterUser, ServerUpdate, UserJoined, BACKEND_ID_KEY, CLIENT_FEATURES_KEY,
+    TAKEOVER_KEY,
 };
-use sshx_core::{rand_alphanumeric, Sid};
-use tokio::sync::mpsc;
+use sshx_core::{Sid, Uid};
+use sshx_crypto::VERIFIER_SALT_LEN;
+use tokio::sync::{mpsc, watch};
 use tokio::time::{self, MissedTickBehavior};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tonic::{Request, Response, Status, Streaming};
-use tracing::{error, info, warn};
+use tracing::{error, info, info_span, warn, Instrument};
 
+use crate::audit::{AuditEvent, Peer};
+use crate::names::check_custom_name;
+use crate::report::ErrorSource;
 use crate::session::{Metadata, Session};
-use crate::ServerState;
+use crate::state::OpenKey;
+use crate::web::protocol::{WsServer, WsShellClosed, WsUser};
+use crate::{ServerState, FEATURES};
 
 /// Interval for synchronizing sequence numbers with the client.
 pub const SYNC_INTERVAL: Duration = Duration::from_secs(5);
 
+/// Number of syncs that can pass without any message from the client, which
+/// sends heartbeats, before its shells are closed as abandoned.
+pub const ABANDONED_SHELL_SYNCS: u32 = 12;
+
 /// Interval for measuring client latency.
 pub const PING_INTERVAL: Duration = Duration::from_secs(2);
 
+/// Interval for sending the list of users to clients that ask for it.
+pub const ROSTER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Largest gRPC message accepted from the client, in bytes.
+pub const MAX_MESSAGE_SIZE: usize = 4 << 20; // 4 MiB
+
+/// Number of names generated for a new session before giving up on collisions.
+const NAME_ATTEMPTS: usize = 5;
+
+/// Longest idempotency key accepted when opening a session.
+const MAX_OPEN_KEY_LENGTH: usize = 64;
+
+/// Largest encrypted color scheme accepted when opening a session.
+const MAX_COLOR_SCHEME_SIZE: usize = 1024;
+
+/// Longest time between a client signing its hello and the server receiving
+/// it, allowing for clock skew.
+const MAX_SIGNATURE_AGE: Duration = Duration::from_secs(300);
+
+/// Length of the random nonce that session tokens are bound to.
+const TOKEN_NONCE_LEN: usize = 16;
+
+/// Length of an Ed25519 public key.
+const CLIENT_KEY_LEN: usize = 32;
+
+/// Largest encrypted shell environment accepted when opening a session.
+const MAX_ENVIRONMENT_SIZE: usize = 1 << 16; // 64 KiB
+
 /// Server that handles gRPC requests from the sshx command-line client.
 #[derive(Clone)]
 pub struct GrpcServer(Arc<ServerState>);
@@ -34,6 +79,94 @@ impl GrpcServer {
     pub fn new(state: Arc<ServerState>) -> Self {
         Self(state)
     }
+
+    /// Returns the details of an open session, with its signed token.
+    fn open_response(&self, origin: &str, name: String, metadata: &Metadata) -> OpenResponse {
+        let token = self.0.session_token(&name, &metadata.token_nonce);
+        let url = self.0.session_url(origin, &name);
+        OpenResponse {
+            name,
+            token,
+            url,
+            banner: self.0.banner().map(String::from),
+            capabilities: Some(Capabilities {
+                features: FEATURES.iter().map(|&f| f.into()).collect(),
+                max_message_size: MAX_MESSAGE_SIZE as u32,
+            }),
+            omit_write_password: self.0.omit_write_password(),
+            identity: self.0.identity(),
+        }
+    }
+
+    /// Create a new session for a request to open one, and insert it.
+    async fn create_session(
+        &self,
+        request: OpenRequest,
+        custom_name: Option<String>,
+        origin: &str,
+    ) -> Result<OpenResponse, Status> {
+        let Some(_slot) = self.0.reserve_session() else {
+            return Err(Status::resource_exhausted(
+                "this server has too many open sessions, please try again later",
+            ));
+        };
+        // Shorter names from words are more likely to collide, so try a few.
+        let name = match &custom_name {
+            Some(name) => name.clone(),
+            None => (0..NAME_ATTEMPTS)
+                .map(|_| self.0.session_names().generate())
+                .find(|name| self.0.lookup(name).is_none())
+                .ok_or_else(|| Status::already_exists("generated duplicate ID"))?,
+        };
+
+        let mut metadata = Metadata {
+            encrypted_zeros: request.encrypted_zeros,
+            name: request.name,
+            write_password_hash: request.write_password_hash,
+            write_password_salt: request.write_password_salt,
+            color_scheme: request.color_scheme,
+            environment: request.environment,
+            client_key: request.client_key,
+            token_nonce: rand::random::<[u8; TOKEN_NONCE_LEN]>().to_vec().into(),
+        };
+        // Older clients do not salt the password, and hashing it is slow.
+        let metadata = tokio::task::spawn_blocking(move || {
+            metadata.salt_write_password();
+            metadata
+        });
+        let metadata = metadata
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let response = self.open_response(origin, name.clone(), &metadata);
+        let session = Arc::new(Session::new(metadata));
+        if custom_name.is_some() {
+            // Requested names are checked across the mesh, since they are not
+            // random and two clients may well ask for the same one.
+            match self.0.insert_new(&name, session).await {
+                Ok(true) => (),
+                Ok(false) => return Err(Status::already_exists("session name is already taken")),
+                Err(err) => return Err(Status::internal(err.to_string())),
+            }
+        } else {
+            self.0.insert(&name, session);
+        }
+        info!(
+            event = "session_created",
+            session = %name,
+            reason = "new session",
+            "creating new session"
+        );
+        Ok(response)
+    }
+}
+
+/// Returns the gRPC service for command-line clients, to be mounted in a Tonic
+/// server alongside other services.
+///
+/// The service needs HTTP/2. Requests may carry a [`Peer`] extension with the
+/// address of the client for access logs, which is otherwise left unknown.
+pub fn service(state: Arc<ServerState>) -> SshxServiceServer<GrpcServer> {
+    SshxServiceServer::new(GrpcServer::new(state)).max_decoding_message_size(MAX_MESSAGE_SIZE)
 }
 
 type RR<T> = Result<Response<T>, Status>;
@@ -43,50 +176,138 @@ impl SshxService for GrpcServer {
     type ChannelStream = ReceiverStream<Result<ServerUpdate, Status>>;
 
     async fn open(&self, request: Request<OpenRequest>) -> RR<OpenResponse> {
-        let request = request.into_inner();
-        let origin = self.0.override_origin().unwrap_or(request.origin);
+        let peer = request
+            .extensions()
+            .get::<Peer>()
+            .cloned()
+            .unwrap_or_default();
+        let mut request = request.into_inner();
+        let origin = match self.0.override_origin() {
+            Some(origin) => origin,
+            None if self.0.allows_origin(&request.origin) => std::mem::take(&mut request.origin),
+            None => return Err(Status::permission_denied("origin is not allowed")),
+        };
         if origin.is_empty() {
             return Err(Status::invalid_argument("origin is empty"));
         }
-        let name = rand_alphanumeric(10);
-        info!(%name, "creating new session");
+        if (request.write_password_salt.as_ref())
+            .is_some_and(|salt| salt.len() != VERIFIER_SALT_LEN)
+        {
+            return Err(Status::invalid_argument("invalid write password salt"));
+        }
+        if (request.color_scheme.as_ref()).is_some_and(|data| data.len() > MAX_COLOR_SCHEME_SIZE) {
+            return Err(Status::invalid_argument("color scheme is too large"));
+        }
+        if (request.client_key.as_ref()).is_some_and(|key| key.len() != CLIENT_KEY_LEN) {
+            return Err(Status::invalid_argument("invalid client key"));
+        }
+        if (request.environment.as_ref()).is_some_and(|data| data.len() > MAX_ENVIRONMENT_SIZE) {
+            return Err(Status::invalid_argument("environment is too large"));
+        }
+        let open_key = request.idempotency_key.take().filter(|key| !key.is_empty());
+        if open_key
+            .as_ref()
+            .is_some_and(|key| key.len() > MAX_OPEN_KEY_LENGTH)
+        {
+            return Err(Status::invalid_argument("idempotency key is too long"));
+        }
 
-        match self.0.lookup(&name) {
-            Some(_) => return Err(Status::already_exists("generated duplicate ID")),
-            None => {
-                let metadata = Metadata {
-                    encrypted_zeros: request.encrypted_zeros,
-                    name: request.name,
-                    write_password_hash: request.write_password_hash,
-                };
-                self.0.insert(&name, Arc::new(Session::new(metadata)));
+        let custom_name = request.session_name.take().filter(|name| !name.is_empty());
+        if let Some(name) = &custom_name {
+            if let Err(err) = check_custom_name(name) {
+                return Err(Status::invalid_argument(err.to_string()));
             }
+        }
+
+        // A retry of an earlier request gets back the session that it opened,
+        // in case the response was lost. The key is reserved first, so that
+        // concurrent retries do not open two sessions.
+        if let Some(key) = &open_key {
+            match self.0.reserve_open_key(key).await {
+                Ok(OpenKey::Reserved) => (),
+                Ok(OpenKey::Pending) => {
+                    return Err(Status::unavailable(
+                        "a request with this idempotency key is in progress",
+                    ));
+                }
+                Ok(OpenKey::Opened(name)) => {
+                    return match self.0.lookup(&name) {
+                        Some(session)
+                            if session.metadata().encrypted_zeros == request.encrypted_zeros =>
+                        {
+                            info!(
+                                event = "session_created",
+                                session = %name,
+                                reason = "retried request",
+                                "reusing session for retried request"
+                            );
+                            let response = self.open_response(&origin, name, session.metadata());
+                            Ok(Response::new(response))
+                        }
+                        _ => Err(Status::already_exists("idempotency key was already used")),
+                    };
+                }
+                Err(err) => return Err(Status::internal(err.to_string())),
+            }
+        }
+        let result = self.create_session(request, custom_name, &origin).await;
+        if let Some(key) = &open_key {
+            let name = result.as_ref().ok().map(|response| response.name.as_str());
+            self.0.finish_open_key(key, name).await;
+        }
+        let response = result?;
+        let event = AuditEvent::SessionCreated {
+            session: response.name.clone(),
         };
-        let token = self.0.mac().chain_update(&name).finalize();
-        let url = format!("{origin}/s/{name}");
-        Ok(Response::new(OpenResponse {
-            name,
-            token: BASE64_STANDARD.encode(token.into_bytes()),
-            url,
-        }))
+        self.0.audit().record(&peer, event);
+        Ok(Response::new(response))
     }
 
     async fn channel(&self, request: Request<Streaming<ClientUpdate>>) -> RR<Self::ChannelStream> {
+        let client_features = request.metadata().get(CLIENT_FEATURES_KEY);
+        let client_features = client_features.and_then(|value| value.to_str().ok());
+        let has_feature = |feature| {
+            client_features.is_some_and(|features| features.split(',').any(|f| f == feature))
+        };
+        let metadata = request.metadata();
+        let options = ChannelOptions {
+            user_joined: has_feature(client_features::USER_JOINED),
+            user_presence: has_feature(client_features::USER_PRESENCE),
+            roster: has_feature(client_features::ROSTER),
+            backend_id: (metadata.get(BACKEND_ID_KEY))
+                .and_then(|value| value.to_str().ok())
+                .map(String::from),
+        };
+        let takeover = (metadata.get_bin(TAKEOVER_KEY)).and_then(|value| value.to_bytes().ok());
         let mut stream = request.into_inner();
         let first_update = match stream.next().await {
             Some(result) => result?,
             None => return Err(Status::invalid_argument("missing first message")),
         };
-        let session_name = match first_update.client_message {
-            Some(ClientMessage::Hello(hello)) => {
-                let (name, token) = hello
-                    .split_once(',')
-                    .ok_or_else(|| Status::invalid_argument("missing name and token"))?;
-                validate_token(self.0.mac(), name, token)?;
-                name.to_string()
-            }
+        let hello = match first_update.client_message {
+            Some(ClientMessage::Hello(hello)) => hello,
             _ => return Err(Status::invalid_argument("invalid first message")),
         };
+        let mut parts = hello.splitn(3, ',');
+        let (Some(name), Some(token)) = (parts.next(), parts.next()) else {
+            return Err(Status::invalid_argument("missing name and token"));
+        };
+        let metadata = match self.0.session_metadata(name).await {
+            Ok(Some(metadata)) => metadata,
+            Ok(None) => return Err(Status::not_found("session not found")),
+            Err(err) => {
+                error!(?err, "failed to read metadata of backend session");
+                return Err(Status::internal(err.to_string()));
+            }
+        };
+        // Authenticate the client before connecting, which may move the session
+        // to this server.
+        validate_token(&self.0, name, &metadata.token_nonce, token)?;
+        let signed_at = match &metadata.client_key {
+            Some(key) => Some(validate_signature(key, name, token, parts.next())?),
+            None => None,
+        };
+        let session_name = name.to_string();
         let session = match self.0.backend_connect(&session_name).await {
             Ok(Some(session)) => session,
             Ok(None) => return Err(Status::not_found("session not found")),
@@ -95,26 +316,99 @@ impl SshxService for GrpcServer {
                 return Err(Status::internal(err.to_string()));
             }
         };
+        if session.metadata().token_nonce != metadata.token_nonce {
+            // The session was closed and its name reused in the meantime.
+            return Err(Status::unauthenticated("invalid token"));
+        }
+        if signed_at.is_some_and(|timestamp| !session.accept_signature(timestamp)) {
+            return Err(Status::unauthenticated(
+                "signature of client key was already used",
+            ));
+        }
+
+        // A client taking over the session replaces the previous one, whose
+        // connections are refused from now on.
+        let is_takeover = takeover.is_some();
+        let respawn = match (takeover, &options.backend_id) {
+            (Some(zeros), Some(id)) => {
+                if zeros != session.metadata().encrypted_zeros {
+                    return Err(Status::permission_denied("invalid encryption key"));
+                }
+                session.take_over(id)
+            }
+            (Some(_), None) => return Err(Status::invalid_argument("missing backend ID")),
+            (None, id) if !session.check_backend(id.as_deref()) => {
+                return Err(Status::failed_precondition(
+                    "session was taken over by another client",
+                ));
+            }
+            (None, _) => Vec::new(),
+        };
+
+        // Only the latest connection of the client receives updates, so that a
+        // stale stream left behind by a retry cannot compete with it.
+        let connection = session.connect_backend();
 
         // We now spawn an asynchronous task that sends updates to the client. Note that
         // when this task finishes, the sender end is dropped, so the receiver is
         // automatically closed.
         let (tx, rx) = mpsc::channel(16);
-        tokio::spawn(async move {
-            if let Err(err) = handle_streaming(&tx, &session, stream).await {
-                warn!(?err, "connection exiting early due to an error");
+        info!(
+            event = "backend_connected",
+            session = %session_name,
+            takeover = is_takeover,
+            "backend client connected"
+        );
+        let span = info_span!("channel", session = %session_name);
+        let name = session_name.clone();
+        let state = self.0.clone();
+        let task = async move {
+            // Start shells in place of those of the client that was replaced.
+            for (_, winsize) in respawn {
+                let id = session.counter().next_sid();
+                let new_shell = NewShell::new(id, (winsize.x, winsize.y));
+                send_msg(&tx, ServerMessage::CreateShell(new_shell)).await;
             }
-        });
+            let result = handle_streaming(&tx, &state, &session, stream, options, connection);
+            let reason = result.await.unwrap_or_else(|err| {
+                warn!(?err, "connection exiting early due to an error");
+                err
+            });
+            info!(
+                event = "backend_disconnected",
+                session = %name,
+                reason,
+                "backend client disconnected"
+            );
+        };
+        let task = self.0.errors().guard(Some(session_name), task);
+        tokio::spawn(task.instrument(span));
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 
     async fn close(&self, request: Request<CloseRequest>) -> RR<CloseResponse> {
+        let peer = request
+            .extensions()
+            .get::<Peer>()
+            .cloned()
+            .unwrap_or_default();
         let request = request.into_inner();
-        validate_token(self.0.mac(), &request.name, &request.token)?;
-        info!("closing session {}", request.name);
-        if let Err(err) = self.0.close_session(&request.name).await {
+        let nonce = match self.0.session_metadata(&request.name).await {
+            Ok(Some(metadata)) => metadata.token_nonce,
+            Ok(None) => return Ok(Response::new(CloseResponse {})), // Already closed.
+            Err(err) => return Err(Status::internal(err.to_string())),
+        };
+        validate_token(&self.0, &request.name, &nonce, &request.token)?;
+        let event = AuditEvent::SessionClosed {
+            session: request.name.clone(),
+            reason: "client".into(),
+        };
+        self.0.audit().record(&peer, event);
+        if let Err(err) = self.0.close_session(&request.name, "client").await {
             error!(?err, "failed to close session {}", request.name);
+            let session = Some(request.name.as_str());
+            self.0.errors().report(ErrorSource::Session, &err, session);
             return Err(Status::internal(err.to_string()));
         }
         Ok(Response::new(CloseResponse {}))
@@ -122,33 +416,117 @@ impl SshxService for GrpcServer {
 }
 
 /// Validate the client token for a session.
-fn validate_token(mac: impl Mac, name: &str, token: &str) -> Result<(), Status> {
-    if let Ok(token) = BASE64_STANDARD.decode(token) {
-        if mac.chain_update(name).verify_slice(&token).is_ok() {
-            return Ok(());
-        }
+#[allow(clippy::result_large_err)]
+fn validate_token(
+    state: &ServerState,
+    name: &str,
+    nonce: &[u8],
+    token: &str,
+) -> Result<(), Status> {
+    if state.check_session_token(name, nonce, token) {
+        Ok(())
+    } else {
+        Err(Status::unauthenticated("invalid token"))
     }
-    Err(Status::unauthenticated("invalid token"))
 }
 
-type ServerTx = mpsc::Sender<Result<ServerUpdate, Status>>;
+/// Check the signature in the hello of a session bound to a client key, which
+/// is `timestamp,signature` after the name and token. Returns the timestamp.
+#[allow(clippy::result_large_err)]
+fn validate_signature(
+    key: &[u8],
+    name: &str,
+    token: &str,
+    signed: Option<&str>,
+) -> Result<u64, Status> {
+    let (timestamp, signature) = signed
+        .and_then(|signed| signed.split_once(','))
+        .ok_or_else(|| Status::unauthenticated("missing signature of client key"))?;
+    let timestamp: u64 = timestamp
+        .parse()
+        .map_err(|_| Status::invalid_argument("invalid signature timestamp"))?;
+
+    // Old signatures are refused, and the session only accepts newer ones than
+    // the last, so that one found in logs cannot be replayed.
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH);
+    let now = now.unwrap_or_default().as_millis() as u64;
+    if now.abs_diff(timestamp) > MAX_SIGNATURE_AGE.as_millis() as u64 {
+        return Err(Status::unauthenticated("signature of client key expired"));
+    }
+    let signature = BASE64_STANDARD
+        .decode(signature)
+        .map_err(|_| Status::invalid_argument("invalid signature encoding"))?;
+    let payload = hello_payload(name, token, timestamp);
+    UnparsedPublicKey::new(&ED25519, key)
+        .verify(&payload, &signature)
+        .map_err(|_| Status::unauthenticated("invalid signature of client key"))?;
+    Ok(timestamp)
+}
+
+pub(crate) type ServerTx = mpsc::Sender<Result<ServerUpdate, Status>>;
+
+/// Options of a Channel() request, from its metadata.
+struct ChannelOptions {
+    /// Report users who join.
+    user_joined: bool,
+    /// Report users already in the session and users who leave.
+    user_presence: bool,
+    /// List all users periodically.
+    roster: bool,
+    /// ID of the client, if it sent one.
+    backend_id: Option<String>,
+}
 
 /// Handle bidirectional streaming messages RPC messages.
+///
+/// The connection ends when another client takes over the session, or when a
+/// newer connection replaces it. Returns the reason that the connection ended.
 async fn handle_streaming(
     tx: &ServerTx,
+    state: &ServerState,
     session: &Session,
     mut stream: Streaming<ClientUpdate>,
-) -> Result<(), &'static str> {
+    options: ChannelOptions,
+    mut connection: watch::Receiver<u64>,
+) -> Result<&'static str, &'static str> {
+    let ChannelOptions {
+        user_joined,
+        user_presence,
+        roster,
+        backend_id,
+    } = options;
+    let mut backend = session.subscribe_backend();
+    let mut user_updates = (user_joined || user_presence).then(|| session.subscribe_broadcast());
+    let mut known_users = HashSet::new();
+    for (id, user) in session.list_users() {
+        known_users.insert(id);
+        if user_presence {
+            send_msg(tx, ServerMessage::UserJoined(user_joined_msg(id, user))).await;
+        }
+    }
+
     let mut sync_interval = time::interval(SYNC_INTERVAL);
     sync_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     let mut ping_interval = time::interval(PING_INTERVAL);
     ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
+    let mut roster_interval = time::interval(ROSTER_INTERVAL);
+    roster_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut silent_syncs = 0;
     loop {
         tokio::select! {
             // Send periodic sync messages to the client.
             _ = sync_interval.tick() => {
+                silent_syncs += 1;
+                if silent_syncs == ABANDONED_SHELL_SYNCS {
+                    for id in session.close_abandoned_shells() {
+                        warn!(%id, "closed shell abandoned by the client");
+                        // Stop the shell in case the client recovers.
+                        send_msg(tx, ServerMessage::CloseShell(id.0)).await;
+                    }
+                }
                 let msg = ServerMessage::Sync(session.sequence_numbers());
                 if !send_msg(tx, msg).await {
                     return Err("failed to send sync message");
@@ -158,57 +536,127 @@ async fn handle_streaming(
             _ = ping_interval.tick() => {
                 send_msg(tx, ServerMessage::Ping(get_time_ms())).await;
             }
+            // Send the list of users periodically, if the client asked for it.
+            _ = roster_interval.tick(), if roster => {
+                send_msg(tx, ServerMessage::Roster(roster_msg(session))).await;
+            }
             // Send buffered server updates to the client.
             Ok(msg) = session.update_rx().recv() => {
+                let bytes = match &msg {
+                    ServerMessage::Input(input) => input.data.len(),
+                    ServerMessage::Stream(stream) => stream.data.len(),
+                    _ => 0,
+                };
+                session.record_client_bandwidth(bytes as u64, 0);
                 if !send_msg(tx, msg).await {
                     return Err("failed to send update message");
                 }
             }
+            // Report users who join or leave the session, if the client asked for it.
+            Some(Ok(msg)) = async { user_updates.as_mut()?.next().await } => {
+                match msg {
+                    WsServer::UserDiff(id, Some(user)) if known_users.insert(id) => {
+                        send_msg(tx, ServerMessage::UserJoined(user_joined_msg(id, user))).await;
+                    }
+                    WsServer::UserDiff(id, None) if known_users.remove(&id) && user_presence => {
+                        send_msg(tx, ServerMessage::UserLeft(id.0)).await;
+                    }
+                    _ => (),
+                }
+            }
             // Handle incoming client messages.
             maybe_update = stream.next() => {
                 if let Some(Ok(update)) = maybe_update {
+                    silent_syncs = 0;
                     if !handle_update(tx, session, update).await {
                         return Err("error responding to client update");
                     }
                 } else {
-                    // The client has hung up on their end.
-                    return Ok(());
+                    return Ok("client hung up");
                 }
             }
+            // Exit when another client takes over the session.
+            Ok(()) = backend.changed() => {
+                if *backend.borrow_and_update() != backend_id {
+                    let msg = String::from("session was taken over by another client");
+                    send_msg(tx, ServerMessage::Error(msg)).await;
+                    return Ok("taken over by another client");
+                }
+            }
+            // Exit when a newer connection from the client replaces this one.
+            Ok(()) = connection.changed() => {
+                let status = Status::aborted("replaced by a newer connection");
+                tx.send(Err(status)).await.ok();
+                return Ok("replaced by a newer connection");
+            }
             // Exit on a session shutdown signal.
             _ = session.terminated() => {
+                if state.is_shutting_down() {
+                    let msg = String::from("server restarting");
+                    send_msg(tx, ServerMessage::Shutdown(msg)).await;
+                    return Ok("server restarting");
+                }
                 let msg = String::from("disconnecting because session is closed");
                 send_msg(tx, ServerMessage::Error(msg)).await;
-                return Ok(());
+                return Ok("session closed");
             }
         };
     }
 }
 
 /// Handles a singe update from the client. Returns `true` on success.
-async fn handle_update(tx: &ServerTx, session: &Session, update: ClientUpdate) -> bool {
+pub(crate) async fn handle_update(tx: &ServerTx, session: &Session, update: ClientUpdate) -> bool {
     session.access();
     match update.client_message {
         Some(ClientMessage::Hello(_)) => {
             return send_err(tx, "unexpected hello".into()).await;
         }
         Some(ClientMessage::Data(data)) => {
-            if let Err(err) = session.add_data(Sid(data.id), data.data, data.seq) {
+            session.record_client_bandwidth(0, data.data.len() as u64);
+            let time = data.time.unwrap_or_else(get_time_ms);
+            if let Err(err) = session.add_data(data.sid(), data.data, data.seq, time) {
                 return send_err(tx, format!("add data: {:?}", err)).await;
             }
         }
         Some(ClientMessage::CreatedShell(new_shell)) => {
-            let id = Sid(new_shell.id);
-            let center = (new_shell.x, new_shell.y);
-            if let Err(err) = session.add_shell(id, center) {
+            if let Err(err) = session.add_shell(new_shell.sid(), new_shell.center()) {
                 return send_err(tx, format!("add shell: {:?}", err)).await;
             }
         }
         Some(ClientMessage::ClosedShell(id)) => {
-            if let Err(err) = session.close_shell(Sid(id)) {
+            // Older clients only report the ID of the shell.
+            if let Err(err) = session.close_shell(Sid(id), WsShellClosed::default()) {
+                return send_err(tx, format!("close shell: {:?}", err)).await;
+            }
+        }
+        Some(ClientMessage::ShellClosed(closed)) => {
+            if let Err(err) = session.close_shell(closed.sid(), closed.into()) {
                 return send_err(tx, format!("close shell: {:?}", err)).await;
             }
         }
+        Some(ClientMessage::Stream(stream)) => {
+            let Some(kind) = stream.kind().to_ws() else {
+                return send_err(tx, format!("unknown kind for stream id={}", stream.id)).await;
+            };
+            session.record_client_bandwidth(0, stream.data.len() as u64);
+            session.send_stream(stream.id, kind, stream.data, stream.offset);
+        }
+        Some(ClientMessage::Inactivity(policy)) => {
+            session.set_inactivity(policy);
+        }
+        Some(ClientMessage::Pin(pinned)) => {
+            session.set_pinned(pinned);
+        }
+        Some(ClientMessage::Palette(palette)) => {
+            if let Err(err) = session.set_palette(palette) {
+                return send_err(tx, format!("set palette: {:?}", err)).await;
+            }
+        }
+        Some(ClientMessage::ShellHealth(health)) => {
+            if let Err(err) = session.set_shell_health(health.sid(), health.into()) {
+                return send_err(tx, format!("set shell health: {:?}", err)).await;
+            }
+        }
         Some(ClientMessage::Pong(ts)) => {
             let latency = get_time_ms().saturating_sub(ts);
             session.send_latency_measurement(latency);
@@ -235,7 +683,27 @@ async fn send_err(tx: &ServerTx, err: String) -> bool {
     send_msg(tx, ServerMessage::Error(err)).await
 }
 
-fn get_time_ms() -> u64 {
+fn user_joined_msg(id: Uid, user: WsUser) -> UserJoined {
+    UserJoined {
+        id: id.0,
+        name: user.name,
+        can_write: user.can_write,
+    }
+}
+
+fn roster_msg(session: &Session) -> Roster {
+    let users = (session.roster().into_iter())
+        .map(|(id, user, joined_at)| RosterUser {
+            id: id.0,
+            name: user.name,
+            can_write: user.can_write,
+            joined_at,
+        })
+        .collect();
+    Roster { users }
+}
+
+pub(crate) fn get_time_ms() -> u64 {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .expect("system time is before the UNIX epoch")
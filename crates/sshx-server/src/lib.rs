@@ -5,49 +5,229 @@
 //! using a hybrid Hyper service, split between a Tonic gRPC handler and an Axum
 //! web listener.
 //!
-//! Most web requests are routed directly to static files located in the `dist/`
-//! folder relative to where this binary is running, allowing the frontend to be
-//! separately developed from the server.
+//! Most web requests are routed directly to static files located in the
+//! `build/` folder relative to where this binary is running, allowing the
+//! frontend to be separately developed from the server. With the `embed`
+//! feature, these files are instead compiled into the binary, so it can run
+//! standalone.
+//!
+//! Instead of listening with [`Server`], other applications can mount the web
+//! app from [`web::app`] and the gRPC service from [`grpc::service`] in their
+//! own servers, with the state from [`Server::state`].
 
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
-use std::{net::SocketAddr, sync::Arc};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use anyhow::Result;
-use hyper::server::conn::AddrIncoming;
+use hyper::server::{accept, conn::AddrIncoming};
+use ipnet::IpNet;
+use sshx_core::protocol::features;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
 use utils::Shutdown;
 
+use crate::names::SessionNames;
+use crate::oidc::OidcOptions;
+use crate::report::ErrorReport;
 use crate::state::ServerState;
+use crate::tls::TlsOptions;
 
+pub mod acl;
+pub mod audit;
+#[doc(hidden)]
+pub mod fuzz;
 pub mod grpc;
 mod listen;
+pub mod metrics;
+pub mod names;
+pub mod oidc;
+pub mod otel;
+pub mod relay;
+pub mod report;
 pub mod session;
 pub mod state;
+pub mod tls;
 pub mod utils;
 pub mod web;
 
+/// Optional protocol features supported by this server, advertised to clients.
+pub(crate) const FEATURES: &[&str] = &[
+    features::SHELL_CLOSED,
+    features::STREAMS,
+    features::TIMESTAMPS,
+    features::USER_PRESENCE,
+    features::THROUGHPUT,
+    features::PROFILES,
+    features::VIEWPORTS,
+    features::NOTES,
+    features::PALETTE,
+    features::SHELL_HEALTH,
+    features::COLOR_SCHEME,
+    features::ENVIRONMENT,
+    features::CLIENT_KEYS,
+];
+
 /// Options when constructing the application server.
 #[derive(Clone, Debug, Default)]
 #[non_exhaustive]
 pub struct ServerOptions {
-    /// Secret used for signing tokens. Set randomly if not provided.
+    /// Secret used for signing tokens, which must be hard to guess. Set
+    /// randomly if not provided.
     pub secret: Option<String>,
 
-    /// Override the origin returned for the Open() RPC.
+    /// Override the origin returned for the Open() RPC, like
+    /// `https://sshx.example.com`.
     pub override_origin: Option<String>,
 
+    /// Origins that clients may request in the Open() RPC when there is no
+    /// override origin, like `https://sshx.example.com`, so that links are not
+    /// minted for other domains. Any origin is accepted if empty.
+    pub allowed_origins: Vec<String>,
+
     /// URL of the Redis server that stores session data.
     pub redis_url: Option<String>,
 
     /// Hostname of this server, if running multiple servers.
     pub host: Option<String>,
+
+    /// Links to sessions on other servers, like `https://sshx.io/s/<name>#<key>`,
+    /// which are mirrored read-only as local sessions with the same names.
+    pub relay_links: Vec<String>,
+
+    /// Port that other servers in the mesh can reach this server on. If the
+    /// host is not given, it is detected from the environment with this port,
+    /// and detected again periodically in case the address changes.
+    pub advertise_port: Option<u16>,
+
+    /// Only serve metrics from [`Server::listen_metrics`], not the main app.
+    pub separate_metrics: bool,
+
+    /// Serve metrics at `/metrics` of the main app to anyone. Otherwise they
+    /// need the admin token there, and are not served if there is none.
+    pub public_metrics: bool,
+
+    /// Networks allowed to connect to the server. If empty, allow all.
+    pub allow_ips: Vec<IpNet>,
+
+    /// Networks denied from connecting to the server.
+    pub deny_ips: Vec<IpNet>,
+
+    /// File with additional `allow <cidr>` and `deny <cidr>` rules.
+    pub ip_rules_file: Option<PathBuf>,
+
+    /// Bearer token for the admin API. The API is disabled if not provided.
+    pub admin_token: Option<String>,
+
+    /// Path to a file where audit events are appended as JSON lines.
+    pub audit_file: Option<PathBuf>,
+
+    /// Key of a Redis stream where audit events are appended.
+    pub audit_stream: Option<String>,
+
+    /// Require web viewers to log in with an OpenID Connect provider.
+    pub oidc: Option<OidcOptions>,
+
+    /// Override the Content-Security-Policy of web responses. If empty, the
+    /// header is not sent. Defaults to [`web::DEFAULT_CSP`].
+    pub content_security_policy: Option<String>,
+
+    /// Terminal input accepted from each web user, in bytes per second, with
+    /// bursts of up to one second. Unlimited if not provided.
+    pub input_rate_limit: Option<u64>,
+
+    /// Terminal and stream data relayed to and from each web user, in bytes,
+    /// before the user is disconnected. Unlimited if not provided.
+    pub max_user_bytes: Option<u64>,
+
+    /// Interval between keepalive pings sent to web clients. Clients that miss
+    /// several pings in a row are disconnected. Defaults to 20 seconds.
+    pub ping_interval: Option<Duration>,
+
+    /// Message shown to every web viewer and printed by the CLI when a session
+    /// is opened, such as a legal notice.
+    pub banner: Option<String>,
+
+    /// Serve the web app and API under this URL path prefix, such as `/sshx`,
+    /// instead of at the root. The gRPC service is always served at the root.
+    pub base_path: Option<String>,
+
+    /// Template for session URLs returned by the Open() RPC, where `{origin}`
+    /// and `{name}` are replaced, like `{origin}/view?session={name}`. Defaults
+    /// to `{origin}/s/{name}` under the base path.
+    pub session_url: Option<String>,
+
+    /// Ask clients to leave the write password out of the links they print,
+    /// so that it must be shared separately from the writable link.
+    pub omit_write_password: bool,
+
+    /// Only let users join with a signed join link, or with the session's
+    /// write password. Either way they also need the encryption key, and a
+    /// link never grants more than the write password: a write link lets
+    /// users write only with the password, if the session has one.
+    pub require_join_grants: bool,
+
+    /// Maximum number of concurrent inbound connections. Further connections
+    /// wait in the listen backlog until one closes. Unlimited if not provided.
+    pub max_connections: Option<usize>,
+
+    /// Maximum number of concurrent WebSocket or event stream connections
+    /// from each IP address. Unlimited if not provided.
+    pub max_ws_per_ip: Option<usize>,
+
+    /// Maximum number of sessions open on this server at once. Further
+    /// sessions are refused until one closes. Unlimited if not provided.
+    pub max_sessions: Option<usize>,
+
+    /// Scheme for generating the names of new sessions. Defaults to 10
+    /// random alphanumeric characters.
+    pub session_names: SessionNames,
+
+    /// How long to wait after a shutdown signal for in-flight WebSocket and
+    /// gRPC streams to finish, before terminating their sessions. Defaults to
+    /// zero, which terminates sessions immediately.
+    pub drain_timeout: Option<Duration>,
+
+    /// Idle time before TCP keepalive probes are sent on connections.
+    pub tcp_keepalive: Option<Duration>,
+
+    /// How long sent data may remain unacknowledged before a TCP connection
+    /// is closed, on Linux. Only applies to listeners from [`Server::bind`].
+    pub tcp_user_timeout: Option<Duration>,
+
+    /// Close connections that have not sent or received any data for this
+    /// long. Unlimited if not provided.
+    pub idle_timeout: Option<Duration>,
+
+    /// Requests accepted from each IP address per second, counted together
+    /// across the web API and gRPC, with bursts of up to one second. Static
+    /// assets are not limited, and IPv6 addresses are counted by their /64
+    /// network. Unlimited if not provided.
+    pub request_rate_limit: Option<u64>,
+
+    /// Serve HTTPS on every listener with these certificates, optionally
+    /// requiring clients to present a certificate of their own.
+    pub tls: Option<TlsOptions>,
+
+    /// Serve a scripted fake session named [`web::DEV_SESSION`], so that the
+    /// web app and headless viewer can be developed without a client. Only
+    /// for local development.
+    pub dev_replay: bool,
 }
 
 /// Stateful object that manages the sshx server, with graceful termination.
 pub struct Server {
     state: Arc<ServerState>,
     shutdown: Shutdown,
+    background_started: AtomicBool,
 }
 
 impl Server {
@@ -56,6 +236,7 @@ impl Server {
         Ok(Self {
             state: Arc::new(ServerState::new(options)?),
             shutdown: Shutdown::new(),
+            background_started: AtomicBool::new(false),
         })
     }
 
@@ -64,34 +245,136 @@ impl Server {
         Arc::clone(&self.state)
     }
 
+    /// Check that the server can serve sessions before it starts listening,
+    /// with an actionable error if not.
+    ///
+    /// This fails if the web app's static files are missing, or if Redis is
+    /// configured but cannot be reached.
+    pub async fn self_check(&self) -> Result<()> {
+        web::check_assets()?;
+        self.state.check_redis().await
+    }
+
+    /// Register a hook that receives internal errors, such as Redis failures,
+    /// proxy errors and panics in session tasks, to alert operators.
+    pub fn on_error(&self, hook: impl Fn(&ErrorReport<'_>) + Send + Sync + 'static) {
+        self.state.errors().add_hook(hook);
+    }
+
     /// Run the application server, listening on a stream of connections.
-    pub async fn listen(&self, incoming: AddrIncoming) -> Result<()> {
+    ///
+    /// This may be called more than once to accept connections from several
+    /// listeners, which all share the same sessions.
+    pub async fn listen(&self, mut incoming: AddrIncoming) -> Result<()> {
+        incoming.set_nodelay(true);
+        incoming.set_keepalive(self.state.tcp_keepalive());
+        self.start_background_tasks();
+        listen::start_server(self.state(), incoming, self.shutdown.wait()).await
+    }
+
+    /// Convenience function to call [`Server::listen`] bound to a TCP address.
+    pub async fn bind(&self, addr: &SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        listen::set_user_timeout(&listener, self.state.tcp_user_timeout())?;
+        self.listen(AddrIncoming::from_listener(listener)?).await
+    }
+
+    /// Run the application server, listening on a Unix domain socket.
+    ///
+    /// Peers on the socket have no IP address, so the IP access rules and
+    /// per-IP limits do not apply to them. Restrict who can connect with the
+    /// permissions of the socket file instead.
+    #[cfg(unix)]
+    pub async fn listen_unix(&self, listener: UnixListener) -> Result<()> {
+        self.start_background_tasks();
+        let incoming = accept::poll_fn(move |cx| {
+            listener
+                .poll_accept(cx)
+                .map(|result| Some(result.map(|(stream, _)| stream)))
+        });
+        listen::start_server(self.state(), incoming, self.shutdown.wait()).await
+    }
+
+    /// Convenience function to call [`Server::listen_unix`] bound to a path.
+    ///
+    /// A stale socket file at the path is removed first.
+    #[cfg(unix)]
+    pub async fn bind_unix(&self, path: &Path) -> Result<()> {
+        if std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        self.listen_unix(UnixListener::bind(path)?).await
+    }
+
+    /// Spawn tasks that maintain the server state, until it is shut down.
+    ///
+    /// This is called when the server first listens, so it only needs to be
+    /// called directly when mounting the server's routes in another app.
+    pub fn start_background_tasks(&self) {
+        if self.background_started.swap(true, Ordering::Relaxed) {
+            return;
+        }
         let state = self.state.clone();
         let terminated = self.shutdown.wait();
         tokio::spawn(async move {
-            let background_tasks = futures_util::future::join(
-                state.listen_for_transfers(),
-                state.close_old_sessions(),
-            );
+            let relays = state.relays().iter().cloned();
+            let relays = relays.map(|origin| relay::relay(state.clone(), origin));
+            let background_tasks = async {
+                tokio::join!(
+                    state.listen_for_transfers(),
+                    state.close_old_sessions(),
+                    state.watch_host(),
+                    state.probe_mesh(),
+                    state.sample_throughput(),
+                    futures_util::future::join_all(relays),
+                )
+            };
             tokio::select! {
                 _ = terminated => {}
                 _ = background_tasks => {}
             }
         });
+    }
 
-        listen::start_server(self.state(), incoming, self.shutdown.wait()).await
+    /// Serve Prometheus metrics on a separate stream of connections.
+    pub async fn listen_metrics(&self, incoming: AddrIncoming) -> Result<()> {
+        listen::start_metrics_server(self.state(), incoming, self.shutdown.wait()).await
     }
 
-    /// Convenience function to call [`Server::listen`] bound to a TCP address.
-    pub async fn bind(&self, addr: &SocketAddr) -> Result<()> {
-        self.listen(AddrIncoming::bind(addr)?).await
+    /// Convenience function to call [`Server::listen_metrics`] bound to a TCP
+    /// address.
+    pub async fn bind_metrics(&self, addr: &SocketAddr) -> Result<()> {
+        self.listen_metrics(AddrIncoming::bind(addr)?).await
+    }
+
+    /// Serve web clients over WebTransport on a UDP address, until the server
+    /// is shut down. This requires built-in TLS, whose certificates are read
+    /// once here, so reloads only apply to the TCP listeners.
+    ///
+    /// The web app discovers the port at `/api/webtransport`. Serve it on the
+    /// same port number as HTTPS, since the default Content-Security-Policy
+    /// only allows connections to the web app's own origin.
+    ///
+    /// Sessions hosted on another server in the mesh are not proxied over
+    /// WebTransport. Their viewers are refused with 421 Misdirected Request,
+    /// and fall back to a WebSocket.
+    #[cfg(feature = "webtransport")]
+    pub async fn bind_webtransport(&self, addr: &SocketAddr) -> Result<()> {
+        let endpoint = web::webtransport::bind(&self.state, addr)?;
+        self.state
+            .set_webtransport_port(endpoint.local_addr()?.port());
+        self.start_background_tasks();
+        web::webtransport::serve(self.state(), endpoint, self.shutdown.wait()).await
     }
 
     /// Send a graceful shutdown signal to the server.
+    ///
+    /// The server stops accepting connections right away, and terminates
+    /// existing sessions once the drain timeout has passed.
     pub fn shutdown(&self) {
-        // Stop receiving new network connections.
         self.shutdown.shutdown();
-        // Terminate each of the existing sessions.
-        self.state.shutdown();
+        if self.state.drain_timeout().is_zero() {
+            self.state.shutdown();
+        }
     }
 }
@@ -1,40 +1,363 @@
-use std::{error::Error as StdError, future::Future, sync::Arc};
+use std::{
+    convert::Infallible,
+    error::Error as StdError,
+    future::{poll_fn, Future},
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
 
 use anyhow::Result;
-use axum::body::HttpBody;
+use axum::{body::HttpBody, extract::ConnectInfo, response::IntoResponse};
+use futures_util::future::BoxFuture;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use futures_util::FutureExt;
 use hyper::{
-    header::CONTENT_TYPE,
-    server::{conn::AddrIncoming, Server as HyperServer},
-    service::make_service_fn,
-    Body, Request,
+    header::{HeaderValue, CONTENT_TYPE},
+    server::{
+        accept::{self, Accept},
+        conn::{AddrIncoming, AddrStream},
+        Server as HyperServer,
+    },
+    service::{make_service_fn, service_fn},
+    Body, Request, StatusCode,
+};
+use sshx_core::proto::FILE_DESCRIPTOR_SET;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::{self, Duration, Instant, Sleep};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tonic::{
+    transport::{server::TcpConnectInfo, Server as TonicServer},
+    Status,
 };
-use sshx_core::proto::{sshx_service_server::SshxServiceServer, FILE_DESCRIPTOR_SET};
-use tonic::transport::Server as TonicServer;
 use tower::{steer::Steer, ServiceBuilder, ServiceExt};
 use tower_http::trace::TraceLayer;
+use tracing::{debug, info_span, warn, Instrument};
+
+use crate::audit::Peer;
+use crate::utils::{RequestId, REQUEST_ID_HEADER};
+use crate::web::PeerAddr;
+use crate::{grpc, tls, web, ServerState};
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+/// How long to wait for connections to close after sessions are terminated.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client has to complete the TLS handshake after connecting.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection accepted by the server, which knows the address of its peer.
+pub(crate) trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// Returns the address that access rules and handlers see for the peer.
+    fn peer_addr(&self) -> PeerAddr;
+}
+
+impl Connection for AddrStream {
+    fn peer_addr(&self) -> PeerAddr {
+        PeerAddr::Tcp(self.remote_addr())
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn peer_addr(&self) -> PeerAddr {
+        PeerAddr::Unix
+    }
+}
+
+/// A connection accepted by the server, holding a permit from the connection
+/// limit and closed if it is idle for too long.
+struct Accepted<C> {
+    inner: MaybeTls<C>,
+    _permit: Option<OwnedSemaphorePermit>,
+    idle: Option<IdleTimer>,
+    /// Subject of the client's TLS certificate, if it presented one.
+    cert_subject: Option<String>,
+}
+
+impl<C> Accepted<C> {
+    fn new(
+        inner: MaybeTls<C>,
+        permit: Option<OwnedSemaphorePermit>,
+        idle_timeout: Option<Duration>,
+    ) -> Self {
+        let cert_subject = match &inner {
+            MaybeTls::Tls(stream) => stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| tls::cert_subject(&cert.0)),
+            MaybeTls::Plain(_) => None,
+        };
+        Self {
+            inner,
+            _permit: permit,
+            idle: idle_timeout.map(IdleTimer::new),
+            cert_subject,
+        }
+    }
+}
+
+/// A stream that is either plaintext or encrypted with TLS.
+enum MaybeTls<C> {
+    Plain(C),
+    Tls(Box<TlsStream<C>>),
+}
+
+impl<C: Connection> MaybeTls<C> {
+    fn peer_addr(&self) -> PeerAddr {
+        match self {
+            MaybeTls::Plain(conn) => conn.peer_addr(),
+            MaybeTls::Tls(stream) => stream.get_ref().0.peer_addr(),
+        }
+    }
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> AsyncRead for MaybeTls<C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(conn) => Pin::new(conn).poll_read(cx, buf),
+            MaybeTls::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> AsyncWrite for MaybeTls<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            MaybeTls::Plain(conn) => Pin::new(conn).poll_write(cx, buf),
+            MaybeTls::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(conn) => Pin::new(conn).poll_flush(cx),
+            MaybeTls::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            MaybeTls::Plain(conn) => Pin::new(conn).poll_shutdown(cx),
+            MaybeTls::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Timer that expires when a connection has not sent or received any data.
+struct IdleTimer {
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl IdleTimer {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            sleep: Box::pin(time::sleep(timeout)),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.sleep.as_mut().reset(Instant::now() + self.timeout);
+    }
+}
+
+impl<C: Connection> Connection for Accepted<C> {
+    fn peer_addr(&self) -> PeerAddr {
+        self.inner.peer_addr()
+    }
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> AsyncRead for Accepted<C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Some(idle) = &mut this.idle {
+            if result.is_ready() {
+                idle.reset();
+            } else if idle.sleep.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(io::ErrorKind::TimedOut.into()));
+            }
+        }
+        result
+    }
+}
+
+impl<C: AsyncRead + AsyncWrite + Unpin> AsyncWrite for Accepted<C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let (Some(idle), Poll::Ready(Ok(_))) = (&mut this.idle, &result) {
+            idle.reset();
+        }
+        result
+    }
 
-use crate::{grpc::GrpcServer, web, ServerState};
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Accept connections from a listener, completing TLS handshakes if enabled.
+///
+/// Only accept a connection once a permit is available, if connections are
+/// limited, leaving any others waiting in the listen backlog. Handshakes run
+/// concurrently, so a slow client does not block others from connecting.
+fn accept_connections<I>(
+    incoming: I,
+    state: Arc<ServerState>,
+) -> impl Accept<Conn = Accepted<I::Conn>, Error = I::Error>
+where
+    I: Accept + Unpin,
+    I::Conn: Connection,
+{
+    let limit = state.connection_limit().cloned();
+    let idle_timeout = state.idle_timeout();
+
+    type Handshake<C> = BoxFuture<'static, Option<Accepted<C>>>;
+    let handshakes: FuturesUnordered<Handshake<I::Conn>> = FuturesUnordered::new();
+
+    let stream = futures_util::stream::unfold(
+        (incoming, handshakes),
+        move |(mut incoming, mut handshakes)| {
+            let limit = limit.clone();
+            let state = Arc::clone(&state);
+            async move {
+                loop {
+                    let accept = async {
+                        let permit = match &limit {
+                            Some(semaphore) => {
+                                Some(Arc::clone(semaphore).acquire_owned().await.ok()?)
+                            }
+                            None => None,
+                        };
+                        let conn = poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx)).await?;
+                        Some((conn, permit))
+                    };
+                    let (conn, permit) = tokio::select! {
+                        Some(conn) = handshakes.next(), if !handshakes.is_empty() => match conn {
+                            Some(conn) => break Some((Ok(conn), (incoming, handshakes))),
+                            None => continue,
+                        },
+                        accepted = accept => accepted?,
+                    };
+                    let conn = match conn {
+                        Ok(conn) => conn,
+                        Err(err) => break Some((Err(err), (incoming, handshakes))),
+                    };
+                    match state.tls() {
+                        Some(tls) => {
+                            let handshake = handshake(tls.acceptor(), conn, permit, idle_timeout);
+                            handshakes.push(handshake.boxed());
+                        }
+                        None => {
+                            let conn = Accepted::new(MaybeTls::Plain(conn), permit, idle_timeout);
+                            break Some((Ok(conn), (incoming, handshakes)));
+                        }
+                    }
+                }
+            }
+        },
+    );
+    accept::from_stream(stream)
+}
+
+/// Complete the TLS handshake on a connection, dropping it on failure.
+async fn handshake<C: Connection>(
+    acceptor: TlsAcceptor,
+    conn: C,
+    permit: Option<OwnedSemaphorePermit>,
+    idle_timeout: Option<Duration>,
+) -> Option<Accepted<C>> {
+    let remote_addr = conn.peer_addr();
+    match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+        Ok(Ok(stream)) => {
+            let stream = MaybeTls::Tls(Box::new(stream));
+            Some(Accepted::new(stream, permit, idle_timeout))
+        }
+        Ok(Err(err)) => {
+            debug!(%remote_addr, %err, "TLS handshake failed");
+            None
+        }
+        Err(_) => {
+            debug!(%remote_addr, "TLS handshake timed out");
+            None
+        }
+    }
+}
+
+/// Set the TCP user timeout of a listening socket, which is inherited by the
+/// connections that it accepts. This is only supported on Linux.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub(crate) fn set_user_timeout(listener: &TcpListener, timeout: Option<Duration>) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    socket2::SockRef::from(listener).set_tcp_user_timeout(timeout)?;
+    Ok(())
+}
 
 /// Bind and listen from the application, with a state and termination signal.
 ///
 /// This internal method is responsible for multiplexing the HTTP and gRPC
-/// servers onto a single, consolidated `hyper` service.
-pub(crate) async fn start_server(
+/// servers onto a single, consolidated `hyper` service. It runs one accept
+/// loop, and may be called once for each listener of a server.
+pub(crate) async fn start_server<I>(
     state: Arc<ServerState>,
-    incoming: AddrIncoming,
+    incoming: I,
     signal: impl Future<Output = ()>,
-) -> Result<()> {
-    type BoxError = Box<dyn StdError + Send + Sync>;
+) -> Result<()>
+where
+    I: Accept + Unpin,
+    I::Conn: Connection,
+    I::Error: Into<BoxError>,
+{
+    let incoming = accept_connections(incoming, state.clone());
 
-    let http_service = web::app()
+    let metrics = state.metrics().clone();
+    let csp = state.content_security_policy().cloned();
+    let http_service = web::app(state.base_path())
         .with_state(state.clone())
         .layer(TraceLayer::new_for_http())
-        .map_response(|r| r.map(|b| b.map_err(BoxError::from).boxed_unsync()))
+        .map_response(move |r| {
+            metrics.record_http_status(r.status());
+            r.map(|b| b.map_err(BoxError::from).boxed_unsync())
+        })
         .map_err(BoxError::from)
         .boxed_clone();
 
+    let ip_filter = state.ip_filter().clone();
+    let rate_limiter = state.rate_limiter().clone();
+    let api_prefix: Arc<str> = format!("{}/api/", state.base_path()).into();
     let grpc_service = TonicServer::builder()
-        .add_service(SshxServiceServer::new(GrpcServer::new(state)))
+        .add_service(grpc::service(state.clone()))
         .add_service(
             tonic_reflection::server::Builder::configure()
                 .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
@@ -50,22 +373,118 @@ pub(crate) async fn start_server(
 
     let svc = Steer::new(
         [http_service, grpc_service],
-        |req: &Request<Body>, _services: &[_]| {
-            let headers = req.headers();
-            match headers.get(CONTENT_TYPE) {
-                Some(content) if content == "application/grpc" => 1,
-                _ => 0,
-            }
-        },
-    );
-    let make_svc = make_service_fn(move |_| {
+        |req: &Request<Body>, _services: &[_]| usize::from(is_grpc(req)),
+    )
+    .boxed_clone();
+    let make_svc = make_service_fn(move |conn: &Accepted<I::Conn>| {
+        let remote_addr = conn.peer_addr();
+        let ip = remote_addr.ip();
+        let peer = Peer {
+            ip,
+            cert_subject: conn.cert_subject.clone(),
+        };
+        let allowed = ip.is_none_or(|ip| ip_filter.is_allowed(ip));
+        if !allowed {
+            debug!(%remote_addr, "rejecting connection from denied address");
+        }
+        let tls = matches!(conn.inner, MaybeTls::Tls(_));
         let svc = svc.clone();
-        async { Ok::<_, std::convert::Infallible>(svc) }
+        let csp = csp.clone();
+        let rate_limiter = rate_limiter.clone();
+        let api_prefix = api_prefix.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                let svc = svc.clone();
+                let csp = csp.clone();
+                let https = tls || web::forwarded_https(req.headers());
+                // The API, including WebSockets, and gRPC count against the
+                // same limit. Static assets of the web app are not limited.
+                let api = is_grpc(&req) || req.uri().path().starts_with(&*api_prefix);
+                let limited = allowed && api && ip.is_some_and(|ip| !rate_limiter.check(ip));
+                let provided = req.headers().get(REQUEST_ID_HEADER);
+                let id = RequestId::new(provided.and_then(|value| value.to_str().ok()));
+                let span = info_span!("request", request_id = %id.0);
+                req.extensions_mut().insert(id.clone());
+                req.extensions_mut().insert(ConnectInfo(remote_addr));
+                req.extensions_mut().insert(peer.clone());
+                req.extensions_mut().insert(TcpConnectInfo {
+                    local_addr: None,
+                    remote_addr: match remote_addr {
+                        PeerAddr::Tcp(addr) => Some(addr),
+                        PeerAddr::Unix => None,
+                    },
+                });
+                async move {
+                    let grpc = is_grpc(&req);
+                    let reject = |status: Status, code: StatusCode| {
+                        if grpc {
+                            let resp = status.to_http();
+                            resp.map(|b| b.map_err(BoxError::from).boxed_unsync())
+                        } else {
+                            let resp = (code, status.message().to_owned()).into_response();
+                            resp.map(|b| b.map_err(BoxError::from).boxed_unsync())
+                        }
+                    };
+                    let mut resp = if !allowed {
+                        let status = Status::permission_denied("address not allowed");
+                        reject(status, StatusCode::FORBIDDEN)
+                    } else if limited {
+                        let status = Status::resource_exhausted("too many requests");
+                        reject(status, StatusCode::TOO_MANY_REQUESTS)
+                    } else {
+                        svc.oneshot(req).await?
+                    };
+                    if !grpc {
+                        web::set_security_headers(resp.headers_mut(), csp.as_ref(), https);
+                    }
+                    // The ID is validated or generated, so it is a valid header value.
+                    let value = HeaderValue::from_str(&id.0).unwrap();
+                    resp.headers_mut().insert(REQUEST_ID_HEADER, value);
+                    Ok::<_, BoxError>(resp)
+                }
+                .instrument(span)
+            }))
+        }
     });
 
-    HyperServer::builder(incoming)
-        .tcp_nodelay(true)
+    let signal = signal.shared();
+    let server = HyperServer::builder(incoming)
         .serve(make_svc)
+        .with_graceful_shutdown(signal.clone());
+
+    // After the drain timeout, end any remaining streams by terminating their
+    // sessions, and then stop waiting for connections to close.
+    let drain_timeout = state.drain_timeout();
+    let force_close = async move {
+        signal.await;
+        time::sleep(drain_timeout).await;
+        state.shutdown();
+        time::sleep(CLOSE_TIMEOUT).await;
+    };
+
+    tokio::select! {
+        result = server => result?,
+        _ = force_close => warn!("connections did not close in time, exiting anyway"),
+    }
+
+    Ok(())
+}
+
+/// Returns whether a request should be routed to the gRPC service.
+fn is_grpc(req: &Request<Body>) -> bool {
+    matches!(req.headers().get(CONTENT_TYPE), Some(content) if content == "application/grpc")
+}
+
+/// Bind and listen for Prometheus metrics requests on a separate port.
+pub(crate) async fn start_metrics_server(
+    state: Arc<ServerState>,
+    incoming: AddrIncoming,
+    signal: impl Future<Output = ()>,
+) -> Result<()> {
+    let app = web::metrics_app().with_state(state);
+
+    HyperServer::builder(incoming)
+        .serve(app.into_make_service())
         .with_graceful_shutdown(signal)
         .await?;
 
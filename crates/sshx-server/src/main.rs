@@ -1,85 +1,679 @@
 use std::{
-    net::{IpAddr, SocketAddr},
+    ffi::OsString,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
     process::ExitCode,
+    sync::Mutex,
+    time::Duration,
 };
 
-use anyhow::Result;
-use clap::Parser;
-use sshx_server::{Server, ServerOptions};
-use tokio::signal::unix::{signal, SignalKind};
-use tracing::{error, info};
+use anyhow::{bail, Context, Result};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use ipnet::IpNet;
+use sshx_core::logfile::{LogFile, LogFileOptions, Rotation};
+use sshx_server::{
+    acl::parse_cidr,
+    names::{Alphabet, SessionNames},
+    oidc::OidcOptions,
+    otel,
+    tls::TlsOptions,
+    Server, ServerOptions,
+};
+use tracing::{error, info, warn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::commands::{Command, Target};
+use crate::signals::{Signal, Signals};
+
+mod commands;
+mod signals;
 
 /// The sshx server CLI interface.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Specify port to listen on.
-    #[clap(long, default_value_t = 8051)]
+    /// Read options from a TOML file, with keys named after the long flags.
+    /// Flags and environment variables take precedence over the file.
+    #[clap(long, env = "SSHX_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Specify port to listen on, for addresses without a port.
+    #[clap(long, env = "SSHX_PORT", default_value_t = 8051)]
     port: u16,
 
-    /// Which IP address or network interface to listen on.
-    #[clap(long, value_parser, default_value = "::1")]
-    listen: IpAddr,
+    /// Address to listen on, may be repeated or comma-separated: an IP address,
+    /// an IP address with a port, or the path of a Unix domain socket
+    /// containing a `/`.
+    #[clap(
+        long,
+        env = "SSHX_LISTEN",
+        value_delimiter = ',',
+        value_parser = parse_listen,
+        default_value = "::1"
+    )]
+    listen: Vec<ListenAddr>,
 
     /// Secret used for signing session tokens.
     #[clap(long, env = "SSHX_SECRET")]
     secret: Option<String>,
 
     /// Override the origin URL returned by the Open() RPC.
-    #[clap(long)]
+    #[clap(long, env = "SSHX_OVERRIDE_ORIGIN")]
     override_origin: Option<String>,
 
+    /// Only accept these origins from clients when the origin is not
+    /// overridden, may be repeated or comma-separated.
+    #[clap(long, env = "SSHX_ALLOW_ORIGIN", value_delimiter = ',')]
+    allow_origin: Vec<String>,
+
     /// URL of the Redis server that stores session data.
     #[clap(long, env = "SSHX_REDIS_URL")]
     redis_url: Option<String>,
 
-    /// Hostname of this server, if running multiple servers.
-    #[clap(long)]
+    /// Address of this server that other servers can reach, like
+    /// `10.0.0.5:8051`, if running multiple servers. Detected from `POD_IP` or
+    /// the hostname if not set.
+    #[clap(long, env = "SSHX_HOST")]
     host: Option<String>,
+
+    /// Serve Prometheus metrics on a separate port instead of at `/metrics`.
+    #[clap(long, env = "SSHX_METRICS_PORT")]
+    metrics_port: Option<u16>,
+
+    /// Serve `/metrics` without the admin token. Otherwise it needs the token
+    /// as a bearer token, and is disabled if there is none.
+    #[clap(long, env = "SSHX_PUBLIC_METRICS")]
+    public_metrics: bool,
+
+    /// Only allow connections from this network (CIDR), may be repeated or
+    /// comma-separated.
+    #[clap(long, env = "SSHX_ALLOW_IP", value_delimiter = ',', value_parser = parse_cidr)]
+    allow_ip: Vec<IpNet>,
+
+    /// Deny connections from this network (CIDR), may be repeated or
+    /// comma-separated.
+    #[clap(long, env = "SSHX_DENY_IP", value_delimiter = ',', value_parser = parse_cidr)]
+    deny_ip: Vec<IpNet>,
+
+    /// File with `allow <cidr>` and `deny <cidr>` rules, one per line.
+    #[clap(long, env = "SSHX_IP_RULES_FILE")]
+    ip_rules_file: Option<PathBuf>,
+
+    /// Bearer token that enables the admin API at `/api/admin`.
+    #[clap(long, env = "SSHX_ADMIN_TOKEN")]
+    admin_token: Option<String>,
+
+    /// Append audit events as JSON lines to this file.
+    #[clap(long, env = "SSHX_AUDIT_FILE")]
+    audit_file: Option<PathBuf>,
+
+    /// Append audit events to this Redis stream, requires `--redis-url`.
+    #[clap(long, env = "SSHX_AUDIT_STREAM")]
+    audit_stream: Option<String>,
+
+    /// Require web viewers to log in with this OpenID Connect issuer.
+    #[clap(
+        long,
+        env = "SSHX_OIDC_ISSUER",
+        requires_all = ["oidc_client_id", "oidc_client_secret", "oidc_redirect_url"]
+    )]
+    oidc_issuer: Option<String>,
+
+    /// Client ID registered with the OpenID Connect provider.
+    #[clap(long, env = "SSHX_OIDC_CLIENT_ID")]
+    oidc_client_id: Option<String>,
+
+    /// Client secret registered with the OpenID Connect provider.
+    #[clap(long, env = "SSHX_OIDC_CLIENT_SECRET")]
+    oidc_client_secret: Option<String>,
+
+    /// Public URL of this server's `/api/auth/callback` route.
+    #[clap(long, env = "SSHX_OIDC_REDIRECT_URL")]
+    oidc_redirect_url: Option<String>,
+
+    /// Override the Content-Security-Policy header, or disable it if empty.
+    #[clap(long, env = "SSHX_CONTENT_SECURITY_POLICY")]
+    content_security_policy: Option<String>,
+
+    /// Limit terminal input from each web user, in bytes per second.
+    #[clap(long, env = "SSHX_INPUT_RATE_LIMIT")]
+    input_rate_limit: Option<u64>,
+
+    /// Disconnect web users after relaying this many bytes of terminal data
+    /// to and from them.
+    #[clap(long, env = "SSHX_MAX_USER_BYTES")]
+    max_user_bytes: Option<u64>,
+
+    /// Seconds between keepalive pings sent to web clients.
+    #[clap(long, env = "SSHX_PING_INTERVAL")]
+    ping_interval: Option<u64>,
+
+    /// Message shown to every web viewer and CLI client, like a legal notice.
+    #[clap(long, env = "SSHX_BANNER")]
+    banner: Option<String>,
+
+    /// Serve the web app under this URL path prefix, such as `/sshx`.
+    #[clap(long, env = "SSHX_BASE_PATH")]
+    base_path: Option<String>,
+
+    /// Template for session URLs returned to clients, with `{origin}` and
+    /// `{name}` placeholders, like `{origin}/view?session={name}`.
+    #[clap(long, env = "SSHX_SESSION_URL")]
+    session_url: Option<String>,
+
+    /// Ask clients not to put the write password in the links they print.
+    #[clap(long, env = "SSHX_OMIT_WRITE_PASSWORD")]
+    omit_write_password: bool,
+
+    /// Only let users join with a signed join link or the write password.
+    #[clap(long, env = "SSHX_REQUIRE_JOIN_GRANTS")]
+    require_join_grants: bool,
+
+    /// Maximum number of concurrent inbound connections.
+    #[clap(long, env = "SSHX_MAX_CONNECTIONS")]
+    max_connections: Option<usize>,
+
+    /// Maximum number of concurrent WebSocket connections from each IP.
+    #[clap(long, env = "SSHX_MAX_WS_PER_IP")]
+    max_ws_per_ip: Option<usize>,
+
+    /// Maximum number of sessions open on this server at once.
+    #[clap(long, env = "SSHX_MAX_SESSIONS")]
+    max_sessions: Option<usize>,
+
+    /// Characters in random session names: alphanumeric, or crockford for
+    /// lowercase base32 without ambiguous letters.
+    #[clap(long, env = "SSHX_NAME_ALPHABET", default_value = "alphanumeric")]
+    name_alphabet: Alphabet,
+
+    /// Number of characters in random session names.
+    #[clap(long, env = "SSHX_NAME_LENGTH", default_value_t = 10)]
+    name_length: usize,
+
+    /// Name sessions with this many random words, like `brave-violet-otter`,
+    /// instead of random characters.
+    #[clap(long, env = "SSHX_NAME_WORDS", conflicts_with_all = ["name_alphabet", "name_length"])]
+    name_words: Option<usize>,
+
+    /// Mirror a session from another server, given its link, to serve it
+    /// read-only to viewers here. May be repeated or space-separated, since
+    /// links can contain commas.
+    #[clap(long = "relay", env = "SSHX_RELAY", value_name = "LINK")]
+    relay_links: Vec<String>,
+
+    /// Limit requests from each IP, per second, across the web API and gRPC.
+    #[clap(long, env = "SSHX_REQUEST_RATE_LIMIT")]
+    request_rate_limit: Option<u64>,
+
+    /// Seconds to wait for open connections to finish when shutting down.
+    #[clap(long, env = "SSHX_DRAIN_TIMEOUT", default_value_t = 10)]
+    drain_timeout: u64,
+
+    /// Seconds of idle time before sending TCP keepalive probes.
+    #[clap(long, env = "SSHX_TCP_KEEPALIVE")]
+    tcp_keepalive: Option<u64>,
+
+    /// Seconds that sent data may go unacknowledged before closing a TCP
+    /// connection (Linux only).
+    #[clap(long, env = "SSHX_TCP_USER_TIMEOUT")]
+    tcp_user_timeout: Option<u64>,
+
+    /// Seconds without any traffic before closing a connection.
+    #[clap(long, env = "SSHX_IDLE_TIMEOUT")]
+    idle_timeout: Option<u64>,
+
+    /// Serve HTTPS with this PEM certificate chain.
+    #[clap(long, env = "SSHX_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key for the certificate in `--tls-cert`, in PEM format.
+    #[clap(long, env = "SSHX_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Require clients to present a certificate signed by one of these PEM
+    /// certificate authorities, requires `--tls-cert`.
+    #[clap(long, env = "SSHX_TLS_CLIENT_CA", requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    /// Also serve web viewers over WebTransport (HTTP/3) on this UDP port,
+    /// requires `--tls-cert`. Use the HTTPS port, which the default
+    /// Content-Security-Policy allows the web app to connect to.
+    #[cfg(feature = "webtransport")]
+    #[clap(long, env = "SSHX_WEBTRANSPORT_PORT", requires = "tls_cert")]
+    webtransport_port: Option<u16>,
+
+    /// Format of log lines, with one JSON object per line for log collectors.
+    #[clap(long, env = "SSHX_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Write logs to this file instead of stderr.
+    #[clap(long, env = "SSHX_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the log file when it would grow past this many megabytes.
+    #[clap(long, env = "SSHX_LOG_MAX_SIZE", requires = "log_file")]
+    log_max_size: Option<u64>,
+
+    /// Rotate the log file periodically: never, hourly or daily.
+    #[clap(long, env = "SSHX_LOG_ROTATION", default_value = "never")]
+    log_rotation: Rotation,
+
+    /// Number of compressed, rotated log files to keep.
+    #[clap(long, env = "SSHX_LOG_KEEP", default_value_t = 7)]
+    log_keep: usize,
+
+    /// Serve a scripted fake session at `/s/~replay#dev`, for developing the
+    /// web app without a client. Do not enable in production.
+    #[clap(long, env = "SSHX_DEV_REPLAY")]
+    dev_replay: bool,
+
+    /// Report internal errors and panics to this Sentry DSN.
+    #[cfg(feature = "sentry")]
+    #[clap(long, env = "SSHX_SENTRY_DSN")]
+    sentry_dsn: Option<String>,
+
+    /// Manage a running server instead of starting one. Other options, like
+    /// the admin token or Redis URL, say how to reach it.
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// Format of the server's log lines.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Set up logging to stderr or a file, and trace export if it is configured.
+fn init_tracing(format: LogFormat, log_file: Option<LogFileOptions>) -> Result<()> {
+    let (tracer, otel_err) = match otel::tracer() {
+        Ok(tracer) => (tracer, None),
+        Err(err) => (None, Some(err)),
+    };
+    let log_path = log_file.as_ref().map(|options| options.path.clone());
+    // Fall back to stderr if the log file can't be opened, to report the error.
+    let (writer, ansi, file_err) = match log_file.map(LogFile::open).transpose() {
+        Ok(Some(file)) => (BoxMakeWriter::new(Mutex::new(file)), false, None),
+        Ok(None) => (BoxMakeWriter::new(std::io::stderr), true, None),
+        Err(err) => (BoxMakeWriter::new(std::io::stderr), true, Some(err)),
+    };
+    tracing_subscriber::registry()
+        .with(EnvFilter::new(
+            std::env::var("RUST_LOG").unwrap_or("info".into()),
+        ))
+        .with(match format {
+            LogFormat::Text => tracing_subscriber::fmt::layer()
+                .with_ansi(ansi)
+                .with_writer(writer)
+                .boxed(),
+            LogFormat::Json => tracing_subscriber::fmt::layer()
+                .json()
+                .flatten_event(true)
+                .with_writer(writer)
+                .boxed(),
+        })
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .init();
+    if let Some(err) = otel_err {
+        warn!(?err, "failed to set up OpenTelemetry trace export");
+    }
+    match file_err {
+        Some(err) => {
+            let path = log_path.unwrap_or_default();
+            Err(err).with_context(|| format!("failed to open log file {}", path.display()))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Parse arguments, filling in options that were not passed on the command
+/// line or through the environment from the config file, if there is one.
+fn parse_args() -> Args {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let Some(path) = &args.config else {
+        return args;
+    };
+    match config_args(path, &matches) {
+        Ok(config) => {
+            let mut argv = std::env::args_os();
+            let argv = argv.next().into_iter().chain(config).chain(argv);
+            Args::parse_from(argv)
+        }
+        Err(err) => Args::command()
+            .error(ErrorKind::InvalidValue, format!("{err:#}"))
+            .exit(),
+    }
+}
+
+/// Translate the options in a config file into command-line arguments,
+/// skipping those that were already given.
+///
+/// Flags are set by `true` and left out by `false`, and arrays repeat options
+/// that can be given more than once.
+fn config_args(path: &Path, matches: &ArgMatches) -> Result<Vec<OsString>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+    let table: toml::Table = text.parse().context("failed to parse config file")?;
+
+    let command = Args::command();
+    let mut config = Vec::new();
+    for (key, value) in table {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(&key) && key != "config");
+        let Some(arg) = arg else {
+            bail!("unknown option in config file: {key}");
+        };
+        let source = matches.value_source(arg.get_id().as_str());
+        if matches!(
+            source,
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(set) if !arg.get_action().takes_values() => {
+                    if set {
+                        config.push(format!("--{key}").into());
+                    }
+                    continue;
+                }
+                toml::Value::Boolean(value) => value.to_string(),
+                _ => bail!("unsupported value for {key} in config file"),
+            };
+            config.push(format!("--{key}={value}").into());
+        }
+    }
+    Ok(config)
+}
+
+/// An address that the server listens on.
+#[derive(Clone, Debug)]
+enum ListenAddr {
+    Ip(IpAddr),
+    Socket(SocketAddr),
+    Unix(PathBuf),
+}
+
+fn parse_listen(s: &str) -> Result<ListenAddr, String> {
+    if s.contains('/') {
+        Ok(ListenAddr::Unix(s.into()))
+    } else if let Ok(ip) = s.parse() {
+        Ok(ListenAddr::Ip(ip))
+    } else if let Ok(addr) = s.parse() {
+        Ok(ListenAddr::Socket(addr))
+    } else {
+        Err(format!("invalid address or socket path: {s}"))
+    }
+}
+
+/// Split relay links given as arguments, which may each hold several links
+/// separated by whitespace.
+fn split_relay_links(values: &[String]) -> Vec<String> {
+    let links = values.iter().flat_map(|value| value.split_whitespace());
+    links.map(String::from).collect()
 }
 
 #[tokio::main]
 async fn start(args: Args) -> Result<()> {
-    let addr = SocketAddr::new(args.listen, args.port);
+    let log_file = args.log_file.clone().map(|path| LogFileOptions {
+        path,
+        max_size: args.log_max_size.map(|size| size * 1024 * 1024),
+        rotation: args.log_rotation,
+        keep: args.log_keep,
+    });
+    init_tracing(args.log_format, log_file)?;
 
-    let mut sigterm = signal(SignalKind::terminate())?;
-    let mut sigint = signal(SignalKind::interrupt())?;
+    // Metrics and WebTransport are served on the first IP address that the
+    // server listens on.
+    let first_ip = args.listen.iter().find_map(|listen| match listen {
+        ListenAddr::Ip(ip) => Some(*ip),
+        ListenAddr::Socket(addr) => Some(addr.ip()),
+        ListenAddr::Unix(_) => None,
+    });
+    let metrics_addr = args
+        .metrics_port
+        .map(|port| SocketAddr::new(first_ip.unwrap_or(Ipv6Addr::LOCALHOST.into()), port));
+
+    let mut signals = Signals::new()?;
 
     let mut options = ServerOptions::default();
     options.secret = args.secret;
     options.override_origin = args.override_origin;
+    options.allowed_origins = args.allow_origin;
     options.redis_url = args.redis_url;
     options.host = args.host;
+    options.advertise_port = args.listen.iter().find_map(|listen| match listen {
+        ListenAddr::Ip(_) => Some(args.port),
+        ListenAddr::Socket(addr) => Some(addr.port()),
+        ListenAddr::Unix(_) => None,
+    });
+    options.separate_metrics = metrics_addr.is_some();
+    options.public_metrics = args.public_metrics;
+    options.allow_ips = args.allow_ip;
+    options.deny_ips = args.deny_ip;
+    options.ip_rules_file = args.ip_rules_file;
+    options.admin_token = args.admin_token;
+    options.audit_file = args.audit_file;
+    options.audit_stream = args.audit_stream;
+    options.content_security_policy = args.content_security_policy;
+    options.input_rate_limit = args.input_rate_limit;
+    options.max_user_bytes = args.max_user_bytes;
+    options.ping_interval = args.ping_interval.map(Duration::from_secs);
+    options.banner = args.banner;
+    options.base_path = args.base_path;
+    options.session_url = args.session_url;
+    options.omit_write_password = args.omit_write_password;
+    options.require_join_grants = args.require_join_grants;
+    options.max_connections = args.max_connections;
+    options.max_ws_per_ip = args.max_ws_per_ip;
+    options.max_sessions = args.max_sessions;
+    options.session_names = match args.name_words {
+        Some(count) => SessionNames::Words { count },
+        None => SessionNames::Random {
+            alphabet: args.name_alphabet,
+            length: args.name_length,
+        },
+    };
+    options.relay_links = split_relay_links(&args.relay_links);
+    options.request_rate_limit = args.request_rate_limit;
+    options.drain_timeout = Some(Duration::from_secs(args.drain_timeout));
+    options.tcp_keepalive = args.tcp_keepalive.map(Duration::from_secs);
+    options.tcp_user_timeout = args.tcp_user_timeout.map(Duration::from_secs);
+    options.idle_timeout = args.idle_timeout.map(Duration::from_secs);
+    options.oidc = args.oidc_issuer.map(|issuer| OidcOptions {
+        issuer,
+        client_id: args.oidc_client_id.unwrap_or_default(),
+        client_secret: args.oidc_client_secret.unwrap_or_default(),
+        redirect_url: args.oidc_redirect_url.unwrap_or_default(),
+    });
+    options.tls = args.tls_cert.map(|cert| TlsOptions {
+        cert,
+        key: args.tls_key.unwrap_or_default(),
+        client_ca: args.tls_client_ca,
+    });
+    options.dev_replay = args.dev_replay;
 
     let server = Server::new(options)?;
+    server.self_check().await?;
 
-    let serve_task = async {
-        info!("server listening at {addr}");
-        server.bind(&addr).await
+    #[cfg(feature = "sentry")]
+    let _sentry = args.sentry_dsn.as_deref().map(|dsn| {
+        let options = sentry::ClientOptions {
+            release: sentry::release_name!(),
+            ..Default::default()
+        };
+        server.on_error(sshx_server::report::sentry_hook);
+        sentry::init((dsn, options))
+    });
+    let meter_provider = otel::meter_provider(server.state().metrics())?;
+
+    let serve_task = futures_util::future::try_join_all(args.listen.iter().map(|listen| {
+        let server = &server;
+        async move {
+            match listen {
+                ListenAddr::Ip(ip) => {
+                    let addr = SocketAddr::new(*ip, args.port);
+                    info!("server listening at {addr}");
+                    server.bind(&addr).await
+                }
+                ListenAddr::Socket(addr) => {
+                    info!("server listening at {addr}");
+                    server.bind(addr).await
+                }
+                #[cfg(unix)]
+                ListenAddr::Unix(path) => {
+                    info!("server listening at {}", path.display());
+                    server.bind_unix(path).await
+                }
+                #[cfg(not(unix))]
+                ListenAddr::Unix(path) => {
+                    bail!(
+                        "cannot listen at {}: Unix sockets are not supported on this platform",
+                        path.display()
+                    )
+                }
+            }
+        }
+    }));
+
+    let metrics_task = async {
+        if let Some(metrics_addr) = metrics_addr {
+            info!("serving metrics at {metrics_addr}");
+            server.bind_metrics(&metrics_addr).await?;
+        }
+        Ok(())
+    };
+
+    #[cfg(feature = "webtransport")]
+    let webtransport_task = async {
+        if let Some(port) = args.webtransport_port {
+            let addr = SocketAddr::new(first_ip.unwrap_or(Ipv6Addr::LOCALHOST.into()), port);
+            info!("serving webtransport at {addr}");
+            server.bind_webtransport(&addr).await?;
+        }
+        Ok(())
     };
+    #[cfg(not(feature = "webtransport"))]
+    let webtransport_task = async { Ok(()) };
 
     let signals_task = async {
-        tokio::select! {
-            Some(()) = sigterm.recv() => (),
-            Some(()) = sigint.recv() => (),
-            else => return Ok(()),
+        loop {
+            match signals.recv().await {
+                Some(Signal::Shutdown) => break,
+                Some(Signal::Reload) => reload_tls(&server),
+                None => return Ok(()),
+            }
         }
         info!("gracefully shutting down...");
         server.shutdown();
         Ok(())
     };
 
-    tokio::try_join!(serve_task, signals_task)?;
+    let result = tokio::try_join!(serve_task, metrics_task, webtransport_task, signals_task);
+    otel::shutdown(meter_provider);
+    result?;
     Ok(())
 }
 
+/// Run a subcommand against a running server, through its admin API if there
+/// is an admin token, or else through Redis if the subcommand supports it.
+#[tokio::main]
+async fn run_command(args: Args, command: Command) -> Result<()> {
+    match command {
+        Command::Sessions { server, command } => {
+            let target = match (args.admin_token.clone(), &args.redis_url) {
+                (Some(token), _) => {
+                    let url = match server {
+                        Some(url) => url.trim_end_matches('/').to_string(),
+                        None => local_url(&args)?,
+                    };
+                    Target::Api { url, token }
+                }
+                (None, Some(redis_url)) => Target::redis(redis_url)?,
+                (None, None) => bail!("pass --admin-token to use the admin API, or --redis-url"),
+            };
+            commands::run_sessions(target, command).await
+        }
+        Command::Mesh { server } => {
+            let Some(token) = args.admin_token.clone() else {
+                bail!("pass --admin-token to use the admin API");
+            };
+            let url = match server {
+                Some(url) => url.trim_end_matches('/').to_string(),
+                None => local_url(&args)?,
+            };
+            commands::run_mesh(&url, &token).await
+        }
+    }
+}
+
+/// Returns the URL of the first address that the server listens on over TCP.
+fn local_url(args: &Args) -> Result<String> {
+    let addr = args.listen.iter().find_map(|listen| match listen {
+        ListenAddr::Ip(ip) => Some(SocketAddr::new(*ip, args.port)),
+        ListenAddr::Socket(addr) => Some(*addr),
+        ListenAddr::Unix(_) => None,
+    });
+    let Some(mut addr) = addr else {
+        bail!("the server only listens on Unix sockets, pass --server with its URL");
+    };
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    let scheme = if args.tls_cert.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    let base_path = args.base_path.as_deref().unwrap_or("").trim_matches('/');
+    match base_path {
+        "" => Ok(format!("{scheme}://{addr}")),
+        _ => Ok(format!("{scheme}://{addr}/{base_path}")),
+    }
+}
+
+/// Reload TLS certificates from disk on a signal, if built-in TLS is enabled.
+fn reload_tls(server: &Server) {
+    let state = server.state();
+    let Some(tls) = state.tls() else {
+        return;
+    };
+    match tls.reload() {
+        Ok(()) => info!("reloaded TLS certificates"),
+        Err(err) => error!("failed to reload TLS certificates: {err:?}"),
+    }
+}
+
 fn main() -> ExitCode {
-    let args = Args::parse();
+    let mut args = parse_args();
 
-    tracing_subscriber::fmt()
-        .with_env_filter(std::env::var("RUST_LOG").unwrap_or("info".into()))
-        .with_writer(std::io::stderr)
-        .init();
+    if let Some(command) = args.command.take() {
+        return match run_command(args, command) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(err) => {
+                eprintln!("error: {err:#}");
+                ExitCode::FAILURE
+            }
+        };
+    }
 
     match start(args) {
         Ok(()) => ExitCode::SUCCESS,
@@ -89,3 +683,65 @@ fn main() -> ExitCode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use clap::{CommandFactory, Parser};
+
+    use super::{config_args, split_relay_links, Args};
+
+    fn args_from_config(text: &str, argv: &[&str]) -> Vec<String> {
+        let path = env::temp_dir().join(format!("sshx-server-config-{}.toml", process::id()));
+        fs::write(&path, text).unwrap();
+        let argv = ["sshx-server"].iter().chain(argv);
+        let matches = Args::command().get_matches_from(argv);
+        let config = config_args(&path, &matches);
+        fs::remove_file(&path).unwrap();
+        let config = config.unwrap().into_iter();
+        config.map(|arg| arg.into_string().unwrap()).collect()
+    }
+
+    #[test]
+    fn config_file_values() {
+        let text = r#"
+            host = "sshx.example.com"
+            drain-timeout = 30
+            allow-origin = ["https://a.example.com", "https://b.example.com"]
+            omit-write-password = true
+            dev-replay = false
+        "#;
+        let mut args = args_from_config(text, &[]);
+        args.sort();
+        assert_eq!(
+            args,
+            [
+                "--allow-origin=https://a.example.com",
+                "--allow-origin=https://b.example.com",
+                "--drain-timeout=30",
+                "--host=sshx.example.com",
+                "--omit-write-password",
+            ]
+        );
+
+        // Options on the command line take precedence.
+        let args = args_from_config(text, &["--drain-timeout=5", "--host", "other"]);
+        assert!(!args.iter().any(|arg| arg.starts_with("--drain-timeout")));
+        assert!(!args.iter().any(|arg| arg.starts_with("--host")));
+    }
+
+    #[test]
+    fn relay_links_split() {
+        let links = "https://a.example.com/s/one#key  https://b.example.com/s/two#key,write";
+        let args = Args::parse_from(["sshx-server", "--relay", links, "--relay", "c"]);
+        assert_eq!(
+            split_relay_links(&args.relay_links),
+            [
+                "https://a.example.com/s/one#key",
+                "https://b.example.com/s/two#key,write",
+                "c",
+            ]
+        );
+    }
+}
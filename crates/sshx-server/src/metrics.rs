@@ -0,0 +1,235 @@
+//! Prometheus metrics collected by the server.
+
+use anyhow::Result;
+use hyper::StatusCode;
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Encoder, GaugeVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+/// Collection of Prometheus metrics for a single server instance.
+///
+/// Each metric is internally reference-counted, so this object is cheap to
+/// clone and share between tasks.
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    registry: Registry,
+
+    /// Number of sessions held in memory by this server.
+    pub sessions: IntGauge,
+
+    /// Number of users connected to sessions on this server.
+    pub users: IntGauge,
+
+    /// Number of currently open WebSocket connections.
+    pub ws_connections: IntGauge,
+
+    /// Number of sessions added to the store of this server.
+    pub sessions_created: IntCounter,
+
+    /// Number of sessions freed after being removed from the store.
+    pub sessions_dropped: IntCounter,
+
+    /// Number of sessions removed from the store that are still referenced.
+    pub sessions_dangling: IntGauge,
+
+    /// Number of receivers of broadcasted events, across all sessions.
+    pub broadcast_receivers: IntGauge,
+
+    /// Number of open subscriptions to the output of shells.
+    pub chunk_subscriptions: IntGauge,
+
+    /// Encrypted terminal bytes relayed, labeled by direction.
+    pub relayed_bytes: IntCounterVec,
+
+    /// Number of failed operations against Redis.
+    pub redis_errors: IntCounter,
+
+    /// Sessions restored from snapshots in Redis, labeled by whether the
+    /// snapshot could be restored.
+    pub session_restores: IntCounterVec,
+
+    /// Sessions moved between servers in the mesh, labeled by whether this
+    /// server received the session or sent it away.
+    pub session_transfers: IntCounterVec,
+
+    /// Round-trip time of the last probe of each other server in the mesh, in
+    /// seconds, labeled by the server's hostname.
+    pub mesh_peer_latency: GaugeVec,
+
+    /// Failed probes of other servers in the mesh, labeled by hostname.
+    pub mesh_probe_failures: IntCounterVec,
+
+    /// HTTP responses sent by the web server, labeled by status code.
+    pub http_responses: IntCounterVec,
+}
+
+impl Metrics {
+    /// Create and register a new set of metrics.
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("sshx".into()), None).unwrap();
+
+        let sessions = IntGauge::new("sessions", "Number of sessions held in memory").unwrap();
+        let users = IntGauge::new("users", "Number of users connected to sessions").unwrap();
+        let ws_connections =
+            IntGauge::new("ws_connections", "Number of open WebSocket connections").unwrap();
+        let sessions_created =
+            IntCounter::new("sessions_created_total", "Number of sessions created").unwrap();
+        let sessions_dropped = IntCounter::new(
+            "sessions_dropped_total",
+            "Number of removed sessions that were freed",
+        )
+        .unwrap();
+        let sessions_dangling = IntGauge::new(
+            "sessions_dangling",
+            "Number of removed sessions that are still referenced",
+        )
+        .unwrap();
+        let broadcast_receivers = IntGauge::new(
+            "broadcast_receivers",
+            "Number of receivers of session broadcasts",
+        )
+        .unwrap();
+        let chunk_subscriptions = IntGauge::new(
+            "chunk_subscriptions",
+            "Number of subscriptions to shell output",
+        )
+        .unwrap();
+        let relayed_bytes = IntCounterVec::new(
+            Opts::new("relayed_bytes_total", "Encrypted terminal bytes relayed"),
+            &["direction"],
+        )
+        .unwrap();
+        let redis_errors =
+            IntCounter::new("redis_errors_total", "Number of failed Redis operations").unwrap();
+        let session_restores = IntCounterVec::new(
+            Opts::new(
+                "session_restores_total",
+                "Sessions restored from storage by result",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let session_transfers = IntCounterVec::new(
+            Opts::new(
+                "session_transfers_total",
+                "Sessions moved between servers by direction",
+            ),
+            &["direction"],
+        )
+        .unwrap();
+        let mesh_peer_latency = GaugeVec::new(
+            Opts::new(
+                "mesh_peer_latency_seconds",
+                "Round-trip time to other servers in the mesh",
+            ),
+            &["peer"],
+        )
+        .unwrap();
+        let mesh_probe_failures = IntCounterVec::new(
+            Opts::new(
+                "mesh_probe_failures_total",
+                "Failed probes of other servers in the mesh",
+            ),
+            &["peer"],
+        )
+        .unwrap();
+        let http_responses = IntCounterVec::new(
+            Opts::new("http_responses_total", "HTTP responses sent by status code"),
+            &["status"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(sessions.clone())).unwrap();
+        registry.register(Box::new(users.clone())).unwrap();
+        registry.register(Box::new(ws_connections.clone())).unwrap();
+        registry
+            .register(Box::new(sessions_created.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(sessions_dropped.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(sessions_dangling.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(broadcast_receivers.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(chunk_subscriptions.clone()))
+            .unwrap();
+        registry.register(Box::new(relayed_bytes.clone())).unwrap();
+        registry.register(Box::new(redis_errors.clone())).unwrap();
+        registry
+            .register(Box::new(session_restores.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(session_transfers.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(mesh_peer_latency.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(mesh_probe_failures.clone()))
+            .unwrap();
+        registry.register(Box::new(http_responses.clone())).unwrap();
+
+        Self {
+            registry,
+            sessions,
+            users,
+            ws_connections,
+            sessions_created,
+            sessions_dropped,
+            sessions_dangling,
+            broadcast_receivers,
+            chunk_subscriptions,
+            relayed_bytes,
+            redis_errors,
+            session_restores,
+            session_transfers,
+            mesh_peer_latency,
+            mesh_probe_failures,
+            http_responses,
+        }
+    }
+
+    /// Record terminal input sent from a web client to the backend.
+    pub fn record_input(&self, bytes: usize) {
+        self.relayed_bytes
+            .with_label_values(&["input"])
+            .inc_by(bytes as u64);
+    }
+
+    /// Record terminal output sent from the backend to a web client.
+    pub fn record_output(&self, bytes: usize) {
+        self.relayed_bytes
+            .with_label_values(&["output"])
+            .inc_by(bytes as u64);
+    }
+
+    /// Record the status code of an HTTP response.
+    pub fn record_http_status(&self, status: StatusCode) {
+        self.http_responses
+            .with_label_values(&[status.as_str()])
+            .inc();
+    }
+
+    /// Gather the current values of all metrics.
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        self.registry.gather()
+    }
+
+    /// Encode all metrics in the Prometheus text exposition format.
+    pub fn encode(&self) -> Result<String> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
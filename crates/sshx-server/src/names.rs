@@ -0,0 +1,157 @@
+//! Generation of the names of new sessions, which appear in their URLs.
+//!
+//! Names only identify sessions, since the encryption key is in the URL
+//! fragment and never sent to the server. They should still be easy to read
+//! aloud, and long enough that new names rarely collide.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use rand::{seq::SliceRandom, thread_rng, Rng};
+
+/// Characters that random session names are made of.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Alphabet {
+    /// Letters of either case and digits.
+    #[default]
+    Alphanumeric,
+    /// Lowercase Crockford base32, which leaves out the easily confused
+    /// letters `i`, `l`, `o` and `u`.
+    Crockford,
+}
+
+impl Alphabet {
+    fn chars(self) -> &'static [u8] {
+        match self {
+            Alphabet::Alphanumeric => {
+                b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789"
+            }
+            Alphabet::Crockford => b"0123456789abcdefghjkmnpqrstvwxyz",
+        }
+    }
+}
+
+impl FromStr for Alphabet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "alphanumeric" => Ok(Alphabet::Alphanumeric),
+            "crockford" => Ok(Alphabet::Crockford),
+            _ => Err(format!("expected alphanumeric or crockford, got {s:?}")),
+        }
+    }
+}
+
+/// Scheme for generating the names of new sessions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionNames {
+    /// Random characters from an alphabet, like `q8JVxBzM4p`.
+    Random {
+        /// Characters that names are made of.
+        alphabet: Alphabet,
+        /// Number of characters in each name.
+        length: usize,
+    },
+    /// Random words joined by dashes, like `brave-violet-otter`.
+    ///
+    /// The last word is an animal and the one before it a color, with any
+    /// others being adjectives.
+    Words {
+        /// Number of words in each name.
+        count: usize,
+    },
+}
+
+impl Default for SessionNames {
+    fn default() -> Self {
+        SessionNames::Random {
+            alphabet: Alphabet::Alphanumeric,
+            length: 10,
+        }
+    }
+}
+
+impl SessionNames {
+    /// Check that generated names have a reasonable length.
+    pub fn check(self) -> Result<()> {
+        match self {
+            SessionNames::Random { length, .. } if !(4..=64).contains(&length) => {
+                bail!("session names must be between 4 and 64 characters long")
+            }
+            SessionNames::Words { count } if !(2..=8).contains(&count) => {
+                bail!("session names must have between 2 and 8 words")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Generate a new, random name.
+    pub fn generate(self) -> String {
+        let mut rng = thread_rng();
+        match self {
+            SessionNames::Random { alphabet, length } => {
+                let chars = alphabet.chars();
+                (0..length)
+                    .map(|_| char::from(chars[rng.gen_range(0..chars.len())]))
+                    .collect()
+            }
+            SessionNames::Words { count } => {
+                let mut words: Vec<&str> = (2..count)
+                    .map(|_| *ADJECTIVES.choose(&mut rng).unwrap())
+                    .collect();
+                words.push(COLORS.choose(&mut rng).unwrap());
+                words.push(ANIMALS.choose(&mut rng).unwrap());
+                words.join("-")
+            }
+        }
+    }
+}
+
+/// Shortest name that a client can ask for, as long as default generated
+/// names, so that short names are not easy to guess.
+pub const MIN_CUSTOM_NAME_LEN: usize = 10;
+
+/// Check a name that the client asked to open a session under, which must be
+/// safe to put in a URL.
+pub fn check_custom_name(name: &str) -> Result<()> {
+    if !(MIN_CUSTOM_NAME_LEN..=64).contains(&name.len()) {
+        bail!("session name must be between {MIN_CUSTOM_NAME_LEN} and 64 characters long");
+    }
+    if !name.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-') {
+        bail!("session name can only contain letters, digits and dashes");
+    }
+    if name.starts_with('-') || name.ends_with('-') {
+        bail!("session name cannot start or end with a dash");
+    }
+    Ok(())
+}
+
+const ADJECTIVES: &[&str] = &[
+    "agile", "ample", "awake", "bold", "brave", "breezy", "bright", "brisk", "calm", "candid",
+    "cheery", "clever", "cosmic", "cozy", "crisp", "curious", "daring", "dapper", "eager", "early",
+    "epic", "fair", "fancy", "fast", "fearless", "fluffy", "fond", "frank", "fresh", "friendly",
+    "gentle", "giant", "glad", "grand", "happy", "hardy", "honest", "humble", "jolly", "jumpy",
+    "keen", "kind", "lively", "loyal", "lucky", "merry", "mighty", "modest", "nimble", "noble",
+    "patient", "plucky", "polite", "proud", "quick", "quiet", "rapid", "ready", "shiny", "silent",
+    "snappy", "steady", "sunny",
+];
+
+const COLORS: &[&str] = &[
+    "amber", "aqua", "azure", "beige", "black", "blue", "bronze", "brown", "coral", "cream",
+    "crimson", "cyan", "gold", "gray", "green", "indigo", "ivory", "jade", "khaki", "lemon",
+    "lilac", "lime", "magenta", "maroon", "mint", "navy", "olive", "orange", "peach", "pink",
+    "plum", "purple", "red", "rose", "ruby", "rust", "salmon", "sand", "scarlet", "silver", "tan",
+    "teal", "violet", "white", "yellow",
+];
+
+const ANIMALS: &[&str] = &[
+    "badger", "bat", "bear", "beaver", "bee", "bison", "camel", "cat", "cobra", "cougar", "coyote",
+    "crab", "crane", "crow", "deer", "dingo", "dog", "dolphin", "dove", "duck", "eagle", "eel",
+    "elk", "falcon", "ferret", "finch", "fox", "frog", "gecko", "goat", "goose", "gopher", "hare",
+    "hawk", "heron", "horse", "ibis", "jackal", "jaguar", "koala", "lemur", "lion", "llama",
+    "lynx", "mole", "moose", "mouse", "newt", "otter", "owl", "panda", "parrot", "pelican",
+    "penguin", "pony", "puffin", "rabbit", "raven", "robin", "seal", "shark", "sloth", "snail",
+    "swan", "tiger", "toad", "trout", "turtle", "walrus", "whale", "wolf", "wombat", "yak",
+    "zebra",
+];
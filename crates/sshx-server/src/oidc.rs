@@ -0,0 +1,185 @@
+//! OpenID Connect login for web viewers, using the authorization code flow.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, ensure, Context, Result};
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
+use url::Url;
+
+/// Options for an OpenID Connect identity provider.
+#[derive(Clone, Debug)]
+pub struct OidcOptions {
+    /// Issuer URL of the provider, used for discovery.
+    pub issuer: String,
+
+    /// Client ID registered with the provider.
+    pub client_id: String,
+
+    /// Client secret registered with the provider.
+    pub client_secret: String,
+
+    /// Public URL of the `/api/auth/callback` route on this server.
+    pub redirect_url: String,
+}
+
+/// Identity of a web viewer that has logged in with the provider.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    /// Subject identifier, unique within the issuer.
+    pub sub: String,
+
+    /// Email address of the user, if provided.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+}
+
+/// Endpoints of the provider, fetched from its discovery document.
+#[derive(Deserialize, Debug)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+/// Response from the token endpoint.
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// Claims of an ID token that are checked by this server.
+#[derive(Deserialize, Debug)]
+struct IdTokenClaims {
+    iss: String,
+    aud: Audience,
+    exp: u64,
+    nonce: Option<String>,
+    sub: String,
+    email: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::One(aud) => aud == client_id,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == client_id),
+        }
+    }
+}
+
+/// Client for a single OpenID Connect provider.
+pub struct OidcClient {
+    options: OidcOptions,
+    http: reqwest::Client,
+    provider: OnceCell<ProviderMetadata>,
+}
+
+impl OidcClient {
+    /// Create a new client. The provider is discovered on first use.
+    pub fn new(options: OidcOptions) -> Self {
+        Self {
+            options,
+            http: reqwest::Client::new(),
+            provider: OnceCell::new(),
+        }
+    }
+
+    /// Returns whether cookies should be marked secure, based on the redirect.
+    pub fn secure_cookies(&self) -> bool {
+        self.options.redirect_url.starts_with("https://")
+    }
+
+    async fn provider(&self) -> Result<&ProviderMetadata> {
+        self.provider
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.options.issuer.trim_end_matches('/'),
+                );
+                let body = self.http.get(&url).send().await?.error_for_status()?;
+                let provider: ProviderMetadata = serde_json::from_slice(&body.bytes().await?)
+                    .context("invalid OpenID discovery document")?;
+                Ok(provider)
+            })
+            .await
+    }
+
+    /// Returns the URL that starts a login at the provider.
+    pub async fn authorize_url(&self, state: &str, nonce: &str) -> Result<String> {
+        let provider = self.provider().await?;
+        let url = Url::parse_with_params(
+            &provider.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", &self.options.client_id),
+                ("redirect_uri", &self.options.redirect_url),
+                ("scope", "openid email"),
+                ("state", state),
+                ("nonce", nonce),
+            ],
+        )?;
+        Ok(url.into())
+    }
+
+    /// Exchange an authorization code for the identity of the user.
+    ///
+    /// The ID token is received directly from the token endpoint over TLS, so
+    /// its claims are validated but not its signature, as permitted by OpenID
+    /// Connect Core, Section 3.1.3.7.
+    pub async fn exchange_code(&self, code: &str, nonce: &str) -> Result<Identity> {
+        let provider = self.provider().await?;
+        let resp = self
+            .http
+            .post(&provider.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.options.redirect_url),
+                ("client_id", &self.options.client_id),
+                ("client_secret", &self.options.client_secret),
+            ])
+            .send()
+            .await?
+            .error_for_status()?;
+        let token: TokenResponse = serde_json::from_slice(&resp.bytes().await?)?;
+
+        let payload = token
+            .id_token
+            .split('.')
+            .nth(1)
+            .context("malformed ID token")?;
+        let claims: IdTokenClaims =
+            serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(payload)?)?;
+
+        ensure!(claims.iss == provider.issuer, "ID token has wrong issuer");
+        ensure!(
+            claims.aud.contains(&self.options.client_id),
+            "ID token has wrong audience",
+        );
+        ensure!(claims.exp > unix_time(), "ID token has expired");
+        if claims.nonce.as_deref() != Some(nonce) {
+            bail!("ID token has wrong nonce");
+        }
+        Ok(Identity {
+            sub: claims.sub,
+            email: claims.email,
+        })
+    }
+}
+
+/// Returns the current Unix timestamp in seconds.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,134 @@
+//! Optional export of traces and metrics over OTLP.
+//!
+//! Export is configured with the standard `OTEL_*` environment variables, and
+//! is only enabled for a signal when an OTLP endpoint is set for it, such as
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`.
+
+use std::env;
+
+use anyhow::Result;
+use opentelemetry::metrics::MeterProvider as _;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use prometheus::proto::MetricType;
+
+use crate::metrics::Metrics;
+
+/// Returns whether OTLP export is configured for a signal.
+fn enabled(signal: &str) -> bool {
+    if env::var("OTEL_SDK_DISABLED").is_ok_and(|value| value.eq_ignore_ascii_case("true")) {
+        return false;
+    }
+    env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some()
+        || env::var_os(format!("OTEL_EXPORTER_OTLP_{signal}_ENDPOINT")).is_some()
+}
+
+/// Resource describing this server, from the environment.
+fn resource() -> Resource {
+    let resource = Resource::default();
+    match resource.get("service.name".into()) {
+        Some(name) if !name.as_str().starts_with("unknown_service") => resource,
+        _ => resource.merge(&Resource::new([KeyValue::new(
+            "service.name",
+            "sshx-server",
+        )])),
+    }
+}
+
+/// Install a global OTLP trace exporter, if configured.
+///
+/// Returns a tracer for use with `tracing-opentelemetry`. This must be called
+/// from within a Tokio runtime, which exports spans in the background.
+pub fn tracer() -> Result<Option<trace::Tracer>> {
+    if !enabled("TRACES") {
+        return Ok(None);
+    }
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_trace_config(trace::config().with_resource(resource()))
+        .install_batch(runtime::Tokio)?;
+    Ok(Some(tracer))
+}
+
+/// Export the server's Prometheus metrics over OTLP, if configured.
+///
+/// Gauges and counters in the registry are read periodically by observable
+/// instruments of the same name, with labels as attributes.
+pub fn meter_provider(metrics: &Metrics) -> Result<Option<SdkMeterProvider>> {
+    if !enabled("METRICS") {
+        return Ok(None);
+    }
+    let provider = opentelemetry_otlp::new_pipeline()
+        .metrics(runtime::Tokio)
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .with_resource(resource())
+        .build()?;
+
+    let meter = provider.meter("sshx-server");
+    for family in metrics.gather() {
+        let name = family.get_name().to_string();
+        let help = family.get_help().to_string();
+        let metrics = metrics.clone();
+        match family.get_field_type() {
+            MetricType::GAUGE => {
+                meter
+                    .i64_observable_gauge(name.clone())
+                    .with_description(help)
+                    .with_callback(move |observer| {
+                        for (value, attrs) in observe(&metrics, &name) {
+                            observer.observe(value as i64, &attrs);
+                        }
+                    })
+                    .init();
+            }
+            MetricType::COUNTER => {
+                meter
+                    .u64_observable_counter(name.clone())
+                    .with_description(help)
+                    .with_callback(move |observer| {
+                        for (value, attrs) in observe(&metrics, &name) {
+                            observer.observe(value as u64, &attrs);
+                        }
+                    })
+                    .init();
+            }
+            _ => {}
+        }
+    }
+    Ok(Some(provider))
+}
+
+/// Read the current values of a metric family, with their labels.
+fn observe(metrics: &Metrics, name: &str) -> Vec<(f64, Vec<KeyValue>)> {
+    let mut values = Vec::new();
+    for family in metrics.gather() {
+        if family.get_name() != name {
+            continue;
+        }
+        for metric in family.get_metric() {
+            let attrs = metric
+                .get_label()
+                .iter()
+                .map(|label| {
+                    KeyValue::new(label.get_name().to_string(), label.get_value().to_string())
+                })
+                .collect();
+            let value = match family.get_field_type() {
+                MetricType::GAUGE => metric.get_gauge().get_value(),
+                _ => metric.get_counter().get_value(),
+            };
+            values.push((value, attrs));
+        }
+    }
+    values
+}
+
+/// Flush and stop all exporters before the process exits.
+pub fn shutdown(meter_provider: Option<SdkMeterProvider>) {
+    if let Some(provider) = meter_provider {
+        provider.shutdown().ok();
+    }
+    global::shutdown_tracer_provider();
+}
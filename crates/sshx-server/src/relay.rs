@@ -0,0 +1,239 @@
+//! Read-only mirrors of sessions that are hosted on other servers.
+//!
+//! A relay joins a session on its origin server as a viewer, and serves the
+//! same shells from a local session with the same name. Terminal data is
+//! copied while still encrypted, so viewers open the relay with the original
+//! key, but nobody can write to the mirrored session. Many relays can then
+//! share a large audience without adding load to the origin.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use sshx_core::Sid;
+use sshx_crypto::Encrypt;
+use tokio::{net::TcpStream, task, time};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{info, warn};
+
+use crate::grpc::get_time_ms;
+use crate::session::{Metadata, Session};
+use crate::web::protocol::{WsClient, WsServer, WsShellClosed, PROTOCOL_VERSION};
+use crate::ServerState;
+
+/// How long to wait before connecting to the origin again after an error.
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// Interval for marking the mirror as active while connected, since there is
+/// no backend client to keep it from expiring.
+const ACCESS_INTERVAL: Duration = Duration::from_secs(60);
+
+type Upstream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// A session on another server, parsed from its link.
+#[derive(Clone, Debug)]
+pub struct Origin {
+    /// Name of the session, which is also used for the local mirror.
+    pub name: String,
+    /// WebSocket endpoint of the session on the origin server.
+    ws_url: String,
+    /// Encryption key from the fragment of the link.
+    key: String,
+}
+
+impl Origin {
+    /// Parse a session link like `https://sshx.io/s/<name>#<key>`.
+    ///
+    /// A write password in the link is ignored, since mirrors are read-only.
+    pub fn parse(link: &str) -> Result<Self> {
+        let (url, secrets) = link
+            .split_once('#')
+            .context("relay link is missing the encryption key")?;
+        let key = secrets.split(',').next().unwrap_or_default();
+        let (scheme, rest) = url.split_once("://").context("invalid relay link")?;
+        let scheme = match scheme {
+            "https" => "wss",
+            "http" => "ws",
+            _ => bail!("relay link must use http or https"),
+        };
+        let Some(index) = rest.rfind("/s/") else {
+            bail!("relay link should look like https://sshx.io/s/<name>#<key>");
+        };
+        let name = &rest[index + 3..];
+        if name.is_empty() || name.contains('/') {
+            bail!("invalid session name in relay link");
+        }
+        Ok(Self {
+            name: name.into(),
+            ws_url: format!("{scheme}://{}/api{}", &rest[..index], &rest[index..]),
+            key: key.into(),
+        })
+    }
+}
+
+/// Mirror a session from its origin until it ends, reconnecting after errors.
+pub async fn relay(state: Arc<ServerState>, origin: Origin) {
+    let key = origin.key.clone();
+    let zeros = match task::spawn_blocking(move || Encrypt::new(&key).zeros()).await {
+        Ok(zeros) => Bytes::from(zeros.to_vec()),
+        Err(_) => return,
+    };
+
+    let mut session = None;
+    while !state.is_shutting_down() {
+        match mirror(&state, &origin, &zeros, &mut session).await {
+            Ok(()) => {
+                info!(session = %origin.name, "relayed session ended");
+                break;
+            }
+            Err(err) => warn!(?err, session = %origin.name, "lost connection to relay origin"),
+        }
+        time::sleep(RECONNECT_DELAY).await;
+    }
+    if let Some(session) = session {
+        if state
+            .lookup(&origin.name)
+            .is_some_and(|s| Arc::ptr_eq(&s, &session))
+        {
+            state.remove(&origin.name);
+        }
+    }
+}
+
+/// Connect to the origin once, copying shells and data into the local session.
+///
+/// Returns `Ok` if the session ended, and an error if the connection was lost.
+async fn mirror(
+    state: &ServerState,
+    origin: &Origin,
+    zeros: &Bytes,
+    session: &mut Option<Arc<Session>>,
+) -> Result<()> {
+    let (mut upstream, _) = tokio_tungstenite::connect_async(&origin.ws_url).await?;
+    let name = match recv(&mut upstream).await? {
+        Some(WsServer::Hello(_, name, ..)) => name,
+        _ => bail!("expected a hello message from the origin"),
+    };
+    let auth = WsClient::Authenticate(zeros.clone(), None, Some(PROTOCOL_VERSION));
+    send(&mut upstream, &auth).await?;
+
+    let session = session.get_or_insert_with(|| {
+        // Nobody knows the write password, so every viewer is read-only.
+        let metadata = Metadata {
+            encrypted_zeros: zeros.clone(),
+            name,
+            write_password_hash: Some(rand::random::<[u8; 32]>().to_vec().into()),
+            write_password_salt: None,
+            color_scheme: None,
+            environment: None,
+            client_key: None,
+            token_nonce: Bytes::new(),
+        };
+        let session = Arc::new(Session::new(metadata));
+        state.insert(&origin.name, session.clone());
+        info!(session = %origin.name, "relaying session");
+        session
+    });
+
+    let mut subscribed = HashSet::new();
+    let mut access_interval = time::interval(ACCESS_INTERVAL);
+    loop {
+        let msg = tokio::select! {
+            msg = recv(&mut upstream) => msg?,
+            _ = access_interval.tick() => {
+                session.access();
+                continue;
+            }
+            _ = session.terminated() => return Ok(()),
+        };
+        match msg {
+            None => return Ok(()),
+            Some(WsServer::InvalidAuth()) => bail!("invalid encryption key for relay origin"),
+            Some(WsServer::Shells(shells)) => {
+                let open: HashSet<Sid> = shells.iter().map(|&(id, _)| id).collect();
+                let local: HashSet<Sid> = session.list_shells().iter().map(|&(id, _)| id).collect();
+                for &id in local.difference(&open) {
+                    session.close_shell(id, WsShellClosed::default())?;
+                }
+                for (id, winsize) in shells {
+                    if !local.contains(&id)
+                        && session.add_shell(id, (winsize.x, winsize.y)).is_err()
+                    {
+                        continue; // The shell was already closed.
+                    }
+                    session.move_shell(id, Some(winsize))?;
+                    if subscribed.insert(id) {
+                        send(&mut upstream, &WsClient::Subscribe(id, 0, true)).await?;
+                    }
+                }
+            }
+            Some(WsServer::ShellClosed(id, closed)) => {
+                session.close_shell(id, closed).ok();
+            }
+            Some(WsServer::ShellHealth(id, health)) => {
+                session.set_shell_health(id, health).ok();
+            }
+            Some(WsServer::Chunks(id, seqnum, chunks)) => {
+                let times = vec![get_time_ms(); chunks.len()];
+                add_chunks(session, id, seqnum, chunks, times)?;
+            }
+            Some(WsServer::TimedChunks(id, seqnum, chunks, times)) => {
+                add_chunks(session, id, seqnum, chunks, times)?;
+            }
+            Some(WsServer::Error(err)) => warn!(%err, "error from relay origin"),
+            Some(_) => (),
+        }
+    }
+}
+
+/// Copy chunks of data into a shell, skipping ahead if the origin pruned data
+/// that the mirror has not received.
+fn add_chunks(
+    session: &Session,
+    id: Sid,
+    mut seqnum: u64,
+    chunks: Vec<Bytes>,
+    times: Vec<u64>,
+) -> Result<()> {
+    if session.list_shells().iter().all(|&(sid, _)| sid != id) {
+        return Ok(()); // The shell was closed in the meantime.
+    }
+    session.skip_data(id, seqnum)?;
+    for (i, chunk) in chunks.into_iter().enumerate() {
+        let len = chunk.len() as u64;
+        let time = times.get(i).copied().unwrap_or_else(get_time_ms);
+        session.add_data(id, chunk, seqnum, time)?;
+        seqnum += len;
+    }
+    Ok(())
+}
+
+async fn send(upstream: &mut Upstream, msg: &WsClient) -> Result<()> {
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(msg, &mut buf)?;
+    upstream.send(Message::Binary(buf)).await?;
+    Ok(())
+}
+
+/// Receive the next message from the origin, or `None` if the session ended.
+async fn recv(upstream: &mut Upstream) -> Result<Option<WsServer>> {
+    while let Some(msg) = upstream.next().await.transpose()? {
+        match msg {
+            Message::Binary(msg) => return Ok(Some(ciborium::de::from_reader(&*msg)?)),
+            Message::Close(Some(frame)) if frame.code != 1000.into() => {
+                bail!(
+                    "origin closed the connection: {} ({})",
+                    frame.reason,
+                    frame.code
+                )
+            }
+            Message::Close(_) => return Ok(None),
+            _ => (), // Ignore pings and other messages.
+        }
+    }
+    bail!("connection to the origin was lost")
+}
@@ -0,0 +1,120 @@
+//! Reporting of internal errors to hooks registered by the operator.
+//!
+//! Internal errors are always logged, but they can also be sent to an error
+//! tracker so that operators are alerted. See [`Server::on_error`].
+//!
+//! [`Server::on_error`]: crate::Server::on_error
+
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+use futures_util::FutureExt;
+use parking_lot::RwLock;
+use tracing::error;
+
+/// Part of the server where an internal error happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorSource {
+    /// A Redis operation failed.
+    Redis,
+    /// A session could not be closed or synchronized.
+    Session,
+    /// A WebSocket could not be proxied to the server that owns its session.
+    Proxy,
+    /// A task serving a session panicked.
+    Panic,
+}
+
+impl ErrorSource {
+    /// Returns a short name for this source, like `redis`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorSource::Redis => "redis",
+            ErrorSource::Session => "session",
+            ErrorSource::Proxy => "proxy",
+            ErrorSource::Panic => "panic",
+        }
+    }
+}
+
+/// An internal error passed to error hooks.
+#[derive(Debug)]
+pub struct ErrorReport<'a> {
+    /// Part of the server where the error happened.
+    pub source: ErrorSource,
+    /// The error itself.
+    pub error: &'a Error,
+    /// Name of the session involved, if any.
+    pub session: Option<&'a str>,
+}
+
+type Hook = Box<dyn Fn(&ErrorReport<'_>) + Send + Sync>;
+
+/// Sends internal errors to every registered hook.
+#[derive(Clone, Default)]
+pub struct ErrorReporter {
+    hooks: Arc<RwLock<Vec<Hook>>>,
+}
+
+impl ErrorReporter {
+    /// Register a hook that is called with every internal error.
+    pub fn add_hook(&self, hook: impl Fn(&ErrorReport<'_>) + Send + Sync + 'static) {
+        self.hooks.write().push(Box::new(hook));
+    }
+
+    /// Send an error to the registered hooks.
+    pub fn report(&self, source: ErrorSource, error: &Error, session: Option<&str>) {
+        let report = ErrorReport {
+            source,
+            error,
+            session,
+        };
+        for hook in self.hooks.read().iter() {
+            hook(&report);
+        }
+    }
+
+    /// Wrap a task serving a session, so that a panic in it is reported.
+    pub fn guard(
+        &self,
+        session: Option<String>,
+        task: impl Future<Output = ()> + Send,
+    ) -> impl Future<Output = ()> + Send {
+        let reporter = self.clone();
+        async move {
+            if let Err(payload) = AssertUnwindSafe(task).catch_unwind().await {
+                let err = anyhow!("task panicked: {}", panic_message(&*payload));
+                error!(?err, "session task panicked");
+                reporter.report(ErrorSource::Panic, &err, session.as_deref());
+            }
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message,
+            None => "unknown panic",
+        },
+    }
+}
+
+/// Error hook that sends errors to Sentry, which must be initialized first.
+#[cfg(feature = "sentry")]
+pub fn sentry_hook(report: &ErrorReport<'_>) {
+    sentry::with_scope(
+        |scope| {
+            scope.set_tag("source", report.source.as_str());
+            if let Some(session) = report.session {
+                scope.set_tag("session", session);
+            }
+        },
+        || sentry::capture_error(AsRef::<dyn std::error::Error>::as_ref(report.error)),
+    );
+}
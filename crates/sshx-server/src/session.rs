@@ -1,30 +1,81 @@
 //! Core logic for sshx sessions, independent of message transport.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ops::DerefMut;
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use bytes::Bytes;
 use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
+use serde::Serialize;
 use sshx_core::{
-    proto::{server_update::ServerMessage, SequenceNumbers},
+    proto::{InactivityPolicy, Palette, SequenceNumbers},
     IdCounter, Sid, Uid,
 };
+use sshx_crypto::{hash_verifier, VERIFIER_SALT_LEN};
 use tokio::sync::{broadcast, watch, Notify};
-use tokio::time::Instant;
+use tokio::time::{self, Duration, Instant};
 use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, WatchStream};
 use tokio_stream::Stream;
 use tracing::{debug, warn};
 
+use crate::grpc::get_time_ms;
 use crate::utils::Shutdown;
-use crate::web::protocol::{WsServer, WsUser, WsWinsize};
+use crate::web::protocol::{
+    WsNotes, WsNotesEdit, WsServer, WsShellClosed, WsShellHealth, WsStreamKind, WsUser, WsWinsize,
+};
 
 mod snapshot;
+mod updates;
+
+pub use updates::{UpdateReceiver, UpdateSender};
 
 /// Store a rolling buffer with at most this quantity of output, per shell.
 const SHELL_STORED_BYTES: u64 = 1 << 21; // 2 MiB
 
+/// Number of samples in the rolling window for the output rate of shells.
+const THROUGHPUT_SAMPLES: usize = 5;
+
+/// Largest size of the shared notes, including edits that were not compacted.
+const NOTES_STORED_BYTES: usize = 1 << 20; // 1 MiB
+
+/// Largest size of the commands and links suggested by the client.
+const PALETTE_STORED_BYTES: usize = 1 << 16; // 64 KiB
+
+/// Number of users in a session from which output is sent to them in batches.
+const BATCH_MIN_USERS: usize = 8;
+
+/// Time to wait for more output before sending it, when sending in batches.
+const BATCH_INTERVAL: Duration = Duration::from_millis(16);
+
+/// Largest number of rows that a terminal can be resized to.
+const MAX_ROWS: u16 = 500;
+
+/// Largest number of columns that a terminal can be resized to.
+const MAX_COLS: u16 = 1000;
+
+/// Largest distance of a terminal from the origin, along either axis.
+const MAX_COORDINATE: u32 = 1 << 20;
+
+/// Bytes of terminal and stream data relayed to and from a connection.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Bandwidth {
+    /// Bytes sent to the connection.
+    pub sent: u64,
+    /// Bytes received from the connection.
+    pub received: u64,
+}
+
+impl Bandwidth {
+    /// Returns the bytes relayed in both directions.
+    pub fn total(&self) -> u64 {
+        self.sent + self.received
+    }
+}
+
 /// Static metadata for this session.
 #[derive(Debug, Clone)]
 pub struct Metadata {
@@ -36,6 +87,36 @@ pub struct Metadata {
 
     /// Password for write access to the session.
     pub write_password_hash: Option<Bytes>,
+
+    /// Salt of the write password hash, or `None` if the hash is compared
+    /// with the encrypted zeros of the write password directly.
+    pub write_password_salt: Option<Bytes>,
+
+    /// Encrypted color scheme of the client's terminal, if it shared one.
+    pub color_scheme: Option<Bytes>,
+
+    /// Encrypted environment of the client's shells, if it shared it.
+    pub environment: Option<Bytes>,
+
+    /// Ed25519 public key of the client, which must sign its connections.
+    pub client_key: Option<Bytes>,
+
+    /// Random value that the session token is bound to, so that a later
+    /// session with the same name has a different token. Empty for sessions
+    /// from older snapshots, whose token only depends on the name.
+    pub token_nonce: Bytes,
+}
+
+impl Metadata {
+    /// Salt and hash the encrypted zeros of a write password, if any, from a
+    /// client or snapshot that stored them as they are.
+    pub fn salt_write_password(&mut self) {
+        if let (Some(zeros), None) = (&self.write_password_hash, &self.write_password_salt) {
+            let salt: [u8; VERIFIER_SALT_LEN] = rand::random();
+            self.write_password_hash = Some(hash_verifier(zeros, &salt).into());
+            self.write_password_salt = Some(salt.to_vec().into());
+        }
+    }
 }
 
 /// In-memory state for a single sshx session.
@@ -50,12 +131,48 @@ pub struct Session {
     /// Metadata for currently connected users.
     users: RwLock<HashMap<Uid, WsUser>>,
 
+    /// Time that each connected user joined, in milliseconds since the Unix
+    /// epoch.
+    join_times: RwLock<HashMap<Uid, u64>>,
+
+    /// Bytes relayed to and from each connected user.
+    user_bandwidth: RwLock<HashMap<Uid, Bandwidth>>,
+
+    /// Bytes relayed to and from the backend client, over all connections.
+    client_bandwidth: Mutex<Bandwidth>,
+
+    /// Encrypted, shared notes that users can edit alongside the shells.
+    notes: RwLock<WsNotes>,
+
     /// Atomic counter to get new, unique IDs.
     counter: IdCounter,
 
     /// Timestamp of the last backend client message from an active connection.
     last_accessed: Mutex<Instant>,
 
+    /// How long to keep the session after its backend client disconnects, if
+    /// the client asked for something other than the server's default.
+    inactivity: Mutex<Option<InactivityPolicy>>,
+
+    /// Whether the session is kept however long its backend client has been
+    /// disconnected, as set by an operator or the client.
+    pinned: AtomicBool,
+
+    /// Timestamp of the latest signed hello accepted from the client, so that
+    /// each signature is only accepted once.
+    last_signature: AtomicU64,
+
+    /// Encrypted commands and links suggested to users by the client, if any.
+    palette: Mutex<Option<Palette>>,
+
+    /// ID of the backend client that last took over the session, if any.
+    /// Connections from other backend clients are refused after a takeover.
+    backend: watch::Sender<Option<String>>,
+
+    /// Number of connections from backend clients so far. Only the latest
+    /// connection is served, and older ones exit when it changes.
+    connections: watch::Sender<u64>,
+
     /// Watch channel source for the ordered list of open shells and sizes.
     source: watch::Sender<Vec<(Sid, WsWinsize)>>,
 
@@ -67,14 +184,17 @@ pub struct Session {
     broadcast: broadcast::Sender<WsServer>,
 
     /// Sender end of a channel that buffers messages for the client.
-    update_tx: async_channel::Sender<ServerMessage>,
+    update_tx: UpdateSender,
 
     /// Receiver end of a channel that buffers messages for the client.
-    update_rx: async_channel::Receiver<ServerMessage>,
+    update_rx: UpdateReceiver,
 
     /// Triggered from metadata events when an immediate snapshot is needed.
     sync_notify: Notify,
 
+    /// Number of open subscriptions to the output of shells.
+    chunk_subscriptions: AtomicUsize,
+
     /// Set when this session has been closed and removed.
     shutdown: Shutdown,
 }
@@ -88,6 +208,10 @@ struct State {
     /// Terminal data chunks.
     data: Vec<Bytes>,
 
+    /// Time that each chunk was produced, in milliseconds since the Unix
+    /// epoch, or 0 if unknown.
+    times: Vec<u64>,
+
     /// Number of pruned data chunks before `data[0]`.
     chunk_offset: u64,
 
@@ -97,6 +221,15 @@ struct State {
     /// Set when this shell is terminated.
     closed: bool,
 
+    /// Sequence numbers at the most recent throughput samples.
+    samples: VecDeque<u64>,
+
+    /// Output rate over the samples, in bytes per second.
+    throughput: u64,
+
+    /// Whether the shell responds to input, as reported by the client.
+    health: WsShellHealth,
+
     /// Updated when any of the above fields change.
     notify: Arc<Notify>,
 }
@@ -105,18 +238,29 @@ impl Session {
     /// Construct a new session.
     pub fn new(metadata: Metadata) -> Self {
         let now = Instant::now();
-        let (update_tx, update_rx) = async_channel::bounded(256);
+        let (update_tx, update_rx) = updates::update_channel();
         Session {
             metadata,
             shells: RwLock::new(HashMap::new()),
             users: RwLock::new(HashMap::new()),
+            join_times: RwLock::new(HashMap::new()),
+            user_bandwidth: RwLock::new(HashMap::new()),
+            client_bandwidth: Mutex::new(Bandwidth::default()),
+            notes: RwLock::new(WsNotes::default()),
             counter: IdCounter::default(),
             last_accessed: Mutex::new(now),
+            inactivity: Mutex::new(None),
+            pinned: AtomicBool::new(false),
+            last_signature: AtomicU64::new(0),
+            palette: Mutex::new(None),
+            backend: watch::channel(None).0,
+            connections: watch::channel(0).0,
             source: watch::channel(Vec::new()).0,
             broadcast: broadcast::channel(64).0,
             update_tx,
             update_rx,
             sync_notify: Notify::new(),
+            chunk_subscriptions: AtomicUsize::new(0),
             shutdown: Shutdown::new(),
         }
     }
@@ -134,13 +278,11 @@ impl Session {
     /// Return the sequence numbers for current shells.
     pub fn sequence_numbers(&self) -> SequenceNumbers {
         let shells = self.shells.read();
-        let mut map = HashMap::with_capacity(shells.len());
-        for (key, value) in &*shells {
-            if !value.closed {
-                map.insert(key.0, value.seqnum);
-            }
-        }
-        SequenceNumbers { map }
+        shells
+            .iter()
+            .filter(|(_, shell)| !shell.closed)
+            .map(|(&id, shell)| (id, shell.seqnum))
+            .collect()
     }
 
     /// Receive a notification on broadcasted message events.
@@ -150,22 +292,41 @@ impl Session {
         BroadcastStream::new(self.broadcast.subscribe())
     }
 
+    /// Returns the number of receivers of broadcasted message events.
+    pub fn broadcast_receivers(&self) -> usize {
+        self.broadcast.receiver_count()
+    }
+
+    /// Returns the number of open subscriptions to the output of shells.
+    pub fn chunk_subscriptions(&self) -> usize {
+        self.chunk_subscriptions.load(Ordering::Relaxed)
+    }
+
     /// Receive a notification every time the set of shells is changed.
     pub fn subscribe_shells(&self) -> impl Stream<Item = Vec<(Sid, WsWinsize)>> + Unpin {
         WatchStream::new(self.source.subscribe())
     }
 
-    /// Subscribe for chunks from a shell, until it is closed.
+    /// Subscribe for chunks from a shell and their times, until it is closed.
     pub fn subscribe_chunks(
         &self,
         id: Sid,
         mut chunknum: u64,
-    ) -> impl Stream<Item = (u64, Vec<Bytes>)> + '_ {
+    ) -> impl Stream<Item = (u64, Vec<Bytes>, Vec<u64>)> + '_ {
+        struct SubscriptionGuard<'a>(&'a AtomicUsize);
+        impl Drop for SubscriptionGuard<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+
         async_stream::stream! {
+            self.chunk_subscriptions.fetch_add(1, Ordering::Relaxed);
+            let _guard = SubscriptionGuard(&self.chunk_subscriptions);
             while !self.shutdown.is_terminated() {
                 // We absolutely cannot hold `shells` across an await point,
                 // since that would cause deadlocks.
-                let (seqnum, chunks, notified) = {
+                let (seqnum, chunks, times, notified) = {
                     let shells = self.shells.read();
                     let shell = match shells.get(&id) {
                         Some(shell) if !shell.closed => shell,
@@ -175,23 +336,75 @@ impl Session {
                     let notified = async move { notify.notified().await };
                     let mut seqnum = shell.byte_offset;
                     let mut chunks = Vec::new();
+                    let mut times = Vec::new();
                     let current_chunks = shell.chunk_offset + shell.data.len() as u64;
                     if chunknum < current_chunks {
                         let start = chunknum.saturating_sub(shell.chunk_offset) as usize;
                         seqnum += shell.data[..start].iter().map(|x| x.len() as u64).sum::<u64>();
                         chunks = shell.data[start..].to_vec();
+                        times = shell.times[start..].to_vec();
                         chunknum = current_chunks;
                     }
-                    (seqnum, chunks, notified)
+                    (seqnum, chunks, times, notified)
                 };
 
                 if !chunks.is_empty() {
-                    yield (seqnum, chunks);
+                    yield (seqnum, chunks, times);
                 }
                 tokio::select! {
                     _ = notified => (),
                     _ = self.terminated() => return,
                 }
+                // With many users, output that arrives in quick succession is
+                // sent together, to save on the overhead of each message.
+                if self.user_count() >= BATCH_MIN_USERS {
+                    tokio::select! {
+                        _ = time::sleep(BATCH_INTERVAL) => (),
+                        _ = self.terminated() => return,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the retained data of a shell from a byte offset, or from the
+    /// oldest retained byte if that was pruned, with the offset it starts at.
+    pub fn read_data(&self, id: Sid, seq: u64) -> Result<(u64, Vec<Bytes>)> {
+        let shells = self.shells.read();
+        let Some(shell) = shells.get(&id) else {
+            bail!("cannot read shell with id={id}, does not exist");
+        };
+        let mut offset = shell.byte_offset;
+        let mut chunks = Vec::new();
+        for chunk in &shell.data {
+            let end = offset + chunk.len() as u64;
+            if end > seq {
+                let start = seq.saturating_sub(offset) as usize;
+                chunks.push(chunk.slice(start..));
+            }
+            offset = end;
+        }
+        Ok((seq.clamp(shell.byte_offset, shell.seqnum), chunks))
+    }
+
+    /// Wait until a shell has data past a byte offset, or it is closed.
+    pub async fn wait_for_data(&self, id: Sid, seq: u64) {
+        let has_data = || match self.shells.read().get(&id) {
+            Some(shell) => shell.closed || shell.seqnum > seq,
+            None => true,
+        };
+        while !has_data() {
+            let Some(notify) = self.shells.read().get(&id).map(|s| Arc::clone(&s.notify)) else {
+                return;
+            };
+            let mut notified = pin!(notify.notified());
+            notified.as_mut().enable();
+            if has_data() {
+                return;
+            }
+            tokio::select! {
+                _ = notified => (),
+                _ = self.terminated() => return,
             }
         }
     }
@@ -215,23 +428,77 @@ impl Session {
         Ok(())
     }
 
-    /// Terminates an existing shell.
-    pub fn close_shell(&self, id: Sid) -> Result<()> {
-        match self.shells.write().get_mut(&id) {
-            Some(shell) if !shell.closed => {
-                shell.closed = true;
-                shell.notify.notify_waiters();
+    /// Terminates an existing shell, telling users why it was closed.
+    ///
+    /// The client reports shells that it is not running when the server asks
+    /// about them, so closing an unknown shell is not an error. If the server
+    /// handed out its ID, the ID is kept closed so that a late message cannot
+    /// open a phantom shell. Other IDs are ignored, so the client cannot grow
+    /// the map of shells without bound.
+    pub fn close_shell(&self, id: Sid, closed: WsShellClosed) -> Result<()> {
+        use std::collections::hash_map::Entry::*;
+        match self.shells.write().entry(id) {
+            Occupied(mut o) if !o.get().closed => {
+                o.get_mut().closed = true;
+                o.get().notify.notify_waiters();
+            }
+            Occupied(_) => return Ok(()),
+            Vacant(v) => {
+                if id < self.counter.get_current_values().0 {
+                    v.insert(State {
+                        closed: true,
+                        ..Default::default()
+                    });
+                }
+                debug!(%id, "closed unknown shell");
+                return Ok(());
             }
-            Some(_) => return Ok(()),
-            None => bail!("cannot close shell with id={id}, does not exist"),
         }
         self.source.send_modify(|source| {
             source.retain(|&(x, _)| x != id);
         });
+        self.broadcast.send(WsServer::ShellClosed(id, closed)).ok();
         self.sync_now();
         Ok(())
     }
 
+    /// Record whether a shell responds to input, telling users if it changed.
+    pub fn set_shell_health(&self, id: Sid, health: WsShellHealth) -> Result<()> {
+        let mut shell = self.get_shell_mut(id)?;
+        if shell.health != health {
+            shell.health = health;
+            drop(shell);
+            self.broadcast.send(WsServer::ShellHealth(id, health)).ok();
+        }
+        Ok(())
+    }
+
+    /// Close all open shells, whose backend client has stopped responding.
+    /// Returns the IDs of the closed shells.
+    pub fn close_abandoned_shells(&self) -> Vec<Sid> {
+        let shells = self.shells.read();
+        let open: Vec<Sid> = (shells.iter())
+            .filter(|(_, shell)| !shell.closed)
+            .map(|(&id, _)| id)
+            .collect();
+        drop(shells);
+        for &id in &open {
+            let closed = WsShellClosed {
+                reason: "client stopped responding".into(),
+                ..Default::default()
+            };
+            self.close_shell(id, closed).ok();
+        }
+        open
+    }
+
+    /// Returns the open shells that have stopped responding to input.
+    pub fn stalled_shells(&self) -> Vec<(Sid, WsShellHealth)> {
+        let shells = self.shells.read();
+        let stalled = shells.iter().filter(|(_, s)| !s.closed && s.health.stalled);
+        stalled.map(|(&id, shell)| (id, shell.health)).collect()
+    }
+
     fn get_shell_mut(&self, id: Sid) -> Result<impl DerefMut<Target = State> + '_> {
         let shells = self.shells.write();
         match shells.get(&id) {
@@ -244,19 +511,26 @@ impl Session {
     }
 
     /// Change the size of a terminal, notifying clients if necessary.
-    pub fn move_shell(&self, id: Sid, winsize: Option<WsWinsize>) -> Result<()> {
+    ///
+    /// Positions far from the origin are rejected, and the number of rows and
+    /// columns is clamped to a reasonable range. Returns the new size.
+    pub fn move_shell(&self, id: Sid, winsize: Option<WsWinsize>) -> Result<WsWinsize> {
         let _guard = self.get_shell_mut(id)?; // Ensures mutual exclusion.
+        let winsize = winsize.map(check_winsize).transpose()?;
+        let mut newsize = None;
         self.source.send_modify(|source| {
             if let Some(idx) = source.iter().position(|&(sid, _)| sid == id) {
                 let (_, oldsize) = source.remove(idx);
-                source.push((id, winsize.unwrap_or(oldsize)));
+                let newsize = newsize.insert(winsize.unwrap_or(oldsize));
+                source.push((id, *newsize));
             }
         });
-        Ok(())
+        newsize.with_context(|| format!("cannot move shell with id={id}, has no position"))
     }
 
-    /// Receive new data into the session.
-    pub fn add_data(&self, id: Sid, data: Bytes, seq: u64) -> Result<()> {
+    /// Receive new data into the session, produced at a time in milliseconds
+    /// since the Unix epoch.
+    pub fn add_data(&self, id: Sid, data: Bytes, seq: u64, time: u64) -> Result<()> {
         let mut shell = self.get_shell_mut(id)?;
 
         if seq <= shell.seqnum && seq + data.len() as u64 > shell.seqnum {
@@ -265,6 +539,7 @@ impl Session {
             debug!(%id, bytes = segment.len(), "adding data to shell");
             shell.seqnum += segment.len() as u64;
             shell.data.push(segment);
+            shell.times.push(time);
 
             // Prune old chunks if we've exceeded the maximum stored bytes.
             let mut stored_bytes = shell.seqnum - shell.byte_offset;
@@ -278,6 +553,7 @@ impl Session {
                     offset += 1;
                 }
                 shell.data.drain(..offset);
+                shell.times.drain(..offset);
             }
 
             shell.notify.notify_waiters();
@@ -286,6 +562,21 @@ impl Session {
         Ok(())
     }
 
+    /// Skip ahead to a later sequence number in a shell, dropping the data that
+    /// is stored, if data before it will never be received.
+    pub fn skip_data(&self, id: Sid, seq: u64) -> Result<()> {
+        let mut shell = self.get_shell_mut(id)?;
+        if seq > shell.seqnum {
+            shell.chunk_offset += shell.data.len() as u64;
+            shell.data.clear();
+            shell.times.clear();
+            shell.seqnum = seq;
+            shell.byte_offset = seq;
+            shell.notify.notify_waiters();
+        }
+        Ok(())
+    }
+
     /// List all the users in the session.
     pub fn list_users(&self) -> Vec<(Uid, WsUser)> {
         self.users
@@ -295,6 +586,45 @@ impl Session {
             .collect()
     }
 
+    /// List the users in the session with the times that they joined, in
+    /// order of their IDs.
+    pub fn roster(&self) -> Vec<(Uid, WsUser, u64)> {
+        let users = self.users.read();
+        let join_times = self.join_times.read();
+        let mut roster: Vec<_> = (users.iter())
+            .map(|(&id, user)| {
+                let joined_at = join_times.get(&id).copied().unwrap_or_default();
+                (id, user.clone(), joined_at)
+            })
+            .collect();
+        roster.sort_by_key(|&(id, ..)| id);
+        roster
+    }
+
+    /// Returns the number of users in the session.
+    pub fn user_count(&self) -> usize {
+        self.users.read().len()
+    }
+
+    /// List the open shells in the session, with their sizes.
+    pub fn list_shells(&self) -> Vec<(Sid, WsWinsize)> {
+        self.source.borrow().clone()
+    }
+
+    /// Returns the retained, encrypted data of every shell in ID order, as
+    /// `(id, seqnum, chunks, times)` where `seqnum` is the offset of the first
+    /// chunk.
+    pub fn retained_chunks(&self) -> Vec<(Sid, u64, Vec<Bytes>, Vec<u64>)> {
+        let mut chunks: Vec<_> = (self.shells.read().iter())
+            .map(|(&id, shell)| {
+                let times = shell.times.clone();
+                (id, shell.byte_offset, shell.data.clone(), times)
+            })
+            .collect();
+        chunks.sort_by_key(|&(id, ..)| id);
+        chunks
+    }
+
     /// Update a user in place by ID, applying a callback to the object.
     pub fn update_user(&self, id: Uid, f: impl FnOnce(&mut WsUser)) -> Result<()> {
         let updated_user = {
@@ -321,7 +651,12 @@ impl Session {
             }
         }
 
-        match self.users.write().entry(id) {
+        let mut users = self.users.write();
+        // Take the first color that nobody else is using.
+        let color = (0..)
+            .find(|color| users.values().all(|user| user.color != *color))
+            .unwrap();
+        match users.entry(id) {
             Occupied(_) => bail!("user already exists with id={id}"),
             Vacant(v) => {
                 let user = WsUser {
@@ -329,8 +664,13 @@ impl Session {
                     cursor: None,
                     focus: None,
                     can_write,
+                    color,
+                    avatar: None,
+                    viewport: None,
                 };
                 v.insert(user.clone());
+                self.join_times.write().insert(id, get_time_ms());
+                (self.user_bandwidth.write()).insert(id, Bandwidth::default());
                 self.broadcast.send(WsServer::UserDiff(id, Some(user))).ok();
                 Ok(UserGuard(self, id))
             }
@@ -342,9 +682,44 @@ impl Session {
         if self.users.write().remove(&id).is_none() {
             warn!(%id, "invariant violation: removed user that does not exist");
         }
+        self.join_times.write().remove(&id);
+        self.user_bandwidth.write().remove(&id);
         self.broadcast.send(WsServer::UserDiff(id, None)).ok();
     }
 
+    /// Count bytes relayed to and from a user, returning the user's totals.
+    pub fn record_user_bandwidth(&self, id: Uid, sent: u64, received: u64) -> Bandwidth {
+        let mut user_bandwidth = self.user_bandwidth.write();
+        let Some(bandwidth) = user_bandwidth.get_mut(&id) else {
+            return Bandwidth::default(); // The user has already left.
+        };
+        bandwidth.sent += sent;
+        bandwidth.received += received;
+        *bandwidth
+    }
+
+    /// List the bytes relayed to and from each connected user, in order of
+    /// their IDs.
+    pub fn user_bandwidth(&self) -> Vec<(Uid, Bandwidth)> {
+        let mut bandwidth: Vec<_> = (self.user_bandwidth.read().iter())
+            .map(|(&id, &bandwidth)| (id, bandwidth))
+            .collect();
+        bandwidth.sort_by_key(|&(id, _)| id);
+        bandwidth
+    }
+
+    /// Count bytes relayed to and from the backend client.
+    pub fn record_client_bandwidth(&self, sent: u64, received: u64) {
+        let mut bandwidth = self.client_bandwidth.lock();
+        bandwidth.sent += sent;
+        bandwidth.received += received;
+    }
+
+    /// Returns the bytes relayed to and from the backend client.
+    pub fn client_bandwidth(&self) -> Bandwidth {
+        *self.client_bandwidth.lock()
+    }
+
     /// Check if a user has write permission in the session.
     pub fn check_write_permission(&self, user_id: Uid) -> Result<()> {
         let users = self.users.read();
@@ -368,11 +743,162 @@ impl Session {
         Ok(())
     }
 
+    /// Returns the shared notes of the session.
+    pub fn notes(&self) -> WsNotes {
+        self.notes.read().clone()
+    }
+
+    /// Add an edit to the shared notes on top of a version, and send it to all
+    /// users. Returns `false` if the edits after that version were compacted,
+    /// so the edit cannot be transformed against them anymore.
+    pub fn edit_notes(&self, user: Uid, base: u64, data: Bytes) -> Result<bool> {
+        let mut notes = self.notes.write();
+        let version = notes.version + notes.edits.len() as u64;
+        ensure!(base <= version, "notes version {base} does not exist");
+        if base < notes.version {
+            return Ok(false);
+        }
+        ensure!(
+            notes_size(&notes) + data.len() <= NOTES_STORED_BYTES,
+            "notes are too large",
+        );
+        let edit = WsNotesEdit { user, base, data };
+        notes.edits.push(edit.clone());
+        // Broadcast while holding the lock, so that edits are sent in order.
+        let msg = WsServer::NotesEdit(version + 1, edit);
+        self.broadcast.send(msg).ok();
+        Ok(true)
+    }
+
+    /// Replace the edits up to a version of the shared notes with the text at
+    /// that version. This does nothing if a later version was compacted.
+    pub fn compact_notes(&self, version: u64, text: Bytes) -> Result<()> {
+        let mut notes = self.notes.write();
+        let latest = notes.version + notes.edits.len() as u64;
+        ensure!(version <= latest, "notes version {version} does not exist");
+        if version < notes.version {
+            return Ok(());
+        }
+        let compacted = (version - notes.version) as usize;
+        let edits = &notes.edits[compacted..];
+        let size = text.len() + edits.iter().map(|e| e.data.len()).sum::<usize>();
+        ensure!(size <= NOTES_STORED_BYTES, "notes are too large");
+        notes.edits.drain(..compacted);
+        notes.version = version;
+        notes.text = Some(text);
+        Ok(())
+    }
+
+    /// Send a notice from the server operator to all users.
+    pub fn send_notice(&self, msg: &str) {
+        self.broadcast.send(WsServer::Notice(msg.into())).ok();
+    }
+
+    /// Returns whether a backend client with this ID may connect, which is
+    /// only allowed for the last client to take over the session, if any.
+    pub fn check_backend(&self, id: Option<&str>) -> bool {
+        match &*self.backend.borrow() {
+            Some(owner) => id == Some(owner.as_str()),
+            None => true,
+        }
+    }
+
+    /// Make a client the backend of the session, replacing the previous one.
+    ///
+    /// The shells of the previous client are closed, and returned so that the
+    /// new client can start shells in their place.
+    pub fn take_over(&self, id: &str) -> Vec<(Sid, WsWinsize)> {
+        self.backend.send_replace(Some(id.into()));
+        let shells = self.list_shells();
+        for &(sid, _) in &shells {
+            let closed = WsShellClosed {
+                reason: "session moved to another machine".into(),
+                ..Default::default()
+            };
+            self.close_shell(sid, closed).ok();
+        }
+        shells
+    }
+
+    /// Receive the ID of the backend client whenever another client takes
+    /// over the session.
+    pub fn subscribe_backend(&self) -> watch::Receiver<Option<String>> {
+        self.backend.subscribe()
+    }
+
+    /// Register a new connection from a backend client, replacing any older
+    /// connection that is still open.
+    ///
+    /// The returned receiver changes when a newer connection replaces this one.
+    pub fn connect_backend(&self) -> watch::Receiver<u64> {
+        self.connections.send_modify(|count| *count += 1);
+        self.connections.subscribe()
+    }
+
+    /// Returns the commands and links suggested to users, if any.
+    pub fn palette(&self) -> Option<Palette> {
+        self.palette.lock().clone()
+    }
+
+    /// Replace the commands and links suggested to users, and send them to
+    /// all users.
+    pub fn set_palette(&self, palette: Palette) -> Result<()> {
+        ensure!(
+            palette.data.len() <= PALETTE_STORED_BYTES,
+            "palette is too large",
+        );
+        let msg = WsServer::Palette(palette.data.clone(), palette.offset);
+        *self.palette.lock() = Some(palette);
+        self.broadcast.send(msg).ok();
+        self.sync_now();
+        Ok(())
+    }
+
+    /// Send data on an auxiliary stream from the client to all users.
+    pub fn send_stream(&self, id: u32, kind: WsStreamKind, data: Bytes, offset: u64) {
+        self.broadcast
+            .send(WsServer::Stream(id, kind, data, offset))
+            .ok();
+    }
+
     /// Send a measurement of the shell latency.
     pub fn send_latency_measurement(&self, latency: u64) {
         self.broadcast.send(WsServer::ShellLatency(latency)).ok();
     }
 
+    /// Returns the output rate of each open shell, in bytes per second.
+    pub fn throughput(&self) -> Vec<(Sid, u64)> {
+        let shells = self.shells.read();
+        let mut throughput: Vec<_> = shells
+            .iter()
+            .filter(|(_, shell)| !shell.closed)
+            .map(|(&id, shell)| (id, shell.throughput))
+            .collect();
+        throughput.sort_unstable();
+        throughput
+    }
+
+    /// Sample the output of each shell, which is done at a regular interval,
+    /// and send the output rates over the last few samples to all users.
+    pub fn sample_throughput(&self, interval: Duration) {
+        for shell in self.shells.write().values_mut() {
+            if shell.samples.len() > THROUGHPUT_SAMPLES {
+                shell.samples.pop_front();
+            }
+            if let Some(&oldest) = shell.samples.front() {
+                let elapsed = interval * shell.samples.len() as u32;
+                let bytes = shell.seqnum.saturating_sub(oldest);
+                shell.throughput = (bytes as f64 / elapsed.as_secs_f64()) as u64;
+            }
+            let seqnum = shell.seqnum;
+            shell.samples.push_back(seqnum);
+        }
+        let throughput = self.throughput();
+        if !throughput.is_empty() {
+            self.broadcast.send(WsServer::Throughput(throughput)).ok();
+        }
+    }
+
     /// Register a backend client heartbeat, refreshing the timestamp.
     pub fn access(&self) {
         *self.last_accessed.lock() = Instant::now();
@@ -383,13 +909,47 @@ impl Session {
         *self.last_accessed.lock()
     }
 
+    /// Set how long to keep the session after its backend client disconnects.
+    pub fn set_inactivity(&self, policy: InactivityPolicy) {
+        *self.inactivity.lock() = Some(policy);
+    }
+
+    /// Returns how long to keep the session after its backend client
+    /// disconnects, or `None` if it uses the server's default.
+    pub fn inactivity(&self) -> Option<InactivityPolicy> {
+        self.inactivity.lock().clone()
+    }
+
+    /// Pin or unpin the session, saving it to storage right away if changed.
+    pub fn set_pinned(&self, pinned: bool) {
+        if self.pinned.swap(pinned, Ordering::Relaxed) != pinned {
+            self.sync_now();
+        }
+    }
+
+    /// Returns whether the session is pinned, so that it never expires.
+    pub fn pinned(&self) -> bool {
+        self.pinned.load(Ordering::Relaxed)
+    }
+
+    /// Accept the timestamp of a signed hello from the client, unless it is
+    /// not newer than the last one, as when a hello is replayed. The session
+    /// is saved to storage right away, so other servers refuse it too.
+    pub fn accept_signature(&self, timestamp: u64) -> bool {
+        let accepted = self.last_signature.fetch_max(timestamp, Ordering::Relaxed) < timestamp;
+        if accepted {
+            self.sync_now();
+        }
+        accepted
+    }
+
     /// Access the sender of the client message channel for this session.
-    pub fn update_tx(&self) -> &async_channel::Sender<ServerMessage> {
+    pub fn update_tx(&self) -> &UpdateSender {
         &self.update_tx
     }
 
     /// Access the receiver of the client message channel for this session.
-    pub fn update_rx(&self) -> &async_channel::Receiver<ServerMessage> {
+    pub fn update_rx(&self) -> &UpdateReceiver {
         &self.update_rx
     }
 
@@ -422,3 +982,32 @@ impl Session {
         self.shutdown.wait().await
     }
 }
+
+/// Returns the number of bytes stored for the shared notes.
+fn notes_size(notes: &WsNotes) -> usize {
+    let text = notes.text.as_ref().map_or(0, |text| text.len());
+    text + notes
+        .edits
+        .iter()
+        .map(|edit| edit.data.len())
+        .sum::<usize>()
+}
+
+/// Validate the position of a terminal, and clamp its size.
+fn check_winsize(winsize: WsWinsize) -> Result<WsWinsize> {
+    ensure!(
+        winsize.x.unsigned_abs() <= MAX_COORDINATE,
+        "horizontal position x={} is out of range",
+        winsize.x,
+    );
+    ensure!(
+        winsize.y.unsigned_abs() <= MAX_COORDINATE,
+        "vertical position y={} is out of range",
+        winsize.y,
+    );
+    Ok(WsWinsize {
+        rows: winsize.rows.clamp(1, MAX_ROWS),
+        cols: winsize.cols.clamp(1, MAX_COLS),
+        ..winsize
+    })
+}
@@ -1,16 +1,17 @@
 //! Snapshot and restore sessions from serialized state.
 
 use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
 
 use anyhow::{ensure, Context, Result};
 use prost::Message;
 use sshx_core::{
-    proto::{SerializedSession, SerializedShell},
+    proto::{SerializedNotes, SerializedNotesEdit, SerializedSession, SerializedShell},
     Sid, Uid,
 };
 
 use super::{Metadata, Session, State};
-use crate::web::protocol::WsWinsize;
+use crate::web::protocol::{WsNotes, WsNotesEdit, WsWinsize};
 
 /// Persist at most this many bytes of output in storage, per shell.
 const SHELL_SNAPSHOT_BYTES: u64 = 1 << 15; // 32 KiB
@@ -48,6 +49,7 @@ impl Session {
                     let shell = SerializedShell {
                         seqnum: shell.seqnum,
                         data: shell.data[prefix..].to_vec(),
+                        times: shell.times[prefix..].to_vec(),
                         chunk_offset,
                         byte_offset,
                         closed: shell.closed,
@@ -56,36 +58,56 @@ impl Session {
                         winsize_rows: winsize.rows.into(),
                         winsize_cols: winsize.cols.into(),
                     };
-                    (sid.0, shell)
+                    (u32::from(*sid), shell)
                 })
                 .collect(),
-            next_sid: ids.0 .0,
-            next_uid: ids.1 .0,
+            next_sid: ids.0.into(),
+            next_uid: ids.1.into(),
             name: self.metadata().name.clone(),
             write_password_hash: self.metadata().write_password_hash.clone(),
+            write_password_salt: self.metadata().write_password_salt.clone(),
+            color_scheme: self.metadata().color_scheme.clone(),
+            environment: self.metadata().environment.clone(),
+            client_key: self.metadata().client_key.clone(),
+            token_nonce: self.metadata().token_nonce.clone(),
+            inactivity: self.inactivity(),
+            pinned: self.pinned(),
+            last_signature: self.last_signature.load(Ordering::Relaxed),
+            notes: Some(serialize_notes(self.notes())),
+            palette: self.palette(),
+            backend_id: self.backend.borrow().clone(),
         };
         let data = message.encode_to_vec();
         ensure!(data.len() < MAX_SNAPSHOT_SIZE, "snapshot too large");
         Ok(zstd::bulk::compress(&data, 3)?)
     }
 
+    /// Read the metadata of a session from a compressed snapshot, without
+    /// restoring the session itself.
+    pub fn restore_metadata(data: &[u8]) -> Result<Metadata> {
+        Ok(snapshot_metadata(&decode_snapshot(data)?))
+    }
+
     /// Restore the session from a previous compressed snapshot.
     pub fn restore(data: &[u8]) -> Result<Self> {
-        let data = zstd::bulk::decompress(data, MAX_SNAPSHOT_SIZE)?;
-        let message = SerializedSession::decode(&*data)?;
+        let message = decode_snapshot(data)?;
+        let (next_sid, next_uid) = message.next_ids();
 
-        let metadata = Metadata {
-            encrypted_zeros: message.encrypted_zeros,
-            name: message.name,
-            write_password_hash: message.write_password_hash,
-        };
+        let mut metadata = snapshot_metadata(&message);
+        metadata.salt_write_password();
 
         let session = Self::new(metadata);
+        *session.inactivity.lock() = message.inactivity;
+        session.pinned.store(message.pinned, Ordering::Relaxed);
+        (session.last_signature).store(message.last_signature, Ordering::Relaxed);
+        *session.notes.write() = deserialize_notes(message.notes.unwrap_or_default());
+        *session.palette.lock() = message.palette;
+        session.backend.send_replace(message.backend_id);
         let mut shells = session.shells.write();
         let mut winsizes = Vec::new();
         for (sid, shell) in message.shells {
             winsizes.push((
-                Sid(sid),
+                Sid::from(sid),
                 WsWinsize {
                     x: shell.winsize_x,
                     y: shell.winsize_y,
@@ -93,22 +115,77 @@ impl Session {
                     cols: shell.winsize_cols.try_into().context("cols overflow")?,
                 },
             ));
+            let stored_bytes: u64 = shell.data.iter().map(|chunk| chunk.len() as u64).sum();
+            ensure!(
+                shell.byte_offset.checked_add(stored_bytes) == Some(shell.seqnum),
+                "shell data does not match its sequence number",
+            );
+            // Snapshots from before chunk times were recorded don't have them.
+            let mut times = shell.times;
+            times.resize(shell.data.len(), 0);
             let shell = State {
                 seqnum: shell.seqnum,
                 data: shell.data,
+                times,
                 chunk_offset: shell.chunk_offset,
                 byte_offset: shell.byte_offset,
                 closed: shell.closed,
-                notify: Default::default(),
+                ..Default::default()
             };
-            shells.insert(Sid(sid), shell);
+            shells.insert(Sid::from(sid), shell);
         }
         drop(shells);
         session.source.send_replace(winsizes);
-        session
-            .counter
-            .set_current_values(Sid(message.next_sid), Uid(message.next_uid));
+        session.counter.set_current_values(next_sid, next_uid);
 
         Ok(session)
     }
 }
+
+fn serialize_notes(notes: WsNotes) -> SerializedNotes {
+    SerializedNotes {
+        version: notes.version,
+        text: notes.text,
+        edits: (notes.edits.into_iter())
+            .map(|edit| SerializedNotesEdit {
+                user: edit.user.0,
+                base: edit.base,
+                data: edit.data,
+            })
+            .collect(),
+    }
+}
+
+fn deserialize_notes(notes: SerializedNotes) -> WsNotes {
+    WsNotes {
+        version: notes.version,
+        text: notes.text,
+        edits: (notes.edits.into_iter())
+            .map(|edit| WsNotesEdit {
+                user: Uid(edit.user),
+                base: edit.base,
+                data: edit.data,
+            })
+            .collect(),
+    }
+}
+
+/// Decompress and decode a snapshot of a session.
+fn decode_snapshot(data: &[u8]) -> Result<SerializedSession> {
+    let data = zstd::bulk::decompress(data, MAX_SNAPSHOT_SIZE)?;
+    Ok(SerializedSession::decode(&*data)?)
+}
+
+/// Static metadata of a session in a snapshot.
+fn snapshot_metadata(message: &SerializedSession) -> Metadata {
+    Metadata {
+        encrypted_zeros: message.encrypted_zeros.clone(),
+        name: message.name.clone(),
+        write_password_hash: message.write_password_hash.clone(),
+        write_password_salt: message.write_password_salt.clone(),
+        color_scheme: message.color_scheme.clone(),
+        environment: message.environment.clone(),
+        client_key: message.client_key.clone(),
+        token_nonce: message.token_nonce.clone(),
+    }
+}
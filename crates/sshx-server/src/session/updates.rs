@@ -0,0 +1,74 @@
+//! Buffered messages for the client, where control messages skip ahead.
+//!
+//! Users can send a lot of terminal input at once, like when pasting a large
+//! file. If it were buffered in the same queue as control messages, creating,
+//! resizing or closing a shell would wait behind all of it. Instead, bulk data
+//! has its own queue, and the client reads from the control queue first.
+
+use async_channel::{RecvError, SendError};
+use sshx_core::proto::server_update::ServerMessage;
+
+/// Number of messages buffered in each queue before senders wait.
+const QUEUE_CAPACITY: usize = 256;
+
+/// Create a channel for messages to the client, with two priorities.
+pub fn update_channel() -> (UpdateSender, UpdateReceiver) {
+    let (control_tx, control_rx) = async_channel::bounded(QUEUE_CAPACITY);
+    let (bulk_tx, bulk_rx) = async_channel::bounded(QUEUE_CAPACITY);
+    (
+        UpdateSender {
+            control_tx,
+            bulk_tx,
+        },
+        UpdateReceiver {
+            control_rx,
+            bulk_rx,
+        },
+    )
+}
+
+/// Returns whether a message carries bulk data, which can wait.
+fn is_bulk(msg: &ServerMessage) -> bool {
+    matches!(msg, ServerMessage::Input(_) | ServerMessage::Stream(_))
+}
+
+/// Sender end of the channel of messages for the client.
+#[derive(Debug, Clone)]
+pub struct UpdateSender {
+    control_tx: async_channel::Sender<ServerMessage>,
+    bulk_tx: async_channel::Sender<ServerMessage>,
+}
+
+impl UpdateSender {
+    /// Queue a message, waiting if its queue is full.
+    ///
+    /// Messages with the same priority stay in order, but control messages
+    /// may be received before bulk data that was sent earlier.
+    pub async fn send(&self, msg: ServerMessage) -> Result<(), SendError<ServerMessage>> {
+        if is_bulk(&msg) {
+            self.bulk_tx.send(msg).await
+        } else {
+            self.control_tx.send(msg).await
+        }
+    }
+}
+
+/// Receiver end of the channel of messages for the client.
+#[derive(Debug, Clone)]
+pub struct UpdateReceiver {
+    control_rx: async_channel::Receiver<ServerMessage>,
+    bulk_rx: async_channel::Receiver<ServerMessage>,
+}
+
+impl UpdateReceiver {
+    /// Receive the next message, preferring control messages over bulk data.
+    ///
+    /// This is cancel-safe, so it can be used in `tokio::select!`.
+    pub async fn recv(&self) -> Result<ServerMessage, RecvError> {
+        tokio::select! {
+            biased;
+            msg = self.control_rx.recv() => msg,
+            msg = self.bulk_rx.recv() => msg,
+        }
+    }
+}
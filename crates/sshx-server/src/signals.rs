@@ -0,0 +1,85 @@
+//! Signals that control the server process, on each platform.
+//!
+//! On Unix, SIGTERM and SIGINT shut the server down gracefully, and SIGHUP
+//! reloads its TLS certificates. On Windows, Ctrl-C, Ctrl-Break, and closing
+//! the console window or shutting down the system all shut the server down.
+
+use anyhow::Result;
+
+/// Action requested by a signal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    /// Shut down gracefully.
+    Shutdown,
+    /// Reload TLS certificates from disk.
+    Reload,
+}
+
+/// Listener for the signals that control the server.
+pub struct Signals {
+    #[cfg(unix)]
+    sigterm: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    sigint: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    sighup: tokio::signal::unix::Signal,
+
+    #[cfg(windows)]
+    ctrl_c: tokio::signal::windows::CtrlC,
+    #[cfg(windows)]
+    ctrl_break: tokio::signal::windows::CtrlBreak,
+    #[cfg(windows)]
+    ctrl_close: tokio::signal::windows::CtrlClose,
+    #[cfg(windows)]
+    ctrl_shutdown: tokio::signal::windows::CtrlShutdown,
+}
+
+impl Signals {
+    /// Start listening for signals, replacing their default handlers.
+    #[cfg(unix)]
+    pub fn new() -> Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+        Ok(Self {
+            sigterm: signal(SignalKind::terminate())?,
+            sigint: signal(SignalKind::interrupt())?,
+            sighup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    /// Start listening for signals, replacing their default handlers.
+    #[cfg(windows)]
+    pub fn new() -> Result<Self> {
+        use tokio::signal::windows;
+        Ok(Self {
+            ctrl_c: windows::ctrl_c()?,
+            ctrl_break: windows::ctrl_break()?,
+            ctrl_close: windows::ctrl_close()?,
+            ctrl_shutdown: windows::ctrl_shutdown()?,
+        })
+    }
+
+    /// Wait for the next signal, or return `None` if they can no longer be
+    /// received.
+    #[cfg(unix)]
+    pub async fn recv(&mut self) -> Option<Signal> {
+        tokio::select! {
+            Some(()) = self.sigterm.recv() => Some(Signal::Shutdown),
+            Some(()) = self.sigint.recv() => Some(Signal::Shutdown),
+            Some(()) = self.sighup.recv() => Some(Signal::Reload),
+            else => None,
+        }
+    }
+
+    /// Wait for the next signal, or return `None` if they can no longer be
+    /// received.
+    #[cfg(windows)]
+    pub async fn recv(&mut self) -> Option<Signal> {
+        tokio::select! {
+            Some(()) = self.ctrl_c.recv() => Some(Signal::Shutdown),
+            Some(()) = self.ctrl_break.recv() => Some(Signal::Shutdown),
+            Some(()) = self.ctrl_close.recv() => Some(Signal::Shutdown),
+            Some(()) = self.ctrl_shutdown.recv() => Some(Signal::Shutdown),
+            else => None,
+        }
+    }
+}
@@ -1,21 +1,41 @@
 //! Stateful components of the server, managing multiple sessions.
 
+use std::collections::{BTreeMap, HashSet};
 use std::pin::pin;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 
-use anyhow::Result;
-use dashmap::DashMap;
+use anyhow::{anyhow, bail, Context, Result};
+use axum::http::HeaderValue;
+use base64::prelude::{
+    Engine as _, BASE64_STANDARD, BASE64_STANDARD_NO_PAD, BASE64_URL_SAFE_NO_PAD,
+};
+use dashmap::{mapref::entry::Entry, DashMap};
 use hmac::{Hmac, Mac as _};
-use sha2::Sha256;
-use sshx_core::rand_alphanumeric;
-use tokio::time;
+use parking_lot::Mutex;
+use serde::Serialize;
+use sha2::{Digest as _, Sha256};
+use sshx_core::{proto::inactivity_policy::Policy, rand_alphanumeric};
+use subtle::ConstantTimeEq;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::{task, time};
 use tokio_stream::StreamExt;
-use tracing::error;
+use tracing::{error, info, instrument, warn};
+use url::Url;
 
 use self::mesh::StorageMesh;
-use crate::session::Session;
-use crate::ServerOptions;
+use crate::acl::IpFilter;
+use crate::audit::{AuditEvent, AuditLog, Peer};
+use crate::metrics::Metrics;
+use crate::names::SessionNames;
+use crate::oidc::OidcClient;
+use crate::relay::Origin;
+use crate::report::{ErrorReporter, ErrorSource};
+use crate::session::{Metadata, Session};
+use crate::tls::TlsConfig;
+use crate::utils::{IpLimiter, RateLimiter};
+use crate::{web, ServerOptions};
 
 pub mod mesh;
 
@@ -24,8 +44,48 @@ pub mod mesh;
 /// If a session has no backend clients making connections in this interval,
 /// then its updated timestamp will be out-of-date, so we close it and remove it
 /// from the state to reduce memory usage.
+///
+/// Clients can choose a different timeout for their own session with an
+/// [`InactivityPolicy`](sshx_core::proto::InactivityPolicy).
 const DISCONNECTED_SESSION_EXPIRY: Duration = Duration::from_secs(300);
 
+/// Shortest timeout for a disconnected session, even if the client asked for
+/// it to be closed right away, since connected clients only send heartbeats
+/// every few seconds.
+const MIN_SESSION_EXPIRY: Duration = Duration::from_secs(10);
+
+/// Minimum estimated entropy of a secret set by the operator, in bits.
+const MIN_SECRET_ENTROPY: f64 = 64.0;
+
+/// Timeout for reaching Redis when the server starts.
+const REDIS_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Interval for detecting whether the address of this server has changed.
+const HOST_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Interval for sampling the output rate of shells.
+const THROUGHPUT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Interval for probing the latency of other servers in the mesh.
+const MESH_PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Timeout for a probe of another server in the mesh.
+const MESH_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a retried request to open a session returns the same session.
+const OPEN_KEY_EXPIRY: Duration = Duration::from_secs(600);
+
+/// Result of reserving the idempotency key of a request to open a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpenKey {
+    /// The key was reserved for this request.
+    Reserved,
+    /// Another request with the key is still in progress.
+    Pending,
+    /// The key was used to open the session with this name.
+    Opened(String),
+}
+
 /// Shared state object for global server logic.
 pub struct ServerState {
     /// Message authentication code for signing tokens.
@@ -34,26 +94,287 @@ pub struct ServerState {
     /// Override the origin returned for the Open() RPC.
     override_origin: Option<String>,
 
+    /// Origins that clients may request in the Open() RPC, or empty for any.
+    allowed_origins: Vec<String>,
+
     /// A concurrent map of session IDs to session objects.
     store: DashMap<String, Arc<Session>>,
 
+    /// Sessions removed from the store, which should be freed soon after.
+    removed: Mutex<Vec<Weak<Session>>>,
+
     /// Storage and distributed communication provider, if enabled.
     mesh: Option<StorageMesh>,
+
+    /// Sessions on other servers that are mirrored locally.
+    relays: Vec<Origin>,
+
+    /// Port advertised with the detected hostname in the mesh, if it was not
+    /// set by the operator.
+    advertise_port: Option<u16>,
+
+    /// Prometheus metrics for this server.
+    metrics: Metrics,
+
+    /// Whether metrics are only served on a separate port.
+    separate_metrics: bool,
+
+    /// Whether metrics in the main app are served without the admin token.
+    public_metrics: bool,
+
+    /// Network access rules for incoming connections.
+    ip_filter: IpFilter,
+
+    /// Bearer token for the admin API, if enabled.
+    admin_token: Option<String>,
+
+    /// Time when this server was started.
+    started: Instant,
+
+    /// Audit log of security-relevant events.
+    audit: AuditLog,
+
+    /// Hooks that receive internal errors.
+    errors: ErrorReporter,
+
+    /// OpenID Connect provider that web viewers must log in with, if enabled.
+    oidc: Option<OidcClient>,
+
+    /// Content-Security-Policy header for web responses, if enabled.
+    content_security_policy: Option<HeaderValue>,
+
+    /// Terminal input accepted from each web user, in bytes per second.
+    input_rate_limit: Option<u64>,
+
+    /// Data relayed to and from each web user before disconnecting, in bytes.
+    max_user_bytes: Option<u64>,
+
+    /// Interval between keepalive pings sent to web clients, if overridden.
+    ping_interval: Option<Duration>,
+
+    /// Message shown to every web viewer and CLI client, if set.
+    banner: Option<String>,
+
+    /// Input channels of Server-Sent Events connections, keyed by
+    /// `{session}/{connection}`.
+    event_inputs: DashMap<String, mpsc::Sender<Vec<u8>>>,
+
+    /// Sessions opened by requests with an idempotency key, and when, if there
+    /// is no mesh. Keys of requests in progress have no session yet.
+    open_keys: DashMap<String, (Option<String>, Instant)>,
+
+    /// Results of probing the other servers in the mesh, by hostname.
+    mesh_peers: Mutex<BTreeMap<String, PeerHealth>>,
+
+    /// Permits for concurrent inbound connections, if limited.
+    connection_limit: Option<Arc<Semaphore>>,
+
+    /// Concurrent WebSocket connections from each IP address.
+    ws_limiter: IpLimiter,
+
+    /// Rate of requests from each IP address, across web and gRPC.
+    rate_limiter: RateLimiter,
+
+    /// Maximum number of sessions open on this server, if limited.
+    max_sessions: Option<usize>,
+
+    /// Slots reserved by requests that are still opening a session.
+    pending_sessions: AtomicUsize,
+
+    /// Scheme for generating the names of new sessions.
+    session_names: SessionNames,
+
+    /// How long to wait for streams to finish when shutting down.
+    drain_timeout: Duration,
+
+    /// Idle time before TCP keepalive probes are sent, if enabled.
+    tcp_keepalive: Option<Duration>,
+
+    /// TCP user timeout of listening sockets, if set.
+    tcp_user_timeout: Option<Duration>,
+
+    /// Timeout for connections without any traffic, if enabled.
+    idle_timeout: Option<Duration>,
+
+    /// Reloadable TLS configuration, if built-in TLS is enabled.
+    tls: Option<TlsConfig>,
+
+    /// URL path prefix of the web app, without a trailing slash.
+    base_path: String,
+
+    /// Template for session URLs returned by the Open() RPC, if overridden.
+    session_url: Option<String>,
+
+    /// Whether clients should leave the write password out of links.
+    omit_write_password: bool,
+
+    /// Whether users must join with a join link or the write password.
+    require_join_grants: bool,
+
+    /// Whether the scripted fake session is served, for development.
+    dev_replay: bool,
+
+    /// Set when the server is shutting down, so clients can reconnect later.
+    shutting_down: AtomicBool,
+
+    /// UDP port that WebTransport is served on, once it is listening.
+    #[cfg(feature = "webtransport")]
+    webtransport_port: Mutex<Option<u16>>,
+}
+
+/// Latest results of probing another server in the mesh.
+#[derive(Debug, Clone, Default)]
+pub struct PeerHealth {
+    /// Round-trip time of the last successful probe.
+    pub latency: Option<Duration>,
+    /// Time of the last successful probe.
+    pub last_ok: Option<Instant>,
+    /// Number of probes in a row that failed.
+    pub failures: u32,
+    /// Error of the last probe, if it failed.
+    pub error: Option<String>,
+}
+
+/// A slot reserved for a session that is being opened, counted against the
+/// limit of sessions until it is dropped.
+pub struct SessionSlot<'a>(&'a AtomicUsize);
+
+impl Drop for SessionSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counts of sessions and their subscriptions, for finding leaks.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct LeakStats {
+    /// Sessions added to the store since the server started.
+    pub sessions_created: u64,
+    /// Sessions that were freed after being removed from the store.
+    pub sessions_dropped: u64,
+    /// Sessions removed from the store that are still referenced.
+    pub sessions_dangling: usize,
+    /// Receivers of broadcasted events, across sessions in the store.
+    pub broadcast_receivers: usize,
+    /// Subscriptions to shell output, across sessions in the store.
+    pub chunk_subscriptions: usize,
+    /// Receivers and subscriptions that are held by dangling sessions.
+    pub dangling_subscriptions: usize,
 }
 
 impl ServerState {
     /// Create an empty server state using the given secret.
     pub fn new(options: ServerOptions) -> Result<Self> {
+        if let Some(secret) = &options.secret {
+            check_secret(secret)?;
+        }
+        if let Some(origin) = &options.override_origin {
+            check_origin(origin, "override origin")?;
+        }
+        for origin in &options.allowed_origins {
+            check_origin(origin, "allowed origin")?;
+        }
+        if let Some(template) = &options.session_url {
+            check_session_url(template)?;
+        }
         let secret = options.secret.unwrap_or_else(|| rand_alphanumeric(22));
+        let metrics = Metrics::new();
+        let errors = ErrorReporter::default();
+        let mut advertise_port = None;
+        let host = match (&options.redis_url, options.host) {
+            (Some(_), Some(host)) => {
+                if !host.contains(':') {
+                    warn!(%host, "mesh host has no port, other servers will connect on port 80");
+                }
+                Some(host)
+            }
+            (Some(_), None) => {
+                let detected = options.advertise_port.and_then(mesh::detect_host);
+                match &detected {
+                    Some(host) => info!(%host, "detected host of this server in the mesh"),
+                    None => warn!("no host set for this server, sessions cannot be proxied to it"),
+                }
+                advertise_port = options.advertise_port.filter(|_| detected.is_some());
+                detected
+            }
+            (None, host) => host,
+        };
         let mesh = match options.redis_url {
-            Some(url) => Some(StorageMesh::new(&url, options.host.as_deref())?),
+            Some(url) => Some(StorageMesh::new(
+                &url,
+                host.as_deref(),
+                metrics.redis_errors.clone(),
+                errors.clone(),
+            )?),
             None => None,
         };
+        let audit_stream = match (options.audit_stream, &mesh) {
+            (Some(key), Some(mesh)) => Some((mesh.clone(), key)),
+            (Some(_), None) => bail!("audit stream requires a Redis URL"),
+            (None, _) => None,
+        };
+        let audit = AuditLog::new(options.audit_file.as_deref(), audit_stream)?;
+        let content_security_policy = match options.content_security_policy.as_deref() {
+            Some("") => None,
+            Some(csp) => Some(HeaderValue::from_str(csp).context("invalid CSP header")?),
+            None => Some(HeaderValue::from_static(web::DEFAULT_CSP)),
+        };
+        options.session_names.check()?;
+        let relays = (options.relay_links.iter())
+            .map(|link| Origin::parse(link))
+            .collect::<Result<_>>()?;
+        let base_path = normalize_base_path(options.base_path.as_deref().unwrap_or(""))?;
+        let tls = options.tls.map(TlsConfig::new).transpose()?;
+        let mut ip_filter = IpFilter::new(options.allow_ips, options.deny_ips);
+        if let Some(path) = &options.ip_rules_file {
+            ip_filter.load_file(path)?;
+        }
         Ok(Self {
             mac: Hmac::new_from_slice(secret.as_bytes()).unwrap(),
             override_origin: options.override_origin,
+            allowed_origins: options.allowed_origins,
             store: DashMap::new(),
+            removed: Mutex::new(Vec::new()),
             mesh,
+            relays,
+            advertise_port,
+            metrics,
+            separate_metrics: options.separate_metrics,
+            public_metrics: options.public_metrics,
+            ip_filter,
+            admin_token: options.admin_token,
+            started: Instant::now(),
+            audit,
+            errors,
+            oidc: options.oidc.map(OidcClient::new),
+            content_security_policy,
+            input_rate_limit: options.input_rate_limit,
+            max_user_bytes: options.max_user_bytes,
+            ping_interval: options.ping_interval,
+            banner: options.banner,
+            event_inputs: DashMap::new(),
+            open_keys: DashMap::new(),
+            mesh_peers: Mutex::new(BTreeMap::new()),
+            connection_limit: options.max_connections.map(|n| Arc::new(Semaphore::new(n))),
+            ws_limiter: IpLimiter::new(options.max_ws_per_ip),
+            max_sessions: options.max_sessions,
+            pending_sessions: AtomicUsize::new(0),
+            session_names: options.session_names,
+            rate_limiter: RateLimiter::new(options.request_rate_limit),
+            drain_timeout: options.drain_timeout.unwrap_or_default(),
+            tcp_keepalive: options.tcp_keepalive,
+            tcp_user_timeout: options.tcp_user_timeout,
+            idle_timeout: options.idle_timeout,
+            tls,
+            base_path,
+            session_url: options.session_url,
+            omit_write_password: options.omit_write_password,
+            require_join_grants: options.require_join_grants,
+            dev_replay: options.dev_replay,
+            shutting_down: AtomicBool::new(false),
+            #[cfg(feature = "webtransport")]
+            webtransport_port: Mutex::new(None),
         })
     }
 
@@ -62,28 +383,448 @@ impl ServerState {
         self.mac.clone()
     }
 
+    /// Fingerprint of the key used for signing tokens, which clients can pin
+    /// to notice when they are sent to a different server.
+    pub fn identity(&self) -> String {
+        let tag = self
+            .mac()
+            .chain_update("identity")
+            .chain_update([0])
+            .finalize();
+        // Hash the tag again, so that the fingerprint is not a valid token.
+        let digest = Sha256::digest(tag.into_bytes());
+        format!("SHA256:{}", BASE64_STANDARD_NO_PAD.encode(digest))
+    }
+
+    /// Returns the token of a session, from its name and token nonce.
+    pub fn session_token(&self, name: &str, nonce: &[u8]) -> String {
+        BASE64_STANDARD.encode(self.session_mac(name, nonce).finalize().into_bytes())
+    }
+
+    /// Check the token returned for a session by the Open() RPC.
+    pub fn check_session_token(&self, name: &str, nonce: &[u8], token: &str) -> bool {
+        match BASE64_STANDARD.decode(token) {
+            Ok(token) => self.session_mac(name, nonce).verify_slice(&token).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn session_mac(&self, name: &str, nonce: &[u8]) -> Hmac<Sha256> {
+        let mac = self.mac().chain_update(name);
+        if nonce.is_empty() {
+            mac // Sessions from older snapshots have no nonce.
+        } else {
+            mac.chain_update([0]).chain_update(nonce)
+        }
+    }
+
+    /// Sign a payload for a specific purpose, returning a URL-safe token.
+    pub fn sign_token(&self, purpose: &str, payload: &str) -> String {
+        let tag = self
+            .mac()
+            .chain_update(purpose)
+            .chain_update([0])
+            .chain_update(payload)
+            .finalize();
+        format!(
+            "{}.{}",
+            BASE64_URL_SAFE_NO_PAD.encode(payload),
+            BASE64_URL_SAFE_NO_PAD.encode(tag.into_bytes()),
+        )
+    }
+
+    /// Verify a token from [`ServerState::sign_token`], returning its payload.
+    pub fn verify_token(&self, purpose: &str, token: &str) -> Option<String> {
+        let (payload, tag) = token.split_once('.')?;
+        let payload = String::from_utf8(BASE64_URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        let tag = BASE64_URL_SAFE_NO_PAD.decode(tag).ok()?;
+        self.mac()
+            .chain_update(purpose)
+            .chain_update([0])
+            .chain_update(&payload)
+            .verify_slice(&tag)
+            .ok()?;
+        Some(payload)
+    }
+
     /// Returns the override origin for the Open() RPC.
     pub fn override_origin(&self) -> Option<String> {
         self.override_origin.clone()
     }
 
+    /// Returns whether a client may request links with the given origin.
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins.is_empty()
+            || (self.allowed_origins.iter()).any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+
+    /// Returns the Prometheus metrics for this server.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Returns whether metrics are only served on a separate port.
+    pub fn separate_metrics(&self) -> bool {
+        self.separate_metrics
+    }
+
+    /// Returns whether metrics in the main app are served without the admin
+    /// token.
+    pub fn public_metrics(&self) -> bool {
+        self.public_metrics
+    }
+
+    /// Returns the network access rules for incoming connections.
+    pub fn ip_filter(&self) -> &IpFilter {
+        &self.ip_filter
+    }
+
+    /// Returns the sessions on other servers that are mirrored locally.
+    pub fn relays(&self) -> &[Origin] {
+        &self.relays
+    }
+
+    /// Returns the hostname of this server, if running multiple servers.
+    pub fn host(&self) -> Option<String> {
+        self.mesh.as_ref().and_then(|mesh| mesh.host())
+    }
+
+    /// Returns how long this server has been running.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Returns the OpenID Connect client, if web viewers must log in.
+    pub fn oidc(&self) -> Option<&OidcClient> {
+        self.oidc.as_ref()
+    }
+
+    /// Returns the terminal input accepted from each web user, in bytes per
+    /// second, if limited.
+    pub fn input_rate_limit(&self) -> Option<u64> {
+        self.input_rate_limit
+    }
+
+    /// Returns the terminal and stream data relayed to and from each web user
+    /// before the user is disconnected, in bytes, if limited.
+    pub fn max_user_bytes(&self) -> Option<u64> {
+        self.max_user_bytes
+    }
+
+    /// Returns the semaphore that limits concurrent inbound connections.
+    pub fn connection_limit(&self) -> Option<&Arc<Semaphore>> {
+        self.connection_limit.as_ref()
+    }
+
+    /// Reserve a slot for opening a new session, returning `None` if the server
+    /// has as many sessions open as it allows. Hold the slot until the session
+    /// is inserted, so that concurrent requests cannot exceed the limit.
+    pub fn reserve_session(&self) -> Option<SessionSlot<'_>> {
+        let reserve = |pending: usize| {
+            let open = self.store.len() + pending;
+            let below = self.max_sessions.is_none_or(|max| open < max);
+            below.then_some(pending + 1)
+        };
+        (self.pending_sessions)
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, reserve)
+            .ok()?;
+        Some(SessionSlot(&self.pending_sessions))
+    }
+
+    /// Returns the scheme for generating the names of new sessions.
+    pub fn session_names(&self) -> SessionNames {
+        self.session_names
+    }
+
+    /// Returns the limiter of concurrent WebSocket connections per address.
+    pub fn ws_limiter(&self) -> &IpLimiter {
+        &self.ws_limiter
+    }
+
+    /// Returns the limiter of requests from each address, across web and gRPC.
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// Returns how long to wait for streams to finish when shutting down.
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+
+    /// Returns the idle time before TCP keepalive probes, if enabled.
+    pub fn tcp_keepalive(&self) -> Option<Duration> {
+        self.tcp_keepalive
+    }
+
+    /// Returns the TCP user timeout of listening sockets, if set.
+    pub fn tcp_user_timeout(&self) -> Option<Duration> {
+        self.tcp_user_timeout
+    }
+
+    /// Returns the timeout for connections without any traffic, if enabled.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Returns the TLS configuration, if built-in TLS is enabled.
+    pub fn tls(&self) -> Option<&TlsConfig> {
+        self.tls.as_ref()
+    }
+
+    /// Returns the URL path prefix of the web app, such as `/sshx`, or an
+    /// empty string if it is served at the root.
+    pub fn base_path(&self) -> &str {
+        &self.base_path
+    }
+
+    /// Returns the public web URL of a session, for the Open() RPC.
+    pub fn session_url(&self, origin: &str, name: &str) -> String {
+        match &self.session_url {
+            // Replace placeholders in one pass, in case the origin has braces.
+            Some(template) => (template.split("{name}"))
+                .map(|part| part.replace("{origin}", origin))
+                .collect::<Vec<_>>()
+                .join(name),
+            None => format!("{origin}{}/s/{name}", self.base_path),
+        }
+    }
+
+    /// Returns whether clients should leave the write password out of links.
+    pub fn omit_write_password(&self) -> bool {
+        self.omit_write_password
+    }
+
+    /// Returns whether users must join with a join link or the write password.
+    pub fn require_join_grants(&self) -> bool {
+        self.require_join_grants
+    }
+
+    /// Returns whether the scripted fake session is served.
+    pub fn dev_replay(&self) -> bool {
+        self.dev_replay
+    }
+
+    /// Returns the interval between keepalive pings, if overridden.
+    pub fn ping_interval(&self) -> Option<Duration> {
+        self.ping_interval
+    }
+
+    /// Returns the message shown to web viewers and CLI clients, if set.
+    pub fn banner(&self) -> Option<&str> {
+        self.banner.as_deref()
+    }
+
+    /// Returns the Content-Security-Policy header for web responses.
+    pub fn content_security_policy(&self) -> Option<&HeaderValue> {
+        self.content_security_policy.as_ref()
+    }
+
+    /// Returns the UDP port that WebTransport is served on, if listening.
+    #[cfg(feature = "webtransport")]
+    pub fn webtransport_port(&self) -> Option<u16> {
+        *self.webtransport_port.lock()
+    }
+
+    /// Record the UDP port that WebTransport is served on.
+    #[cfg(feature = "webtransport")]
+    pub(crate) fn set_webtransport_port(&self, port: u16) {
+        *self.webtransport_port.lock() = Some(port);
+    }
+
+    /// Returns the input channels of Server-Sent Events connections.
+    pub fn event_inputs(&self) -> &DashMap<String, mpsc::Sender<Vec<u8>>> {
+        &self.event_inputs
+    }
+
+    /// Returns the audit log for security-relevant events.
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    /// Returns the hooks that receive internal errors.
+    pub fn errors(&self) -> &ErrorReporter {
+        &self.errors
+    }
+
+    /// Returns whether the admin API is enabled.
+    pub fn admin_enabled(&self) -> bool {
+        self.admin_token.is_some()
+    }
+
+    /// Check a bearer token for the admin API, in constant time.
+    pub fn check_admin_token(&self, token: &str) -> bool {
+        match &self.admin_token {
+            Some(admin_token) => bool::from(admin_token.as_bytes().ct_eq(token.as_bytes())),
+            None => false,
+        }
+    }
+
+    /// Refresh gauges from the current state and encode all metrics.
+    pub fn encode_metrics(&self) -> Result<String> {
+        let mut users = 0;
+        for entry in &self.store {
+            users += entry.value().user_count();
+        }
+        let leaks = self.leak_stats();
+        self.metrics.sessions.set(self.store.len() as i64);
+        self.metrics.users.set(users as i64);
+        let metrics = &self.metrics;
+        metrics
+            .sessions_dangling
+            .set(leaks.sessions_dangling as i64);
+        metrics
+            .broadcast_receivers
+            .set(leaks.broadcast_receivers as i64);
+        metrics
+            .chunk_subscriptions
+            .set(leaks.chunk_subscriptions as i64);
+        self.metrics.encode()
+    }
+
+    /// Count sessions and subscriptions, to find leaks in the fan-out of
+    /// session events.
+    ///
+    /// Sessions are removed from the store when closed, and are freed when
+    /// their last connection ends. Any that stay around are dangling, along
+    /// with their subscriptions.
+    pub fn leak_stats(&self) -> LeakStats {
+        let mut stats = LeakStats {
+            sessions_created: self.metrics.sessions_created.get(),
+            ..Default::default()
+        };
+        for entry in &self.store {
+            stats.broadcast_receivers += entry.value().broadcast_receivers();
+            stats.chunk_subscriptions += entry.value().chunk_subscriptions();
+        }
+        let mut removed = self.removed.lock();
+        removed.retain(|session| match session.upgrade() {
+            Some(session) => {
+                stats.sessions_dangling += 1;
+                stats.dangling_subscriptions +=
+                    session.broadcast_receivers() + session.chunk_subscriptions();
+                true
+            }
+            None => {
+                self.metrics.sessions_dropped.inc();
+                false
+            }
+        });
+        stats.sessions_dropped = self.metrics.sessions_dropped.get();
+        stats
+    }
+
+    /// Check that Redis is reachable, if configured.
+    pub async fn check_redis(&self) -> Result<()> {
+        if let Some(mesh) = &self.mesh {
+            let result = match time::timeout(REDIS_CHECK_TIMEOUT, mesh.ping()).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!("timed out")),
+            };
+            result.context("failed to reach Redis, check that the Redis URL is correct")?;
+        }
+        Ok(())
+    }
+
+    /// Reserve an idempotency key for a request to open a session, across the
+    /// mesh if there is one, so that concurrent retries cannot both open one.
+    pub async fn reserve_open_key(&self, key: &str) -> Result<OpenKey> {
+        if let Some(mesh) = &self.mesh {
+            return Ok(match mesh.reserve_open_key(key, OPEN_KEY_EXPIRY).await? {
+                None => OpenKey::Reserved,
+                Some(name) if name.is_empty() => OpenKey::Pending,
+                Some(name) => OpenKey::Opened(name),
+            });
+        }
+        Ok(match self.open_keys.entry(key.to_string()) {
+            Entry::Occupied(entry) if entry.get().1.elapsed() < OPEN_KEY_EXPIRY => {
+                match &entry.get().0 {
+                    Some(name) => OpenKey::Opened(name.clone()),
+                    None => OpenKey::Pending,
+                }
+            }
+            Entry::Occupied(mut entry) => {
+                entry.insert((None, Instant::now()));
+                OpenKey::Reserved
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((None, Instant::now()));
+                OpenKey::Reserved
+            }
+        })
+    }
+
+    /// Record the session opened by a request with a reserved idempotency key,
+    /// or release the key if the request failed.
+    pub async fn finish_open_key(&self, key: &str, name: Option<&str>) {
+        if let Some(mesh) = &self.mesh {
+            if let Err(err) = mesh.finish_open_key(key, name, OPEN_KEY_EXPIRY).await {
+                warn!(?err, "failed to record idempotency key");
+            }
+            return;
+        }
+        match name {
+            Some(name) => {
+                let entry = (Some(name.to_string()), Instant::now());
+                self.open_keys.insert(key.to_string(), entry);
+            }
+            None => {
+                self.open_keys.remove(key);
+            }
+        }
+    }
+
     /// Lookup a local session by name.
     pub fn lookup(&self, name: &str) -> Option<Arc<Session>> {
         self.store.get(name).map(|s| s.clone())
     }
 
+    /// List all sessions in the local store, with their names.
+    pub fn sessions(&self) -> Vec<(String, Arc<Session>)> {
+        self.store
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
     /// Insert a session into the local store.
     pub fn insert(&self, name: &str, session: Arc<Session>) {
+        self.start_sync(name, &session);
+        self.metrics.sessions_created.inc();
+        if let Some(prev_session) = self.store.insert(name.to_string(), session) {
+            prev_session.shutdown();
+            self.removed.lock().push(Arc::downgrade(&prev_session));
+        }
+    }
+
+    /// Insert a new session into the local store, unless a session with the
+    /// same name is open on this server or elsewhere in the mesh. Returns
+    /// whether the session was inserted. Names of closed sessions are reused.
+    pub async fn insert_new(&self, name: &str, session: Arc<Session>) -> Result<bool> {
         if let Some(mesh) = &self.mesh {
+            if !mesh.claim(name).await? {
+                return Ok(false);
+            }
+        }
+        match self.store.entry(name.to_string()) {
+            Entry::Occupied(_) => return Ok(false),
+            Entry::Vacant(entry) => entry.insert(session.clone()),
+        };
+        self.start_sync(name, &session);
+        self.metrics.sessions_created.inc();
+        Ok(true)
+    }
+
+    /// Start saving a session to the mesh, if there is one.
+    fn start_sync(&self, name: &str, session: &Arc<Session>) {
+        if let Some(mesh) = &self.mesh {
+            let guard_name = Some(name.to_string());
             let name = name.to_string();
             let session = session.clone();
             let mesh = mesh.clone();
-            tokio::spawn(async move {
+            let task = async move {
                 mesh.background_sync(&name, session).await;
-            });
-        }
-        if let Some(prev_session) = self.store.insert(name.to_string(), session) {
-            prev_session.shutdown();
+            };
+            tokio::spawn(self.errors.guard(guard_name, task));
         }
     }
 
@@ -91,14 +832,18 @@ impl ServerState {
     pub fn remove(&self, name: &str) -> bool {
         if let Some((_, session)) = self.store.remove(name) {
             session.shutdown();
+            self.removed.lock().push(Arc::downgrade(&session));
             true
         } else {
             false
         }
     }
 
-    /// Close a session permanently on this and other servers.
-    pub async fn close_session(&self, name: &str) -> Result<()> {
+    /// Close a session permanently on this and other servers, logging the
+    /// reason that it was closed.
+    #[instrument(skip(self))]
+    pub async fn close_session(&self, name: &str, reason: &str) -> Result<()> {
+        info!(event = "session_closed", session = %name, reason, "closing session");
         self.remove(name);
         if let Some(mesh) = &self.mesh {
             mesh.mark_closed(name).await?;
@@ -106,8 +851,23 @@ impl ServerState {
         Ok(())
     }
 
+    /// Returns the metadata of a session on this server or elsewhere in the
+    /// mesh, to authenticate a client before connecting it.
+    pub async fn session_metadata(&self, name: &str) -> Result<Option<Metadata>> {
+        if let Some(session) = self.lookup(name) {
+            return Ok(Some(session.metadata().clone()));
+        }
+        if let Some(mesh) = &self.mesh {
+            if let (_, Some(snapshot)) = mesh.get_owner_snapshot(name).await? {
+                return Session::restore_metadata(&snapshot).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
     /// Connect to a session by name from the `sshx` client, which provides the
     /// actual terminal backend.
+    #[instrument(skip(self))]
     pub async fn backend_connect(&self, name: &str) -> Result<Option<Arc<Session>>> {
         if let Some(session) = self.lookup(name) {
             return Ok(Some(session));
@@ -116,7 +876,40 @@ impl ServerState {
         if let Some(mesh) = &self.mesh {
             let (owner, snapshot) = mesh.get_owner_snapshot(name).await?;
             if let Some(snapshot) = snapshot {
-                let session = Arc::new(Session::restore(&snapshot)?);
+                let from = owner.as_deref().unwrap_or("none");
+                let restores = &self.metrics.session_restores;
+                let session = match Session::restore(&snapshot) {
+                    Ok(session) => Arc::new(session),
+                    Err(err) => {
+                        restores.with_label_values(&["error"]).inc();
+                        error!(
+                            event = "session_restore_failed",
+                            session = %name,
+                            from,
+                            snapshot_bytes = snapshot.len(),
+                            ?err,
+                            "failed to restore session from storage"
+                        );
+                        return Err(err);
+                    }
+                };
+                restores.with_label_values(&["ok"]).inc();
+                let transferred = owner.is_some() && owner != mesh.host();
+                if transferred {
+                    self.metrics
+                        .session_transfers
+                        .with_label_values(&["received"])
+                        .inc();
+                }
+                info!(
+                    event = "session_transferred",
+                    session = %name,
+                    reason = "restored from storage",
+                    from,
+                    shells = session.list_shells().len(),
+                    snapshot_bytes = snapshot.len(),
+                    "moving session to this server"
+                );
                 self.insert(name, session.clone());
                 if let Some(owner) = owner {
                     mesh.notify_transfer(name, &owner).await?;
@@ -129,6 +922,7 @@ impl ServerState {
     }
 
     /// Connect to a session from a web browser frontend, possibly redirecting.
+    #[instrument(skip(self))]
     pub async fn frontend_connect(
         &self,
         name: &str,
@@ -139,7 +933,7 @@ impl ServerState {
 
         if let Some(mesh) = &self.mesh {
             let mut owner = mesh.get_owner(name).await?;
-            if owner.is_some() && owner.as_deref() == mesh.host() {
+            if owner.is_some() && owner == mesh.host() {
                 // Do not redirect back to the same server.
                 owner = None;
             }
@@ -154,34 +948,269 @@ impl ServerState {
         if let Some(mesh) = &self.mesh {
             let mut transfers = pin!(mesh.listen_for_transfers());
             while let Some(name) = transfers.next().await {
+                if self.lookup(&name).is_none() {
+                    continue; // Closed by this server, or already moved.
+                }
+                // Failing to check only affects logs and metrics.
+                let closed = mesh.is_closed(&name).await.unwrap_or(false);
+                if closed {
+                    info!(
+                        event = "session_closed",
+                        session = %name,
+                        reason = "closed by another server",
+                        "removing session from this server"
+                    );
+                } else {
+                    self.metrics
+                        .session_transfers
+                        .with_label_values(&["sent"])
+                        .inc();
+                    info!(
+                        event = "session_transferred",
+                        session = %name,
+                        reason = "claimed by another server",
+                        "moving session away from this server"
+                    );
+                }
                 self.remove(&name);
             }
         }
     }
 
+    /// Periodically detect the address of this server again, if it was not set
+    /// by the operator, and advertise it to the mesh if it has changed.
+    pub async fn watch_host(&self) {
+        let (Some(mesh), Some(port)) = (&self.mesh, self.advertise_port) else {
+            return;
+        };
+        loop {
+            time::sleep(HOST_CHECK_INTERVAL).await;
+            let detected = match task::spawn_blocking(move || mesh::detect_host(port)).await {
+                Ok(Some(host)) => host,
+                _ => continue,
+            };
+            if mesh.host().as_ref() != Some(&detected) {
+                info!(host = %detected, "address of this server changed, registering again");
+                mesh.set_host(detected);
+            }
+        }
+    }
+
+    /// Periodically register this server in the mesh, and probe the latency of
+    /// the other servers, which sessions are proxied to.
+    pub async fn probe_mesh(&self) {
+        let Some(mesh) = &self.mesh else {
+            return;
+        };
+        let http = reqwest::Client::new();
+        let mut interval = time::interval(MESH_PROBE_INTERVAL);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let Some(host) = mesh.host() else {
+                continue;
+            };
+            if let Err(err) = mesh.register_node(&host, 3 * MESH_PROBE_INTERVAL).await {
+                warn!(?err, "failed to register this server in the mesh");
+                continue;
+            }
+            let mut peers = match mesh.list_nodes().await {
+                Ok(nodes) => nodes,
+                Err(err) => {
+                    warn!(?err, "failed to list servers in the mesh");
+                    continue;
+                }
+            };
+            peers.retain(|peer| *peer != host);
+            let probes = peers.iter().map(|peer| self.probe_peer(&http, peer));
+            let results = futures_util::future::join_all(probes).await;
+
+            let metrics = &self.metrics;
+            let mut health = self.mesh_peers.lock();
+            health.retain(|peer, _| {
+                let keep = peers.contains(peer);
+                if !keep {
+                    info!(%peer, "server left the mesh");
+                    metrics.mesh_peer_latency.remove_label_values(&[peer]).ok();
+                }
+                keep
+            });
+            for (peer, result) in peers.into_iter().zip(results) {
+                let entry = health.entry(peer.clone()).or_default();
+                match result {
+                    Ok(latency) => {
+                        if entry.failures > 0 {
+                            info!(%peer, failures = entry.failures, "server in the mesh is reachable again");
+                        }
+                        let seconds = latency.as_secs_f64();
+                        metrics
+                            .mesh_peer_latency
+                            .with_label_values(&[&peer])
+                            .set(seconds);
+                        *entry = PeerHealth {
+                            latency: Some(latency),
+                            last_ok: Some(Instant::now()),
+                            failures: 0,
+                            error: None,
+                        };
+                    }
+                    Err(err) => {
+                        if entry.failures == 0 {
+                            warn!(%peer, ?err, "failed to reach server in the mesh");
+                        }
+                        metrics
+                            .mesh_probe_failures
+                            .with_label_values(&[&peer])
+                            .inc();
+                        entry.failures += 1;
+                        entry.error = Some(format!("{err:#}"));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Measure the round-trip time of a request to another server.
+    async fn probe_peer(&self, http: &reqwest::Client, peer: &str) -> Result<Duration> {
+        let url = format!("http://{peer}{}/api/mesh/ping", self.base_path);
+        let start = Instant::now();
+        let resp = http.get(url).timeout(MESH_PROBE_TIMEOUT).send().await?;
+        resp.error_for_status()?;
+        Ok(start.elapsed())
+    }
+
+    /// Returns the latest results of probing other servers in the mesh.
+    pub fn mesh_peers(&self) -> BTreeMap<String, PeerHealth> {
+        self.mesh_peers.lock().clone()
+    }
+
+    /// Periodically measure the output rate of shells, sending it to users.
+    pub async fn sample_throughput(&self) {
+        let mut interval = time::interval(THROUGHPUT_INTERVAL);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            for entry in &self.store {
+                entry.value().sample_throughput(THROUGHPUT_INTERVAL);
+            }
+        }
+    }
+
     /// Close all sessions that have been disconnected for too long.
     pub async fn close_old_sessions(&self) {
         loop {
-            time::sleep(DISCONNECTED_SESSION_EXPIRY / 5).await;
+            time::sleep(MIN_SESSION_EXPIRY / 2).await;
+            self.leak_stats(); // Forget about removed sessions that were freed.
+            self.open_keys
+                .retain(|_, (_, created)| created.elapsed() < OPEN_KEY_EXPIRY);
             let mut to_close = Vec::new();
             for entry in &self.store {
                 let session = entry.value();
-                if session.last_accessed().elapsed() > DISCONNECTED_SESSION_EXPIRY {
+                if session.pinned() {
+                    continue;
+                }
+                let expiry = match session.inactivity().and_then(|p| p.policy) {
+                    None => Some(DISCONNECTED_SESSION_EXPIRY),
+                    Some(Policy::KeepSecs(secs)) => {
+                        Some(Duration::from_secs(secs.into()).max(MIN_SESSION_EXPIRY))
+                    }
+                    Some(Policy::KeepForever(_)) => None,
+                };
+                if expiry.is_some_and(|expiry| session.last_accessed().elapsed() > expiry) {
                     to_close.push(entry.key().clone());
                 }
             }
             for name in to_close {
-                if let Err(err) = self.close_session(&name).await {
+                self.audit.record(
+                    &Peer::default(),
+                    AuditEvent::SessionClosed {
+                        session: name.clone(),
+                        reason: "expired".into(),
+                    },
+                );
+                if let Err(err) = self.close_session(&name, "expired").await {
                     error!(?err, "failed to close old session {name}");
+                    self.errors.report(ErrorSource::Session, &err, Some(&name));
                 }
             }
         }
     }
 
+    /// Returns whether the server is shutting down.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+
     /// Send a graceful shutdown signal to every session.
     pub fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
         for entry in &self.store {
             entry.value().shutdown();
         }
     }
 }
+
+/// Check that a secret is hard enough to guess, estimating its entropy from its
+/// length and the kinds of characters it uses.
+fn check_secret(secret: &str) -> Result<()> {
+    let mut pool = 0;
+    if secret.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if secret.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if secret.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if secret.chars().any(|c| !c.is_ascii_alphanumeric()) {
+        pool += 33;
+    }
+    let distinct: HashSet<char> = secret.chars().collect();
+    let entropy = secret.chars().count() as f64 * f64::from(pool).log2();
+    if entropy < MIN_SECRET_ENTROPY || distinct.len() < 8 {
+        bail!(
+            "secret is too weak, use a random string of at least 16 letters and digits, such as \
+             from `openssl rand -base64 24`"
+        );
+    }
+    Ok(())
+}
+
+/// Check that an origin is an HTTP(S) URL with a host and nothing after it.
+fn check_origin(origin: &str, kind: &str) -> Result<()> {
+    let url = Url::parse(origin).with_context(|| format!("invalid {kind} {origin:?}"))?;
+    if !matches!(url.scheme(), "http" | "https")
+        || !url.has_host()
+        || url.path() != "/"
+        || origin.ends_with('/')
+        || url.query().is_some()
+        || url.fragment().is_some()
+    {
+        bail!("{kind} should look like https://sshx.example.com, got {origin:?}");
+    }
+    Ok(())
+}
+
+/// Check that a session URL template has the name of the session in it.
+fn check_session_url(template: &str) -> Result<()> {
+    if !template.contains("{name}") {
+        bail!("session URL template must contain {{name}}, got {template:?}");
+    }
+    if template.contains('#') {
+        bail!("session URL template cannot have a fragment, got {template:?}");
+    }
+    Ok(())
+}
+
+/// Normalize a URL path prefix to have a leading slash and no trailing slash.
+fn normalize_base_path(path: &str) -> Result<String> {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        return Ok(String::new());
+    }
+    if path.contains(['?', '#', ':']) || path.split('/').any(|s| s.is_empty() || s == "..") {
+        bail!("invalid base path: {path}");
+    }
+    Ok(format!("/{path}"))
+}
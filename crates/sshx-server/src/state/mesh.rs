@@ -1,14 +1,17 @@
 //! Storage and distributed communication.
 
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::{pin::pin, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use deadpool::managed::Manager;
+use prometheus::IntCounter;
 use redis::AsyncCommands;
-use tokio::time;
+use tokio::{sync::watch, time};
 use tokio_stream::{Stream, StreamExt};
 use tracing::error;
 
+use crate::report::{ErrorReporter, ErrorSource};
 use crate::session::Session;
 
 /// Interval for syncing the latest session state into persistent storage.
@@ -33,12 +36,22 @@ fn set_opts() -> redis::SetOptions {
 #[derive(Clone)]
 pub struct StorageMesh {
     redis: deadpool_redis::Pool,
-    host: Option<String>,
+    host: Arc<watch::Sender<Option<String>>>,
+    redis_errors: IntCounter,
+    errors: ErrorReporter,
 }
 
 impl StorageMesh {
     /// Construct a new storage object from Redis URL.
-    pub fn new(redis_url: &str, host: Option<&str>) -> Result<Self> {
+    ///
+    /// Failed Redis operations are counted in the `redis_errors` metric, and
+    /// sent to the error hooks.
+    pub fn new(
+        redis_url: &str,
+        host: Option<&str>,
+        redis_errors: IntCounter,
+        errors: ErrorReporter,
+    ) -> Result<Self> {
         let redis = deadpool_redis::Config::from_url(redis_url)
             .builder()?
             .max_size(4)
@@ -48,23 +61,42 @@ impl StorageMesh {
 
         Ok(Self {
             redis,
-            host: host.map(|s| s.to_string()),
+            host: Arc::new(watch::Sender::new(host.map(|s| s.to_string()))),
+            redis_errors,
+            errors,
         })
     }
 
+    /// Record a failed Redis operation, converting the error for propagation.
+    fn fail(&self, err: impl Into<anyhow::Error>) -> anyhow::Error {
+        self.redis_errors.inc();
+        let err = err.into();
+        self.errors.report(ErrorSource::Redis, &err, None);
+        err
+    }
+
     /// Returns the hostname of this server, if running in mesh node.
-    pub fn host(&self) -> Option<&str> {
-        self.host.as_deref()
+    pub fn host(&self) -> Option<String> {
+        self.host.borrow().clone()
+    }
+
+    /// Change the hostname of this server, after its address has changed.
+    ///
+    /// Sessions are owned by the new hostname from their next sync, and
+    /// transfers are received on it right away.
+    pub fn set_host(&self, host: String) {
+        self.host.send_replace(Some(host));
     }
 
     /// Retrieve the hostname of the owner of a session.
     pub async fn get_owner(&self, name: &str) -> Result<Option<String>> {
-        let mut conn = self.redis.get().await?;
+        let mut conn = self.redis.get().await.map_err(|e| self.fail(e))?;
         let (owner, closed) = redis::pipe()
             .get(format!("session:{{{name}}}:owner"))
             .get(format!("session:{{{name}}}:closed"))
             .query_async(&mut conn)
-            .await?;
+            .await
+            .map_err(|e| self.fail(e))?;
         if closed {
             Ok(None)
         } else {
@@ -72,18 +104,43 @@ impl StorageMesh {
         }
     }
 
+    /// Claim the name of a new session for this server, unless a session with
+    /// the name is already open in the mesh. Returns whether it was claimed.
+    ///
+    /// The name of a closed session can be claimed again. Claiming it deletes
+    /// the marker left by [`mark_closed`](Self::mark_closed), which would
+    /// otherwise hide the new session from [`get_owner`](Self::get_owner).
+    pub async fn claim(&self, name: &str) -> Result<bool> {
+        let host = self.host().context("server has no host to claim names")?;
+        let mut conn = self.redis.get().await.map_err(|e| self.fail(e))?;
+        let opts = set_opts().conditional_set(redis::ExistenceCheck::NX);
+        let claimed: Option<String> = conn
+            .set_options(format!("session:{{{name}}}:owner"), host, opts)
+            .await
+            .map_err(|e| self.fail(e))?;
+        if claimed.is_none() {
+            return Ok(false);
+        }
+        // A closed session with the same name no longer hides the new one.
+        conn.del::<_, ()>(format!("session:{{{name}}}:closed"))
+            .await
+            .map_err(|e| self.fail(e))?;
+        Ok(true)
+    }
+
     /// Retrieve the owner and snapshot of a session.
     pub async fn get_owner_snapshot(
         &self,
         name: &str,
     ) -> Result<(Option<String>, Option<Vec<u8>>)> {
-        let mut conn = self.redis.get().await?;
+        let mut conn = self.redis.get().await.map_err(|e| self.fail(e))?;
         let (owner, snapshot, closed) = redis::pipe()
             .get(format!("session:{{{name}}}:owner"))
             .get(format!("session:{{{name}}}:snapshot"))
             .get(format!("session:{{{name}}}:closed"))
             .query_async(&mut conn)
-            .await?;
+            .await
+            .map_err(|e| self.fail(e))?;
         if closed {
             Ok((None, None))
         } else {
@@ -104,6 +161,7 @@ impl StorageMesh {
             let mut conn = match self.redis.get().await {
                 Ok(conn) => conn,
                 Err(err) => {
+                    let err = self.fail(err);
                     error!(?err, "failed to connect to redis for sync");
                     continue;
                 }
@@ -112,24 +170,61 @@ impl StorageMesh {
                 Ok(snapshot) => snapshot,
                 Err(err) => {
                     error!(?err, "failed to snapshot session {name}");
+                    self.errors.report(ErrorSource::Session, &err, Some(name));
                     continue;
                 }
             };
             let mut pipe = redis::pipe();
-            if let Some(host) = &self.host {
+            if let Some(host) = self.host() {
                 pipe.set_options(format!("session:{{{name}}}:owner"), host, set_opts());
             }
             pipe.set_options(format!("session:{{{name}}}:snapshot"), snapshot, set_opts());
             match pipe.query_async(&mut conn).await {
                 Ok(()) => {}
-                Err(err) => error!(?err, "failed to sync session {name}"),
+                Err(err) => {
+                    let err = self.fail(err);
+                    error!(?err, "failed to sync session {name}");
+                }
             }
         }
     }
 
+    /// List the names of all sessions stored in Redis, with their owners.
+    pub async fn list_sessions(&self) -> Result<Vec<(String, Option<String>)>> {
+        let mut conn = self.redis.get().await.map_err(|e| self.fail(e))?;
+        let mut names = Vec::new();
+        let mut keys = conn
+            .scan_match::<_, String>("session:{*}:snapshot")
+            .await
+            .map_err(|e| self.fail(e))?;
+        while let Some(key) = keys.next_item().await {
+            let name = key
+                .strip_prefix("session:{")
+                .and_then(|key| key.strip_suffix("}:snapshot"));
+            names.extend(name.map(String::from));
+        }
+        drop(keys);
+        // Keys may be returned more than once while scanning.
+        names.sort();
+        names.dedup();
+
+        let mut pipe = redis::pipe();
+        for name in &names {
+            pipe.get(format!("session:{{{name}}}:owner"));
+        }
+        let owners: Vec<Option<String>> = if names.is_empty() {
+            Vec::new()
+        } else {
+            pipe.query_async(&mut conn)
+                .await
+                .map_err(|e| self.fail(e))?
+        };
+        Ok(names.into_iter().zip(owners).collect())
+    }
+
     /// Mark a session as closed, so it will expire and never be accessed again.
     pub async fn mark_closed(&self, name: &str) -> Result<()> {
-        let mut conn = self.redis.get().await?;
+        let mut conn = self.redis.get().await.map_err(|e| self.fail(e))?;
         let (owner,): (Option<String>,) = redis::pipe()
             .get_del(format!("session:{{{name}}}:owner"))
             .del(format!("session:{{{name}}}:snapshot"))
@@ -137,33 +232,159 @@ impl StorageMesh {
             .set_options(format!("session:{{{name}}}:closed"), true, set_opts())
             .ignore()
             .query_async(&mut conn)
-            .await?;
+            .await
+            .map_err(|e| self.fail(e))?;
         if let Some(owner) = owner {
             self.notify_transfer(name, &owner).await?;
         }
         Ok(())
     }
 
+    /// Returns whether a session was closed, rather than claimed by another
+    /// server.
+    pub async fn is_closed(&self, name: &str) -> Result<bool> {
+        let mut conn = self.redis.get().await.map_err(|e| self.fail(e))?;
+        let closed: Option<bool> = conn
+            .get(format!("session:{{{name}}}:closed"))
+            .await
+            .map_err(|e| self.fail(e))?;
+        Ok(closed.unwrap_or(false))
+    }
+
+    /// Reserve an idempotency key for opening a session, until it expires.
+    ///
+    /// Returns `None` if this call reserved the key, or else the name of the
+    /// session opened with it, which is empty while that request is pending.
+    pub async fn reserve_open_key(&self, key: &str, expiry: Duration) -> Result<Option<String>> {
+        let mut conn = self.redis.get().await.map_err(|e| self.fail(e))?;
+        let opts = redis::SetOptions::default()
+            .conditional_set(redis::ExistenceCheck::NX)
+            .with_expiration(redis::SetExpiry::PX(expiry.as_millis() as usize));
+        let reserved: Option<String> = conn
+            .set_options(format!("open_key:{{{key}}}"), "", opts)
+            .await
+            .map_err(|e| self.fail(e))?;
+        if reserved.is_some() {
+            return Ok(None);
+        }
+        let name: Option<String> = conn
+            .get(format!("open_key:{{{key}}}"))
+            .await
+            .map_err(|e| self.fail(e))?;
+        Ok(Some(name.unwrap_or_default()))
+    }
+
+    /// Record the session opened with a reserved idempotency key, or release
+    /// the key if no session was opened.
+    pub async fn finish_open_key(
+        &self,
+        key: &str,
+        name: Option<&str>,
+        expiry: Duration,
+    ) -> Result<()> {
+        let mut conn = self.redis.get().await.map_err(|e| self.fail(e))?;
+        match name {
+            Some(name) => {
+                let opts = redis::SetOptions::default()
+                    .conditional_set(redis::ExistenceCheck::XX)
+                    .with_expiration(redis::SetExpiry::PX(expiry.as_millis() as usize));
+                let _: Option<String> = conn
+                    .set_options(format!("open_key:{{{key}}}"), name, opts)
+                    .await
+                    .map_err(|e| self.fail(e))?;
+            }
+            None => {
+                () = conn
+                    .del(format!("open_key:{{{key}}}"))
+                    .await
+                    .map_err(|e| self.fail(e))?;
+            }
+        }
+        Ok(())
+    }
+
     /// Notify a host that a session has been transferred.
     pub async fn notify_transfer(&self, name: &str, host: &str) -> Result<()> {
-        let mut conn = self.redis.get().await?;
-        () = conn.publish(format!("transfers:{host}"), name).await?;
+        let mut conn = self.redis.get().await.map_err(|e| self.fail(e))?;
+        () = conn
+            .publish(format!("transfers:{host}"), name)
+            .await
+            .map_err(|e| self.fail(e))?;
+        Ok(())
+    }
+
+    /// Register this server as a node of the mesh, until the registration
+    /// expires after some time without being renewed.
+    pub async fn register_node(&self, host: &str, expiry: Duration) -> Result<()> {
+        let mut conn = self.redis.get().await.map_err(|e| self.fail(e))?;
+        let opts = redis::SetOptions::default()
+            .with_expiration(redis::SetExpiry::PX(expiry.as_millis() as usize));
+        () = conn
+            .set_options(format!("node:{{{host}}}"), true, opts)
+            .await
+            .map_err(|e| self.fail(e))?;
+        Ok(())
+    }
+
+    /// List the hostnames of all registered nodes of the mesh.
+    pub async fn list_nodes(&self) -> Result<Vec<String>> {
+        let mut conn = self.redis.get().await.map_err(|e| self.fail(e))?;
+        let mut hosts = Vec::new();
+        let mut keys = conn
+            .scan_match::<_, String>("node:{*}")
+            .await
+            .map_err(|e| self.fail(e))?;
+        while let Some(key) = keys.next_item().await {
+            let host = key
+                .strip_prefix("node:{")
+                .and_then(|key| key.strip_suffix('}'));
+            hosts.extend(host.map(String::from));
+        }
+        // Keys may be returned more than once while scanning.
+        hosts.sort();
+        hosts.dedup();
+        Ok(hosts)
+    }
+
+    /// Check that Redis is reachable and responding to commands.
+    pub async fn ping(&self) -> Result<()> {
+        let mut conn = self.redis.get().await.map_err(|e| self.fail(e))?;
+        let _: String = redis::cmd("PING")
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| self.fail(e))?;
+        Ok(())
+    }
+
+    /// Append an entry to a Redis stream, such as the audit log.
+    pub async fn append_stream(&self, key: &str, entry: &str) -> Result<()> {
+        let mut conn = self.redis.get().await.map_err(|e| self.fail(e))?;
+        let _: String = redis::cmd("XADD")
+            .arg(key)
+            .arg("*")
+            .arg("entry")
+            .arg(entry)
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| self.fail(e))?;
         Ok(())
     }
 
     /// Listen for sessions that are transferred away from this host.
     pub fn listen_for_transfers(&self) -> impl Stream<Item = String> + Send + '_ {
         async_stream::stream! {
-            let Some(host) = &self.host else {
-                // If not in a mesh, there are no transfers.
-                return;
-            };
-
+            let mut host_rx = self.host.subscribe();
             loop {
+                let Some(host) = host_rx.borrow_and_update().clone() else {
+                    // If not in a mesh, there are no transfers.
+                    return;
+                };
+
                 // Requires an owned, non-pool connection for ownership reasons.
                 let conn = match self.redis.manager().create().await {
                     Ok(conn) => conn,
                     Err(err) => {
+                        let err = self.fail(err);
                         error!(?err, "failed to connect to redis for pub/sub");
                         time::sleep(Duration::from_secs(5)).await;
                         continue;
@@ -171,13 +392,20 @@ impl StorageMesh {
                 };
                 let mut pubsub = conn.into_pubsub();
                 if let Err(err) = pubsub.subscribe(format!("transfers:{host}")).await {
+                    let err = self.fail(err);
                     error!(?err, "failed to subscribe to transfers");
                     time::sleep(Duration::from_secs(1)).await;
                     continue;
                 }
 
                 let mut msg_stream = pin!(pubsub.into_on_message());
-                while let Some(msg) = msg_stream.next().await {
+                loop {
+                    let msg = tokio::select! {
+                        Some(msg) = msg_stream.next() => msg,
+                        // Subscribe again with the new hostname.
+                        Ok(()) = host_rx.changed() => break,
+                        else => break,
+                    };
                     match msg.get_payload::<String>() {
                         Ok(payload) => yield payload,
                         Err(err) => {
@@ -190,3 +418,23 @@ impl StorageMesh {
         }
     }
 }
+
+/// Detect the address of this server from its environment, for other servers
+/// in the mesh to reach it on a port.
+///
+/// This is the `POD_IP` variable if set, which Kubernetes can provide through
+/// the downward API, or else the address that the hostname resolves to.
+pub fn detect_host(port: u16) -> Option<String> {
+    let ip = match std::env::var("POD_IP") {
+        Ok(ip) => ip.parse::<IpAddr>().ok()?,
+        Err(_) => {
+            let hostname = std::env::var("HOSTNAME").ok()?;
+            (hostname.as_str(), port)
+                .to_socket_addrs()
+                .ok()?
+                .map(|addr| addr.ip())
+                .find(|ip| !ip.is_loopback())?
+        }
+    };
+    Some(SocketAddr::new(ip, port).to_string())
+}
@@ -0,0 +1,159 @@
+//! Built-in TLS for the server, with optional client certificate checks.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use parking_lot::RwLock;
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Paths to the PEM files used for serving HTTPS.
+#[derive(Clone, Debug)]
+pub struct TlsOptions {
+    /// Certificate chain of the server.
+    pub cert: PathBuf,
+
+    /// Private key of the server, in PKCS #8, PKCS #1 or SEC1 format.
+    pub key: PathBuf,
+
+    /// Certificate authorities that client certificates are verified against.
+    /// If set, clients must present a valid certificate to connect.
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Acceptor for TLS connections, which can be reloaded from disk while the
+/// server is running.
+///
+/// Reloading only affects new connections, so existing ones are not dropped.
+pub struct TlsConfig {
+    options: TlsOptions,
+    acceptor: RwLock<TlsAcceptor>,
+}
+
+impl TlsConfig {
+    /// Load the certificates and keys in the options.
+    pub fn new(options: TlsOptions) -> Result<Self> {
+        let acceptor = RwLock::new(load_acceptor(&options)?);
+        Ok(Self { options, acceptor })
+    }
+
+    /// Returns the current acceptor for new connections.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.read().clone()
+    }
+
+    /// Read the certificates and keys from disk again, such as after they are
+    /// renewed. On error, the previous ones remain in use.
+    pub fn reload(&self) -> Result<()> {
+        let acceptor = load_acceptor(&self.options)?;
+        *self.acceptor.write() = acceptor;
+        Ok(())
+    }
+
+    /// Read a QUIC configuration from disk for serving WebTransport over
+    /// HTTP/3, with the same certificates and client certificate checks.
+    #[cfg(feature = "webtransport")]
+    pub fn quic_config(&self) -> Result<quinn::ServerConfig> {
+        load_quic_config(&self.options)
+    }
+}
+
+fn load_acceptor(options: &TlsOptions) -> Result<TlsAcceptor> {
+    let certs = load_certs(&options.cert)?;
+    let key = load_key(&options.key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match &options.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(&cert)
+                    .with_context(|| format!("invalid CA certificate in {}", path.display()))?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .context("invalid TLS certificate or key")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(feature = "webtransport")]
+fn load_quic_config(options: &TlsOptions) -> Result<quinn::ServerConfig> {
+    use quinn::crypto::rustls::QuicServerConfig;
+    use quinn::rustls;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    let der = |cert: Certificate| CertificateDer::from(cert.0);
+    let certs = load_certs(&options.cert)?.into_iter().map(der).collect();
+    let key = PrivateKeyDer::try_from(load_key(&options.key)?.0).map_err(anyhow::Error::msg)?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?;
+    let builder = match &options.client_ca {
+        Some(path) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(der(cert))
+                    .with_context(|| format!("invalid CA certificate in {}", path.display()))?;
+            }
+            let verifier =
+                rustls::server::WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .context("invalid TLS certificate or key")?;
+    config.alpn_protocols = vec![b"h3".to_vec()];
+    let config = QuicServerConfig::try_from(config)?;
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(config)))
+}
+
+/// Returns the subject of a DER-encoded certificate, like `CN=alice, O=Acme`.
+pub fn cert_subject(der: &[u8]) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    Some(cert.subject().to_string())
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .with_context(|| format!("failed to read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("no certificates found in {}", path.display());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = open(path)?;
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("failed to read private key from {}", path.display()))?;
+        match item {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => bail!("no private key found in {}", path.display()),
+        }
+    }
+}
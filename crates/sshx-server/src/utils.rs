@@ -2,10 +2,13 @@
 
 use std::fmt::Debug;
 use std::future::Future;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 
+use dashmap::{mapref::entry::Entry, DashMap};
 use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
 
 /// A cloneable structure that handles shutdown signals.
 #[derive(Clone)]
@@ -61,3 +64,233 @@ impl Debug for Shutdown {
             .finish()
     }
 }
+
+/// A token bucket rate limiter, which refills continuously over time.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Construct a full bucket that refills at `rate` tokens per second.
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Attempt to take tokens from the bucket, returning `false` if there are
+    /// not enough available.
+    pub fn try_take(&mut self, amount: f64) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= amount {
+            self.tokens -= amount;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns the maximum number of tokens the bucket can hold.
+    pub fn capacity(&self) -> f64 {
+        self.capacity
+    }
+
+    /// Returns whether the bucket has refilled to its capacity.
+    pub fn is_full(&self) -> bool {
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.capacity
+    }
+
+    /// Returns how long until `amount` tokens will be available.
+    pub fn time_until(&self, amount: f64) -> Duration {
+        Duration::from_secs_f64((amount - self.tokens).max(0.0) / self.rate)
+    }
+}
+
+/// Counts concurrent connections from each IP address, up to an optional
+/// limit.
+#[derive(Debug, Clone, Default)]
+pub struct IpLimiter {
+    limit: Option<usize>,
+    counts: Arc<DashMap<IpAddr, usize>>,
+}
+
+impl IpLimiter {
+    /// Construct a limiter allowing `limit` connections from each address.
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            counts: Default::default(),
+        }
+    }
+
+    /// Count a new connection from an address, returning `None` if it is
+    /// already at the limit. The connection is counted until the permit is
+    /// dropped.
+    pub fn acquire(&self, ip: IpAddr) -> Option<IpPermit> {
+        // Only addresses with a connection are in the map, so a refused one
+        // must not leave an entry behind.
+        let mut count = match self.counts.entry(ip) {
+            Entry::Occupied(entry) if self.limit.is_some_and(|limit| *entry.get() >= limit) => {
+                return None;
+            }
+            Entry::Vacant(_) if self.limit == Some(0) => return None,
+            entry => entry.or_insert(0),
+        };
+        *count += 1;
+        Some(IpPermit {
+            ip,
+            counts: Arc::clone(&self.counts),
+        })
+    }
+}
+
+/// A connection counted by an [`IpLimiter`].
+#[derive(Debug)]
+pub struct IpPermit {
+    ip: IpAddr,
+    counts: Arc<DashMap<IpAddr, usize>>,
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        self.counts.remove_if_mut(&self.ip, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+/// Number of addresses tracked by a [`RateLimiter`] before idle ones are
+/// pruned.
+const RATE_LIMITER_PRUNE_LEN: usize = 10_000;
+
+/// Limits the rate of requests from each IP address, with a token bucket per
+/// address, allowing bursts of up to one second.
+///
+/// IPv6 addresses are counted by their /64 network, since a single client
+/// usually controls all of one.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    rate: Option<u64>,
+    buckets: Arc<DashMap<IpAddr, TokenBucket>>,
+    /// Number of tracked addresses at which idle ones are next pruned.
+    prune_len: Arc<AtomicUsize>,
+}
+
+impl RateLimiter {
+    /// Construct a limiter allowing `rate` requests per second from each
+    /// address, or any number if not provided.
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate,
+            buckets: Default::default(),
+            prune_len: Arc::new(AtomicUsize::new(RATE_LIMITER_PRUNE_LEN)),
+        }
+    }
+
+    /// Count a request from an address, returning `false` if it is over the
+    /// limit.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let Some(rate) = self.rate else {
+            return true;
+        };
+        self.prune();
+        let rate = rate as f64;
+        let mut bucket = self
+            .buckets
+            .entry(rate_limit_key(ip))
+            .or_insert_with(|| TokenBucket::new(rate, rate));
+        bucket.try_take(1.0)
+    }
+
+    /// Remove idle addresses once there are too many. Pruning happens again
+    /// only after the number of addresses doubles, so its cost is spread over
+    /// the requests from new addresses.
+    fn prune(&self) {
+        let prune_len = self.prune_len.load(Ordering::Relaxed);
+        if self.buckets.len() < prune_len {
+            return;
+        }
+        // Only one caller prunes at a time.
+        let pruning = self.prune_len.compare_exchange(
+            prune_len,
+            usize::MAX,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+        if pruning.is_ok() {
+            self.buckets.retain(|_, bucket| !bucket.is_full());
+            let len = (2 * self.buckets.len()).max(RATE_LIMITER_PRUNE_LEN);
+            self.prune_len.store(len, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns the address that requests are counted under: the /64 network of an
+/// IPv6 address, or the IPv4 address that it maps.
+fn rate_limit_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+        },
+    }
+}
+
+/// Header that carries the ID of a request, from a client or to a response.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Identifier of an HTTP, gRPC, or WebSocket request, used to correlate logs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Use an ID provided by the client or a proxy if it is well-formed, or
+    /// generate a new one.
+    pub fn new(provided: Option<&str>) -> Self {
+        match provided {
+            Some(id)
+                if !id.is_empty()
+                    && id.len() <= 64
+                    && id
+                        .bytes()
+                        .all(|c| c.is_ascii_alphanumeric() || b"-_.".contains(&c)) =>
+            {
+                Self(id.into())
+            }
+            _ => Self(sshx_core::rand_alphanumeric(16)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IpLimiter;
+
+    #[test]
+    fn ip_limiter_forgets_addresses() {
+        let ip = "10.0.0.1".parse().unwrap();
+        let limiter = IpLimiter::new(Some(1));
+        let permit = limiter.acquire(ip).unwrap();
+        assert!(limiter.acquire(ip).is_none());
+        assert_eq!(limiter.counts.len(), 1);
+        drop(permit);
+        assert!(limiter.counts.is_empty());
+
+        let limiter = IpLimiter::new(Some(0));
+        assert!(limiter.acquire(ip).is_none());
+        assert!(limiter.counts.is_empty());
+    }
+}
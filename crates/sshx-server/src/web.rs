@@ -1,34 +1,305 @@
 //! HTTP and WebSocket handlers for the sshx web interface.
 
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 
-use axum::routing::{get, get_service};
+use anyhow::Result;
+use axum::body::{Body, StreamBody};
+use axum::extract::connect_info::Connected;
+use axum::extract::{FromRequestParts, OriginalUri, State};
+use axum::http::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, CONTENT_TYPE, HOST,
+    STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS,
+};
+use axum::http::{Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
 use axum::Router;
-use tower_http::services::{ServeDir, ServeFile};
+use hyper::server::conn::AddrStream;
+use tokio_stream::StreamExt;
+use tower_http::compression::predicate::{And, DefaultPredicate, NotForContentType, Predicate};
+use tower_http::compression::CompressionLayer;
+use tracing::error;
 
+use crate::report::ErrorSource;
 use crate::ServerState;
 
+mod admin;
+mod auth;
+mod dev;
+#[cfg(feature = "embed")]
+mod embed;
+mod events;
+mod links;
+mod output;
 pub mod protocol;
 mod socket;
+mod transcript;
+#[cfg(feature = "webtransport")]
+pub(crate) mod webtransport;
+
+pub use dev::{DEV_KEY, DEV_SESSION};
+
+/// Default Content-Security-Policy, compatible with the SvelteKit frontend.
+///
+/// SvelteKit bootstraps with an inline script, and encryption runs in a
+/// WebAssembly module built from the `sshx-crypto` crate.
+pub const DEFAULT_CSP: &str = "default-src 'self'; \
+    script-src 'self' 'unsafe-inline' 'wasm-unsafe-eval'; \
+    style-src 'self' 'unsafe-inline'; img-src 'self' data: blob:; font-src 'self' data:; \
+    media-src 'self' https://sshx.s3.amazonaws.com; connect-src 'self'; \
+    worker-src 'self' blob:; object-src 'none'; base-uri 'self'; frame-ancestors 'none'";
+
+/// Set security headers on a web response, unless the handler already did.
+///
+/// Strict-Transport-Security is only sent if the request was made over HTTPS,
+/// since it would otherwise lock browsers out of a server without TLS.
+pub fn set_security_headers(headers: &mut HeaderMap, csp: Option<&HeaderValue>, https: bool) {
+    let defaults = [
+        (X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        (HeaderName::from_static("x-frame-options"), "DENY"),
+    ];
+    for (name, value) in defaults {
+        headers
+            .entry(name)
+            .or_insert(HeaderValue::from_static(value));
+    }
+    if https {
+        headers
+            .entry(STRICT_TRANSPORT_SECURITY)
+            .or_insert(HeaderValue::from_static("max-age=63072000"));
+    }
+    if let Some(csp) = csp {
+        headers
+            .entry(CONTENT_SECURITY_POLICY)
+            .or_insert_with(|| csp.clone());
+    }
+}
+
+/// Address of the peer of a connection, which handlers extract from each of
+/// its requests with [`ConnectInfo`](axum::extract::ConnectInfo).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerAddr {
+    /// A peer connected over TCP, from this address.
+    Tcp(SocketAddr),
+    /// A peer on a Unix domain socket, which has no address of its own.
+    ///
+    /// Access to the socket is controlled by the permissions of its file
+    /// instead, so these peers are exempt from IP access rules and per-IP
+    /// limits, which would otherwise put every local peer in one bucket.
+    Unix,
+}
+
+impl PeerAddr {
+    /// Returns the IP address of the peer, or `None` on a Unix domain socket.
+    pub fn ip(self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(addr) => Some(addr.ip()),
+            PeerAddr::Unix => None,
+        }
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => addr.fmt(f),
+            PeerAddr::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+impl Connected<&AddrStream> for PeerAddr {
+    fn connect_info(target: &AddrStream) -> Self {
+        PeerAddr::Tcp(target.remote_addr())
+    }
+}
 
 /// Returns the web application server, routed with Axum.
-pub fn app() -> Router<Arc<ServerState>> {
-    let root_spa = ServeFile::new("build/spa.html")
-        .precompressed_gzip()
-        .precompressed_br();
+///
+/// Every route is nested under the base path, if it is not empty. This can be
+/// mounted in another Axum app once given the state of a [`Server`], which
+/// should have its background tasks started. The app must be served with
+/// [`Router::into_make_service_with_connect_info`] for a [`PeerAddr`], since
+/// handlers limit connections by the address of the peer.
+///
+/// [`Server`]: crate::Server
+pub fn app(base_path: &str) -> Router<Arc<ServerState>> {
+    let router = Router::new()
+        .nest("/api", backend())
+        .route("/metrics", get(get_metrics).layer(compression()));
+
+    // Serves static SvelteKit build files, embedded in the binary.
+    #[cfg(feature = "embed")]
+    let router = router.fallback(
+        |State(state): State<Arc<ServerState>>, req: Request<Body>| async move {
+            let (parts, _) = req.into_parts();
+            if let Some(redirect) = auth::require_login(&state, &parts) {
+                return redirect;
+            }
+            embed::serve_asset(parts.uri, parts.headers).await
+        },
+    );
+
+    // Serves static SvelteKit build files from the working directory.
+    #[cfg(not(feature = "embed"))]
+    let router = {
+        use tower::ServiceExt;
+        use tower_http::services::{ServeDir, ServeFile};
+
+        let root_spa = ServeFile::new("build/spa.html")
+            .precompressed_gzip()
+            .precompressed_br();
+        let static_files = ServeDir::new("build")
+            .precompressed_gzip()
+            .precompressed_br()
+            .fallback(root_spa);
+        router.fallback(
+            |State(state): State<Arc<ServerState>>, req: Request<Body>| async move {
+                let (parts, body) = req.into_parts();
+                if let Some(redirect) = auth::require_login(&state, &parts) {
+                    return redirect;
+                }
+                let req = Request::from_parts(parts, body);
+                match static_files.oneshot(req).await {
+                    Ok(resp) => resp.into_response(),
+                    Err(err) => match err {},
+                }
+            },
+        )
+    };
 
-    // Serves static SvelteKit build files.
-    let static_files = ServeDir::new("build")
-        .precompressed_gzip()
-        .precompressed_br()
-        .fallback(root_spa);
+    if base_path.is_empty() {
+        router
+    } else {
+        Router::new().nest(base_path, router)
+    }
+}
+
+/// Check that the static files of the web app can be served.
+pub fn check_assets() -> Result<()> {
+    // Embedded files are always present, since the build requires them.
+    #[cfg(not(feature = "embed"))]
+    if !std::path::Path::new("build/spa.html").is_file() {
+        anyhow::bail!(
+            "web app not found at build/spa.html, run `npm run build` in this directory or build \
+             the server with `--features embed`"
+        );
+    }
+    Ok(())
+}
 
+/// Returns a web server that only exposes Prometheus metrics.
+pub fn metrics_app() -> Router<Arc<ServerState>> {
     Router::new()
-        .nest("/api", backend())
-        .fallback_service(get_service(static_files))
+        .route("/metrics", get(serve_metrics))
+        .layer(compression())
 }
 
 /// Routes for the backend web API server.
+///
+/// Responses are compressed if the client accepts it.
 fn backend() -> Router<Arc<ServerState>> {
-    Router::new().route("/s/:name", get(socket::get_session_ws))
+    let router = Router::new()
+        .route("/s/:name", get(socket::get_session_ws))
+        .route("/s/:name/events", get(events::get_session_events))
+        .route("/s/:name/events/:id", post(events::post_session_event))
+        .route("/s/:name/links", post(links::mint_link))
+        .route("/s/:name/transcript", get(transcript::get_transcript))
+        .route("/s/:name/shells/:id/output", get(output::get_output))
+        .route("/mesh/ping", get(|| async { StatusCode::NO_CONTENT }))
+        .nest("/admin", admin::routes())
+        .nest("/auth", auth::routes());
+
+    #[cfg(feature = "webtransport")]
+    let router = router.route("/webtransport", get(webtransport::get_info));
+
+    router.layer(compression())
+}
+
+/// Compress dynamic responses, except for event streams that must not be
+/// buffered.
+fn compression() -> CompressionLayer<And<DefaultPredicate, NotForContentType>> {
+    let predicate = DefaultPredicate::new().and(NotForContentType::const_new("text/event-stream"));
+    CompressionLayer::new().compress_when(predicate)
+}
+
+/// Serve metrics from the main app, unless they are on a separate port.
+///
+/// Metrics reveal activity on the server, so they need the admin token unless
+/// they were made public.
+async fn get_metrics(State(state): State<Arc<ServerState>>, req: Request<Body>) -> Response {
+    if state.separate_metrics() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if !state.public_metrics() {
+        let (mut parts, _) = req.into_parts();
+        if let Err(status) = admin::Admin::from_request_parts(&mut parts, &state).await {
+            return status.into_response();
+        }
+    }
+    serve_metrics(State(state)).await
+}
+
+/// Serve the current metrics in Prometheus text format.
+async fn serve_metrics(State(state): State<Arc<ServerState>>) -> Response {
+    match state.encode_metrics() {
+        Ok(text) => ([(CONTENT_TYPE, "text/plain; version=0.0.4")], text).into_response(),
+        Err(err) => {
+            error!(?err, "failed to encode metrics");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Returns whether a request was made over HTTPS to a proxy in front of the
+/// server, from the `X-Forwarded-Proto` header.
+pub fn forwarded_https(headers: &HeaderMap) -> bool {
+    let proto = headers.get("x-forwarded-proto");
+    let proto = proto
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let first = proto.split(',').next().unwrap_or_default();
+    first.trim().eq_ignore_ascii_case("https")
+}
+
+/// Forward a request for a session to the server in the mesh that hosts it.
+///
+/// The response body is streamed back, and `guard` is held until it ends, so
+/// a connection permit keeps counting for the lifetime of a proxied stream.
+async fn proxy_request(
+    state: &ServerState,
+    host: &str,
+    name: &str,
+    mut req: Request<Body>,
+    guard: impl Send + 'static,
+) -> Response {
+    // Nested routers see a path without their prefix, so use the original.
+    let uri = (req.extensions().get::<OriginalUri>()).map_or(req.uri(), |uri| &uri.0);
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let uri = match format!("http://{host}{path}").parse() {
+        Ok(uri) => uri,
+        Err(err) => {
+            error!(?err, "invalid proxy target");
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+    *req.uri_mut() = uri;
+    req.headers_mut().remove(HOST);
+    match hyper::Client::new().request(req).await {
+        Ok(resp) => resp.map(|body| {
+            let body = body.map(move |chunk| {
+                let _guard = &guard;
+                chunk
+            });
+            axum::body::boxed(StreamBody::new(body))
+        }),
+        Err(err) => {
+            error!(?err, "failed to proxy request");
+            let err = anyhow::Error::new(err);
+            state.errors().report(ErrorSource::Proxy, &err, Some(name));
+            (StatusCode::BAD_GATEWAY, format!("proxy request: {err}")).into_response()
+        }
+    }
 }
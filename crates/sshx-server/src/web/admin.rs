@@ -0,0 +1,318 @@
+//! Authenticated REST API for server operators.
+
+use std::sync::Arc;
+
+use axum::extract::{FromRequestParts, Path, State};
+use axum::http::{header::AUTHORIZATION, request::Parts, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{async_trait, Json, Router};
+use serde::{Deserialize, Serialize};
+use sshx_core::proto::SequenceNumbers;
+use sshx_core::{Sid, Uid};
+use tracing::{error, info};
+
+use crate::audit::{AuditEvent, Peer};
+use crate::report::ErrorSource;
+use crate::session::{Bandwidth, Session};
+use crate::state::LeakStats;
+use crate::web::protocol::{WsUser, WsWinsize};
+use crate::ServerState;
+
+/// Routes for the admin API, nested under `/api/admin`.
+pub fn routes() -> Router<Arc<ServerState>> {
+    Router::new()
+        .route("/stats", get(get_stats))
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/:name", get(get_session).delete(close_session))
+        .route("/sessions/:name/notice", post(session_notice))
+        .route(
+            "/sessions/:name/pin",
+            put(pin_session).delete(unpin_session),
+        )
+        .route("/notice", post(broadcast_notice))
+        .route("/mesh", get(get_mesh))
+        .route("/tls/reload", post(reload_tls))
+        .route("/debug/leaks", get(get_leaks))
+}
+
+/// Extractor that rejects requests without a valid admin bearer token.
+///
+/// If no admin token is configured, the API is disabled and every request
+/// receives a 404 response. Holds the remote client of the operator.
+pub struct Admin(Peer);
+
+impl Admin {
+    /// Record an action taken by this operator in the audit log.
+    fn audit(&self, state: &ServerState, action: &str, session: Option<&str>) {
+        let event = AuditEvent::AdminAction {
+            action: action.into(),
+            session: session.map(String::from),
+        };
+        state.audit().record(&self.0, event);
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<ServerState>> for Admin {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<ServerState>,
+    ) -> Result<Self, Self::Rejection> {
+        if !state.admin_enabled() {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        let peer = parts.extensions.get::<Peer>().cloned().unwrap_or_default();
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if state.check_admin_token(token) => Ok(Admin(peer)),
+            _ => {
+                state.audit().record(&peer, AuditEvent::AdminAuthFailed);
+                Err(StatusCode::UNAUTHORIZED)
+            }
+        }
+    }
+}
+
+/// Aggregate statistics about this server.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Stats {
+    host: Option<String>,
+    uptime_secs: u64,
+    sessions: usize,
+    users: usize,
+    ws_connections: i64,
+}
+
+/// Summary of a session, as returned when listing sessions.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SessionSummary {
+    name: String,
+    users: usize,
+    shells: usize,
+    idle_secs: u64,
+    has_write_password: bool,
+    pinned: bool,
+    client_bandwidth: Bandwidth,
+}
+
+/// Detailed information about a single session.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SessionDetail {
+    #[serde(flatten)]
+    summary: SessionSummary,
+    user_list: Vec<(Uid, WsUser)>,
+    shell_list: Vec<(Sid, WsWinsize)>,
+    sequence_numbers: SequenceNumbers,
+    throughput: Vec<(Sid, u64)>,
+    user_bandwidth: Vec<(Uid, Bandwidth)>,
+}
+
+/// Health of the mesh, as seen from this server.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MeshHealth {
+    host: Option<String>,
+    peers: Vec<MeshPeer>,
+}
+
+/// Latest results of probing another server in the mesh.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MeshPeer {
+    host: String,
+    latency_ms: Option<f64>,
+    last_ok_secs: Option<u64>,
+    failures: u32,
+    error: Option<String>,
+}
+
+/// Request body for sending a notice to users.
+#[derive(Deserialize, Debug)]
+struct Notice {
+    message: String,
+}
+
+fn summarize(name: String, session: &Session) -> SessionSummary {
+    SessionSummary {
+        name,
+        users: session.user_count(),
+        shells: session.list_shells().len(),
+        idle_secs: session.last_accessed().elapsed().as_secs(),
+        has_write_password: session.metadata().write_password_hash.is_some(),
+        pinned: session.pinned(),
+        client_bandwidth: session.client_bandwidth(),
+    }
+}
+
+async fn get_stats(_: Admin, State(state): State<Arc<ServerState>>) -> Json<Stats> {
+    let sessions = state.sessions();
+    Json(Stats {
+        host: state.host(),
+        uptime_secs: state.uptime().as_secs(),
+        sessions: sessions.len(),
+        users: sessions.iter().map(|(_, s)| s.user_count()).sum(),
+        ws_connections: state.metrics().ws_connections.get(),
+    })
+}
+
+async fn get_mesh(_: Admin, State(state): State<Arc<ServerState>>) -> Json<MeshHealth> {
+    let peers = state
+        .mesh_peers()
+        .into_iter()
+        .map(|(host, health)| MeshPeer {
+            host,
+            latency_ms: health.latency.map(|latency| latency.as_secs_f64() * 1000.0),
+            last_ok_secs: health.last_ok.map(|time| time.elapsed().as_secs()),
+            failures: health.failures,
+            error: health.error,
+        });
+    Json(MeshHealth {
+        host: state.host(),
+        peers: peers.collect(),
+    })
+}
+
+async fn get_leaks(_: Admin, State(state): State<Arc<ServerState>>) -> Json<LeakStats> {
+    Json(state.leak_stats())
+}
+
+async fn list_sessions(
+    _: Admin,
+    State(state): State<Arc<ServerState>>,
+) -> Json<Vec<SessionSummary>> {
+    let mut sessions: Vec<_> = state
+        .sessions()
+        .into_iter()
+        .map(|(name, session)| summarize(name, &session))
+        .collect();
+    sessions.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(sessions)
+}
+
+async fn get_session(
+    _: Admin,
+    Path(name): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> Response {
+    let Some(session) = state.lookup(&name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    Json(SessionDetail {
+        user_list: session.list_users(),
+        shell_list: session.list_shells(),
+        sequence_numbers: session.sequence_numbers(),
+        throughput: session.throughput(),
+        user_bandwidth: session.user_bandwidth(),
+        summary: summarize(name, &session),
+    })
+    .into_response()
+}
+
+async fn close_session(
+    admin: Admin,
+    Path(name): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> StatusCode {
+    if state.lookup(&name).is_none() {
+        return StatusCode::NOT_FOUND;
+    }
+    admin.audit(&state, "close_session", Some(&name));
+    match state.close_session(&name, "admin").await {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(err) => {
+            error!(?err, "failed to close session {name}");
+            state
+                .errors()
+                .report(ErrorSource::Session, &err, Some(&name));
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+async fn session_notice(
+    admin: Admin,
+    Path(name): Path<String>,
+    State(state): State<Arc<ServerState>>,
+    Json(notice): Json<Notice>,
+) -> StatusCode {
+    match state.lookup(&name) {
+        Some(session) => {
+            admin.audit(&state, "session_notice", Some(&name));
+            session.send_notice(&notice.message);
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn pin_session(
+    admin: Admin,
+    Path(name): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> StatusCode {
+    set_pinned(admin, &name, &state, true)
+}
+
+async fn unpin_session(
+    admin: Admin,
+    Path(name): Path<String>,
+    State(state): State<Arc<ServerState>>,
+) -> StatusCode {
+    set_pinned(admin, &name, &state, false)
+}
+
+/// Pin or unpin a session, so that it is kept while its client is away.
+fn set_pinned(admin: Admin, name: &str, state: &ServerState, pinned: bool) -> StatusCode {
+    match state.lookup(name) {
+        Some(session) => {
+            let action = if pinned {
+                "pin_session"
+            } else {
+                "unpin_session"
+            };
+            admin.audit(state, action, Some(name));
+            session.set_pinned(pinned);
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn broadcast_notice(
+    admin: Admin,
+    State(state): State<Arc<ServerState>>,
+    Json(notice): Json<Notice>,
+) -> StatusCode {
+    admin.audit(&state, "broadcast_notice", None);
+    for (_, session) in state.sessions() {
+        session.send_notice(&notice.message);
+    }
+    StatusCode::NO_CONTENT
+}
+
+async fn reload_tls(admin: Admin, State(state): State<Arc<ServerState>>) -> Response {
+    let Some(tls) = state.tls() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    admin.audit(&state, "reload_tls", None);
+    match tls.reload() {
+        Ok(()) => {
+            info!("reloaded TLS certificates from admin API");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(err) => {
+            error!(?err, "failed to reload TLS certificates");
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")).into_response()
+        }
+    }
+}
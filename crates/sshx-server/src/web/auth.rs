@@ -0,0 +1,246 @@
+//! Login routes for web viewers, when OpenID Connect is enabled.
+
+use std::sync::Arc;
+
+use axum::extract::{FromRequestParts, Query, State};
+use axum::http::header::{COOKIE, SET_COOKIE};
+use axum::http::{request::Parts, HeaderMap, StatusCode};
+use axum::response::{AppendHeaders, IntoResponse, Redirect, Response};
+use axum::routing::get;
+use axum::{async_trait, Router};
+use serde::{Deserialize, Serialize};
+use sshx_core::rand_alphanumeric;
+use tracing::warn;
+
+use crate::audit::{AuditEvent, Peer};
+use crate::oidc::{unix_time, Identity};
+use crate::ServerState;
+
+/// Cookie that holds the identity of a logged-in viewer.
+const AUTH_COOKIE: &str = "sshx_auth";
+
+/// Cookie that holds the state of a login in progress.
+const FLOW_COOKIE: &str = "sshx_oidc";
+
+/// How long a viewer stays logged in, in seconds.
+const LOGIN_EXPIRY: u64 = 12 * 60 * 60;
+
+/// How long a viewer has to complete a login at the provider, in seconds.
+const FLOW_EXPIRY: u64 = 10 * 60;
+
+/// Routes for logging in, nested under `/api/auth`.
+pub fn routes() -> Router<Arc<ServerState>> {
+    Router::new()
+        .route("/login", get(login))
+        .route("/callback", get(callback))
+        .route("/logout", get(logout))
+}
+
+/// Contents of the signed login cookie.
+#[derive(Serialize, Deserialize)]
+struct LoginCookie {
+    #[serde(flatten)]
+    identity: Identity,
+    exp: u64,
+}
+
+/// Contents of the signed cookie for a login in progress.
+#[derive(Serialize, Deserialize)]
+struct FlowCookie {
+    state: String,
+    nonce: String,
+    next: String,
+    exp: u64,
+}
+
+/// Extractor for the identity of a web viewer, rejecting the request if they
+/// must log in first.
+///
+/// The identity is `None` if OpenID Connect is disabled.
+pub struct Viewer(pub Option<Identity>);
+
+#[async_trait]
+impl FromRequestParts<Arc<ServerState>> for Viewer {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<ServerState>,
+    ) -> Result<Self, Self::Rejection> {
+        if state.oidc().is_none() {
+            return Ok(Viewer(None));
+        }
+        match identity(state, &parts.headers) {
+            Some(identity) => Ok(Viewer(Some(identity))),
+            None => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+/// Redirect to the login page if the viewer of a page must log in first.
+pub fn require_login(state: &ServerState, parts: &Parts) -> Option<Response> {
+    if state.oidc().is_none() || identity(state, &parts.headers).is_some() {
+        return None;
+    }
+    let next = parts
+        .uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let base = state.base_path();
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("next", &format!("{base}{next}"))
+        .finish();
+    Some(Redirect::to(&format!("{base}/api/auth/login?{query}")).into_response())
+}
+
+/// Returns the identity of a logged-in viewer from their cookies.
+fn identity(state: &ServerState, headers: &HeaderMap) -> Option<Identity> {
+    let payload = state.verify_token("login", get_cookie(headers, AUTH_COOKIE)?)?;
+    let cookie: LoginCookie = serde_json::from_str(&payload).ok()?;
+    (cookie.exp > unix_time()).then_some(cookie.identity)
+}
+
+/// Find the value of a cookie in the request headers.
+fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then_some(value)
+        })
+}
+
+/// Build a `Set-Cookie` header value for this server.
+fn set_cookie(state: &ServerState, name: &str, value: &str, max_age: u64) -> String {
+    let secure = match state.oidc() {
+        Some(oidc) if oidc.secure_cookies() => "; Secure",
+        _ => "",
+    };
+    let path = state.base_path();
+    format!("{name}={value}; Path={path}/; Max-Age={max_age}; HttpOnly; SameSite=Lax{secure}")
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    next: Option<String>,
+}
+
+async fn login(State(state): State<Arc<ServerState>>, Query(query): Query<LoginQuery>) -> Response {
+    let Some(oidc) = state.oidc() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // Only redirect to local paths after login, to avoid an open redirect.
+    let next = match query.next {
+        Some(next) if is_local_path(&next) => next,
+        _ => format!("{}/", state.base_path()),
+    };
+    let flow = FlowCookie {
+        state: rand_alphanumeric(22),
+        nonce: rand_alphanumeric(22),
+        next,
+        exp: unix_time() + FLOW_EXPIRY,
+    };
+    let url = match oidc.authorize_url(&flow.state, &flow.nonce).await {
+        Ok(url) => url,
+        Err(err) => {
+            warn!(?err, "failed to start OpenID Connect login");
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+    let token = state.sign_token("oidc-flow", &serde_json::to_string(&flow).unwrap());
+    let cookie = set_cookie(&state, FLOW_COOKIE, &token, FLOW_EXPIRY);
+    ([(SET_COOKIE, cookie)], Redirect::to(&url)).into_response()
+}
+
+/// Returns whether a path to redirect to stays on this server.
+///
+/// Browsers read backslashes as slashes, so `/\evil.example` is another host.
+/// Encoded backslashes and control characters are refused too, in case they
+/// are decoded or stripped on the way.
+fn is_local_path(next: &str) -> bool {
+    next.starts_with('/')
+        && !next.starts_with("//")
+        && !next.contains('\\')
+        && !next.to_ascii_lowercase().contains("%5c")
+        && !next.chars().any(char::is_control)
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: String,
+    state: String,
+}
+
+async fn callback(
+    State(state): State<Arc<ServerState>>,
+    peer: Peer,
+    headers: HeaderMap,
+    Query(query): Query<CallbackQuery>,
+) -> Response {
+    let Some(oidc) = state.oidc() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let flow = get_cookie(&headers, FLOW_COOKIE)
+        .and_then(|token| state.verify_token("oidc-flow", token))
+        .and_then(|payload| serde_json::from_str::<FlowCookie>(&payload).ok())
+        .filter(|flow| flow.exp > unix_time() && flow.state == query.state);
+    let Some(flow) = flow else {
+        return (StatusCode::BAD_REQUEST, "invalid or expired login attempt").into_response();
+    };
+
+    let identity = match oidc.exchange_code(&query.code, &flow.nonce).await {
+        Ok(identity) => identity,
+        Err(err) => {
+            warn!(?err, "failed to complete OpenID Connect login");
+            return (StatusCode::UNAUTHORIZED, "login failed").into_response();
+        }
+    };
+    let event = AuditEvent::Login {
+        subject: identity.sub.clone(),
+        email: identity.email.clone(),
+    };
+    state.audit().record(&peer, event);
+
+    let login = LoginCookie {
+        identity,
+        exp: unix_time() + LOGIN_EXPIRY,
+    };
+    let token = state.sign_token("login", &serde_json::to_string(&login).unwrap());
+    let cookies = [
+        (
+            SET_COOKIE,
+            set_cookie(&state, AUTH_COOKIE, &token, LOGIN_EXPIRY),
+        ),
+        (SET_COOKIE, set_cookie(&state, FLOW_COOKIE, "", 0)),
+    ];
+    (AppendHeaders(cookies), Redirect::to(&flow.next)).into_response()
+}
+
+async fn logout(State(state): State<Arc<ServerState>>) -> Response {
+    let cookie = set_cookie(&state, AUTH_COOKIE, "", 0);
+    let home = format!("{}/", state.base_path());
+    ([(SET_COOKIE, cookie)], Redirect::to(&home)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_local_path;
+
+    #[test]
+    fn local_paths() {
+        assert!(is_local_path("/"));
+        assert!(is_local_path("/s/abc#key"));
+        assert!(!is_local_path("https://evil.example"));
+        assert!(!is_local_path("//evil.example"));
+        assert!(!is_local_path("/\\evil.example"));
+        assert!(!is_local_path("/%5Cevil.example"));
+        assert!(!is_local_path("/%5cevil.example"));
+        assert!(!is_local_path("/\t/evil.example"));
+    }
+}
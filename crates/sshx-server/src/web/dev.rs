@@ -0,0 +1,264 @@
+//! Scripted fake session for developing the web app without a client.
+//!
+//! When [`ServerOptions::dev_replay`] is set, connecting to the session named
+//! [`DEV_SESSION`] plays the same sequence of [`WsServer`] messages every time,
+//! encrypted with [`DEV_KEY`], instead of looking up a real session. Terminal
+//! input is echoed back and new shells can be created, so that the frontend and
+//! headless viewer can be exercised end to end.
+//!
+//! [`ServerOptions::dev_replay`]: crate::ServerOptions::dev_replay
+
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::Bytes;
+use sshx_core::{Sid, Uid};
+use sshx_crypto::Encrypt;
+use tokio::task;
+use tokio::time::{self, Instant};
+
+use super::protocol::{
+    close_codes, WsCapabilities, WsClient, WsServer, WsUser, WsWinsize, PROTOCOL_VERSION,
+};
+use super::socket::{Transport, MAX_MESSAGE_SIZE};
+
+/// Name of the scripted session, which cannot collide with generated names.
+pub const DEV_SESSION: &str = "~replay";
+
+/// Encryption key of the scripted session, as in `/s/~replay#dev`.
+pub const DEV_KEY: &str = "dev";
+
+/// ID of the user connected to the scripted session.
+const VIEWER: Uid = Uid(1);
+
+/// ID of the scripted user, who chats with the viewer.
+const BOT: Uid = Uid(2);
+
+/// Shell prompt printed by the script, and after each echoed line of input.
+const PROMPT: &str = "\x1b[1;32mdev@sshx\x1b[0m:\x1b[1;34m~\x1b[0m$ ";
+
+/// Number of shells opened by the script, so that created shells get new IDs.
+const SCRIPT_SHELLS: u32 = 2;
+
+/// One step of the script.
+enum Step {
+    /// Open a shell with this size.
+    Open(Sid, WsWinsize),
+    /// Print output to a shell.
+    Output(Sid, &'static str),
+    /// Send a chat message from the scripted user.
+    Chat(&'static str),
+    /// Close a shell.
+    Close(Sid),
+}
+
+/// Steps of the script, each run this many milliseconds after the last one.
+const SCRIPT: &[(u64, Step)] = &[
+    (0, Step::Open(Sid(1), winsize(0, 0, 24, 80))),
+    (300, Step::Output(Sid(1), PROMPT)),
+    (700, Step::Output(Sid(1), "ls\r\n")),
+    (
+        200,
+        Step::Output(Sid(1), "Cargo.toml  README.md  crates  src\r\n"),
+    ),
+    (100, Step::Output(Sid(1), PROMPT)),
+    (800, Step::Chat("Hello from the replay script!")),
+    (500, Step::Open(Sid(2), winsize(720, 80, 12, 60))),
+    (
+        300,
+        Step::Output(Sid(2), "$ for i in 1 2 3; do echo $i; sleep 1; done\r\n"),
+    ),
+    (1000, Step::Output(Sid(2), "1\r\n")),
+    (1000, Step::Output(Sid(2), "2\r\n")),
+    (1000, Step::Output(Sid(2), "3\r\n$ exit\r\n")),
+    (500, Step::Close(Sid(2))),
+    (
+        500,
+        Step::Chat("That's the end of the script, try typing in the shell."),
+    ),
+];
+
+const fn winsize(x: i32, y: i32, rows: u16, cols: u16) -> WsWinsize {
+    WsWinsize { x, y, rows, cols }
+}
+
+/// Shells of the scripted session, as seen by one connection.
+struct Replay {
+    encrypt: Encrypt,
+    shells: BTreeMap<Sid, WsWinsize>,
+    /// Encrypted output chunks of each shell, with their offsets in bytes.
+    output: BTreeMap<Sid, Vec<(u64, Bytes)>>,
+    subscribed: HashSet<Sid>,
+}
+
+/// Play the script over a connection until the client disconnects.
+pub(super) async fn replay(socket: &mut impl Transport) -> Result<()> {
+    // Deriving the key is slow, so it runs off the async runtime.
+    let encrypt = task::spawn_blocking(|| Encrypt::new(DEV_KEY)).await?;
+    let capabilities = WsCapabilities {
+        features: Vec::new(),
+        max_message_size: MAX_MESSAGE_SIZE as u32,
+    };
+    let hello = WsServer::Hello(VIEWER, DEV_SESSION.into(), PROTOCOL_VERSION, capabilities);
+    socket.send(hello).await?;
+    match socket.recv().await? {
+        Some(WsClient::Authenticate(zeros, _, _)) if *zeros == *encrypt.zeros() => (),
+        _ => {
+            socket.send(WsServer::InvalidAuth()).await?;
+            socket
+                .close_with(close_codes::UNAUTHORIZED, "invalid authentication")
+                .await?;
+            return Ok(());
+        }
+    }
+    let users = vec![(VIEWER, user("you", 0)), (BOT, user("replay", 1))];
+    socket.send(WsServer::Users(users)).await?;
+    socket.send(WsServer::Shells(Vec::new())).await?;
+
+    let mut replay = Replay {
+        encrypt,
+        shells: BTreeMap::new(),
+        output: BTreeMap::new(),
+        subscribed: HashSet::new(),
+    };
+    let mut steps = SCRIPT.iter().peekable();
+    let mut deadline = Instant::now();
+    loop {
+        let next = steps
+            .peek()
+            .map(|(delay, _)| deadline + Duration::from_millis(*delay));
+        tokio::select! {
+            _ = time::sleep_until(next.unwrap_or(deadline)), if next.is_some() => {
+                if let Some((_, step)) = steps.next() {
+                    replay.step(socket, step).await?;
+                }
+                deadline = next.unwrap_or(deadline);
+            }
+            msg = socket.recv() => match msg? {
+                Some(msg) => replay.handle(socket, msg).await?,
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+fn user(name: &str, color: u32) -> WsUser {
+    WsUser {
+        name: name.into(),
+        cursor: None,
+        focus: None,
+        can_write: true,
+        color,
+        avatar: None,
+        viewport: None,
+    }
+}
+
+impl Replay {
+    /// Run a step of the script.
+    async fn step(&mut self, socket: &mut impl Transport, step: &Step) -> Result<()> {
+        match *step {
+            Step::Open(id, winsize) => {
+                self.shells.insert(id, winsize);
+                self.send_shells(socket).await
+            }
+            Step::Output(id, text) => self.output(socket, id, text.as_bytes()).await,
+            Step::Chat(text) => {
+                let msg = WsServer::Hear(BOT, "replay".into(), text.into());
+                socket.send(msg).await
+            }
+            Step::Close(id) => {
+                self.shells.remove(&id);
+                self.send_shells(socket).await
+            }
+        }
+    }
+
+    /// Respond to a message from the client, like a real session would.
+    async fn handle(&mut self, socket: &mut impl Transport, msg: WsClient) -> Result<()> {
+        match msg {
+            WsClient::Create(x, y) => {
+                let ids = self.shells.keys().chain(self.output.keys());
+                let id = Sid(ids.map(|id| id.0).fold(SCRIPT_SHELLS, u32::max) + 1);
+                self.shells.insert(
+                    id,
+                    WsWinsize {
+                        x,
+                        y,
+                        ..Default::default()
+                    },
+                );
+                self.send_shells(socket).await?;
+                self.output(socket, id, PROMPT.as_bytes()).await?;
+            }
+            WsClient::Close(id) => {
+                self.shells.remove(&id);
+                self.send_shells(socket).await?;
+            }
+            WsClient::Move(id, Some(winsize)) => {
+                if let Some(shell) = self.shells.get_mut(&id) {
+                    *shell = winsize;
+                    self.send_shells(socket).await?;
+                }
+            }
+            WsClient::Data(id, data, offset) => {
+                let input = self.encrypt.segment(0x200000000, offset, &data);
+                let mut echo = Vec::new();
+                for byte in input {
+                    match byte {
+                        b'\r' => echo.extend_from_slice(format!("\r\n{PROMPT}").as_bytes()),
+                        0x7f => echo.extend_from_slice(b"\x08 \x08"),
+                        _ => echo.push(byte),
+                    }
+                }
+                self.output(socket, id, &echo).await?;
+            }
+            WsClient::Subscribe(id, chunknum, _) => {
+                self.subscribed.insert(id);
+                let chunks = self.output.get(&id).map_or(&[][..], |chunks| {
+                    chunks.get(chunknum as usize..).unwrap_or_default()
+                });
+                if let Some(&(seqnum, _)) = chunks.first() {
+                    let chunks = chunks.iter().map(|(_, chunk)| chunk.clone()).collect();
+                    socket.send(WsServer::Chunks(id, seqnum, chunks)).await?;
+                }
+            }
+            WsClient::Chat(text) => {
+                socket
+                    .send(WsServer::Hear(VIEWER, "you".into(), text))
+                    .await?
+            }
+            WsClient::Ping(ts) => socket.send(WsServer::Pong(ts)).await?,
+            _ => (), // Profiles, cursors, streams and notes are not simulated.
+        }
+        Ok(())
+    }
+
+    async fn send_shells(&self, socket: &mut impl Transport) -> Result<()> {
+        let shells = self.shells.iter().map(|(&id, &winsize)| (id, winsize));
+        socket.send(WsServer::Shells(shells.collect())).await
+    }
+
+    /// Append output to an open shell, sending it to the client if subscribed.
+    async fn output(&mut self, socket: &mut impl Transport, id: Sid, data: &[u8]) -> Result<()> {
+        if !self.shells.contains_key(&id) {
+            return Ok(());
+        }
+        let chunks = self.output.entry(id).or_default();
+        let offset = chunks
+            .last()
+            .map_or(0, |(offset, chunk)| offset + chunk.len() as u64);
+        let chunk = Bytes::from(
+            self.encrypt
+                .segment(0x100000000 | id.0 as u64, offset, data),
+        );
+        chunks.push((offset, chunk.clone()));
+        if self.subscribed.contains(&id) {
+            socket
+                .send(WsServer::Chunks(id, offset, vec![chunk]))
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+//! Serves the frontend build from static assets embedded in the binary.
+
+use axum::http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, VARY};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use include_dir::{include_dir, Dir, File};
+
+/// Files from the SvelteKit build, including precompressed variants.
+static BUILD: Dir<'static> = include_dir!("$CARGO_MANIFEST_DIR/../../build");
+
+/// Single-page app fallback, for routes that do not match a static file.
+const SPA_FALLBACK: &str = "spa.html";
+
+/// Serve a static file from the embedded build, like `ServeDir` would.
+pub async fn serve_asset(uri: Uri, headers: HeaderMap) -> Response {
+    let path = uri.path().trim_matches('/');
+    let path = if path.is_empty() {
+        "index.html".to_string()
+    } else if BUILD.get_file(path).is_none() && BUILD.get_dir(path).is_some() {
+        format!("{path}/index.html")
+    } else {
+        path.to_string()
+    };
+
+    let path = match BUILD.get_file(&path) {
+        Some(_) => path,
+        None => SPA_FALLBACK.to_string(),
+    };
+    let Some(file) = BUILD.get_file(&path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let mime = mime_guess::from_path(&path).first_or_octet_stream();
+    let mut resp_headers = HeaderMap::new();
+    resp_headers.insert(CONTENT_TYPE, HeaderValue::from_str(mime.as_ref()).unwrap());
+    resp_headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+
+    let contents = match precompressed(&path, &headers) {
+        Some((compressed, encoding)) => {
+            resp_headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+            compressed.contents()
+        }
+        None => file.contents(),
+    };
+
+    (resp_headers, contents).into_response()
+}
+
+/// Find a precompressed variant of a file that is accepted by the client.
+fn precompressed(
+    path: &str,
+    headers: &HeaderMap,
+) -> Option<(&'static File<'static>, &'static str)> {
+    for (encoding, ext) in [("br", "br"), ("gzip", "gz")] {
+        if accepts_encoding(headers, encoding) {
+            if let Some(file) = BUILD.get_file(format!("{path}.{ext}")) {
+                return Some((file, encoding));
+            }
+        }
+    }
+    None
+}
+
+/// Check if an encoding is listed in the request's `Accept-Encoding` header.
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let disabled =
+                parts.any(|param| matches!(param, "q=0" | "q=0.0" | "q=0.00" | "q=0.000"));
+            name.eq_ignore_ascii_case(encoding) && !disabled
+        })
+}
@@ -0,0 +1,176 @@
+//! Server-Sent Events fallback for clients that cannot use WebSockets.
+//!
+//! The event stream carries the same CBOR-encoded [`WsServer`] messages as the
+//! WebSocket, base64-encoded in the `data` field of each event. Its first event
+//! is named `connection` and holds the path that [`WsClient`] messages must be
+//! posted to, one per request body.
+//!
+//! Sessions hosted on another server in the mesh are proxied there, both the
+//! event stream and the messages posted to it.
+//!
+//! [`WsServer`]: crate::web::protocol::WsServer
+//! [`WsClient`]: crate::web::protocol::WsClient
+
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use axum::body::Body;
+use axum::extract::{ConnectInfo, OriginalUri, Path, Query, State};
+use axum::http::{HeaderMap, Request, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use bytes::Bytes;
+use sshx_core::rand_alphanumeric;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tracing::{field, info_span, warn, Instrument};
+
+use crate::audit::Peer;
+use crate::web::auth::Viewer;
+use crate::web::links::JoinGrant;
+use crate::web::protocol::{WsClient, WsServer};
+use crate::web::proxy_request;
+use crate::web::socket::{handle_socket, Client, Transport, WsParams, MAX_MESSAGE_SIZE};
+use crate::web::PeerAddr;
+use crate::ServerState;
+
+/// A connection that sends messages as Server-Sent Events, and receives them
+/// from POST requests.
+struct EventTransport {
+    events: mpsc::Sender<Event>,
+    input: mpsc::Receiver<Vec<u8>>,
+}
+
+#[axum::async_trait]
+impl Transport for EventTransport {
+    async fn send(&mut self, msg: WsServer) -> Result<()> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&msg, &mut buf)?;
+        let event = Event::default().data(BASE64_STANDARD.encode(buf));
+        self.events
+            .send(event)
+            .await
+            .map_err(|_| anyhow!("event stream was closed"))
+    }
+
+    async fn recv(&mut self) -> Result<Option<WsClient>> {
+        tokio::select! {
+            Some(msg) = self.input.recv() => Ok(Some(ciborium::de::from_reader(&*msg)?)),
+            _ = self.events.closed() => Ok(None),
+        }
+    }
+
+    async fn close_with(&mut self, code: u16, reason: &str) -> Result<()> {
+        let event = Event::default()
+            .event("close")
+            .data(format!("{code} {reason}"));
+        self.events
+            .send(event)
+            .await
+            .map_err(|_| anyhow!("event stream was closed"))
+    }
+}
+
+/// Stream messages from a session as Server-Sent Events.
+pub async fn get_session_events(
+    Path(name): Path<String>,
+    Query(params): Query<WsParams>,
+    Viewer(identity): Viewer,
+    ConnectInfo(addr): ConnectInfo<PeerAddr>,
+    peer: Peer,
+    State(state): State<Arc<ServerState>>,
+    req: Request<Body>,
+) -> Response {
+    // Peers on a Unix domain socket have no address, so they are not limited.
+    let permit = addr.ip().map(|ip| state.ws_limiter().acquire(ip));
+    if matches!(permit, Some(None)) {
+        let msg = "too many connections from this address";
+        return (StatusCode::TOO_MANY_REQUESTS, msg).into_response();
+    }
+    let session = match state.frontend_connect(&name).await {
+        Ok(Ok(session)) => session,
+        Ok(Err(Some(host))) => return proxy_request(&state, &host, &name, req, permit).await,
+        Ok(Err(None)) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            warn!(?err, "failed to connect to frontend session");
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let grant = match &params.grant {
+        Some(token) => match JoinGrant::verify(&state, &name, token) {
+            Some(grant) => Some(grant),
+            None => return (StatusCode::FORBIDDEN, "invalid or expired link").into_response(),
+        },
+        None => None,
+    };
+    let client = Client {
+        peer,
+        subject: identity.map(|id| id.sub),
+        grant,
+    };
+
+    let id = rand_alphanumeric(22);
+    let key = format!("{name}/{id}");
+    let (events_tx, events_rx) = mpsc::channel(64);
+    let (input_tx, input_rx) = mpsc::channel(16);
+    state.event_inputs().insert(key.clone(), input_tx);
+
+    let connection = Event::default()
+        .event("connection")
+        .data(format!("{}/api/s/{name}/events/{id}", state.base_path()));
+    events_tx.send(connection).await.ok();
+
+    let span = info_span!("sse", session = %name, user_id = field::Empty);
+    let errors = state.errors().clone();
+    let guard_name = Some(name.clone());
+    let task = async move {
+        let _permit = permit;
+        let mut transport = EventTransport {
+            events: events_tx,
+            input: input_rx,
+        };
+        if let Err(err) = handle_socket(&mut transport, &state, &name, client, session).await {
+            warn!(?err, "event stream exiting early");
+        }
+        state.event_inputs().remove(&key);
+    };
+    tokio::spawn(errors.guard(guard_name, task).instrument(span));
+
+    let stream = ReceiverStream::new(events_rx).map(Ok::<_, Infallible>);
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Receive a message from the client of a Server-Sent Events connection.
+pub async fn post_session_event(
+    Path((name, id)): Path<(String, String)>,
+    State(state): State<Arc<ServerState>>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if body.len() > MAX_MESSAGE_SIZE {
+        return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+    }
+    let Some(input) = state
+        .event_inputs()
+        .get(&format!("{name}/{id}"))
+        .map(|input| input.clone())
+    else {
+        // The event stream may be held by the server hosting the session.
+        if let Ok(Err(Some(host))) = state.frontend_connect(&name).await {
+            let mut req = Request::post(uri).body(Body::from(body)).unwrap();
+            *req.headers_mut() = headers;
+            return proxy_request(&state, &host, &name, req, ()).await;
+        }
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    match input.send(body.to_vec()).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
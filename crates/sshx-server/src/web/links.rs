@@ -0,0 +1,118 @@
+//! Signed, expiring join links that grant a role in a session.
+//!
+//! Users always need the encryption key to join. A link then limits what they
+//! can do, and never grants more than the write password:
+//!
+//! - With a read link, users are read-only, even with the write password.
+//! - With a write link, users can write if the session has no write password,
+//!   or if they also provide it.
+//! - Without a link, users can write with the write password or if the session
+//!   has none, like before. When the server requires join links, users without
+//!   one are refused unless they provide the write password.
+
+use std::sync::Arc;
+
+use axum::extract::{Path, State};
+use axum::http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+
+use crate::oidc::unix_time;
+use crate::ServerState;
+
+/// Longest lifetime of a join link, in seconds.
+const MAX_LINK_EXPIRY: u64 = 30 * 24 * 60 * 60;
+
+/// Permission granted by a signed join link, carried in the `grant` query
+/// parameter of the WebSocket upgrade.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JoinGrant {
+    /// Name of the session this link is for.
+    pub name: String,
+    /// Whether the link allows writing, for users who have the write password.
+    pub write: bool,
+    /// Unix timestamp in seconds when the link expires.
+    pub exp: u64,
+}
+
+impl JoinGrant {
+    /// Verify a signed grant for a session, returning `None` if it is invalid
+    /// or has expired.
+    pub fn verify(state: &ServerState, name: &str, token: &str) -> Option<Self> {
+        let payload = state.verify_token("join", token)?;
+        let grant: JoinGrant = serde_json::from_str(&payload).ok()?;
+        (grant.name == name && !grant.is_expired()).then_some(grant)
+    }
+
+    /// Returns whether this grant has expired.
+    pub fn is_expired(&self) -> bool {
+        self.exp <= unix_time()
+    }
+}
+
+/// Role that a join link grants.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+enum Role {
+    Read,
+    Write,
+}
+
+/// Request body for minting a join link.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MintRequest {
+    role: Role,
+    expires_in: u64,
+}
+
+/// Response with a newly minted join link.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MintResponse {
+    grant: String,
+    path: String,
+    expires_at: u64,
+}
+
+/// Mint a join link, authenticated by the session token from the Open() RPC.
+///
+/// The returned path must have the session's encryption key appended as the
+/// URL fragment, just like the original session URL.
+pub async fn mint_link(
+    Path(name): Path<String>,
+    State(state): State<Arc<ServerState>>,
+    headers: HeaderMap,
+    Json(req): Json<MintRequest>,
+) -> Response {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let nonce = match state.session_metadata(&name).await {
+        Ok(Some(metadata)) => metadata.token_nonce,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => return (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    };
+    match token {
+        Some(token) if state.check_session_token(&name, &nonce, token) => {}
+        _ => return StatusCode::UNAUTHORIZED.into_response(),
+    }
+    if req.expires_in == 0 || req.expires_in > MAX_LINK_EXPIRY {
+        return (StatusCode::BAD_REQUEST, "invalid link expiry").into_response();
+    }
+
+    let grant = JoinGrant {
+        name: name.clone(),
+        write: req.role == Role::Write,
+        exp: unix_time() + req.expires_in,
+    };
+    let token = state.sign_token("join", &serde_json::to_string(&grant).unwrap());
+    Json(MintResponse {
+        path: format!("{}/s/{name}?grant={token}", state.base_path()),
+        grant: token,
+        expires_at: grant.exp,
+    })
+    .into_response()
+}
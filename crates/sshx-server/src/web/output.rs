@@ -0,0 +1,84 @@
+//! Polling for the output of a shell over plain HTTP.
+//!
+//! This lets minimal clients, like scripts using curl or accessibility tools,
+//! follow a shell without WebSockets or CBOR. The output is still encrypted,
+//! and is decrypted by the client with the key at the returned offset.
+
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderName, Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use sshx_core::Sid;
+use tokio::time::{self, Duration};
+
+use crate::audit::Peer;
+use crate::web::auth::Viewer;
+use crate::web::transcript::connect_with_key;
+use crate::ServerState;
+
+/// Longest time that a request waits for new output.
+const MAX_WAIT: Duration = Duration::from_secs(30);
+
+/// Header with the byte offset of the first byte in the response.
+const OFFSET_HEADER: HeaderName = HeaderName::from_static("x-sshx-offset");
+
+/// Header with the byte offset to request the following output from.
+const NEXT_HEADER: HeaderName = HeaderName::from_static("x-sshx-next");
+
+/// Query parameters for polling the output of a shell.
+#[derive(Deserialize, Debug)]
+pub struct OutputParams {
+    /// Byte offset in the shell's output to start from.
+    #[serde(default)]
+    from: u64,
+    /// Seconds to wait for output past the offset, if there is none yet.
+    #[serde(default)]
+    wait: u64,
+    /// Signed join link, needed if the server requires one.
+    grant: Option<String>,
+}
+
+/// Return the encrypted output of a shell from a byte offset, as raw bytes.
+///
+/// The caller authenticates like for transcripts, with the encrypted zeros
+/// block as a bearer token. Output that was pruned is skipped, so the offset
+/// of the response is in the `x-sshx-offset` header, and the offset to poll
+/// from next is in the `x-sshx-next` header.
+pub async fn get_output(
+    Path((name, id)): Path<(String, u32)>,
+    Query(params): Query<OutputParams>,
+    State(state): State<Arc<ServerState>>,
+    _: Viewer,
+    peer: Peer,
+    req: Request<Body>,
+) -> Response {
+    let grant = params.grant.as_deref();
+    let session = match connect_with_key(&state, &name, &peer, req, grant).await {
+        Ok(session) => session,
+        Err(resp) => return resp,
+    };
+    let id = Sid(id);
+    let wait = Duration::from_secs(params.wait).min(MAX_WAIT);
+    time::timeout(wait, session.wait_for_data(id, params.from))
+        .await
+        .ok();
+
+    let Ok((offset, chunks)) = session.read_data(id, params.from) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let data = chunks.concat();
+    let next = offset + data.len() as u64;
+    (
+        [
+            (CONTENT_TYPE, "application/octet-stream".to_string()),
+            (OFFSET_HEADER, offset.to_string()),
+            (NEXT_HEADER, next.to_string()),
+        ],
+        data,
+    )
+        .into_response()
+}
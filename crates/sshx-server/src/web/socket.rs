@@ -1,159 +1,466 @@
 use std::collections::HashSet;
+use std::pin::pin;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use axum::extract::{
     ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
-    Path, State,
+    ConnectInfo, Path, Query, State,
 };
-use axum::response::IntoResponse;
+use axum::http::header::{COOKIE, SEC_WEBSOCKET_PROTOCOL};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
 use bytes::Bytes;
 use futures_util::SinkExt;
-use sshx_core::proto::{server_update::ServerMessage, NewShell, TerminalInput, TerminalSize};
+use serde::Deserialize;
+use sshx_core::proto::{
+    server_update::ServerMessage, NewShell, StreamData, StreamKind, TerminalInput, TerminalSize,
+};
 use sshx_core::Sid;
+use sshx_crypto::hash_verifier;
 use subtle::ConstantTimeEq;
 use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time;
 use tokio_stream::StreamExt;
-use tracing::{error, info_span, warn, Instrument};
+use tokio_tungstenite::tungstenite;
+use tracing::{error, field, info_span, warn, Instrument, Span};
+
+use crate::audit::{AuditEvent, Peer};
+use crate::oidc::unix_time;
+use crate::report::ErrorSource;
+use crate::session::{Bandwidth, Metadata, Session};
+use crate::utils::TokenBucket;
+use crate::web::auth::Viewer;
+use crate::web::dev;
+use crate::web::links::JoinGrant;
+use crate::web::protocol::{
+    close_codes, WsCapabilities, WsClient, WsServer, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::web::PeerAddr;
+use crate::{ServerState, FEATURES};
+
+/// Maximum size of an inbound WebSocket message from a client.
+pub(super) const MAX_MESSAGE_SIZE: usize = 1 << 20; // 1 MiB
+
+/// Sustained rate of inbound messages allowed per connection, per second.
+const MESSAGE_RATE: f64 = 100.0;
+
+/// Number of inbound messages a connection can send in a single burst.
+const MESSAGE_BURST: f64 = 200.0;
+
+/// Longest avatar that a user can set, in characters.
+const MAX_AVATAR_CHARS: usize = 8;
+
+/// WebSocket subprotocol that encodes messages as JSON text instead of CBOR.
+const JSON_PROTOCOL: &str = "sshx-json";
+
+/// Default interval between keepalive pings sent to web clients.
+const PING_INTERVAL: Duration = Duration::from_secs(20);
 
-use crate::session::Session;
-use crate::web::protocol::{WsClient, WsServer};
-use crate::ServerState;
+/// Number of keepalive pings in a row that a client can miss before it is
+/// disconnected.
+const MAX_MISSED_PONGS: u32 = 3;
 
+/// Query parameters for the WebSocket upgrade.
+#[derive(Deserialize, Debug)]
+pub struct WsParams {
+    /// Signed join link that scopes the user's role and lifetime.
+    pub grant: Option<String>,
+}
+
+/// Information about the web client on the other end of a connection.
+pub(super) struct Client {
+    /// Remote client, as recorded in the audit log.
+    pub peer: Peer,
+    /// Subject identifier, if logged in with OpenID Connect.
+    pub subject: Option<String>,
+    /// Verified join link that the client connected with, if any.
+    pub grant: Option<JoinGrant>,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn get_session_ws(
     Path(name): Path<String>,
+    Query(params): Query<WsParams>,
     ws: WebSocketUpgrade,
+    Viewer(identity): Viewer,
+    ConnectInfo(addr): ConnectInfo<PeerAddr>,
+    peer: Peer,
+    headers: HeaderMap,
     State(state): State<Arc<ServerState>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |mut socket| {
-        let span = info_span!("ws", %name);
-        async move {
+) -> Response {
+    // Peers on a Unix domain socket have no address, so they are not limited.
+    let permit = addr.ip().map(|ip| state.ws_limiter().acquire(ip));
+    if matches!(permit, Some(None)) {
+        let msg = "too many connections from this address";
+        return (StatusCode::TOO_MANY_REQUESTS, msg).into_response();
+    }
+    let ws = ws
+        .max_message_size(MAX_MESSAGE_SIZE)
+        .max_frame_size(MAX_MESSAGE_SIZE)
+        .protocols([JSON_PROTOCOL]);
+    if state.dev_replay() && name == dev::DEV_SESSION {
+        return ws.on_upgrade(|socket| async move {
+            let _permit = permit;
+            if let Err(err) = dev::replay(&mut WsTransport::new(socket)).await {
+                warn!(?err, "scripted session ended with an error");
+            }
+        });
+    }
+    // Created here so that the span is a child of the upgrade request's span.
+    let span = info_span!("ws", session = %name, user_id = field::Empty);
+    let errors = state.errors().clone();
+    let guard_name = Some(name.clone());
+    ws.on_upgrade(move |socket| {
+        let task = async move {
+            let _permit = permit;
+            let mut socket = WsTransport::new(socket);
+            state.metrics().ws_connections.inc();
             match state.frontend_connect(&name).await {
                 Ok(Ok(session)) => {
-                    if let Err(err) = handle_socket(&mut socket, session).await {
+                    let grant = match &params.grant {
+                        Some(token) => match JoinGrant::verify(&state, &name, token) {
+                            Some(grant) => Some(grant),
+                            None => {
+                                socket
+                                    .close_with(close_codes::FORBIDDEN, "invalid or expired link")
+                                    .await
+                                    .ok();
+                                state.metrics().ws_connections.dec();
+                                return;
+                            }
+                        },
+                        None => None,
+                    };
+                    let client = Client {
+                        peer,
+                        subject: identity.map(|id| id.sub),
+                        grant,
+                    };
+                    if let Err(err) =
+                        handle_socket(&mut socket, &state, &name, client, session).await
+                    {
                         warn!(?err, "websocket exiting early");
+                        if is_message_too_large(&err) {
+                            socket
+                                .close_with(close_codes::MESSAGE_TOO_LARGE, "message too large")
+                                .await
+                                .ok();
+                        }
                     } else {
-                        socket.close().await.ok();
+                        socket.inner.close().await.ok();
                     }
                 }
                 Ok(Err(Some(host))) => {
-                    if let Err(err) = proxy_redirect(&mut socket, &host, &name).await {
+                    let cookie = headers.get(COOKIE);
+                    let grant = params.grant.as_deref();
+                    if let Err(err) = proxy_redirect(
+                        &mut socket.inner,
+                        &host,
+                        state.base_path(),
+                        &name,
+                        grant,
+                        cookie,
+                    )
+                    .await
+                    {
                         error!(?err, "failed to proxy websocket");
-                        let frame = CloseFrame {
-                            code: 4500,
-                            reason: format!("proxy redirect: {err}").into(),
-                        };
-                        socket.send(Message::Close(Some(frame))).await.ok();
+                        state.errors().report(ErrorSource::Proxy, &err, Some(&name));
+                        socket
+                            .close_with(
+                                close_codes::INTERNAL_ERROR,
+                                &format!("proxy redirect: {err}"),
+                            )
+                            .await
+                            .ok();
                     } else {
-                        socket.close().await.ok();
+                        socket.inner.close().await.ok();
                     }
                 }
                 Ok(Err(None)) => {
-                    let frame = CloseFrame {
-                        code: 4404,
-                        reason: "could not find the requested session".into(),
-                    };
-                    socket.send(Message::Close(Some(frame))).await.ok();
+                    socket
+                        .close_with(
+                            close_codes::NOT_FOUND,
+                            "could not find the requested session",
+                        )
+                        .await
+                        .ok();
                 }
                 Err(err) => {
                     error!(?err, "failed to connect to frontend session");
-                    let frame = CloseFrame {
-                        code: 4500,
-                        reason: format!("session connect: {err}").into(),
-                    };
-                    socket.send(Message::Close(Some(frame))).await.ok();
+                    socket
+                        .close_with(
+                            close_codes::INTERNAL_ERROR,
+                            &format!("session connect: {err}"),
+                        )
+                        .await
+                        .ok();
                 }
             }
-        }
-        .instrument(span)
+            state.metrics().ws_connections.dec();
+        };
+        errors.guard(guard_name, task).instrument(span)
     })
 }
 
-/// Handle an incoming live WebSocket connection to a given session.
-async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<()> {
-    /// Send a message to the client over WebSocket.
-    async fn send(socket: &mut WebSocket, msg: WsServer) -> Result<()> {
-        let mut buf = Vec::new();
-        ciborium::ser::into_writer(&msg, &mut buf)?;
-        socket.send(Message::Binary(buf)).await?;
+/// A message-oriented connection to a web client, carrying [`WsServer`] and
+/// [`WsClient`] messages in some encoding.
+///
+/// The protocol handler is written against this trait so that transports other
+/// than WebSocket, such as WebTransport, can share it.
+#[axum::async_trait]
+pub trait Transport: Send {
+    /// Send a message to the client.
+    async fn send(&mut self, msg: WsServer) -> Result<()>;
+
+    /// Receive the next message, or `None` if the client disconnected.
+    async fn recv(&mut self) -> Result<Option<WsClient>>;
+
+    /// Close the connection with an application error code and reason.
+    async fn close_with(&mut self, code: u16, reason: &str) -> Result<()>;
+
+    /// Send a keepalive ping, returning how many pings in a row went unanswered
+    /// before this one. Transports that detect dead peers on their own can
+    /// leave this as a no-op.
+    async fn ping(&mut self) -> Result<u32> {
+        Ok(0)
+    }
+}
+
+/// A WebSocket connection to a web client, tracking keepalive pings.
+///
+/// Messages are sent as binary CBOR, or as JSON text if the client negotiated
+/// the [`JSON_PROTOCOL`] subprotocol.
+struct WsTransport {
+    inner: WebSocket,
+    /// Whether messages are encoded as JSON text instead of CBOR.
+    json: bool,
+    /// Number of pings sent since a message was last received.
+    unanswered_pings: u32,
+}
+
+impl WsTransport {
+    fn new(inner: WebSocket) -> Self {
+        let json = inner.protocol().is_some_and(|p| p == JSON_PROTOCOL);
+        Self {
+            inner,
+            json,
+            unanswered_pings: 0,
+        }
+    }
+}
+
+#[axum::async_trait]
+impl Transport for WsTransport {
+    async fn send(&mut self, msg: WsServer) -> Result<()> {
+        let msg = if self.json {
+            Message::Text(serde_json::to_string(&msg)?)
+        } else {
+            let mut buf = Vec::new();
+            ciborium::ser::into_writer(&msg, &mut buf)?;
+            Message::Binary(buf)
+        };
+        self.inner.send(msg).await?;
         Ok(())
     }
 
-    /// Receive a message from the client over WebSocket.
-    async fn recv(socket: &mut WebSocket) -> Result<Option<WsClient>> {
+    async fn recv(&mut self) -> Result<Option<WsClient>> {
         Ok(loop {
-            match socket.recv().await.transpose()? {
-                Some(Message::Text(_)) => warn!("ignoring text message over WebSocket"),
-                Some(Message::Binary(msg)) => break Some(ciborium::de::from_reader(&*msg)?),
+            let msg = self.inner.recv().await.transpose()?;
+            self.unanswered_pings = 0;
+            match msg {
+                Some(Message::Text(msg)) if self.json => break Some(serde_json::from_str(&msg)?),
+                Some(Message::Binary(msg)) if !self.json => {
+                    break Some(ciborium::de::from_reader(&*msg)?)
+                }
+                Some(Message::Text(_) | Message::Binary(_)) => {
+                    warn!("ignoring message in the wrong encoding over WebSocket")
+                }
                 Some(_) => (), // ignore other message types, keep looping
                 None => break None,
             }
         })
     }
 
+    async fn close_with(&mut self, code: u16, reason: &str) -> Result<()> {
+        let frame = CloseFrame {
+            code,
+            reason: reason.to_owned().into(),
+        };
+        self.inner.send(Message::Close(Some(frame))).await?;
+        Ok(())
+    }
+
+    async fn ping(&mut self) -> Result<u32> {
+        let missed = self.unanswered_pings;
+        self.inner.send(Message::Ping(Vec::new())).await?;
+        self.unanswered_pings += 1;
+        Ok(missed)
+    }
+}
+
+/// Handle an incoming live connection to a given session.
+pub(super) async fn handle_socket(
+    socket: &mut impl Transport,
+    state: &ServerState,
+    name: &str,
+    client: Client,
+    session: Arc<Session>,
+) -> Result<()> {
     let metadata = session.metadata();
     let user_id = session.counter().next_uid();
+    Span::current().record("user_id", user_id.0);
     session.sync_now();
-    send(socket, WsServer::Hello(user_id, metadata.name.clone())).await?;
-
-    let can_write = match recv(socket).await? {
-        Some(WsClient::Authenticate(bytes, write_password_bytes)) => {
-            // Constant-time comparison of bytes, converting Choice to bool
-            if !bool::from(bytes.ct_eq(metadata.encrypted_zeros.as_ref())) {
-                send(socket, WsServer::InvalidAuth()).await?;
-                return Ok(());
-            }
-
-            match (write_password_bytes, &metadata.write_password_hash) {
-                // No password needed, so all users can write (default).
-                (_, None) => true,
-
-                // Password stored but not provided, user is read-only.
-                (None, Some(_)) => false,
+    let capabilities = WsCapabilities {
+        features: FEATURES.iter().map(|&f| f.into()).collect(),
+        max_message_size: MAX_MESSAGE_SIZE as u32,
+    };
+    let hello = WsServer::Hello(
+        user_id,
+        metadata.name.clone(),
+        PROTOCOL_VERSION,
+        capabilities,
+    );
+    socket.send(hello).await?;
+    if let Some(banner) = state.banner() {
+        socket.send(WsServer::Banner(banner.into())).await?;
+    }
 
-                // Password stored and provided, compare them.
-                (Some(provided), Some(stored)) => {
-                    if !bool::from(provided.ct_eq(stored)) {
-                        send(socket, WsServer::InvalidAuth()).await?;
-                        return Ok(());
-                    }
-                    true
-                }
-            }
-        }
-        _ => {
-            send(socket, WsServer::InvalidAuth()).await?;
+    let can_write = match socket.recv().await? {
+        Some(WsClient::Authenticate(_, _, Some(version)))
+            if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) =>
+        {
+            let reason = format!("unsupported protocol version {version}");
+            socket
+                .close_with(close_codes::UPGRADE_REQUIRED, &reason)
+                .await?;
             return Ok(());
         }
+        Some(WsClient::Authenticate(bytes, write_password_bytes, _)) => {
+            // Hashing the write password is slow, so it runs off the async runtime.
+            let metadata = metadata.clone();
+            let grant = client.grant.clone();
+            let require_grant = state.require_join_grants();
+            task::spawn_blocking(move || {
+                let write_password = write_password_bytes.as_deref();
+                let can_write = authenticate(&metadata, &bytes, write_password)?;
+                authorize(&metadata, can_write, grant.as_ref(), require_grant)
+            })
+            .await?
+        }
+        _ => None,
+    };
+    let Some(can_write) = can_write else {
+        let event = AuditEvent::AuthFailed {
+            session: name.into(),
+        };
+        state.audit().record(&client.peer, event);
+        socket.send(WsServer::InvalidAuth()).await?;
+        socket
+            .close_with(close_codes::UNAUTHORIZED, "invalid authentication")
+            .await?;
+        return Ok(());
     };
+    let event = AuditEvent::UserAuthenticated {
+        session: name.into(),
+        user_id,
+        can_write,
+        subject: client.subject,
+    };
+    state.audit().record(&client.peer, event);
 
     let _user_guard = session.user_scope(user_id, can_write)?;
 
     let update_tx = session.update_tx(); // start listening for updates before any state reads
     let mut broadcast_stream = session.subscribe_broadcast();
-    send(socket, WsServer::Users(session.list_users())).await?;
+    socket.send(WsServer::Users(session.list_users())).await?;
+    socket.send(WsServer::Notes(session.notes())).await?;
+    if let Some(palette) = session.palette() {
+        socket
+            .send(WsServer::Palette(palette.data, palette.offset))
+            .await?;
+    }
+    if let Some(colors) = &session.metadata().color_scheme {
+        socket.send(WsServer::ColorScheme(colors.clone())).await?;
+    }
+    if let Some(environment) = &session.metadata().environment {
+        socket
+            .send(WsServer::Environment(environment.clone()))
+            .await?;
+    }
+    for (id, health) in session.stalled_shells() {
+        socket.send(WsServer::ShellHealth(id, health)).await?;
+    }
 
     let mut subscribed = HashSet::new(); // prevent duplicate subscriptions
-    let (chunks_tx, mut chunks_rx) = mpsc::channel::<(Sid, u64, Vec<Bytes>)>(1);
+    let (chunks_tx, mut chunks_rx) = mpsc::channel::<(Sid, u64, Vec<Bytes>, Option<Vec<u64>>)>(1);
 
     let mut shells_stream = session.subscribe_shells();
+    let mut rate_limit = TokenBucket::new(MESSAGE_RATE, MESSAGE_BURST);
+    let mut input_limit =
+        (state.input_rate_limit()).map(|rate| TokenBucket::new(rate as f64, rate as f64));
+    let mut link_expiry = pin!(grant_expiry(client.grant.as_ref()));
+    let ping_interval = state.ping_interval().unwrap_or(PING_INTERVAL);
+    let mut keepalive = time::interval_at(time::Instant::now() + ping_interval, ping_interval);
+    keepalive.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
     loop {
         let msg = tokio::select! {
-            _ = session.terminated() => break,
+            _ = session.terminated() => {
+                if state.is_shutting_down() {
+                    socket.close_with(close_codes::UNAVAILABLE, "server restarting").await?;
+                    return Ok(());
+                }
+                break;
+            }
+            _ = &mut link_expiry => {
+                socket.close_with(close_codes::EXPIRED, "link expired").await?;
+                return Ok(());
+            }
+            _ = keepalive.tick() => {
+                if socket.ping().await? >= MAX_MISSED_PONGS {
+                    socket.close_with(close_codes::TIMEOUT, "keepalive timeout").await?;
+                    return Ok(());
+                }
+                continue;
+            }
             Some(result) = broadcast_stream.next() => {
                 let msg = result.context("client fell behind on broadcast stream")?;
-                send(socket, msg).await?;
+                let bytes = match &msg {
+                    WsServer::Stream(_, _, data, _) => data.len() as u64,
+                    _ => 0,
+                };
+                socket.send(msg).await?;
+                if bytes > 0 {
+                    let bandwidth = session.record_user_bandwidth(user_id, bytes, 0);
+                    if over_limit(state, bandwidth) {
+                        socket.close_with(close_codes::BANDWIDTH_EXCEEDED, "bandwidth limit exceeded").await?;
+                        return Ok(());
+                    }
+                }
                 continue;
             }
             Some(shells) = shells_stream.next() => {
-                send(socket, WsServer::Shells(shells)).await?;
+                socket.send(WsServer::Shells(shells)).await?;
                 continue;
             }
-            Some((id, seqnum, chunks)) = chunks_rx.recv() => {
-                send(socket, WsServer::Chunks(id, seqnum, chunks)).await?;
+            Some((id, seqnum, chunks, times)) = chunks_rx.recv() => {
+                let bytes = chunks.iter().map(|c| c.len()).sum();
+                let msg = match times {
+                    Some(times) => WsServer::TimedChunks(id, seqnum, chunks, times),
+                    None => WsServer::Chunks(id, seqnum, chunks),
+                };
+                socket.send(msg).await?;
+                state.metrics().record_output(bytes);
+                let bandwidth = session.record_user_bandwidth(user_id, bytes as u64, 0);
+                if over_limit(state, bandwidth) {
+                    socket.close_with(close_codes::BANDWIDTH_EXCEEDED, "bandwidth limit exceeded").await?;
+                    return Ok(());
+                }
                 continue;
             }
-            result = recv(socket) => {
+            result = socket.recv() => {
                 match result? {
                     Some(msg) => msg,
                     None => break,
@@ -161,69 +468,114 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
             }
         };
 
+        if !rate_limit.try_take(1.0) {
+            socket
+                .close_with(close_codes::RATE_LIMITED, "rate limit exceeded")
+                .await?;
+            return Ok(());
+        }
+
         match msg {
-            WsClient::Authenticate(_, _) => {}
+            WsClient::Authenticate(..) => {}
             WsClient::SetName(name) => {
                 if !name.is_empty() {
                     session.update_user(user_id, |user| user.name = name)?;
                 }
             }
+            WsClient::SetProfile(profile) => {
+                let avatar = profile.avatar.map(|avatar| avatar.trim().to_owned());
+                if avatar
+                    .as_ref()
+                    .is_some_and(|a| a.chars().count() > MAX_AVATAR_CHARS)
+                {
+                    let msg = format!("avatar is longer than {MAX_AVATAR_CHARS} characters");
+                    socket.send(WsServer::Error(msg)).await?;
+                    continue;
+                }
+                session.update_user(user_id, |user| {
+                    if !profile.name.is_empty() {
+                        user.name = profile.name;
+                    }
+                    user.avatar = avatar.filter(|avatar| !avatar.is_empty());
+                })?;
+            }
             WsClient::SetCursor(cursor) => {
                 session.update_user(user_id, |user| user.cursor = cursor)?;
             }
             WsClient::SetFocus(id) => {
                 session.update_user(user_id, |user| user.focus = id)?;
             }
+            WsClient::SetViewport(viewport) => {
+                session.update_user(user_id, |user| user.viewport = viewport)?;
+            }
             WsClient::Create(x, y) => {
                 if let Err(e) = session.check_write_permission(user_id) {
-                    send(socket, WsServer::Error(e.to_string())).await?;
+                    socket.send(WsServer::Error(e.to_string())).await?;
                     continue;
                 }
                 let id = session.counter().next_sid();
                 session.sync_now();
-                let new_shell = NewShell { id: id.0, x, y };
+                let new_shell = NewShell::new(id, (x, y));
                 update_tx
                     .send(ServerMessage::CreateShell(new_shell))
                     .await?;
             }
             WsClient::Close(id) => {
                 if let Err(e) = session.check_write_permission(user_id) {
-                    send(socket, WsServer::Error(e.to_string())).await?;
+                    socket.send(WsServer::Error(e.to_string())).await?;
                     continue;
                 }
-                update_tx.send(ServerMessage::CloseShell(id.0)).await?;
+                update_tx.send(ServerMessage::CloseShell(id.into())).await?;
             }
             WsClient::Move(id, winsize) => {
                 if let Err(e) = session.check_write_permission(user_id) {
-                    send(socket, WsServer::Error(e.to_string())).await?;
-                    continue;
-                }
-                if let Err(err) = session.move_shell(id, winsize) {
-                    send(socket, WsServer::Error(err.to_string())).await?;
+                    socket.send(WsServer::Error(e.to_string())).await?;
                     continue;
                 }
-                if let Some(winsize) = winsize {
-                    let msg = ServerMessage::Resize(TerminalSize {
-                        id: id.0,
-                        rows: winsize.rows as u32,
-                        cols: winsize.cols as u32,
-                    });
-                    session.update_tx().send(msg).await?;
+                let resized = winsize.is_some();
+                match session.move_shell(id, winsize) {
+                    Ok(winsize) if resized => {
+                        let msg = ServerMessage::Resize(TerminalSize {
+                            id: id.into(),
+                            rows: winsize.rows as u32,
+                            cols: winsize.cols as u32,
+                        });
+                        session.update_tx().send(msg).await?;
+                    }
+                    Ok(_) => (),
+                    Err(err) => socket.send(WsServer::Error(err.to_string())).await?,
                 }
             }
             WsClient::Data(id, data, offset) => {
                 if let Err(e) = session.check_write_permission(user_id) {
-                    send(socket, WsServer::Error(e.to_string())).await?;
+                    socket.send(WsServer::Error(e.to_string())).await?;
                     continue;
                 }
+                if let Some(bucket) = &mut input_limit {
+                    // Inputs larger than the burst size take the whole bucket.
+                    let amount = (data.len() as f64).min(bucket.capacity());
+                    if !bucket.try_take(amount) {
+                        let wait = bucket.time_until(amount).as_millis() as u64;
+                        socket.send(WsServer::Throttled(id, wait)).await?;
+                        continue;
+                    }
+                }
+                state.metrics().record_input(data.len());
+                let bandwidth = session.record_user_bandwidth(user_id, 0, data.len() as u64);
+                if over_limit(state, bandwidth) {
+                    socket
+                        .close_with(close_codes::BANDWIDTH_EXCEEDED, "bandwidth limit exceeded")
+                        .await?;
+                    return Ok(());
+                }
                 let input = TerminalInput {
-                    id: id.0,
+                    id: id.into(),
                     data,
                     offset,
                 };
                 update_tx.send(ServerMessage::Input(input)).await?;
             }
-            WsClient::Subscribe(id, chunknum) => {
+            WsClient::Subscribe(id, chunknum, timed) => {
                 if subscribed.contains(&id) {
                     continue;
                 }
@@ -233,8 +585,9 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
                 tokio::spawn(async move {
                     let stream = session.subscribe_chunks(id, chunknum);
                     tokio::pin!(stream);
-                    while let Some((seqnum, chunks)) = stream.next().await {
-                        if chunks_tx.send((id, seqnum, chunks)).await.is_err() {
+                    while let Some((seqnum, chunks, times)) = stream.next().await {
+                        let times = timed.then_some(times);
+                        if chunks_tx.send((id, seqnum, chunks, times)).await.is_err() {
                             break;
                         }
                     }
@@ -243,22 +596,165 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
             WsClient::Chat(msg) => {
                 session.send_chat(user_id, &msg)?;
             }
+            WsClient::NotesEdit(base, data) => {
+                if let Err(e) = session.check_write_permission(user_id) {
+                    socket.send(WsServer::Error(e.to_string())).await?;
+                    continue;
+                }
+                match session.edit_notes(user_id, base, data) {
+                    Ok(true) => (),
+                    // The edit is too old, so the client starts over from the latest notes.
+                    Ok(false) => socket.send(WsServer::Notes(session.notes())).await?,
+                    Err(e) => socket.send(WsServer::Error(e.to_string())).await?,
+                }
+            }
+            WsClient::NotesCompact(version, text) => {
+                if let Err(e) = session.check_write_permission(user_id) {
+                    socket.send(WsServer::Error(e.to_string())).await?;
+                    continue;
+                }
+                if let Err(e) = session.compact_notes(version, text) {
+                    socket.send(WsServer::Error(e.to_string())).await?;
+                }
+            }
+            WsClient::Stream(id, kind, data, offset) => {
+                if let Err(e) = session.check_write_permission(user_id) {
+                    socket.send(WsServer::Error(e.to_string())).await?;
+                    continue;
+                }
+                let bandwidth = session.record_user_bandwidth(user_id, 0, data.len() as u64);
+                if over_limit(state, bandwidth) {
+                    socket
+                        .close_with(close_codes::BANDWIDTH_EXCEEDED, "bandwidth limit exceeded")
+                        .await?;
+                    return Ok(());
+                }
+                let msg = ServerMessage::Stream(StreamData {
+                    id,
+                    kind: StreamKind::from(kind).into(),
+                    data,
+                    offset,
+                });
+                update_tx.send(msg).await?;
+            }
             WsClient::Ping(ts) => {
-                send(socket, WsServer::Pong(ts)).await?;
+                socket.send(WsServer::Pong(ts)).await?;
             }
         }
     }
     Ok(())
 }
 
+/// Returns whether a user has been relayed more data than the server allows.
+fn over_limit(state: &ServerState, bandwidth: Bandwidth) -> bool {
+    state
+        .max_user_bytes()
+        .is_some_and(|max| bandwidth.total() > max)
+}
+
+/// Resolves when a join link expires, or never if there is no link.
+async fn grant_expiry(grant: Option<&JoinGrant>) {
+    match grant {
+        Some(grant) => {
+            let remaining = grant.exp.saturating_sub(unix_time());
+            time::sleep(Duration::from_secs(remaining)).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Check if a WebSocket error was caused by a client exceeding the size limit.
+pub(super) fn is_message_too_large(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        matches!(
+            err.downcast_ref::<tungstenite::Error>(),
+            Some(tungstenite::Error::Capacity(_))
+        )
+    })
+}
+
+/// Check a user's credentials, returning whether they have write access.
+///
+/// Returns `None` if the encryption key or write password is incorrect.
+pub(super) fn authenticate(
+    metadata: &Metadata,
+    zeros: &[u8],
+    write_password: Option<&[u8]>,
+) -> Option<bool> {
+    // Constant-time comparison of bytes, converting Choice to bool
+    if !bool::from(zeros.ct_eq(metadata.encrypted_zeros.as_ref())) {
+        return None;
+    }
+
+    match (write_password, &metadata.write_password_hash) {
+        // No password needed, so all users can write (default).
+        (_, None) => Some(true),
+
+        // Password stored but not provided, user is read-only.
+        (None, Some(_)) => Some(false),
+
+        // Password stored and provided, compare them after salting.
+        (Some(provided), Some(stored)) => {
+            let provided = match &metadata.write_password_salt {
+                Some(salt) => hash_verifier(provided, salt),
+                None => provided.to_vec(),
+            };
+            bool::from(provided.ct_eq(stored.as_ref())).then_some(true)
+        }
+    }
+}
+
+/// Decide whether an authenticated user can write, taking their join link into
+/// account, or return `None` if they cannot join. See [`crate::web::links`].
+pub(super) fn authorize(
+    metadata: &Metadata,
+    can_write: bool,
+    grant: Option<&JoinGrant>,
+    require_grant: bool,
+) -> Option<bool> {
+    match grant {
+        Some(grant) => Some(can_write && grant.write),
+        // Without a link, only users who provided the write password can join.
+        None if require_grant => {
+            (can_write && metadata.write_password_hash.is_some()).then_some(true)
+        }
+        None => Some(can_write),
+    }
+}
+
 /// Transparently reverse-proxy a WebSocket connection to a different host.
-async fn proxy_redirect(socket: &mut WebSocket, host: &str, name: &str) -> Result<()> {
+///
+/// The join link and login cookie of the client are forwarded, if present, as
+/// well as the subprotocol that it negotiated, so that messages are encoded the
+/// same way on both ends.
+async fn proxy_redirect(
+    socket: &mut WebSocket,
+    host: &str,
+    base_path: &str,
+    name: &str,
+    grant: Option<&str>,
+    cookie: Option<&HeaderValue>,
+) -> Result<()> {
     use tokio_tungstenite::{
         connect_async,
-        tungstenite::protocol::{CloseFrame as TCloseFrame, Message as TMessage},
+        tungstenite::{
+            client::IntoClientRequest,
+            protocol::{CloseFrame as TCloseFrame, Message as TMessage},
+        },
     };
 
-    let (mut upstream, _) = connect_async(format!("ws://{host}/api/s/{name}")).await?;
+    let mut url = format!("ws://{host}{base_path}/api/s/{name}");
+    if let Some(grant) = grant {
+        url = format!("{url}?grant={grant}");
+    }
+    let mut req = url.into_client_request()?;
+    if let Some(cookie) = cookie {
+        req.headers_mut().insert(COOKIE, cookie.clone());
+    }
+    if let Some(protocol) = socket.protocol() {
+        (req.headers_mut()).insert(SEC_WEBSOCKET_PROTOCOL, protocol.clone());
+    }
+    let (mut upstream, _) = connect_async(req).await?;
     loop {
         // Due to axum having its own WebSocket API types, we need to manually translate
         // between it and tungstenite's message type.
@@ -0,0 +1,131 @@
+//! Download of the encrypted transcript of a session.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use axum::body::{Body, StreamBody};
+use axum::extract::{Path, Query, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::{Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use serde::Deserialize;
+use tokio_stream::StreamExt;
+use tracing::error;
+
+use crate::audit::{AuditEvent, Peer};
+use crate::session::Session;
+use crate::web::auth::Viewer;
+use crate::web::links::JoinGrant;
+use crate::web::protocol::TranscriptRecord;
+use crate::web::proxy_request;
+use crate::web::socket::authenticate;
+use crate::ServerState;
+
+/// Query parameters for downloading a transcript.
+#[derive(Deserialize, Debug)]
+pub struct TranscriptParams {
+    /// Include the time of each chunk, for replaying with the original pacing.
+    #[serde(default)]
+    timestamps: bool,
+    /// Signed join link, needed if the server requires one.
+    grant: Option<String>,
+}
+
+/// Stream the retained, encrypted terminal data of a session as a file.
+///
+/// The caller proves that they hold the encryption key by passing the base64
+/// encrypted zeros block as a bearer token. The response is a CBOR sequence of
+/// [`TranscriptRecord`] values, which can be decrypted offline with the key.
+pub async fn get_transcript(
+    Path(name): Path<String>,
+    Query(params): Query<TranscriptParams>,
+    State(state): State<Arc<ServerState>>,
+    _: Viewer,
+    peer: Peer,
+    req: Request<Body>,
+) -> Response {
+    let grant = params.grant.as_deref();
+    let session = match connect_with_key(&state, &name, &peer, req, grant).await {
+        Ok(session) => session,
+        Err(resp) => return resp,
+    };
+    let event = AuditEvent::TranscriptDownloaded {
+        session: name.clone(),
+    };
+    state.audit().record(&peer, event);
+
+    let metadata = session.metadata();
+    let header = TranscriptRecord::Header(name.clone(), metadata.encrypted_zeros.clone());
+    let shells = session.retained_chunks().into_iter();
+    let records = std::iter::once(header).chain(shells.map(move |(id, seqnum, chunks, times)| {
+        if params.timestamps {
+            TranscriptRecord::TimedChunks(id, seqnum, chunks, times)
+        } else {
+            TranscriptRecord::Chunks(id, seqnum, chunks)
+        }
+    }));
+    let body = tokio_stream::iter(records).map(|record| {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&record, &mut buf).unwrap();
+        Ok::<_, Infallible>(buf)
+    });
+
+    let disposition = format!("attachment; filename=\"sshx-{name}.cbor\"");
+    (
+        [
+            (CONTENT_TYPE, "application/cbor-seq".to_string()),
+            (CONTENT_DISPOSITION, disposition),
+        ],
+        StreamBody::new(body),
+    )
+        .into_response()
+}
+
+/// Connect to a session, checking that the caller holds its encryption key.
+///
+/// The caller proves this by passing the base64 encrypted zeros block as a
+/// bearer token, without revealing the key itself. If the server requires join
+/// links, the caller must pass one too, since there is no write password here.
+///
+/// Requests for sessions hosted on another server in the mesh are proxied
+/// there, and the proxied response is returned as the error.
+pub(super) async fn connect_with_key(
+    state: &ServerState,
+    name: &str,
+    peer: &Peer,
+    req: Request<Body>,
+    grant: Option<&str>,
+) -> Result<Arc<Session>, Response> {
+    let session = match state.frontend_connect(name).await {
+        Ok(Ok(session)) => session,
+        Ok(Err(Some(host))) => return Err(proxy_request(state, &host, name, req, ()).await),
+        Ok(Err(None)) => return Err(StatusCode::NOT_FOUND.into_response()),
+        Err(err) => {
+            error!(?err, "failed to connect to frontend session");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+
+    let granted = grant.and_then(|token| JoinGrant::verify(state, name, token));
+    if state.require_join_grants() && granted.is_none() {
+        return Err((StatusCode::FORBIDDEN, "join link required").into_response());
+    }
+
+    let zeros = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|value| BASE64_STANDARD.decode(value).ok());
+    match zeros {
+        Some(zeros) if authenticate(session.metadata(), &zeros, None).is_some() => Ok(session),
+        _ => {
+            let event = AuditEvent::AuthFailed {
+                session: name.into(),
+            };
+            state.audit().record(peer, event);
+            Err(StatusCode::UNAUTHORIZED.into_response())
+        }
+    }
+}
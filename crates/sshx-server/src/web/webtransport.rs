@@ -0,0 +1,361 @@
+//! WebTransport endpoint for web clients, served over HTTP/3.
+//!
+//! Browsers that support WebTransport connect to `/api/wt/s/{name}` with an
+//! extended CONNECT request. The server then opens a single bidirectional
+//! stream, carrying the same CBOR-encoded [`WsServer`] and [`WsClient`]
+//! messages as the WebSocket, each prefixed by its length as a 32-bit
+//! big-endian integer.
+//!
+//! Any request that cannot be served here, such as for a session on another
+//! server in the mesh, is refused with an HTTP error so that the client falls
+//! back to a WebSocket.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use axum::extract::State;
+use axum::response::{IntoResponse, Json, Response};
+use bytes::{BufMut, Bytes, BytesMut};
+use h3::ext::Protocol;
+use h3::proto::varint::VarInt;
+use h3::server::RequestStream;
+use http::{Method, StatusCode};
+use quinn::rustls::pki_types::CertificateDer;
+use quinn::{ReadError, ReadExactError};
+use serde::Serialize;
+use tokio::time;
+use tokio_tungstenite::tungstenite::error::{CapacityError, Error as WsError};
+use tracing::{debug, error, field, info_span, warn, Instrument};
+
+use crate::audit::Peer;
+use crate::tls;
+use crate::web::dev;
+use crate::web::links::JoinGrant;
+use crate::web::protocol::{close_codes, WsClient, WsServer};
+use crate::web::socket::{
+    handle_socket, is_message_too_large, Client, Transport, MAX_MESSAGE_SIZE,
+};
+use crate::ServerState;
+
+/// How long a client has to complete the QUIC handshake after connecting.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval between QUIC keepalive packets, which also detect dead clients.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time without any packets from a client before its connection is closed.
+const MAX_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait for a client to close its connection after the session.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stream type of a bidirectional stream in a WebTransport session.
+const WEBTRANSPORT_STREAM: u32 = 0x41;
+
+/// Capsule that closes a WebTransport session with an error code and reason.
+const CLOSE_SESSION_CAPSULE: u32 = 0x2843;
+
+/// Longest reason allowed in a close capsule, in bytes.
+const MAX_CLOSE_REASON: usize = 1024;
+
+/// Where web clients can connect with WebTransport.
+#[derive(Serialize, Debug)]
+struct WtInfo {
+    /// UDP port of the HTTP/3 endpoint, on the same host as the web app.
+    port: u16,
+}
+
+/// Returns where to connect with WebTransport, if it is served.
+///
+/// Browsers do not send cookies with WebTransport requests, so it is not
+/// offered when viewers must log in with OpenID Connect.
+pub async fn get_info(State(state): State<Arc<ServerState>>) -> Response {
+    match state.webtransport_port() {
+        Some(port) if state.oidc().is_none() => Json(WtInfo { port }).into_response(),
+        _ => axum::http::StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Bind a QUIC endpoint for WebTransport, with the server's TLS certificates.
+pub(crate) fn bind(state: &ServerState, addr: &SocketAddr) -> Result<quinn::Endpoint> {
+    let tls = (state.tls()).context("serving WebTransport requires TLS certificates")?;
+    let mut config = tls.quic_config()?;
+    let mut transport = quinn::TransportConfig::default();
+    transport
+        .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL))
+        .max_idle_timeout(Some(MAX_IDLE_TIMEOUT.try_into()?));
+    config.transport_config(Arc::new(transport));
+    Ok(quinn::Endpoint::server(config, *addr)?)
+}
+
+/// Accept WebTransport connections on an endpoint until the signal fires.
+///
+/// Connections are checked against the IP access rules and request rate
+/// limits, like those on the TCP listeners.
+pub(crate) async fn serve(
+    state: Arc<ServerState>,
+    endpoint: quinn::Endpoint,
+    signal: impl Future<Output = ()>,
+) -> Result<()> {
+    let mut signal = pin!(signal);
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            _ = &mut signal => break,
+        };
+        let remote_addr = incoming.remote_address();
+        if !state.ip_filter().is_allowed(remote_addr.ip()) {
+            debug!(%remote_addr, "rejecting connection from denied address");
+            incoming.refuse();
+            continue;
+        }
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(state, incoming).await {
+                debug!(%remote_addr, ?err, "webtransport connection failed");
+            }
+        });
+    }
+
+    // Sessions end their streams when they are terminated after the drain
+    // timeout, so wait for that before closing the endpoint.
+    let timeout = state.drain_timeout() + CLOSE_TIMEOUT;
+    if time::timeout(timeout, endpoint.wait_idle()).await.is_err() {
+        warn!("webtransport connections did not close in time, exiting anyway");
+    }
+    endpoint.close(0u32.into(), b"server shutting down");
+    Ok(())
+}
+
+/// Serve the WebTransport session on a single QUIC connection.
+async fn handle_connection(state: Arc<ServerState>, incoming: quinn::Incoming) -> Result<()> {
+    let quic = time::timeout(HANDSHAKE_TIMEOUT, incoming)
+        .await
+        .context("QUIC handshake timed out")??;
+    let peer = Peer {
+        ip: Some(quic.remote_address().ip()),
+        cert_subject: peer_cert_subject(&quic),
+    };
+
+    let mut conn = h3::server::builder()
+        .enable_webtransport(true)
+        .enable_extended_connect(true)
+        .enable_datagram(true)
+        .max_webtransport_sessions(1)
+        .send_grease(true)
+        .build::<_, Bytes>(h3_quinn::Connection::new(quic.clone()))
+        .await?;
+    let result = serve_request(&state, &quic, &mut conn, peer).await;
+
+    // Closing the connection right away could cut off the response or close
+    // capsule, so wait for the client to close it instead.
+    time::timeout(CLOSE_TIMEOUT, drain_requests(&mut conn))
+        .await
+        .ok();
+    result
+}
+
+/// Serve the first request on an HTTP/3 connection, which should open a
+/// WebTransport session.
+async fn serve_request(
+    state: &ServerState,
+    quic: &quinn::Connection,
+    conn: &mut h3::server::Connection<h3_quinn::Connection, Bytes>,
+    peer: Peer,
+) -> Result<()> {
+    let ip = quic.remote_address().ip();
+    let Some(resolver) = conn.accept().await? else {
+        return Ok(());
+    };
+    let (req, mut stream) = resolver.resolve_request().await?;
+
+    let prefix = format!("{}/api/wt/s/", state.base_path());
+    let name = match req.uri().path().strip_prefix(&prefix) {
+        Some(name) if !name.is_empty() && !name.contains('/') => name.to_owned(),
+        _ => return refuse(&mut stream, StatusCode::NOT_FOUND).await,
+    };
+    if req.method() != Method::CONNECT
+        || req.extensions().get::<Protocol>() != Some(&Protocol::WEB_TRANSPORT)
+    {
+        return refuse(&mut stream, StatusCode::BAD_REQUEST).await;
+    }
+    if !state.rate_limiter().check(ip) {
+        return refuse(&mut stream, StatusCode::TOO_MANY_REQUESTS).await;
+    }
+    // Viewers who must log in, and the scripted development session, are
+    // served over WebSocket instead.
+    if state.oidc().is_some() || (state.dev_replay() && name == dev::DEV_SESSION) {
+        return refuse(&mut stream, StatusCode::NOT_FOUND).await;
+    }
+    let Some(permit) = state.ws_limiter().acquire(ip) else {
+        return refuse(&mut stream, StatusCode::TOO_MANY_REQUESTS).await;
+    };
+
+    let session = match state.frontend_connect(&name).await {
+        Ok(Ok(session)) => session,
+        // Only the WebSocket is proxied to the server that hosts the session.
+        Ok(Err(Some(_))) => return refuse(&mut stream, StatusCode::MISDIRECTED_REQUEST).await,
+        Ok(Err(None)) => return refuse(&mut stream, StatusCode::NOT_FOUND).await,
+        Err(err) => {
+            error!(?err, "failed to connect to frontend session");
+            return refuse(&mut stream, StatusCode::INTERNAL_SERVER_ERROR).await;
+        }
+    };
+    let grant_token = (req.uri().query())
+        .and_then(|query| url::form_urlencoded::parse(query.as_bytes()).find(|(k, _)| k == "grant"))
+        .map(|(_, token)| token.into_owned());
+    let grant = match &grant_token {
+        Some(token) => match JoinGrant::verify(state, &name, token) {
+            Some(grant) => Some(grant),
+            None => return refuse(&mut stream, StatusCode::FORBIDDEN).await,
+        },
+        None => None,
+    };
+
+    let resp = http::Response::builder()
+        .status(StatusCode::OK)
+        .header("sec-webtransport-http3-draft", "draft02")
+        .body(())?;
+    stream.send_response(resp).await?;
+
+    // The session ID is the ID of the CONNECT stream, which leads the header
+    // of every stream in the session.
+    let (mut send, recv) = quic.open_bi().await?;
+    let mut header = BytesMut::new();
+    VarInt::from_u32(WEBTRANSPORT_STREAM).encode(&mut header);
+    VarInt::from(stream.id()).encode(&mut header);
+    send.write_all(&header).await?;
+
+    let span = info_span!("wt", session = %name, user_id = field::Empty);
+    let errors = state.errors().clone();
+    let guard_name = Some(name.clone());
+    let task = async {
+        let _permit = permit;
+        let mut transport = WtTransport {
+            send,
+            recv,
+            connect: stream,
+        };
+        let client = Client {
+            peer,
+            subject: None,
+            grant,
+        };
+        let result = tokio::select! {
+            result = handle_socket(&mut transport, state, &name, client, session) => result,
+            // Keep reading the control stream, and end the session with the
+            // connection if the client goes away.
+            _ = drain_requests(conn) => Ok(()),
+        };
+        if let Err(err) = result {
+            warn!(?err, "webtransport exiting early");
+            if is_message_too_large(&err) {
+                let reason = "message too large";
+                (transport
+                    .close_with(close_codes::MESSAGE_TOO_LARGE, reason)
+                    .await)
+                    .ok();
+            }
+        }
+        transport.connect.finish().await.ok();
+    };
+    errors.guard(guard_name, task).instrument(span).await;
+    Ok(())
+}
+
+/// Refuse a request with an HTTP error, so the client uses a WebSocket.
+async fn refuse<S>(stream: &mut RequestStream<S, Bytes>, status: StatusCode) -> Result<()>
+where
+    S: h3::quic::BidiStream<Bytes>,
+{
+    let resp = http::Response::builder().status(status).body(())?;
+    stream.send_response(resp).await?;
+    stream.finish().await?;
+    Ok(())
+}
+
+/// Read requests until the HTTP/3 connection closes, refusing any further
+/// sessions since only one is allowed per connection.
+async fn drain_requests(conn: &mut h3::server::Connection<h3_quinn::Connection, Bytes>) {
+    while let Ok(Some(resolver)) = conn.accept().await {
+        if let Ok((_, mut stream)) = resolver.resolve_request().await {
+            refuse(&mut stream, StatusCode::TOO_MANY_REQUESTS)
+                .await
+                .ok();
+        }
+    }
+}
+
+/// Returns the subject of the client's TLS certificate, if it presented one.
+fn peer_cert_subject(quic: &quinn::Connection) -> Option<String> {
+    let certs = quic.peer_identity()?;
+    let certs = certs.downcast::<Vec<CertificateDer<'static>>>().ok()?;
+    tls::cert_subject(certs.first()?)
+}
+
+/// A WebTransport session with a web client, over one bidirectional stream.
+struct WtTransport {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    /// Stream of the CONNECT request, which carries the close capsule.
+    connect: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+}
+
+#[axum::async_trait]
+impl Transport for WtTransport {
+    async fn send(&mut self, msg: WsServer) -> Result<()> {
+        let mut buf = vec![0; 4];
+        ciborium::ser::into_writer(&msg, &mut buf)?;
+        let len = u32::try_from(buf.len() - 4)?;
+        buf[..4].copy_from_slice(&len.to_be_bytes());
+        self.send.write_all(&buf).await?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Option<WsClient>> {
+        let mut len = [0; 4];
+        match self.recv.read_exact(&mut len).await {
+            Ok(()) => (),
+            Err(ReadExactError::FinishedEarly(0)) => return Ok(None),
+            Err(ReadExactError::ReadError(ReadError::ConnectionLost(_) | ReadError::Reset(_))) => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err.into()),
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            // Reported like an oversized WebSocket message.
+            let err = CapacityError::MessageTooLong {
+                size: len,
+                max_size: MAX_MESSAGE_SIZE,
+            };
+            return Err(WsError::Capacity(err).into());
+        }
+        let mut buf = vec![0; len];
+        self.recv.read_exact(&mut buf).await?;
+        Ok(Some(ciborium::de::from_reader(&*buf)?))
+    }
+
+    async fn close_with(&mut self, code: u16, reason: &str) -> Result<()> {
+        let mut end = reason.len().min(MAX_CLOSE_REASON);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        let reason = &reason[..end];
+        let mut capsule = BytesMut::new();
+        VarInt::from_u32(CLOSE_SESSION_CAPSULE).encode(&mut capsule);
+        VarInt::from_u32(4 + reason.len() as u32).encode(&mut capsule);
+        capsule.put_u32(code.into());
+        capsule.put_slice(reason.as_bytes());
+        self.connect.send_data(capsule.freeze()).await?;
+        self.connect.finish().await?;
+        Ok(())
+    }
+}
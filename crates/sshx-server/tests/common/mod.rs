@@ -4,15 +4,19 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{ensure, Result};
+use bytes::Bytes;
 use futures_util::{SinkExt, StreamExt};
 use hyper::{server::conn::AddrIncoming, StatusCode};
 use sshx::encrypt::Encrypt;
-use sshx_core::proto::sshx_service_client::SshxServiceClient;
+use sshx_core::proto::{sshx_service_client::SshxServiceClient, OpenRequest};
 use sshx_core::{Sid, Uid};
 use sshx_server::{
     state::ServerState,
-    web::protocol::{WsClient, WsServer, WsUser, WsWinsize},
-    Server,
+    web::protocol::{
+        ColorScheme, PaletteItem, ShellEnvironment, WsCapabilities, WsClient, WsNotes, WsServer,
+        WsShellClosed, WsShellHealth, WsStreamKind, WsUser, WsWinsize, PROTOCOL_VERSION,
+    },
+    Server, ServerOptions,
 };
 use tokio::net::{TcpListener, TcpStream};
 use tokio::time;
@@ -31,19 +35,12 @@ impl TestServer {
     /// Returns an object with the local address, as well as a custom [`Drop`]
     /// implementation that gracefully shuts down the server.
     pub async fn new() -> Self {
-        let listener = TcpListener::bind("[::1]:0").await.unwrap();
-        let local_addr = listener.local_addr().unwrap();
-
-        let incoming = AddrIncoming::from_listener(listener).unwrap();
-        let server = Arc::new(Server::new(Default::default()).unwrap());
-        {
-            let server = Arc::clone(&server);
-            tokio::spawn(async move {
-                server.listen(incoming).await.unwrap();
-            });
-        }
+        Self::builder().start().await
+    }
 
-        TestServer { local_addr, server }
+    /// Returns a builder for a test server with non-default options.
+    pub fn builder() -> TestServerBuilder {
+        TestServerBuilder::default()
     }
 
     /// Returns the local TCP address of this server.
@@ -66,10 +63,20 @@ impl TestServer {
         SshxServiceClient::connect(self.endpoint()).await.unwrap()
     }
 
+    /// Returns the server, to listen on more addresses.
+    pub fn server(&self) -> Arc<Server> {
+        Arc::clone(&self.server)
+    }
+
     /// Return the current server state object.
     pub fn state(&self) -> Arc<ServerState> {
         self.server.state()
     }
+
+    /// Send a graceful shutdown signal to the server.
+    pub fn shutdown(&self) {
+        self.server.shutdown();
+    }
 }
 
 impl Drop for TestServer {
@@ -78,6 +85,87 @@ impl Drop for TestServer {
     }
 }
 
+/// Builder for a [`TestServer`] with custom options.
+#[derive(Default)]
+pub struct TestServerBuilder {
+    options: ServerOptions,
+}
+
+impl TestServerBuilder {
+    /// Set the secret used for signing tokens.
+    pub fn secret(mut self, secret: &str) -> Self {
+        self.options.secret = Some(secret.into());
+        self
+    }
+
+    /// Override the origin returned by the Open() RPC.
+    pub fn override_origin(mut self, origin: &str) -> Self {
+        self.options.override_origin = Some(origin.into());
+        self
+    }
+
+    /// Store sessions in Redis, as one server of a mesh that the others reach
+    /// at its local address.
+    pub fn redis(mut self, url: &str) -> Self {
+        self.options.redis_url = Some(url.into());
+        self
+    }
+
+    /// Enable the admin API with a bearer token.
+    pub fn admin_token(mut self, token: &str) -> Self {
+        self.options.admin_token = Some(token.into());
+        self
+    }
+
+    /// Change any other options, such as limits.
+    pub fn options(mut self, f: impl FnOnce(&mut ServerOptions)) -> Self {
+        f(&mut self.options);
+        self
+    }
+
+    /// Start the server, listening on an unused local port.
+    pub async fn start(mut self) -> TestServer {
+        let listener = TcpListener::bind("[::1]:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        if self.options.redis_url.is_some() {
+            self.options.host.get_or_insert(local_addr.to_string());
+        }
+
+        let incoming = AddrIncoming::from_listener(listener).unwrap();
+        let server = Arc::new(Server::new(self.options).unwrap());
+        {
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                server.listen(incoming).await.unwrap();
+            });
+        }
+
+        TestServer { local_addr, server }
+    }
+}
+
+/// Redis server for tests of a mesh of servers, from the `SSHX_TEST_REDIS`
+/// environment variable. Those tests are skipped if it is not set, with a
+/// message so that they do not pass silently.
+pub fn test_redis_url() -> Option<String> {
+    let url = std::env::var("SSHX_TEST_REDIS").ok();
+    if url.is_none() {
+        let test = std::thread::current().name().unwrap_or("test").to_string();
+        eprintln!("skipping {test}: set SSHX_TEST_REDIS to run it against Redis");
+    }
+    url
+}
+
+/// Request to open a session from `sshx.io`, encrypted with this key. Tests
+/// override the other fields that they care about.
+pub fn open_request(encrypt: &Encrypt) -> OpenRequest {
+    OpenRequest {
+        origin: "sshx.io".into(),
+        encrypted_zeros: encrypt.zeros().into(),
+        ..Default::default()
+    }
+}
+
 /// A WebSocket client that interacts with the server, used for testing.
 pub struct ClientSocket {
     inner: WebSocketStream<MaybeTlsStream<TcpStream>>,
@@ -85,11 +173,23 @@ pub struct ClientSocket {
     write_encrypt: Option<Encrypt>,
 
     pub user_id: Uid,
+    pub capabilities: WsCapabilities,
     pub users: BTreeMap<Uid, WsUser>,
     pub shells: BTreeMap<Sid, WsWinsize>,
     pub data: HashMap<Sid, String>,
+    pub times: HashMap<Sid, Vec<u64>>,
     pub messages: Vec<(Uid, String, String)>,
     pub errors: Vec<String>,
+    pub notices: Vec<String>,
+    pub banner: Option<String>,
+    pub throttled: Vec<(Sid, u64)>,
+    pub closed: Vec<(Sid, WsShellClosed)>,
+    pub health: HashMap<Sid, WsShellHealth>,
+    pub streams: Vec<(u32, WsStreamKind, Vec<u8>)>,
+    pub notes: WsNotes,
+    pub palette: Vec<PaletteItem>,
+    pub color_scheme: Option<ColorScheme>,
+    pub environment: Option<ShellEnvironment>,
 }
 
 impl ClientSocket {
@@ -103,11 +203,23 @@ impl ClientSocket {
             encrypt: Encrypt::new(key),
             write_encrypt: write_password.map(Encrypt::new),
             user_id: Uid(0),
+            capabilities: WsCapabilities::default(),
             users: BTreeMap::new(),
             shells: BTreeMap::new(),
             data: HashMap::new(),
+            times: HashMap::new(),
             messages: Vec::new(),
             errors: Vec::new(),
+            notices: Vec::new(),
+            banner: None,
+            throttled: Vec::new(),
+            closed: Vec::new(),
+            health: HashMap::new(),
+            streams: Vec::new(),
+            notes: WsNotes::default(),
+            palette: Vec::new(),
+            color_scheme: None,
+            environment: None,
         };
         this.authenticate().await;
         Ok(this)
@@ -117,8 +229,12 @@ impl ClientSocket {
         let encrypted_zeros = self.encrypt.zeros().into();
         let write_zeros = self.write_encrypt.as_ref().map(|e| e.zeros().into());
 
-        self.send(WsClient::Authenticate(encrypted_zeros, write_zeros))
-            .await;
+        self.send(WsClient::Authenticate(
+            encrypted_zeros,
+            write_zeros,
+            Some(PROTOCOL_VERSION),
+        ))
+        .await;
     }
 
     pub async fn send(&mut self, msg: WsClient) {
@@ -154,12 +270,37 @@ impl ClientSocket {
         }
     }
 
+    /// Skip over any other messages until the server closes the connection.
+    pub async fn expect_close_eventually(&mut self, code: u16) {
+        loop {
+            match self.inner.next().await.unwrap().unwrap() {
+                Message::Close(Some(frame)) => break assert_eq!(frame.code, code.into()),
+                Message::Close(None) => panic!("connection closed without a code"),
+                _ => (),
+            }
+        }
+    }
+
+    fn add_chunks(&mut self, id: Sid, seqnum: u64, chunks: Vec<Bytes>) {
+        let value = self.data.entry(id).or_default();
+        assert_eq!(seqnum, value.len() as u64);
+        for buf in chunks {
+            let plaintext =
+                self.encrypt
+                    .segment(0x100000000 | id.0 as u64, value.len() as u64, &buf);
+            value.push_str(std::str::from_utf8(&plaintext).unwrap());
+        }
+    }
+
     pub async fn flush(&mut self) {
         const FLUSH_DURATION: Duration = Duration::from_millis(50);
         let flush_task = async {
             while let Some(msg) = self.recv().await {
                 match msg {
-                    WsServer::Hello(user_id, _) => self.user_id = user_id,
+                    WsServer::Hello(user_id, _, _, capabilities) => {
+                        self.user_id = user_id;
+                        self.capabilities = capabilities;
+                    }
                     WsServer::InvalidAuth() => panic!("invalid authentication"),
                     WsServer::Users(users) => self.users = BTreeMap::from_iter(users),
                     WsServer::UserDiff(id, maybe_user) => {
@@ -169,24 +310,49 @@ impl ClientSocket {
                         }
                     }
                     WsServer::Shells(shells) => self.shells = BTreeMap::from_iter(shells),
-                    WsServer::Chunks(id, seqnum, chunks) => {
-                        let value = self.data.entry(id).or_default();
-                        assert_eq!(seqnum, value.len() as u64);
-                        for buf in chunks {
-                            let plaintext = self.encrypt.segment(
-                                0x100000000 | id.0 as u64,
-                                value.len() as u64,
-                                &buf,
-                            );
-                            value.push_str(std::str::from_utf8(&plaintext).unwrap());
-                        }
+                    WsServer::Chunks(id, seqnum, chunks) => self.add_chunks(id, seqnum, chunks),
+                    WsServer::TimedChunks(id, seqnum, chunks, times) => {
+                        self.add_chunks(id, seqnum, chunks);
+                        self.times.entry(id).or_default().extend(times);
                     }
                     WsServer::Hear(id, name, msg) => {
                         self.messages.push((id, name, msg));
                     }
                     WsServer::ShellLatency(_) => {}
+                    WsServer::Throughput(_) => {}
                     WsServer::Pong(_) => {}
                     WsServer::Error(err) => self.errors.push(err),
+                    WsServer::Notice(msg) => self.notices.push(msg),
+                    WsServer::Banner(msg) => self.banner = Some(msg),
+                    WsServer::ShellClosed(id, closed) => self.closed.push((id, closed)),
+                    WsServer::ShellHealth(id, health) => {
+                        self.health.insert(id, health);
+                    }
+                    WsServer::Throttled(id, wait) => self.throttled.push((id, wait)),
+                    WsServer::Stream(id, kind, data, offset) => {
+                        let stream_num = 0x300000000 | id as u64;
+                        let plaintext = self.encrypt.segment(stream_num, offset, &data);
+                        self.streams.push((id, kind, plaintext));
+                    }
+                    WsServer::Notes(notes) => self.notes = notes,
+                    WsServer::NotesEdit(version, edit) => {
+                        let notes = &mut self.notes;
+                        if version == notes.version + notes.edits.len() as u64 + 1 {
+                            notes.edits.push(edit);
+                        }
+                    }
+                    WsServer::Palette(data, offset) => {
+                        let plaintext = self.encrypt.segment(0x600000000, offset, &data);
+                        self.palette = serde_json::from_slice(&plaintext).unwrap();
+                    }
+                    WsServer::ColorScheme(data) => {
+                        let plaintext = self.encrypt.segment(0x700000000, 0, &data);
+                        self.color_scheme = Some(serde_json::from_slice(&plaintext).unwrap());
+                    }
+                    WsServer::Environment(data) => {
+                        let plaintext = self.encrypt.segment(0x800000000, 0, &data);
+                        self.environment = Some(serde_json::from_slice(&plaintext).unwrap());
+                    }
                 }
             }
         };
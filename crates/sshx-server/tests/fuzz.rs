@@ -0,0 +1,37 @@
+//! Replay inputs saved from fuzzing, which once crashed the server or cover
+//! interesting cases. New crashes found by `cargo fuzz` should be added to
+//! `fuzz/regressions/<target>` once they are fixed.
+
+use std::fs;
+use std::path::PathBuf;
+
+use sshx_server::fuzz;
+
+fn replay(target: &str, f: fn(&[u8])) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../../fuzz/regressions")
+        .join(target);
+    let mut count = 0;
+    for entry in fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        println!("replaying {}", path.display());
+        f(&fs::read(&path).unwrap());
+        count += 1;
+    }
+    assert!(count > 0, "no inputs in {}", dir.display());
+}
+
+#[test]
+fn ws_client_regressions() {
+    replay("ws_client", fuzz::ws_client);
+}
+
+#[test]
+fn client_update_regressions() {
+    replay("client_update", fuzz::client_update);
+}
+
+#[test]
+fn snapshot_regressions() {
+    replay("snapshot", fuzz::snapshot);
+}
@@ -0,0 +1,189 @@
+//! Property tests for the bookkeeping of terminal data in sessions.
+//!
+//! Each byte of generated data is determined by its offset in the terminal
+//! stream, so any chunk that is stored or sent at the wrong offset is caught.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::{FutureExt, StreamExt};
+use proptest::prelude::*;
+use sshx_core::{Sid, Uid};
+use sshx_server::session::{Metadata, Session};
+use sshx_server::web::protocol::WsServer;
+
+/// Maximum bytes of terminal data stored by the server for each shell.
+const SHELL_STORED_BYTES: u64 = 1 << 21;
+
+/// Maximum bytes of terminal data kept for each shell in snapshots.
+const SHELL_SNAPSHOT_BYTES: u64 = 1 << 15;
+
+/// Number of samples in the rolling window for the output rate of shells.
+const THROUGHPUT_SAMPLES: usize = 5;
+
+/// Terminal data from an offset in the stream.
+fn stream_data(seq: u64, len: u64) -> Bytes {
+    (seq..seq + len).map(|i| (i % 251) as u8).collect()
+}
+
+fn new_session() -> Session {
+    let session = Session::new(Metadata {
+        encrypted_zeros: Bytes::new(),
+        name: String::new(),
+        write_password_hash: None,
+        write_password_salt: None,
+        color_scheme: None,
+        environment: None,
+        client_key: None,
+        token_nonce: Bytes::new(),
+    });
+    session.add_shell(Sid(1), (0, 0)).unwrap();
+    session
+}
+
+/// Add data to a session from a list of `(offset, length)` pairs, where the
+/// offset is relative to the current sequence number, returning the number of
+/// chunks that were accepted.
+fn add_all(session: &Session, ops: &[(i64, u64)]) -> u64 {
+    let mut seqnum: u64 = 0;
+    let mut chunks = 0;
+    for (i, &(offset, len)) in ops.iter().enumerate() {
+        let seq = seqnum.saturating_add_signed(offset);
+        session
+            .add_data(Sid(1), stream_data(seq, len), seq, i as u64)
+            .unwrap();
+        if seq <= seqnum && seq + len > seqnum {
+            seqnum = seq + len;
+            chunks += 1;
+        }
+    }
+    assert_eq!(session.sequence_numbers().map[&1], seqnum);
+    chunks
+}
+
+/// Check that retained chunks are contiguous, end at the sequence number, and
+/// hold the right bytes, returning their total length.
+fn check_retained(session: &Session) -> u64 {
+    let retained = session.retained_chunks();
+    assert_eq!(retained.len(), 1);
+    let (_, byte_offset, chunks, times) = &retained[0];
+    assert_eq!(chunks.len(), times.len());
+    let mut offset = *byte_offset;
+    for chunk in chunks {
+        assert!(!chunk.is_empty());
+        assert_eq!(chunk, &stream_data(offset, chunk.len() as u64));
+        offset += chunk.len() as u64;
+    }
+    assert_eq!(offset, session.sequence_numbers().map[&1]);
+    offset - byte_offset
+}
+
+fn ops() -> impl Strategy<Value = Vec<(i64, u64)>> {
+    // Mostly new data, with some overlapping, stale and out-of-order writes.
+    let op = (
+        prop_oneof![4 => Just(0i64), 1 => -(1i64 << 16)..0, 1 => 1i64..(1 << 10)],
+        1u64..(1 << 17),
+    );
+    prop::collection::vec(op, 0..48)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn add_data_keeps_contiguous_suffix(ops in ops()) {
+        let session = new_session();
+        add_all(&session, &ops);
+        let stored = check_retained(&session);
+        prop_assert!(stored <= SHELL_STORED_BYTES);
+    }
+
+    #[test]
+    fn subscribe_chunks_from_any_index(ops in ops(), extra in 0u64..3) {
+        let session = new_session();
+        let total = add_all(&session, &ops);
+        let (_, byte_offset, chunks, times) = session.retained_chunks().remove(0);
+        let chunk_offset = total - chunks.len() as u64;
+
+        for chunknum in 0..total + extra {
+            let mut stream = Box::pin(session.subscribe_chunks(Sid(1), chunknum));
+            let first = stream.next().now_or_never();
+            if chunknum >= total {
+                prop_assert!(first.is_none());
+                continue;
+            }
+            let (seqnum, sent, sent_times) = first.flatten().unwrap();
+            let start = (chunknum.max(chunk_offset) - chunk_offset) as usize;
+            let skipped: u64 = chunks[..start].iter().map(|c| c.len() as u64).sum();
+            prop_assert_eq!(seqnum, byte_offset + skipped);
+            prop_assert_eq!(&sent[..], &chunks[start..]);
+            prop_assert_eq!(&sent_times[..], &times[start..]);
+        }
+    }
+
+    #[test]
+    fn snapshot_restore_is_equivalent(ops in ops()) {
+        let session = new_session();
+        add_all(&session, &ops);
+        let snapshot = session.snapshot().unwrap();
+        let restored = Session::restore(&snapshot).unwrap();
+
+        prop_assert_eq!(restored.sequence_numbers(), session.sequence_numbers());
+        prop_assert_eq!(restored.list_shells(), session.list_shells());
+        let stored = check_retained(&restored);
+        prop_assert!(stored <= SHELL_SNAPSHOT_BYTES);
+        let (_, _, chunks, _) = restored.retained_chunks().remove(0);
+
+        // The restored data is a suffix of the original, at the same offsets.
+        let original = session.retained_chunks().remove(0).2;
+        prop_assert!(original.ends_with(&chunks));
+
+        // Restoring is idempotent.
+        prop_assert_eq!(restored.snapshot().unwrap(), snapshot);
+    }
+
+    #[test]
+    fn throughput_is_rolling_average(output in prop::collection::vec(0u64..5000, 1..20)) {
+        let session = new_session();
+        let mut updates = session.subscribe_broadcast();
+        let mut seqnum = 0;
+        for (i, &len) in output.iter().enumerate() {
+            session.add_data(Sid(1), stream_data(seqnum, len), seqnum, 0).unwrap();
+            seqnum += len;
+            session.sample_throughput(Duration::from_secs(1));
+
+            // The first sample only sets a baseline.
+            let window = i.min(THROUGHPUT_SAMPLES);
+            let expected = match window {
+                0 => 0,
+                _ => output[i + 1 - window..=i].iter().sum::<u64>() / window as u64,
+            };
+            prop_assert_eq!(session.throughput(), vec![(Sid(1), expected)]);
+            let update = updates.next().now_or_never().flatten().unwrap().unwrap();
+            let is_expected = matches!(
+                update,
+                WsServer::Throughput(throughput) if throughput == [(Sid(1), expected)]
+            );
+            prop_assert!(is_expected);
+        }
+    }
+}
+
+#[tokio::test]
+async fn subscribe_chunks_batches_with_many_users() {
+    let session = new_session();
+    let _users: Vec<_> = (1..=8)
+        .map(|id| session.user_scope(Uid(id), false).unwrap())
+        .collect();
+
+    // The second chunk arrives after the subscriber is notified of the first,
+    // but is still sent in the same batch.
+    let mut stream = Box::pin(session.subscribe_chunks(Sid(1), 0));
+    assert!(stream.next().now_or_never().is_none());
+    session.add_data(Sid(1), stream_data(0, 10), 0, 0).unwrap();
+    assert!(stream.next().now_or_never().is_none());
+    session.add_data(Sid(1), stream_data(10, 5), 10, 0).unwrap();
+    let (seqnum, chunks, _) = stream.next().await.unwrap();
+    assert_eq!(seqnum, 0);
+    assert_eq!(chunks, [stream_data(0, 10), stream_data(10, 5)]);
+}
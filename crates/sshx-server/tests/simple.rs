@@ -1,6 +1,17 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 use sshx::encrypt::Encrypt;
-use sshx_core::proto::*;
+use sshx_core::{proto::*, Uid};
+use sshx_server::names::{Alphabet, SessionNames};
+use sshx_server::report::ErrorSource;
+use sshx_server::utils::RateLimiter;
+use sshx_server::{oidc::OidcOptions, tls::TlsOptions, Server, ServerOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::time::{self, Duration};
 
 use crate::common::*;
 
@@ -11,18 +22,257 @@ async fn test_rpc() -> Result<()> {
     let server = TestServer::new().await;
     let mut client = server.grpc_client().await;
 
-    let req = OpenRequest {
-        origin: "sshx.io".into(),
-        encrypted_zeros: Encrypt::new("").zeros().into(),
-        name: String::new(),
-        write_password_hash: None,
-    };
+    let req = open_request(&Encrypt::new(""));
     let resp = client.open(req).await?;
     assert!(!resp.into_inner().name.is_empty());
 
     Ok(())
 }
 
+#[tokio::test]
+async fn test_open_idempotency_key() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let req = OpenRequest {
+        idempotency_key: Some("retry-key".into()),
+        ..open_request(&Encrypt::new(""))
+    };
+    let first = client.open(req.clone()).await?.into_inner();
+    let retry = client.open(req.clone()).await?.into_inner();
+    assert_eq!(first, retry);
+    assert_eq!(server.state().sessions().len(), 1);
+
+    // The key cannot be used to get the token of a session with another key.
+    let other = OpenRequest {
+        encrypted_zeros: Encrypt::new("other").zeros().into(),
+        ..req
+    };
+    let status = client.open(other).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::AlreadyExists);
+
+    // Concurrent requests with a key never open two sessions.
+    let req = OpenRequest {
+        idempotency_key: Some("concurrent-key".into()),
+        ..open_request(&Encrypt::new(""))
+    };
+    let mut other_client = server.grpc_client().await;
+    let (first, second) = tokio::join!(client.open(req.clone()), other_client.open(req));
+    let names: Vec<_> = [first, second]
+        .into_iter()
+        .filter_map(|resp| resp.ok().map(|resp| resp.into_inner().name))
+        .collect();
+    assert!(!names.is_empty());
+    assert!(names.iter().all(|name| *name == names[0]));
+    assert_eq!(server.state().sessions().len(), 2);
+
+    // A key is released if its request fails, so it can be retried.
+    let taken = OpenRequest {
+        idempotency_key: Some("failed-key".into()),
+        session_name: Some(names[0].clone()),
+        ..open_request(&Encrypt::new(""))
+    };
+    let status = client.open(taken.clone()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::AlreadyExists);
+    let retry = OpenRequest {
+        session_name: None,
+        ..taken
+    };
+    client.open(retry).await?;
+    assert_eq!(server.state().sessions().len(), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mesh_open_idempotency_key() -> Result<()> {
+    let Some(redis) = test_redis_url() else {
+        return Ok(());
+    };
+    let first = TestServer::builder().redis(&redis).start().await;
+    let second = TestServer::builder().redis(&redis).start().await;
+
+    // Keys are shared by the mesh, so a retry to another server does not open
+    // a second session.
+    let key = format!("mesh-key-{}", sshx_core::rand_alphanumeric(16));
+    let req = OpenRequest {
+        idempotency_key: Some(key),
+        ..open_request(&Encrypt::new(""))
+    };
+    first.grpc_client().await.open(req.clone()).await?;
+    let status = second.grpc_client().await.open(req).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::AlreadyExists);
+    assert!(second.state().sessions().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_override_origin() -> Result<()> {
+    let server = TestServer::builder()
+        .secret("3uTbQx8Kc2LwZpYa7RmN")
+        .override_origin("https://sshx.example.com")
+        .start()
+        .await;
+    let mut client = server.grpc_client().await;
+
+    let req = open_request(&Encrypt::new(""));
+    let resp = client.open(req).await?.into_inner();
+    let url = format!("https://sshx.example.com/s/{}", resp.name);
+    assert_eq!(resp.url, url);
+    let session = server.state().lookup(&resp.name).unwrap();
+    let nonce = &session.metadata().token_nonce;
+    assert!(server
+        .state()
+        .check_session_token(&resp.name, nonce, &resp.token));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allowed_origins() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| options.allowed_origins = vec!["https://sshx.example.com".into()])
+        .start()
+        .await;
+    let mut client = server.grpc_client().await;
+
+    let req = |origin: &str| OpenRequest {
+        origin: origin.into(),
+        ..open_request(&Encrypt::new(""))
+    };
+    let resp = client
+        .open(req("https://sshx.example.com"))
+        .await?
+        .into_inner();
+    assert_eq!(
+        resp.url,
+        format!("https://sshx.example.com/s/{}", resp.name)
+    );
+
+    let status = client
+        .open(req("https://phishing.example"))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+    let mut options = ServerOptions::default();
+    options.allowed_origins = vec!["sshx.example.com".into()];
+    let err = Server::new(options).err().unwrap();
+    assert!(err.to_string().contains("invalid allowed origin"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_names() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| options.session_names = SessionNames::Words { count: 3 })
+        .start()
+        .await;
+    let mut client = server.grpc_client().await;
+
+    let req = open_request(&Encrypt::new(""));
+    let resp = client.open(req.clone()).await?.into_inner();
+    assert_eq!(resp.name.split('-').count(), 3);
+    assert!(resp.url.ends_with(&format!("/s/{}", resp.name)));
+
+    // Clients can ask for a name, as long as it is valid and not taken.
+    let custom = |name: &str| OpenRequest {
+        session_name: Some(name.into()),
+        ..req.clone()
+    };
+    let resp = client.open(custom("team-demo-day")).await?.into_inner();
+    assert_eq!(resp.name, "team-demo-day");
+    assert!(resp.url.ends_with("/s/team-demo-day"));
+    let status = client.open(custom("team-demo-day")).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::AlreadyExists);
+    for name in ["demo-day", "team demo day", "-team-demo", "~replay-team"] {
+        let status = client.open(custom(name)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    let name = SessionNames::Random {
+        alphabet: Alphabet::Crockford,
+        length: 12,
+    }
+    .generate();
+    assert_eq!(name.len(), 12);
+    assert!(name
+        .chars()
+        .all(|c| c.is_ascii_digit() || c.is_ascii_lowercase()));
+    assert!(!name.contains(['i', 'l', 'o', 'u']));
+
+    let mut options = ServerOptions::default();
+    options.session_names = SessionNames::Words { count: 1 };
+    assert!(Server::new(options).is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reused_session_name() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let req = OpenRequest {
+        session_name: Some("team-demo-day".into()),
+        ..open_request(&Encrypt::new(""))
+    };
+    let first = client.open(req.clone()).await?.into_inner();
+    let close = |token: &str| CloseRequest {
+        name: first.name.clone(),
+        token: token.into(),
+    };
+    client.close(close(&first.token)).await?;
+
+    // The token of the closed session does not work for the next one.
+    let second = client.open(req).await?.into_inner();
+    assert_eq!(second.name, first.name);
+    assert_ne!(second.token, first.token);
+    let status = client.close(close(&first.token)).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    client.close(close(&second.token)).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mesh_reused_session_name() -> Result<()> {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let Some(redis) = test_redis_url() else {
+        return Ok(());
+    };
+    let first = TestServer::builder().redis(&redis).start().await;
+    let second = TestServer::builder().redis(&redis).start().await;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let req = OpenRequest {
+        session_name: Some(format!("mesh-reclaim-{}", now.as_micros())),
+        ..open_request(&Encrypt::new(""))
+    };
+    let resp = first
+        .grpc_client()
+        .await
+        .open(req.clone())
+        .await?
+        .into_inner();
+    let close = CloseRequest {
+        name: resp.name.clone(),
+        token: resp.token,
+    };
+    first.grpc_client().await.close(close).await?;
+
+    // Another server can claim the name once the session is closed, and the
+    // closed marker no longer hides the new session from the mesh.
+    second.grpc_client().await.open(req).await?;
+    let owner = first.state().frontend_connect(&resp.name).await?;
+    assert_eq!(owner.err(), Some(Some(second.local_addr().to_string())));
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_web_get() -> Result<()> {
     let server = TestServer::new().await;
@@ -32,3 +282,746 @@ async fn test_web_get() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_metrics() -> Result<()> {
+    let server = TestServer::builder().admin_token("hunter2").start().await;
+    let mut client = server.grpc_client().await;
+
+    let req = open_request(&Encrypt::new(""));
+    client.open(req).await?;
+
+    // Metrics need the admin token, unless they are public.
+    let url = format!("{}/metrics", server.endpoint());
+    let http = reqwest::Client::new();
+    assert_eq!(http.get(&url).send().await?.status(), 401);
+    let resp = http.get(&url).bearer_auth("hunter2").send().await?;
+    assert!(resp.status().is_success());
+    let text = resp.text().await?;
+    assert!(text.contains("sshx_sessions 1"));
+    assert!(text.contains("sshx_ws_connections 0"));
+    assert!(text.contains("sshx_sessions_created_total 1"));
+    assert!(text.contains("sshx_sessions_dangling 0"));
+
+    let resp = http
+        .get(&url)
+        .bearer_auth("hunter2")
+        .header("accept-encoding", "gzip")
+        .send()
+        .await?;
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+
+    let server = TestServer::new().await;
+    let resp = reqwest::get(format!("{}/metrics", server.endpoint())).await?;
+    assert_eq!(resp.status(), 404);
+    let server = TestServer::builder()
+        .options(|options| options.public_metrics = true)
+        .start()
+        .await;
+    let resp = reqwest::get(format!("{}/metrics", server.endpoint())).await?;
+    assert!(resp.status().is_success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_api() -> Result<()> {
+    let server = TestServer::builder().admin_token("hunter2").start().await;
+    let mut client = server.grpc_client().await;
+
+    let req = open_request(&Encrypt::new(""));
+    let name = client.open(req).await?.into_inner().name;
+
+    let http = reqwest::Client::new();
+    let admin = |path: &str| format!("{}/api/admin{path}", server.endpoint());
+
+    let resp = http.get(admin("/sessions")).send().await?;
+    assert_eq!(resp.status(), 401);
+    let resp = http
+        .get(admin("/sessions"))
+        .bearer_auth("wrong")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 401);
+
+    let resp = http
+        .get(admin("/stats"))
+        .bearer_auth("hunter2")
+        .send()
+        .await?;
+    assert!(resp.status().is_success());
+    assert!(resp.text().await?.contains(r#""sessions":1"#));
+
+    let resp = http
+        .get(admin("/sessions"))
+        .bearer_auth("hunter2")
+        .send()
+        .await?;
+    assert!(resp.text().await?.contains(&name));
+
+    let resp = http
+        .get(admin(&format!("/sessions/{name}")))
+        .bearer_auth("hunter2")
+        .send()
+        .await?;
+    assert!(resp
+        .text()
+        .await?
+        .contains(r#""sequenceNumbers":{"map":{}}"#));
+
+    // Without a mesh, there are no other servers to probe.
+    let resp = http
+        .get(admin("/mesh"))
+        .bearer_auth("hunter2")
+        .send()
+        .await?;
+    assert_eq!(resp.text().await?, r#"{"host":null,"peers":[]}"#);
+    let resp = http
+        .get(format!("{}/api/mesh/ping", server.endpoint()))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 204);
+
+    let resp = http
+        .delete(admin(&format!("/sessions/{name}")))
+        .bearer_auth("hunter2")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 204);
+    assert!(server.state().lookup(&name).is_none());
+
+    // The closed session was freed, so nothing leaked.
+    let resp = http
+        .get(admin("/debug/leaks"))
+        .bearer_auth("hunter2")
+        .send()
+        .await?;
+    let text = resp.text().await?;
+    assert!(text.contains(r#""sessionsCreated":1"#));
+    assert!(text.contains(r#""sessionsDropped":1"#));
+    assert!(text.contains(r#""sessionsDangling":0"#));
+
+    let resp = http
+        .get(admin(&format!("/sessions/{name}")))
+        .bearer_auth("hunter2")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_disabled() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let resp = reqwest::Client::new()
+        .get(format!("{}/api/admin/stats", server.endpoint()))
+        .bearer_auth("")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_audit_log() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("sshx-audit-{}.log", std::process::id()));
+    let server = TestServer::builder()
+        .options(|options| options.audit_file = Some(path.clone()))
+        .start()
+        .await;
+    let mut client = server.grpc_client().await;
+
+    let req = open_request(&Encrypt::new(""));
+    let name = client.open(req).await?.into_inner().name;
+
+    let log = std::fs::read_to_string(&path)?;
+    std::fs::remove_file(&path)?;
+    assert!(log.contains(r#""event":"session_created""#));
+    assert!(log.contains(&format!(r#""session":"{name}""#)));
+    assert!(log.contains(r#""ip":"::1""#));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_oidc_required() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| {
+            options.oidc = Some(OidcOptions {
+                issuer: "https://login.invalid".into(),
+                client_id: "sshx".into(),
+                client_secret: "secret".into(),
+                redirect_url: "https://sshx.invalid/api/auth/callback".into(),
+            });
+        })
+        .start()
+        .await;
+
+    let http = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+    let resp = http
+        .get(format!("{}/s/abc", server.endpoint()))
+        .send()
+        .await?;
+    assert!(resp.status().is_redirection());
+    assert_eq!(
+        resp.headers()["location"],
+        "/api/auth/login?next=%2Fs%2Fabc"
+    );
+
+    assert!(ClientSocket::connect(&server.ws_endpoint("abc"), "", None)
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_security_headers() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let resp = reqwest::get(server.endpoint()).await?;
+    let headers = resp.headers();
+    assert_eq!(headers["x-content-type-options"], "nosniff");
+    assert!(!headers.contains_key("strict-transport-security"));
+    let csp = headers["content-security-policy"].to_str()?;
+    assert!(csp.contains("frame-ancestors 'none'"));
+
+    // HSTS is only sent over HTTPS, including through a proxy.
+    let resp = reqwest::Client::new()
+        .get(server.endpoint())
+        .header("x-forwarded-proto", "https")
+        .send()
+        .await?;
+    assert!(resp.headers().contains_key("strict-transport-security"));
+
+    let server = TestServer::builder()
+        .options(|options| options.content_security_policy = Some(String::new()))
+        .start()
+        .await;
+
+    let resp = reqwest::get(server.endpoint()).await?;
+    assert!(!resp.headers().contains_key("content-security-policy"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_request_id() -> Result<()> {
+    let server = TestServer::new().await;
+    let http = reqwest::Client::new();
+
+    let resp = http.get(server.endpoint()).send().await?;
+    let id = resp.headers()["x-request-id"].to_str()?;
+    assert_eq!(id.len(), 16);
+
+    let resp = http
+        .get(server.endpoint())
+        .header("x-request-id", "trace-abc.123")
+        .send()
+        .await?;
+    assert_eq!(resp.headers()["x-request-id"], "trace-abc.123");
+
+    // Malformed IDs from clients are replaced.
+    let resp = http
+        .get(server.endpoint())
+        .header("x-request-id", "bad id!")
+        .send()
+        .await?;
+    assert_ne!(resp.headers()["x-request-id"], "bad id!");
+
+    let mut client = server.grpc_client().await;
+    let req = open_request(&Encrypt::new(""));
+    let resp = client.open(req).await?;
+    assert!(resp.metadata().contains_key("x-request-id"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_base_path() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| {
+            options.base_path = Some("/sshx/".into());
+            options.public_metrics = true;
+        })
+        .start()
+        .await;
+    let mut client = server.grpc_client().await;
+
+    let req = OpenRequest {
+        origin: "https://example.com".into(),
+        ..open_request(&Encrypt::new(""))
+    };
+    let resp = client.open(req).await?.into_inner();
+    let name = resp.name;
+    assert_eq!(resp.url, format!("https://example.com/sshx/s/{name}"));
+
+    let resp = reqwest::get(format!("{}/sshx/metrics", server.endpoint())).await?;
+    assert!(resp.status().is_success());
+    let resp = reqwest::get(format!("{}/metrics", server.endpoint())).await?;
+    assert_eq!(resp.status(), 404);
+
+    let uri = format!("ws://{}/sshx/api/s/{name}", server.local_addr());
+    let mut s = ClientSocket::connect(&uri, "", None).await?;
+    s.flush().await;
+    assert_eq!(s.user_id, Uid(1));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_url_template() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| {
+            options.session_url = Some("{origin}/view?session={name}".into());
+            options.omit_write_password = true;
+        })
+        .start()
+        .await;
+    let mut client = server.grpc_client().await;
+
+    let req = OpenRequest {
+        origin: "https://example.com".into(),
+        ..open_request(&Encrypt::new(""))
+    };
+    let resp = client.open(req).await?.into_inner();
+    let name = resp.name;
+    assert_eq!(resp.url, format!("https://example.com/view?session={name}"));
+    assert!(resp.omit_write_password);
+
+    Ok(())
+}
+
+#[test]
+fn test_invalid_session_url_template() {
+    for template in ["{origin}/s/", "{origin}/s/{name}#key"] {
+        let mut options = ServerOptions::default();
+        options.session_url = Some(template.into());
+        assert!(Server::new(options).is_err());
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("sshx-test-{}.sock", std::process::id()));
+    // Peers on the socket have no address, so IP rules and limits do not apply.
+    let mut options = ServerOptions::default();
+    options.allow_ips = vec!["10.0.0.0/8".parse()?];
+    options.request_rate_limit = Some(1);
+    let server = Arc::new(Server::new(options)?);
+    {
+        let server = Arc::clone(&server);
+        let path = path.clone();
+        tokio::spawn(async move { server.bind_unix(&path).await.unwrap() });
+    }
+    time::sleep(Duration::from_millis(100)).await;
+
+    for _ in 0..3 {
+        let mut stream = UnixStream::connect(&path).await?;
+        stream
+            .write_all(
+                b"GET /api/mesh/ping HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await?;
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await?;
+        assert!(resp.starts_with("HTTP/1.1 204 No Content"));
+    }
+
+    server.shutdown();
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_limit() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| options.max_connections = Some(1))
+        .start()
+        .await;
+    let url = format!("{}/api/mesh/ping", server.endpoint());
+
+    // A second connection waits to be accepted while the first one is open.
+    let held = TcpStream::connect(server.local_addr()).await?;
+    time::sleep(Duration::from_millis(50)).await;
+    let client = reqwest::Client::new();
+    let result = client
+        .get(&url)
+        .timeout(Duration::from_millis(300))
+        .send()
+        .await;
+    assert!(result.is_err());
+
+    drop(held);
+    let resp = client.get(&url).send().await?;
+    assert!(resp.status().is_success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_idle_timeout() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| options.idle_timeout = Some(Duration::from_millis(200)))
+        .start()
+        .await;
+
+    let mut stream = TcpStream::connect(server.local_addr()).await?;
+    let mut buf = Vec::new();
+    let read = time::timeout(Duration::from_secs(2), stream.read_to_end(&mut buf)).await?;
+    assert!(read.is_err() || buf.is_empty());
+
+    let resp = reqwest::get(format!("{}/api/mesh/ping", server.endpoint())).await?;
+    assert!(resp.status().is_success());
+
+    Ok(())
+}
+
+/// Create a certificate for `sshx.test` with the given common name.
+fn test_cert(name: &str, ca: bool) -> Result<rcgen::Certificate> {
+    use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa};
+
+    let mut params = CertificateParams::new(vec!["sshx.test".into()]);
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, name);
+    if ca {
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    }
+    Ok(rcgen::Certificate::from_params(params)?)
+}
+
+/// Returns an HTTP client that trusts a CA and resolves `sshx.test` to a
+/// test server.
+fn tls_client(server: &TestServer, ca: &rcgen::Certificate) -> Result<reqwest::ClientBuilder> {
+    let root = reqwest::Certificate::from_pem(ca.serialize_pem()?.as_bytes())?;
+    Ok(reqwest::Client::builder()
+        .add_root_certificate(root)
+        .resolve("sshx.test", server.local_addr()))
+}
+
+#[tokio::test]
+async fn test_tls_client_cert() -> Result<()> {
+    let ca = test_cert("Test CA", true)?;
+    let server_cert = test_cert("sshx.test", false)?;
+    let client_cert = test_cert("alice", false)?;
+
+    let dir = std::env::temp_dir().join(format!("sshx-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("ca.pem"), ca.serialize_pem()?)?;
+    std::fs::write(
+        dir.join("cert.pem"),
+        server_cert.serialize_pem_with_signer(&ca)?,
+    )?;
+    std::fs::write(dir.join("key.pem"), server_cert.serialize_private_key_pem())?;
+
+    let server = TestServer::builder()
+        .admin_token("hunter2")
+        .options(|options| {
+            options.audit_file = Some(dir.join("audit.log"));
+            options.tls = Some(TlsOptions {
+                cert: dir.join("cert.pem"),
+                key: dir.join("key.pem"),
+                client_ca: Some(dir.join("ca.pem")),
+            });
+        })
+        .start()
+        .await;
+    let url = format!(
+        "https://sshx.test:{}/api/admin/stats",
+        server.local_addr().port()
+    );
+
+    // Connections without a client certificate are rejected.
+    let result = tls_client(&server, &ca)?.build()?.get(&url).send().await;
+    assert!(result.is_err());
+
+    let pem =
+        client_cert.serialize_pem_with_signer(&ca)? + &client_cert.serialize_private_key_pem();
+    let identity = reqwest::Identity::from_pem(pem.as_bytes())?;
+    let http = tls_client(&server, &ca)?.identity(identity).build()?;
+    let resp = http.get(&url).bearer_auth("wrong").send().await?;
+    assert_eq!(resp.status(), 401);
+
+    let log = std::fs::read_to_string(dir.join("audit.log"))?;
+    std::fs::remove_dir_all(&dir)?;
+    assert!(log.contains(r#""cert_subject":"CN=alice""#));
+    assert!(log.contains(r#""event":"admin_auth_failed""#));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_reload() -> Result<()> {
+    let old_ca = test_cert("Old CA", true)?;
+    let new_ca = test_cert("New CA", true)?;
+    let server_cert = test_cert("sshx.test", false)?;
+
+    let dir = std::env::temp_dir().join(format!("sshx-tls-reload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(
+        dir.join("cert.pem"),
+        server_cert.serialize_pem_with_signer(&old_ca)?,
+    )?;
+    std::fs::write(dir.join("key.pem"), server_cert.serialize_private_key_pem())?;
+
+    let server = TestServer::builder()
+        .admin_token("hunter2")
+        .options(|options| {
+            options.tls = Some(TlsOptions {
+                cert: dir.join("cert.pem"),
+                key: dir.join("key.pem"),
+                client_ca: None,
+            });
+        })
+        .start()
+        .await;
+    let url = |path: &str| format!("https://sshx.test:{}{path}", server.local_addr().port());
+
+    let old_http = tls_client(&server, &old_ca)?.build()?;
+    let resp = old_http.get(url("/api/mesh/ping")).send().await?;
+    assert!(resp.status().is_success());
+    assert!(resp.headers().contains_key("strict-transport-security"));
+
+    // Renew the certificate, and then reload it over the existing connection.
+    std::fs::write(
+        dir.join("cert.pem"),
+        server_cert.serialize_pem_with_signer(&new_ca)?,
+    )?;
+    let resp = old_http
+        .post(url("/api/admin/tls/reload"))
+        .bearer_auth("hunter2")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 204);
+
+    let new_http = tls_client(&server, &new_ca)?.build()?;
+    let resp = new_http.get(url("/api/mesh/ping")).send().await?;
+    assert!(resp.status().is_success());
+    let result = tls_client(&server, &old_ca)?
+        .build()?
+        .get(url("/api/mesh/ping"))
+        .send()
+        .await;
+    assert!(result.is_err());
+
+    // A broken certificate is rejected, and the previous one stays in use.
+    std::fs::write(dir.join("cert.pem"), "not a certificate")?;
+    let resp = new_http
+        .post(url("/api/admin/tls/reload"))
+        .bearer_auth("hunter2")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 500);
+    std::fs::remove_dir_all(&dir)?;
+
+    let resp = tls_client(&server, &new_ca)?
+        .build()?
+        .get(url("/api/mesh/ping"))
+        .send()
+        .await?;
+    assert!(resp.status().is_success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_error_hook() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| options.redis_url = Some("redis://127.0.0.1:1".into()))
+        .start()
+        .await;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    server.state().errors().add_hook(move |report| {
+        tx.send(report.source).ok();
+    });
+
+    // Syncing the new session fails, since Redis is not reachable.
+    let mut client = server.grpc_client().await;
+    let req = open_request(&Encrypt::new(""));
+    client.open(req).await?;
+
+    let source = time::timeout(Duration::from_secs(5), rx.recv()).await?;
+    assert_eq!(source, Some(ErrorSource::Redis));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_request_rate_limit() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| options.request_rate_limit = Some(2))
+        .start()
+        .await;
+    let url = format!("{}/api/mesh/ping", server.endpoint());
+
+    let http = reqwest::Client::new();
+    let mut client = server.grpc_client().await;
+    let req = open_request(&Encrypt::new(""));
+    assert!(http.get(&url).send().await?.status().is_success());
+    assert!(http.get(&url).send().await?.status().is_success());
+
+    // The gRPC service shares the same limit as the web app.
+    let status = client.open(req).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    assert_eq!(http.get(&url).send().await?.status(), 429);
+
+    // Static assets of the web app are not limited.
+    let resp = http.get(server.endpoint()).send().await?;
+    assert_ne!(resp.status(), 429);
+
+    time::sleep(Duration::from_millis(600)).await;
+    assert!(http.get(&url).send().await?.status().is_success());
+
+    Ok(())
+}
+
+#[test]
+fn test_rate_limiter_ipv6() {
+    let limiter = RateLimiter::new(Some(1));
+    assert!(limiter.check("2001:db8::1".parse().unwrap()));
+
+    // Addresses in the same /64 network share a limit.
+    assert!(!limiter.check("2001:db8::2".parse().unwrap()));
+    assert!(limiter.check("2001:db8:0:1::1".parse().unwrap()));
+
+    // IPv4-mapped addresses count as the IPv4 address.
+    assert!(limiter.check("10.0.0.1".parse().unwrap()));
+    assert!(!limiter.check("::ffff:10.0.0.1".parse().unwrap()));
+}
+
+#[tokio::test]
+async fn test_max_sessions() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| options.max_sessions = Some(1))
+        .start()
+        .await;
+    let mut client = server.grpc_client().await;
+
+    let req = open_request(&Encrypt::new(""));
+    let resp = client.open(req.clone()).await?.into_inner();
+    let status = client.open(req.clone()).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+    let close = CloseRequest {
+        name: resp.name,
+        token: resp.token,
+    };
+    client.close(close).await?;
+
+    // Concurrent requests cannot both take the last slot.
+    let mut other_client = server.grpc_client().await;
+    let (first, second) = tokio::join!(client.open(req.clone()), other_client.open(req));
+    assert_eq!(usize::from(first.is_ok()) + usize::from(second.is_ok()), 1);
+    assert_eq!(server.state().sessions().len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_self_check() -> Result<()> {
+    let mut options = ServerOptions::default();
+    options.secret = Some("password".into());
+    let err = Server::new(options).err().unwrap();
+    assert!(err.to_string().contains("secret is too weak"));
+
+    let mut options = ServerOptions::default();
+    options.secret = Some("3uTbQx8Kc2LwZpYa".into());
+    options.override_origin = Some("sshx.example.com".into());
+    let err = Server::new(options).err().unwrap();
+    assert!(err.to_string().contains("invalid override origin"));
+
+    let mut options = ServerOptions::default();
+    options.override_origin = Some("https://sshx.example.com".into());
+    options.redis_url = Some("redis://127.0.0.1:1".into());
+    let server = Server::new(options)?;
+    let err = server.state().check_redis().await.unwrap_err();
+    assert!(err.to_string().contains("failed to reach Redis"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_embedded_routes() -> Result<()> {
+    use std::net::SocketAddr;
+
+    use axum::{routing::get, Router};
+    use base64::prelude::{Engine as _, BASE64_STANDARD};
+    use hyper::server::conn::AddrIncoming;
+    use sshx_core::proto::sshx_service_client::SshxServiceClient;
+    use sshx_server::{grpc, web};
+
+    let server = Server::new(ServerOptions::default())?;
+    server.start_background_tasks();
+
+    let app = Router::new()
+        .route("/", get(|| async { "host app" }))
+        .nest("/sshx", web::app("").with_state(server.state()));
+    let incoming = AddrIncoming::bind(&"[::1]:0".parse()?)?;
+    let web_addr = incoming.local_addr();
+    let make_svc = app.into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(hyper::Server::builder(incoming).serve(make_svc));
+
+    let listener = tokio::net::TcpListener::bind("[::1]:0").await?;
+    let grpc_addr = listener.local_addr()?;
+    let incoming = futures_util::stream::poll_fn(move |cx| {
+        let accepted = listener.poll_accept(cx);
+        accepted.map(|result| Some(result.map(|(stream, _)| stream)))
+    });
+    let grpc_server = tonic::transport::Server::builder()
+        .add_service(grpc::service(server.state()))
+        .serve_with_incoming(incoming);
+    tokio::spawn(grpc_server);
+
+    let mut client = SshxServiceClient::connect(format!("http://{grpc_addr}")).await?;
+    let encrypt = Encrypt::new("key");
+    let req = open_request(&encrypt);
+    let name = client.open(req).await?.into_inner().name;
+
+    let http = reqwest::Client::new();
+    let resp = http.get(format!("http://{web_addr}/")).send().await?;
+    assert_eq!(resp.text().await?, "host app");
+    let resp = http
+        .get(format!("http://{web_addr}/sshx/api/s/{name}/transcript"))
+        .bearer_auth(BASE64_STANDARD.encode(encrypt.zeros()))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200);
+
+    server.shutdown();
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_detect_host() -> Result<()> {
+    use sshx_server::state::mesh::detect_host;
+
+    std::env::set_var("POD_IP", "10.1.2.3");
+    assert_eq!(detect_host(8051).as_deref(), Some("10.1.2.3:8051"));
+
+    let mut options = ServerOptions::default();
+    options.redis_url = Some("redis://127.0.0.1:1".into());
+    options.advertise_port = Some(8051);
+    let server = Server::new(options.clone())?;
+    assert_eq!(server.state().host().as_deref(), Some("10.1.2.3:8051"));
+
+    // A host set by the operator is not replaced.
+    options.host = Some("sshx-0.sshx:8051".into());
+    let server = Server::new(options)?;
+    assert_eq!(server.state().host().as_deref(), Some("sshx-0.sshx:8051"));
+
+    std::env::set_var("POD_IP", "fd00::1");
+    assert_eq!(detect_host(8051).as_deref(), Some("[fd00::1]:8051"));
+    std::env::set_var("POD_IP", "not an ip");
+    assert_eq!(detect_host(8051), None);
+    std::env::remove_var("POD_IP");
+
+    Ok(())
+}
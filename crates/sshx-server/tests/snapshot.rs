@@ -2,11 +2,12 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use sshx::{controller::Controller, runner::Runner};
-use sshx_core::{Sid, Uid};
+use sshx_core::{proto::InactivityPolicy, Sid, Uid};
 use sshx_server::{
     session::Session,
     web::protocol::{WsClient, WsWinsize},
 };
+use tokio::time::{self, Duration};
 
 use crate::common::*;
 
@@ -38,6 +39,7 @@ async fn test_basic_restore() -> Result<()> {
     s.send_input(Sid(1), b"hello there!").await;
     s.send_input(Sid(1), b" - another message").await;
     s.send(WsClient::Move(Sid(1), Some(new_size))).await;
+    s.send(WsClient::NotesEdit(0, b"notes"[..].into())).await;
     s.flush().await;
     assert!(s.shells.contains_key(&Sid(1)));
 
@@ -48,11 +50,64 @@ async fn test_basic_restore() -> Result<()> {
         .insert(&name, Arc::new(Session::restore(&data)?));
 
     let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
-    s.send(WsClient::Subscribe(Sid(1), 0)).await;
+    s.send(WsClient::Subscribe(Sid(1), 0, false)).await;
     s.flush().await;
 
     assert_eq!(s.read(Sid(1)), "hello there! - another message");
     assert_eq!(s.shells.get(&Sid(1)).unwrap(), &new_size);
+    assert_eq!(s.notes.edits.len(), 1);
+    assert_eq!(&s.notes.edits[0].data[..], b"notes");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_restore_inactivity_policy() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let policy = InactivityPolicy::keep(Some(Duration::from_secs(90)));
+    controller.set_inactivity(policy.clone());
+    let name = controller.name().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let session = server.state().lookup(&name).unwrap();
+    assert_eq!(session.inactivity(), None);
+    time::timeout(Duration::from_secs(2), async {
+        while session.inactivity().is_none() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+    assert_eq!(session.inactivity(), Some(policy.clone()));
+
+    // The policy is kept when the session moves to another server.
+    let restored = Session::restore(&session.snapshot()?)?;
+    assert_eq!(restored.inactivity(), Some(policy));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_restore_pinned() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    controller.pin();
+    let name = controller.name().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let session = server.state().lookup(&name).unwrap();
+    time::timeout(Duration::from_secs(2), async {
+        while !session.pinned() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+
+    // The pin is kept when the session moves to another server.
+    let restored = Session::restore(&session.snapshot()?)?;
+    assert!(restored.pinned());
 
     Ok(())
 }
@@ -0,0 +1,160 @@
+#![cfg(feature = "webtransport")]
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::{Buf, Bytes};
+use h3::ext::Protocol;
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::rustls::{self, pki_types::CertificateDer};
+use sshx::encrypt::Encrypt;
+use sshx_server::session::{Metadata, Session};
+use sshx_server::tls::TlsOptions;
+use sshx_server::web::protocol::{WsClient, WsServer};
+use tokio::time::{self, Duration};
+
+use crate::common::*;
+
+pub mod common;
+
+/// Create a certificate for `sshx.test`, either as a CA or to be signed by one.
+fn test_cert(name: &str, ca: bool) -> Result<rcgen::Certificate> {
+    use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa};
+
+    let mut params = CertificateParams::new(vec!["sshx.test".into()]);
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, name);
+    if ca {
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    }
+    Ok(rcgen::Certificate::from_params(params)?)
+}
+
+#[tokio::test]
+async fn test_webtransport() -> Result<()> {
+    let ca = test_cert("Test CA", true)?;
+    let server_cert = test_cert("sshx.test", false)?;
+
+    let dir = std::env::temp_dir().join(format!("sshx-wt-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(
+        dir.join("cert.pem"),
+        server_cert.serialize_pem_with_signer(&ca)?,
+    )?;
+    std::fs::write(dir.join("key.pem"), server_cert.serialize_private_key_pem())?;
+
+    let server = TestServer::builder()
+        .options(|options| {
+            options.tls = Some(TlsOptions {
+                cert: dir.join("cert.pem"),
+                key: dir.join("key.pem"),
+                client_ca: None,
+            });
+        })
+        .start()
+        .await;
+    let wt_server = server.server();
+    tokio::spawn(async move { wt_server.bind_webtransport(&"[::1]:0".parse()?).await });
+    let port = loop {
+        match server.state().webtransport_port() {
+            Some(port) => break port,
+            None => time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+    std::fs::remove_dir_all(&dir)?;
+
+    let root = reqwest::Certificate::from_pem(ca.serialize_pem()?.as_bytes())?;
+    let http = reqwest::Client::builder()
+        .add_root_certificate(root)
+        .resolve("sshx.test", server.local_addr())
+        .build()?;
+    let url = format!(
+        "https://sshx.test:{}/api/webtransport",
+        server.local_addr().port()
+    );
+    let info: serde_json::Value = serde_json::from_str(&http.get(url).send().await?.text().await?)?;
+    assert_eq!(info["port"], port);
+
+    let encrypt = Encrypt::new("key");
+    server.state().insert(
+        "wt-session",
+        Arc::new(Session::new(Metadata {
+            encrypted_zeros: encrypt.zeros().into(),
+            name: "wt-session".into(),
+            write_password_hash: None,
+            write_password_salt: None,
+            color_scheme: None,
+            environment: None,
+            client_key: None,
+            token_nonce: Bytes::new(),
+        })),
+    );
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateDer::from(ca.serialize_der()?))?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![b"h3".to_vec()];
+    let mut endpoint = quinn::Endpoint::client("[::1]:0".parse()?)?;
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(crypto)?,
+    )));
+
+    let connect = |name: &str| {
+        let endpoint = endpoint.clone();
+        let uri = format!("https://sshx.test:{port}/api/wt/s/{name}");
+        async move {
+            let addr = format!("[::1]:{port}").parse()?;
+            let quic = endpoint.connect(addr, "sshx.test")?.await?;
+            // The client's HTTP/3 connection is not driven, since it would
+            // reject the stream that the server opens for the session.
+            let (driver, mut send_request) = h3::client::builder()
+                .enable_extended_connect(true)
+                .enable_datagram(true)
+                .build::<_, _, Bytes>(h3_quinn::Connection::new(quic.clone()))
+                .await?;
+            let req = http::Request::builder()
+                .method(http::Method::CONNECT)
+                .uri(uri)
+                .extension(Protocol::WEB_TRANSPORT)
+                .body(())?;
+            let mut stream = send_request.send_request(req).await?;
+            let resp = stream.recv_response().await?;
+            anyhow::Ok((quic, resp.status(), (driver, send_request, stream)))
+        }
+    };
+
+    // Sessions that are not on this server are refused, so clients fall back
+    // to a WebSocket.
+    let (_quic, status, _h3) = connect("missing").await?;
+    assert_eq!(status, 404);
+
+    let (quic, status, _h3) = connect("wt-session").await?;
+    assert_eq!(status, 200);
+    let (mut send, mut recv) = quic.accept_bi().await?;
+    let mut header = [0; 3];
+    recv.read_exact(&mut header).await?;
+    assert_eq!(header, [0x40, 0x41, 0]); // stream type, then session ID
+
+    let mut read_msg = async || -> Result<WsServer> {
+        let mut len = [0; 4];
+        recv.read_exact(&mut len).await?;
+        let mut buf = vec![0; u32::from_be_bytes(len) as usize];
+        recv.read_exact(&mut buf).await?;
+        Ok(ciborium::de::from_reader(buf.reader())?)
+    };
+    assert!(matches!(read_msg().await?, WsServer::Hello(..)));
+
+    let auth = WsClient::Authenticate(encrypt.zeros().into(), None, None);
+    let mut buf = vec![0; 4];
+    ciborium::ser::into_writer(&auth, &mut buf)?;
+    let len = (buf.len() as u32 - 4).to_be_bytes();
+    buf[..4].copy_from_slice(&len);
+    send.write_all(&buf).await?;
+    assert!(matches!(read_msg().await?, WsServer::Users(_)));
+
+    Ok(())
+}
@@ -1,11 +1,38 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use anyhow::{Context, Result};
-use sshx::{controller::Controller, encrypt::Encrypt, runner::Runner};
+use bytes::Bytes;
+use prost::Message;
+use sshx::{
+    chaos::Chaos,
+    client_key::ClientKey,
+    controller::{Controller, OpenOptions, Viewers},
+    encrypt::Encrypt,
+    keys::{KeyProvider, KeySource},
+    known_servers::{IdentityCheck, KnownServers},
+    proxy::Socks5Proxy,
+    resume::SavedSession,
+    runner::{Runner, Script, Watchdog},
+    viewer::WebClient,
+};
 use sshx_core::{
-    proto::{server_update::ServerMessage, NewShell, TerminalInput},
-    Sid, Uid,
+    proto::{
+        client_update::ClientMessage, server_update::ServerMessage, Capabilities, ClientUpdate,
+        ClosedShell, NewShell, OpenRequest, StreamData, StreamKind, TerminalInput,
+    },
+    rand_alphanumeric, Sid, Uid,
 };
-use sshx_server::web::protocol::{WsClient, WsWinsize};
+use sshx_server::web::protocol::{
+    close_codes, features, ColorScheme, PaletteItem, ShellEnvironment, TranscriptRecord, WsClient,
+    WsNotes, WsProfile, WsServer, WsStreamKind, WsViewport, WsWinsize, PROTOCOL_VERSION,
+};
+use sshx_server::web::{DEV_KEY, DEV_SESSION};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::time::{self, Duration};
+use tokio_tungstenite::tungstenite;
 
 use crate::common::*;
 
@@ -19,6 +46,42 @@ async fn test_handshake() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_capabilities() -> Result<()> {
+    let server = TestServer::new().await;
+    let controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    assert!(controller.capabilities().has(features::STREAMS));
+    assert!(!controller.capabilities().has("unknown"));
+    assert!(controller.capabilities().max_message_size > 0);
+
+    let name = controller.name();
+    let key = controller.encryption_key();
+    let mut s = ClientSocket::connect(&server.ws_endpoint(name), key, None).await?;
+    s.flush().await;
+    assert!(s.capabilities.has(features::SHELL_CLOSED));
+    assert!(s.capabilities.has(features::STREAMS));
+    assert_eq!(s.capabilities.max_message_size, 1 << 20);
+
+    controller.close().await?;
+    Ok(())
+}
+
+#[test]
+fn test_closed_shell_without_feature() {
+    // Servers that don't advertise the feature only accept the shell's ID.
+    let closed = ClosedShell::new(Sid(3), "shell exited");
+    let capabilities = Capabilities::default();
+    let msg = closed.clone().into_message(&capabilities);
+    assert_eq!(msg, ClientMessage::ClosedShell(3));
+
+    let capabilities = Capabilities {
+        features: vec![features::SHELL_CLOSED.into()],
+        ..Default::default()
+    };
+    let msg = closed.clone().into_message(&capabilities);
+    assert_eq!(msg, ClientMessage::ShellClosed(closed));
+}
+
 #[tokio::test]
 async fn test_command() -> Result<()> {
     let server = TestServer::new().await;
@@ -52,6 +115,43 @@ async fn test_command() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_update_priority() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+    let req = open_request(&Encrypt::new("key"));
+    let name = client.open(req).await?.into_inner().name;
+    let session = server.state().lookup(&name).context("missing session")?;
+
+    // Control messages are received before bulk input that was sent earlier.
+    for offset in 0..3 {
+        let input = TerminalInput {
+            id: 1,
+            data: Bytes::from_static(b"x"),
+            offset,
+        };
+        session
+            .update_tx()
+            .send(ServerMessage::Input(input))
+            .await?;
+    }
+    session
+        .update_tx()
+        .send(ServerMessage::CloseShell(1))
+        .await?;
+
+    let updates = session.update_rx();
+    assert_eq!(updates.recv().await?, ServerMessage::CloseShell(1));
+    for expected in 0..3 {
+        match updates.recv().await? {
+            ServerMessage::Input(input) => assert_eq!(input.offset, expected),
+            msg => panic!("unexpected message: {msg:?}"),
+        }
+    }
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_ws_missing() -> Result<()> {
     let server = TestServer::new().await;
@@ -62,7 +162,51 @@ async fn test_ws_missing() -> Result<()> {
         .is_err());
 
     let mut s = ClientSocket::connect(&server.ws_endpoint("foobar"), "", None).await?;
-    s.expect_close(4404).await;
+    s.expect_close(close_codes::NOT_FOUND).await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ws_invalid_auth() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), "wrong", None).await?;
+    s.expect_close_eventually(close_codes::UNAUTHORIZED).await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dev_replay() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut s = ClientSocket::connect(&server.ws_endpoint(DEV_SESSION), DEV_KEY, None).await?;
+    s.expect_close(close_codes::NOT_FOUND).await;
+
+    let server = TestServer::builder()
+        .options(|options| options.dev_replay = true)
+        .start()
+        .await;
+    let endpoint = server.ws_endpoint(DEV_SESSION);
+    let mut s = ClientSocket::connect(&endpoint, "wrong", None).await?;
+    s.expect_close_eventually(close_codes::UNAUTHORIZED).await;
+
+    let mut s = ClientSocket::connect(&endpoint, DEV_KEY, None).await?;
+    s.flush().await;
+    assert_eq!(s.user_id, Uid(1));
+    assert_eq!(s.users.len(), 2);
+    assert_eq!(s.shells.len(), 1);
+
+    s.send(WsClient::Subscribe(Sid(1), 0, false)).await;
+    while !s.read(Sid(1)).contains("dev@sshx") {
+        s.flush().await;
+    }
+    s.send_input(Sid(1), b"hi").await;
+    s.flush().await;
+    assert!(s.read(Sid(1)).ends_with("$ hi"));
 
     Ok(())
 }
@@ -85,7 +229,7 @@ async fn test_ws_basic() -> Result<()> {
     assert_eq!(s.shells.len(), 1);
     assert!(s.shells.contains_key(&Sid(1)));
 
-    s.send(WsClient::Subscribe(Sid(1), 0)).await;
+    s.send(WsClient::Subscribe(Sid(1), 0, false)).await;
     assert_eq!(s.read(Sid(1)), "");
 
     s.send_input(Sid(1), b"hello!").await;
@@ -100,7 +244,7 @@ async fn test_ws_basic() -> Result<()> {
 }
 
 #[tokio::test]
-async fn test_ws_resize() -> Result<()> {
+async fn test_timed_chunks() -> Result<()> {
     let server = TestServer::new().await;
 
     let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
@@ -109,177 +253,2515 @@ async fn test_ws_resize() -> Result<()> {
     tokio::spawn(async move { controller.run().await });
 
     let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
-
-    s.send(WsClient::Move(Sid(1), None)).await; // error: does not exist yet!
-    s.flush().await;
-    assert_eq!(s.errors.len(), 1);
-
     s.send(WsClient::Create(0, 0)).await;
     s.flush().await;
-    assert_eq!(s.shells.len(), 1);
-    assert_eq!(*s.shells.get(&Sid(1)).unwrap(), WsWinsize::default());
+    assert!(s.capabilities.has(features::TIMESTAMPS));
 
-    let new_size = WsWinsize {
-        x: 42,
-        y: 105,
-        rows: 200,
-        cols: 20,
-    };
-    s.send(WsClient::Move(Sid(1), Some(new_size))).await;
-    s.send(WsClient::Move(Sid(2), Some(new_size))).await; // error: does not exist
+    let start = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    s.send(WsClient::Subscribe(Sid(1), 0, true)).await;
+    s.send_input(Sid(1), b"hello!").await;
     s.flush().await;
-    assert_eq!(s.shells.len(), 1);
-    assert_eq!(*s.shells.get(&Sid(1)).unwrap(), new_size);
-    assert_eq!(s.errors.len(), 2);
-
-    s.send(WsClient::Close(Sid(1))).await;
+    s.send_input(Sid(1), b" 123").await;
     s.flush().await;
-    assert_eq!(s.shells.len(), 0);
+    let end = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
 
-    s.send(WsClient::Move(Sid(1), None)).await; // error: shell was closed
-    s.flush().await;
-    assert_eq!(s.errors.len(), 3);
+    assert_eq!(s.read(Sid(1)), "hello! 123");
+    let times = &s.times[&Sid(1)];
+    assert_eq!(times.len(), 2);
+    assert!(times.iter().all(|time| (start..=end).contains(time)));
+    assert!(times[0] <= times[1]);
 
     Ok(())
 }
 
 #[tokio::test]
-async fn test_users_join() -> Result<()> {
+async fn test_web_client() -> Result<()> {
     let server = TestServer::new().await;
 
     let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
-    let name = controller.name().to_owned();
-    let key = controller.encryption_key().to_owned();
+    let url = controller.url().to_owned();
     tokio::spawn(async move { controller.run().await });
 
-    let endpoint = server.ws_endpoint(&name);
-    let mut s1 = ClientSocket::connect(&endpoint, &key, None).await?;
-    s1.flush().await;
-    assert_eq!(s1.users.len(), 1);
-
-    let mut s2 = ClientSocket::connect(&endpoint, &key, None).await?;
-    s2.flush().await;
-    assert_eq!(s2.users.len(), 2);
+    let mut client = WebClient::connect(&url).await?;
+    assert_eq!(client.user_id(), Uid(1));
+    client.send(WsClient::Create(0, 0)).await?;
 
-    drop(s2);
-    let mut s3 = ClientSocket::connect(&endpoint, &key, None).await?;
-    s3.flush().await;
-    assert_eq!(s3.users.len(), 2);
+    let mut output = String::new();
+    let mut subscribed = false;
+    let read_output = async {
+        while let Some(msg) = client.recv().await? {
+            match msg {
+                WsServer::Shells(shells) if !subscribed && !shells.is_empty() => {
+                    subscribed = true;
+                    client.subscribe(Sid(1), 0).await?;
+                    client.send_input(Sid(1), b"hello!").await?;
+                }
+                WsServer::Chunks(_, _, chunks) => {
+                    for chunk in chunks {
+                        output.push_str(std::str::from_utf8(&chunk)?);
+                    }
+                    if output == "hello!" {
+                        break;
+                    }
+                }
+                _ => (),
+            }
+        }
+        anyhow::Ok(())
+    };
+    time::timeout(Duration::from_secs(2), read_output).await??;
+    assert_eq!(output, "hello!");
 
-    s1.flush().await;
-    assert_eq!(s1.users.len(), 2);
+    let bad_url = url.replace('#', "#wrong");
+    assert!(WebClient::connect(&bad_url).await.is_err());
 
     Ok(())
 }
 
 #[tokio::test]
-async fn test_users_metadata() -> Result<()> {
+async fn test_web_client_follow() -> Result<()> {
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::mpsc;
+
     let server = TestServer::new().await;
 
     let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
-    let name = controller.name().to_owned();
-    let key = controller.encryption_key().to_owned();
+    let url = controller.url().to_owned();
     tokio::spawn(async move { controller.run().await });
 
-    let endpoint = server.ws_endpoint(&name);
-    let mut s = ClientSocket::connect(&endpoint, &key, None).await?;
-    s.flush().await;
-    assert_eq!(s.users.len(), 1);
-    assert_eq!(s.users.get(&s.user_id).unwrap().cursor, None);
+    let mut client = WebClient::connect(&url).await?;
+    client.send(WsClient::Create(0, 0)).await?;
 
-    s.send(WsClient::SetName("mr. foo".into())).await;
-    s.send(WsClient::SetCursor(Some((40, 524)))).await;
-    s.flush().await;
-    let user = s.users.get(&s.user_id).unwrap();
-    assert_eq!(user.name, "mr. foo");
-    assert_eq!(user.cursor, Some((40, 524)));
+    let (input_tx, input_rx) = mpsc::channel(1);
+    let (out, mut reader) = tokio::io::duplex(1024);
+    let follow = client.follow(None, Some(input_rx), out);
+    let interact = async {
+        input_tx.send(b"hello!".to_vec()).await?;
+        let mut output = Vec::new();
+        while output != b"hello!" {
+            let mut buf = [0; 64];
+            let n = reader.read(&mut buf).await?;
+            output.extend_from_slice(&buf[..n]);
+        }
+        drop(input_tx); // stop following
+        anyhow::Ok(())
+    };
+    let (followed, interacted) = time::timeout(Duration::from_secs(2), async {
+        tokio::join!(follow, interact)
+    })
+    .await?;
+    followed?;
+    interacted?;
 
     Ok(())
 }
 
 #[tokio::test]
-async fn test_chat_messages() -> Result<()> {
-    let server = TestServer::new().await;
+async fn test_relay() -> Result<()> {
+    let origin = TestServer::new().await;
 
-    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let script = Script::new().output("hello").wait_for("never");
+    let mut controller =
+        Controller::new(&origin.endpoint(), "", Runner::Script(script), false).await?;
+    let url = controller.url().to_owned();
     let name = controller.name().to_owned();
-    let key = controller.encryption_key().to_owned();
     tokio::spawn(async move { controller.run().await });
 
-    let endpoint = server.ws_endpoint(&name);
-    let mut s1 = ClientSocket::connect(&endpoint, &key, None).await?;
-    let mut s2 = ClientSocket::connect(&endpoint, &key, None).await?;
+    let mut client = WebClient::connect(&url).await?;
+    client.send(WsClient::Create(0, 0)).await?;
 
-    s1.send(WsClient::SetName("billy".into())).await;
-    s1.send(WsClient::Chat("hello there!".into())).await;
-    s1.flush().await;
+    let link = url.clone();
+    let relay = TestServer::builder()
+        .options(|options| options.relay_links = vec![link])
+        .start()
+        .await;
+    let relay_url = url.replace(&origin.endpoint(), &relay.endpoint());
+    time::timeout(Duration::from_secs(2), async {
+        while relay.state().lookup(&name).is_none() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
 
-    s2.flush().await;
-    assert_eq!(s2.messages.len(), 1);
-    assert_eq!(
-        s2.messages[0],
-        (s1.user_id, "billy".into(), "hello there!".into())
+    let mut viewer = WebClient::connect(&relay_url).await?;
+    let mut output = String::new();
+    let read_output = async {
+        while let Some(msg) = viewer.recv().await? {
+            match msg {
+                WsServer::Shells(shells) if !shells.is_empty() && output.is_empty() => {
+                    viewer.subscribe(shells[0].0, 0).await?;
+                    viewer.send_input(shells[0].0, b"never").await?;
+                }
+                WsServer::TimedChunks(_, _, chunks, _) | WsServer::Chunks(_, _, chunks) => {
+                    for chunk in chunks {
+                        output.push_str(std::str::from_utf8(&chunk)?);
+                    }
+                }
+                WsServer::Error(err) => return Ok(err),
+                _ => (),
+            }
+        }
+        anyhow::bail!("relay closed the connection")
+    };
+    let err = time::timeout(Duration::from_secs(5), read_output).await??;
+    assert!(err.contains("permission"), "unexpected error: {err}");
+
+    let read_output = async {
+        while output != "hello" {
+            if let Some(WsServer::Chunks(_, _, chunks)) = viewer.recv().await? {
+                for chunk in chunks {
+                    output.push_str(std::str::from_utf8(&chunk)?);
+                }
+            }
+        }
+        anyhow::Ok(())
+    };
+    time::timeout(Duration::from_secs(5), read_output).await??;
+    assert_eq!(output, "hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_notifier() -> Result<()> {
+    use axum::{routing::post, Json, Router};
+    use serde_json::Value;
+    use sshx::notify::Notifier;
+    use tokio::sync::mpsc;
+
+    async fn next_event(rx: &mut mpsc::UnboundedReceiver<Value>) -> Result<Value> {
+        let body = time::timeout(Duration::from_secs(2), rx.recv()).await?;
+        body.context("webhook channel closed")
+    }
+
+    let (hook_tx, mut hook_rx) = mpsc::unbounded_channel();
+    let app = Router::new().route(
+        "/hook",
+        post(|Json(body): Json<Value>| async move {
+            hook_tx.send(body).unwrap();
+        }),
     );
+    let hook = axum::Server::bind(&"[::1]:0".parse()?).serve(app.into_make_service());
+    let hook_url = format!("http://{}/hook", hook.local_addr());
+    tokio::spawn(hook);
 
-    let mut s3 = ClientSocket::connect(&endpoint, &key, None).await?;
-    s3.flush().await;
-    assert_eq!(s1.messages.len(), 1);
-    assert_eq!(s3.messages.len(), 0);
+    let server = TestServer::new().await;
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    controller.set_notifier(Notifier::new(&hook_url)?);
+    let url = controller.url().to_owned();
+    let name = controller.name().to_owned();
+
+    let started = next_event(&mut hook_rx).await?;
+    assert_eq!(started["event"], "started");
+    assert_eq!(started["session"], name.as_str());
+    assert!(started["text"].as_str().unwrap().contains(&url));
+
+    let join = async {
+        // Wait for the controller to connect, by creating a shell.
+        let mut client = WebClient::connect(&url).await?;
+        client.send(WsClient::Create(0, 0)).await?;
+        while let Some(msg) = client.recv().await? {
+            if matches!(msg, WsServer::Shells(shells) if !shells.is_empty()) {
+                break;
+            }
+        }
+        let _viewer = WebClient::connect(&url).await?;
+        let expected = format!("User 2 joined sshx session {name}");
+        loop {
+            // The first user may also be reported, depending on timing.
+            let joined = next_event(&mut hook_rx).await?;
+            assert_eq!(joined["event"], "userJoined");
+            if joined["text"] == expected.as_str() {
+                break;
+            }
+        }
+        anyhow::Ok(())
+    };
+    tokio::select! {
+        _ = controller.run() => unreachable!(),
+        result = join => result?,
+    }
+
+    controller.close().await?;
+    assert_eq!(next_event(&mut hook_rx).await?["event"], "ended");
 
     Ok(())
 }
 
 #[tokio::test]
-async fn test_read_write_permissions() -> Result<()> {
+async fn test_viewer_presence() -> Result<()> {
     let server = TestServer::new().await;
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    assert!(controller.capabilities().has(features::USER_PRESENCE));
+    let url = controller.url().to_owned();
+    let mut viewers = controller.viewers();
+    assert_eq!(*viewers.borrow(), Viewers::default());
 
-    // create controller with read-only mode enabled
-    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, true).await?;
-    let name = controller.name().to_owned();
-    let key = controller.encryption_key().to_owned();
-    let write_url = controller
-        .write_url()
-        .expect("Should have write URL when enable_readers is true")
-        .to_string();
+    let presence = async {
+        let timeout = Duration::from_secs(2);
+        let first = WebClient::connect(&url).await?;
+        let second = WebClient::connect(&url).await?;
+        time::timeout(timeout, viewers.wait_for(|v| v.current == 2)).await??;
+        assert!(viewers.borrow().any_joined);
 
-    tokio::spawn(async move { controller.run().await });
+        first.close().await?;
+        time::timeout(timeout, viewers.wait_for(|v| v.current == 1)).await??;
+        second.close().await?;
+        time::timeout(timeout, viewers.wait_for(|v| v.current == 0)).await??;
+        assert!(viewers.borrow().any_joined);
+        anyhow::Ok(())
+    };
+    tokio::select! {
+        _ = controller.run() => unreachable!(),
+        result = presence => result?,
+    }
 
-    let write_password = write_url
-        .split(',')
-        .nth(1)
-        .expect("Write URL should contain password");
+    Ok(())
+}
 
-    // connect with write access
-    let mut writer =
+#[tokio::test]
+async fn test_session_takeover() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut first = Controller::new(&server.endpoint(), "", Runner::Echo, true).await?;
+    let name = first.name().to_owned();
+    let key = first.encryption_key().to_owned();
+    let write_url = first.write_url().context("missing write URL")?.to_owned();
+    let token = first.token().to_owned();
+    let client_key = first.client_key().context("missing client key")?.clone();
+    let mut released = first.released();
+    tokio::spawn(async move { first.run().await });
+
+    let write_password = write_url.rsplit_once(',').unwrap().1;
+    let mut s =
         ClientSocket::connect(&server.ws_endpoint(&name), &key, Some(write_password)).await?;
-    writer.flush().await;
+    s.send(WsClient::Create(3, 4)).await;
+    s.flush().await;
+    assert!(s.shells.contains_key(&Sid(1)));
 
-    // test write permissions
-    writer.send(WsClient::Create(0, 0)).await;
-    writer.flush().await;
-    assert_eq!(
-        writer.shells.len(),
-        1,
-        "Writer should be able to create a shell"
-    );
-    assert!(writer.errors.is_empty(), "Writer should not receive errors");
+    let bad_url = format!("{}#wrongkey", write_url.split_once('#').unwrap().0);
+    let mut bad = Controller::take_over(&server.endpoint(), &bad_url, &token, Runner::Echo).await?;
+    tokio::select! {
+        _ = bad.run() => unreachable!(),
+        _ = time::sleep(Duration::from_millis(200)) => (),
+    }
+    assert!(!*released.borrow());
 
-    // connect with read-only access
-    let mut reader = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
-    reader.flush().await;
+    // The token alone is not enough, since the session is bound to the client key.
+    let mut stolen =
+        Controller::take_over(&server.endpoint(), &write_url, &token, Runner::Echo).await?;
+    tokio::select! {
+        _ = stolen.run() => unreachable!(),
+        _ = time::sleep(Duration::from_millis(200)) => (),
+    }
+    assert!(!*released.borrow());
 
-    // test read-only restrictions
-    reader.send(WsClient::Create(0, 0)).await;
-    reader.flush().await;
-    assert!(
-        !reader.errors.is_empty(),
-        "Reader should receive an error when attempting to create shell"
-    );
-    assert_eq!(
-        reader.shells.len(),
-        1,
-        "Reader should still see the existing shell"
+    let mut second =
+        Controller::take_over(&server.endpoint(), &write_url, &token, Runner::Echo).await?;
+    second.set_client_key(client_key.to_string().parse().unwrap());
+    assert_eq!(second.name(), name);
+    assert_eq!(second.write_url(), Some(&*write_url));
+    tokio::spawn(async move { second.run().await });
+    time::timeout(Duration::from_secs(5), released.wait_for(|&r| r)).await??;
+
+    // The shell of the first client is replaced by one in the same place.
+    s.flush().await;
+    assert_eq!(s.closed.len(), 1);
+    assert_eq!(s.closed[0].0, Sid(1));
+    assert_eq!(s.closed[0].1.reason, "session moved to another machine");
+    assert_eq!(s.shells.len(), 1);
+    let (&id, winsize) = s.shells.iter().next().unwrap();
+    assert_ne!(id, Sid(1));
+    assert_eq!((winsize.x, winsize.y), (3, 4));
+
+    s.send(WsClient::Subscribe(id, 0, false)).await;
+    s.send_input(id, b"hello").await;
+    s.flush().await;
+    assert_eq!(s.read(id), "hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resume_session() -> Result<()> {
+    let server = TestServer::new().await;
+    let path = std::env::temp_dir().join(format!("sshx-resume-{}", std::process::id()));
+
+    let mut first = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = first.name().to_owned();
+    let key = first.encryption_key().to_owned();
+    SavedSession::new(&server.endpoint(), &first).save(&path)?;
+    #[cfg(unix)]
+    {
+        // Files written before are made private again.
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+        SavedSession::new(&server.endpoint(), &first).save(&path)?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    let first = tokio::spawn(async move { first.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await;
+    s.flush().await;
+    assert!(s.shells.contains_key(&Sid(1)));
+
+    // The client is restarted, and takes over its session from the file.
+    first.abort();
+    let saved = SavedSession::load(&path)?.context("missing saved session")?;
+    SavedSession::remove(&path)?;
+    assert!(SavedSession::load(&path)?.is_none());
+    assert_eq!(saved.server, server.endpoint());
+    assert_ne!(
+        SavedSession::default_path("https://a.example.com"),
+        SavedSession::default_path("https://b.example.com"),
     );
+    let mut second =
+        Controller::take_over(&saved.server, &saved.url, &saved.token, Runner::Echo).await?;
+    second.set_client_key(saved.client_key.context("missing client key")?);
+    assert!(second.reattach().await?);
+    assert_eq!(second.name(), name);
+    tokio::spawn(async move { second.run().await });
+
+    // The shell is started again by the new client.
+    s.flush().await;
+    assert_eq!(s.closed.len(), 1);
+    assert_eq!(s.shells.len(), 1);
+    let id = *s.shells.keys().next().unwrap();
+    s.send(WsClient::Subscribe(id, 0, false)).await;
+    s.send_input(id, b"hello").await;
+    s.flush().await;
+    assert_eq!(s.read(id), "hello");
+
+    // Saved sessions that the server refuses, here without their client key,
+    // are replaced by new ones.
+    let mut unsigned =
+        Controller::take_over(&saved.server, &saved.url, &saved.token, Runner::Echo).await?;
+    assert!(!unsigned.reattach().await?);
+
+    // Sessions that were closed cannot be resumed.
+    let mut third =
+        Controller::take_over(&saved.server, &saved.url, &saved.token, Runner::Echo).await?;
+    third.close().await?;
+    assert!(!third.reattach().await?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_roster() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let url = controller.url().to_owned();
+    let mut roster = controller.roster();
+
+    // The first roster is sent as soon as the channel opens.
+    let viewer = WebClient::connect(&url).await?;
+    let check = async {
+        let timeout = Duration::from_secs(2);
+        time::timeout(timeout, roster.wait_for(|users| !users.is_empty())).await??;
+        let users = roster.borrow().clone();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].name, format!("User {}", users[0].id));
+        assert!(users[0].can_write);
+        assert!(users[0].joined_at > 0);
+        anyhow::Ok(())
+    };
+    tokio::select! {
+        _ = controller.run() => unreachable!(),
+        result = check => result?,
+    }
+    viewer.close().await?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_systemd_watchdog() -> Result<()> {
+    use sshx::systemd::Systemd;
+    use tokio::net::UnixDatagram;
+
+    async fn next_notification(socket: &UnixDatagram) -> Result<String> {
+        let mut buf = [0; 64];
+        let n = time::timeout(Duration::from_secs(2), socket.recv(&mut buf)).await??;
+        Ok(String::from_utf8(buf[..n].to_vec())?)
+    }
+
+    let path = std::env::temp_dir().join(format!("sshx-notify-{}.sock", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let socket = UnixDatagram::bind(&path)?;
+    let systemd = Systemd::new(path.to_str().unwrap(), Some(Duration::from_millis(200)))?;
+
+    let server = TestServer::new().await;
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let last_heard = controller.last_heard();
+    assert!(last_heard.borrow().is_none());
+
+    let notifications = async {
+        assert_eq!(next_notification(&socket).await?, "READY=1");
+        assert_eq!(next_notification(&socket).await?, "WATCHDOG=1");
+        assert_eq!(next_notification(&socket).await?, "WATCHDOG=1");
+        anyhow::Ok(())
+    };
+    tokio::select! {
+        _ = controller.run() => unreachable!(),
+        result = systemd.supervise(last_heard) => result?,
+        result = notifications => result?,
+    }
+    std::fs::remove_file(&path)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_agent_sessions() -> Result<()> {
+    use sshx::agent::Agent;
+
+    let server = TestServer::new().await;
+    let agent = Agent::new(&server.endpoint(), Runner::Echo, false);
+    let api = agent.open("api", None).await?;
+    let web = agent.open("web", Some(Runner::Echo)).await?;
+    assert_ne!(api.url, web.url);
+    assert!(agent.open("api", None).await.is_err());
+    assert_eq!(agent.list(), [api.clone(), web.clone()]);
+    assert_eq!(server.state().sessions().len(), 2);
+
+    // Each session is independent, and keeps running after others close.
+    let mut client = WebClient::connect(&web.url).await?;
+    agent.close("api").await?;
+    assert!(agent.close("api").await.is_err());
+    assert_eq!(agent.list(), [web]);
+    assert_eq!(server.state().sessions().len(), 1);
+    client.send(WsClient::Create(0, 0)).await?;
+    let shells = async {
+        while let Some(msg) = client.recv().await? {
+            if matches!(msg, WsServer::Shells(shells) if !shells.is_empty()) {
+                return Ok(());
+            }
+        }
+        anyhow::bail!("connection closed")
+    };
+    time::timeout(Duration::from_secs(2), shells).await??;
+
+    agent.close_all().await;
+    assert!(agent.list().is_empty());
+    assert!(server.state().sessions().is_empty());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_agent_control_socket() -> Result<()> {
+    use serde_json::Value;
+    use sshx::agent::Agent;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+
+    async fn request(stream: &mut BufReader<UnixStream>, command: &str) -> Result<Value> {
+        stream.write_all(format!("{command}\n").as_bytes()).await?;
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    let path = std::env::temp_dir().join(format!("sshx-agent-{}.sock", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    let listener = UnixListener::bind(&path)?;
+
+    let server = TestServer::new().await;
+    let agent = Agent::new(&server.endpoint(), Runner::Echo, false);
+    agent.open("api", None).await?;
+
+    let commands = async {
+        let mut stream = BufReader::new(UnixStream::connect(&path).await?);
+        let resp = request(&mut stream, "open web").await?;
+        assert_eq!(resp["session"]["name"], "web");
+        assert!(resp["session"]["url"].as_str().unwrap().contains("/s/"));
+
+        let resp = request(&mut stream, "close api").await?;
+        assert_eq!(resp["closed"], "api");
+
+        let resp = request(&mut stream, "list").await?;
+        let sessions = resp["sessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0]["name"], "web");
+
+        let resp = request(&mut stream, "reboot").await?;
+        assert!(resp["error"].as_str().unwrap().contains("unknown command"));
+        anyhow::Ok(())
+    };
+    tokio::select! {
+        result = agent.serve_control(listener) => result?,
+        result = commands => result?,
+    }
+    agent.close_all().await;
+    std::fs::remove_file(&path)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chaos_delays() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let script = (0..10).fold(Script::new(), |script, i| script.output(format!("{i}")));
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Script(script), false).await?;
+    controller.set_chaos("delay=1,max_delay=20".parse().unwrap());
+    let url = controller.url().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut client = WebClient::connect(&url).await?;
+    client.set_chaos("delay=0.5,max_delay=20".parse().unwrap());
+    client.send(WsClient::Create(0, 0)).await?;
+
+    let mut output = String::new();
+    let mut subscribed = false;
+    let read_output = async {
+        while let Some(msg) = client.recv().await? {
+            match msg {
+                WsServer::Shells(shells) if !subscribed && !shells.is_empty() => {
+                    subscribed = true;
+                    client.subscribe(Sid(1), 0).await?;
+                }
+                WsServer::Chunks(_, seqnum, chunks) => {
+                    assert_eq!(seqnum, output.len() as u64);
+                    for chunk in chunks {
+                        output.push_str(std::str::from_utf8(&chunk)?);
+                    }
+                    if output.len() == 10 {
+                        break;
+                    }
+                }
+                _ => (),
+            }
+        }
+        anyhow::Ok(())
+    };
+    time::timeout(Duration::from_secs(5), read_output).await??;
+    assert_eq!(output, "0123456789");
+
+    assert!("disconnect=0.5,drop=1".parse::<Chaos>().is_ok());
+    assert!("drop=2".parse::<Chaos>().is_err());
+    assert!("jitter=0.1".parse::<Chaos>().is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ws_resize() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+
+    s.send(WsClient::Move(Sid(1), None)).await; // error: does not exist yet!
+    s.flush().await;
+    assert_eq!(s.errors.len(), 1);
+
+    s.send(WsClient::Create(0, 0)).await;
+    s.flush().await;
+    assert_eq!(s.shells.len(), 1);
+    assert_eq!(*s.shells.get(&Sid(1)).unwrap(), WsWinsize::default());
+
+    let new_size = WsWinsize {
+        x: 42,
+        y: 105,
+        rows: 200,
+        cols: 20,
+    };
+    s.send(WsClient::Move(Sid(1), Some(new_size))).await;
+    s.send(WsClient::Move(Sid(2), Some(new_size))).await; // error: does not exist
+    s.flush().await;
+    assert_eq!(s.shells.len(), 1);
+    assert_eq!(*s.shells.get(&Sid(1)).unwrap(), new_size);
+    assert_eq!(s.errors.len(), 2);
+
+    // Terminals cannot be moved far away, and their size is clamped.
+    let far_away = WsWinsize {
+        y: -5_000_000,
+        ..new_size
+    };
+    s.send(WsClient::Move(Sid(1), Some(far_away))).await; // error: out of range
+    let huge = WsWinsize {
+        rows: 0,
+        cols: u16::MAX,
+        ..new_size
+    };
+    s.send(WsClient::Move(Sid(1), Some(huge))).await;
+    s.flush().await;
+    assert_eq!(s.errors.len(), 3);
+    assert!(s.errors[2].contains("vertical position"));
+    let clamped = *s.shells.get(&Sid(1)).unwrap();
+    assert_eq!((clamped.x, clamped.y), (42, 105));
+    assert_eq!((clamped.rows, clamped.cols), (1, 1000));
+
+    s.send(WsClient::Close(Sid(1))).await;
+    s.flush().await;
+    assert_eq!(s.shells.len(), 0);
+    assert_eq!(s.closed.len(), 1);
+    assert_eq!(s.closed[0].0, Sid(1));
+    assert_eq!(s.closed[0].1.reason, "closed by a user");
+
+    s.send(WsClient::Move(Sid(1), None)).await; // error: shell was closed
+    s.flush().await;
+    assert_eq!(s.errors.len(), 4);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_script_runner() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let script = Script::new()
+        .output("$ ")
+        .wait_for("ls\r")
+        .output("a.txt\r\n")
+        .output("b.txt\r\n")
+        .sleep(Duration::from_millis(100))
+        .output("$ ")
+        .wait_for("exit\r")
+        .exit();
+    let runner = Runner::Script(script.clone());
+    let mut controller = Controller::new(&server.endpoint(), "", runner, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await;
+    s.flush().await;
+    s.send(WsClient::Subscribe(Sid(1), 0, false)).await;
+    s.flush().await;
+    assert_eq!(s.read(Sid(1)), "$ ");
+
+    s.send_input(Sid(1), b"ls\r").await;
+    s.flush().await;
+    assert_eq!(s.read(Sid(1)), "$ a.txt\r\nb.txt\r\n");
+    time::sleep(Duration::from_millis(100)).await;
+    s.flush().await;
+    assert_eq!(s.read(Sid(1)), "$ a.txt\r\nb.txt\r\n$ ");
+
+    let new_size = WsWinsize {
+        rows: 50,
+        cols: 120,
+        ..Default::default()
+    };
+    s.send(WsClient::Move(Sid(1), Some(new_size))).await;
+    s.send_input(Sid(1), b"exit\r").await;
+    s.flush().await;
+    assert_eq!(script.input(Sid(1)), b"ls\rexit\r");
+    assert_eq!(script.sizes(Sid(1)).last(), Some(&(50, 120)));
+    assert_eq!(s.closed.len(), 1);
+    assert_eq!(s.closed[0].1.reason, "shell exited");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shell_watchdog() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let script = Script::new().wait_for("wake\r").output("ok");
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Script(script), false).await?;
+    controller.set_watchdog(Watchdog {
+        timeout: Duration::from_millis(100),
+        restart: false,
+    });
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await;
+    s.flush().await;
+    assert!(s.capabilities.has(features::SHELL_HEALTH));
+
+    // Input without any output makes the shell stalled after a while.
+    s.send_input(Sid(1), b"ls\r").await;
+    s.flush().await;
+    assert!(s.health.is_empty());
+    time::sleep(Duration::from_millis(150)).await;
+    s.flush().await;
+    assert!(s.health[&Sid(1)].stalled);
+
+    // Users who join later are told about the stalled shell.
+    let mut s2 = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s2.flush().await;
+    assert!(s2.health[&Sid(1)].stalled);
+
+    // The shell recovers when it prints something.
+    s.send_input(Sid(1), b"wake\r").await;
+    s.flush().await;
+    assert!(!s.health[&Sid(1)].stalled);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_phantom_shells() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    let session = server.state().lookup(&name).context("missing session")?;
+    session.add_shell(Sid(7), (0, 0))?;
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.flush().await;
+    assert!(s.shells.contains_key(&Sid(7)));
+
+    // The client answers the first sync by reporting that it has no such shell.
+    tokio::spawn(async move { controller.run().await });
+    time::sleep(Duration::from_millis(200)).await;
+    s.flush().await;
+    assert!(s.shells.is_empty());
+    assert_eq!(s.closed.len(), 1);
+    assert_eq!(s.closed[0].0, Sid(7));
+    assert_eq!(s.closed[0].1.reason, "shell is not running");
+
+    // Unknown shells reported closed by the client cannot be opened later.
+    let id = session.counter().next_sid();
+    session.close_shell(id, Default::default())?;
+    assert!(session.add_shell(id, (0, 0)).is_err());
+
+    // IDs that the server never handed out are not remembered.
+    session.close_shell(Sid(1000), Default::default())?;
+    session.add_shell(Sid(1000), (0, 0))?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_abandoned_shells() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let req = open_request(&Encrypt::new(""));
+    let name = client.open(req).await?.into_inner().name;
+    let session = server.state().lookup(&name).context("missing session")?;
+    session.add_shell(Sid(1), (0, 0))?;
+    session.add_shell(Sid(2), (0, 0))?;
+    session.close_shell(Sid(2), Default::default())?;
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), "", None).await?;
+    s.flush().await;
+    assert_eq!(s.shells.len(), 1);
+
+    // Only shells that are still open are closed.
+    assert_eq!(session.close_abandoned_shells(), [Sid(1)]);
+    s.flush().await;
+    assert!(s.shells.is_empty());
+    assert_eq!(s.closed.len(), 1);
+    assert_eq!(s.closed[0].0, Sid(1));
+    assert_eq!(s.closed[0].1.reason, "client stopped responding");
+    assert!(session.close_abandoned_shells().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_key_provider() -> Result<()> {
+    struct Custody;
+    impl KeyProvider for Custody {
+        fn key(&self) -> Result<String> {
+            Ok("kept-in-custody".into())
+        }
+    }
+
+    let server = TestServer::new().await;
+    let options = OpenOptions {
+        keys: Some(Arc::new(Custody)),
+        ..Default::default()
+    };
+    let controller = Controller::open(&server.endpoint(), "", Runner::Echo, options).await?;
+    assert_eq!(controller.encryption_key(), "kept-in-custody");
+    assert!(controller.url().ends_with("#kept-in-custody"));
+
+    let mut s = ClientSocket::connect(
+        &server.ws_endpoint(controller.name()),
+        "kept-in-custody",
+        None,
+    )
+    .await?;
+    s.flush().await;
+    assert_eq!(s.user_id, Uid(1));
+
+    // Keys that would break the session link are refused.
+    let options = OpenOptions {
+        keys: Some(Arc::new(KeySource::Command("echo bad,key".into()))),
+        ..Default::default()
+    };
+    assert!(
+        Controller::open(&server.endpoint(), "", Runner::Echo, options)
+            .await
+            .is_err()
+    );
+
+    assert_eq!(
+        "env:SSHX_KEY".parse(),
+        Ok(KeySource::Env("SSHX_KEY".into()))
+    );
+    assert!("keychain".parse::<KeySource>().is_err());
+
+    Ok(())
+}
+
+/// Serve a minimal SOCKS5 proxy that requires a username and password.
+async fn socks5_proxy(user: &'static str, password: &'static str) -> (String, Arc<AtomicU32>) {
+    async fn handshake(stream: &mut TcpStream, user: &str, password: &str) -> Result<String> {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).await?;
+        let mut methods = vec![0; header[1] as usize];
+        stream.read_exact(&mut methods).await?;
+        anyhow::ensure!(methods.contains(&2), "client did not offer password auth");
+        stream.write_all(&[5, 2]).await?;
+
+        stream.read_exact(&mut header).await?;
+        let mut got_user = vec![0; header[1] as usize];
+        stream.read_exact(&mut got_user).await?;
+        let mut len = [0; 1];
+        stream.read_exact(&mut len).await?;
+        let mut got_password = vec![0; len[0] as usize];
+        stream.read_exact(&mut got_password).await?;
+        if got_user != user.as_bytes() || got_password != password.as_bytes() {
+            stream.write_all(&[1, 1]).await?;
+            anyhow::bail!("wrong credentials");
+        }
+        stream.write_all(&[1, 0]).await?;
+
+        let mut request = [0; 4];
+        stream.read_exact(&mut request).await?;
+        let host = match request[3] {
+            1 => {
+                let mut ip = [0; 4];
+                stream.read_exact(&mut ip).await?;
+                std::net::Ipv4Addr::from(ip).to_string()
+            }
+            3 => {
+                stream.read_exact(&mut len).await?;
+                let mut name = vec![0; len[0] as usize];
+                stream.read_exact(&mut name).await?;
+                String::from_utf8(name)?
+            }
+            4 => {
+                let mut ip = [0; 16];
+                stream.read_exact(&mut ip).await?;
+                format!("[{}]", std::net::Ipv6Addr::from(ip))
+            }
+            atyp => anyhow::bail!("unknown address type {atyp}"),
+        };
+        let port = stream.read_u16().await?;
+        stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+        Ok(format!("{host}:{port}"))
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(AtomicU32::new(0));
+    let counter = Arc::clone(&connections);
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let counter = Arc::clone(&counter);
+            tokio::spawn(async move {
+                let target = handshake(&mut stream, user, password).await?;
+                let mut upstream = TcpStream::connect(target).await?;
+                counter.fetch_add(1, Ordering::SeqCst);
+                io::copy_bidirectional(&mut stream, &mut upstream).await?;
+                anyhow::Ok(())
+            });
+        }
+    });
+    (addr, connections)
+}
+
+#[tokio::test]
+async fn test_socks5_proxy() -> Result<()> {
+    let server = TestServer::new().await;
+    let (addr, connections) = socks5_proxy("alice", "hunter2").await;
+
+    let mut proxy: Socks5Proxy = addr.parse().unwrap();
+    proxy.auth = Some(("alice".into(), "hunter2".into()));
+    let options = OpenOptions {
+        socks5: Some(proxy.clone()),
+        ..Default::default()
+    };
+    let mut controller = Controller::open(&server.endpoint(), "", Runner::Echo, options).await?;
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    // The session works as usual, with its stream also going through the proxy.
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await;
+    s.flush().await;
+    assert_eq!(s.shells.len(), 1);
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+    proxy.auth = Some(("alice".into(), "wrong".into()));
+    let options = OpenOptions {
+        socks5: Some(proxy),
+        ..Default::default()
+    };
+    assert!(
+        Controller::open(&server.endpoint(), "", Runner::Echo, options)
+            .await
+            .is_err()
+    );
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+    assert!("localhost".parse::<Socks5Proxy>().is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_color_scheme() -> Result<()> {
+    let server = TestServer::new().await;
+    let options = OpenOptions {
+        color_scheme: Some(ColorScheme::Light),
+        ..Default::default()
+    };
+    let controller = Controller::open(&server.endpoint(), "", Runner::Echo, options).await?;
+
+    let mut s = ClientSocket::connect(
+        &server.ws_endpoint(controller.name()),
+        controller.encryption_key(),
+        None,
+    )
+    .await?;
+    s.flush().await;
+    assert!(s.capabilities.has(features::COLOR_SCHEME));
+    assert_eq!(s.color_scheme, Some(ColorScheme::Light));
+
+    // Sessions without a color scheme leave the choice to the web app.
+    let controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let mut s = ClientSocket::connect(
+        &server.ws_endpoint(controller.name()),
+        controller.encryption_key(),
+        None,
+    )
+    .await?;
+    s.flush().await;
+    assert_eq!(s.color_scheme, None);
+
+    assert_eq!(ColorScheme::from_colorfgbg("15;0"), Some(ColorScheme::Dark));
+    assert_eq!(
+        ColorScheme::from_colorfgbg("0;default;15"),
+        Some(ColorScheme::Light)
+    );
+    assert_eq!(ColorScheme::from_colorfgbg("default;default"), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shell_environment() -> Result<()> {
+    let server = TestServer::new().await;
+    let environment = ShellEnvironment {
+        shell: "/bin/zsh".into(),
+        term: "xterm-256color".into(),
+        vars: vec![("TERM".into(), "xterm-256color".into())],
+    };
+    let options = OpenOptions {
+        environment: Some(environment.clone()),
+        ..Default::default()
+    };
+    let controller = Controller::open(&server.endpoint(), "", Runner::Echo, options).await?;
+
+    let mut s = ClientSocket::connect(
+        &server.ws_endpoint(controller.name()),
+        controller.encryption_key(),
+        None,
+    )
+    .await?;
+    s.flush().await;
+    assert!(s.capabilities.has(features::ENVIRONMENT));
+    assert_eq!(s.environment, Some(environment));
+
+    // The server does not store arbitrarily large environments.
+    let options = OpenOptions {
+        environment: Some(ShellEnvironment {
+            vars: vec![("HUGE".into(), "x".repeat(100_000))],
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    assert!(
+        Controller::open(&server.endpoint(), "", Runner::Echo, options)
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_identity() -> Result<()> {
+    let server = TestServer::builder()
+        .secret("3uTbQx8Kc2LwZpYa7RmN")
+        .start()
+        .await;
+    let other = TestServer::builder()
+        .secret("3uTbQx8Kc2LwZpYa7RmN")
+        .start()
+        .await;
+    assert_eq!(server.state().identity(), other.state().identity());
+    assert_ne!(
+        server.state().identity(),
+        TestServer::new().await.state().identity()
+    );
+
+    let endpoint = server.endpoint();
+    let path = std::env::temp_dir().join(format!("sshx-known-servers-{}", rand_alphanumeric(8)));
+    let open = |check| {
+        let options = OpenOptions {
+            known_servers: Some(KnownServers::new(&path, check)),
+            ..Default::default()
+        };
+        Controller::open(&endpoint, "", Runner::Echo, options)
+    };
+
+    // The identity is pinned the first time, then checked.
+    open(IdentityCheck::Strict).await?;
+    let contents = std::fs::read_to_string(&path)?;
+    let identity = server.state().identity();
+    assert_eq!(contents, format!("{endpoint} {identity}\n"));
+    open(IdentityCheck::Strict).await?;
+
+    // Pretend that the server was replaced by one with a different identity.
+    std::fs::write(&path, format!("{endpoint} SHA256:other\n"))?;
+    let err = open(IdentityCheck::Strict).await.err().unwrap();
+    assert!(err.to_string().contains("changed from SHA256:other"));
+    open(IdentityCheck::Warn).await?;
+    open(IdentityCheck::Off).await?;
+
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_users_join() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let endpoint = server.ws_endpoint(&name);
+    let mut s1 = ClientSocket::connect(&endpoint, &key, None).await?;
+    s1.flush().await;
+    assert_eq!(s1.users.len(), 1);
+
+    let mut s2 = ClientSocket::connect(&endpoint, &key, None).await?;
+    s2.flush().await;
+    assert_eq!(s2.users.len(), 2);
+
+    drop(s2);
+    let mut s3 = ClientSocket::connect(&endpoint, &key, None).await?;
+    s3.flush().await;
+    assert_eq!(s3.users.len(), 2);
+
+    s1.flush().await;
+    assert_eq!(s1.users.len(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_users_metadata() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let endpoint = server.ws_endpoint(&name);
+    let mut s = ClientSocket::connect(&endpoint, &key, None).await?;
+    s.flush().await;
+    assert_eq!(s.users.len(), 1);
+    assert_eq!(s.users.get(&s.user_id).unwrap().cursor, None);
+
+    s.send(WsClient::SetName("mr. foo".into())).await;
+    s.send(WsClient::SetCursor(Some((40, 524)))).await;
+    s.flush().await;
+    let user = s.users.get(&s.user_id).unwrap();
+    assert_eq!(user.name, "mr. foo");
+    assert_eq!(user.cursor, Some((40, 524)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_profiles() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let endpoint = server.ws_endpoint(&name);
+    let mut s1 = ClientSocket::connect(&endpoint, &key, None).await?;
+    let mut s2 = ClientSocket::connect(&endpoint, &key, None).await?;
+    s1.flush().await;
+    s2.flush().await;
+    assert!(s1.capabilities.has(features::PROFILES));
+    assert_eq!(s2.users[&s1.user_id].color, 0);
+    assert_eq!(s2.users[&s2.user_id].color, 1);
+
+    let profile = WsProfile {
+        name: "alice".into(),
+        avatar: Some(" 🦀 ".into()),
+    };
+    s1.send(WsClient::SetProfile(profile)).await;
+    s1.flush().await;
+    s2.flush().await;
+    let user = &s2.users[&s1.user_id];
+    assert_eq!(user.name, "alice");
+    assert_eq!(user.avatar.as_deref(), Some("🦀"));
+
+    // An empty name is left unchanged, and long avatars are rejected.
+    s1.send(WsClient::SetProfile(WsProfile::default())).await;
+    let profile = WsProfile {
+        name: String::new(),
+        avatar: Some("much too long".into()),
+    };
+    s1.send(WsClient::SetProfile(profile)).await;
+    s1.flush().await;
+    let user = &s1.users[&s1.user_id];
+    assert_eq!((user.name.as_str(), &user.avatar), ("alice", &None));
+    assert_eq!(s1.errors.len(), 1);
+
+    // Colors of users who left are given to the next users who join.
+    drop(s1);
+    s2.flush().await;
+    assert_eq!(s2.users.len(), 1);
+    let mut s3 = ClientSocket::connect(&endpoint, &key, None).await?;
+    s3.flush().await;
+    assert_eq!(s3.users[&s3.user_id].color, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_viewport() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let endpoint = server.ws_endpoint(&name);
+    let mut s1 = ClientSocket::connect(&endpoint, &key, None).await?;
+    let mut s2 = ClientSocket::connect(&endpoint, &key, None).await?;
+    s1.flush().await;
+    assert!(s1.capabilities.has(features::VIEWPORTS));
+    assert_eq!(s1.users[&s1.user_id].viewport, None);
+
+    let viewport = WsViewport {
+        x: -120,
+        y: 45,
+        zoom: 150,
+    };
+    s1.send(WsClient::SetViewport(Some(viewport))).await;
+    s2.flush().await;
+    assert_eq!(s2.users[&s1.user_id].viewport, Some(viewport));
+
+    s1.send(WsClient::SetViewport(None)).await;
+    s2.flush().await;
+    assert_eq!(s2.users[&s1.user_id].viewport, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chat_messages() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let endpoint = server.ws_endpoint(&name);
+    let mut s1 = ClientSocket::connect(&endpoint, &key, None).await?;
+    let mut s2 = ClientSocket::connect(&endpoint, &key, None).await?;
+
+    s1.send(WsClient::SetName("billy".into())).await;
+    s1.send(WsClient::Chat("hello there!".into())).await;
+    s1.flush().await;
+
+    s2.flush().await;
+    assert_eq!(s2.messages.len(), 1);
+    assert_eq!(
+        s2.messages[0],
+        (s1.user_id, "billy".into(), "hello there!".into())
+    );
+
+    let mut s3 = ClientSocket::connect(&endpoint, &key, None).await?;
+    s3.flush().await;
+    assert_eq!(s1.messages.len(), 1);
+    assert_eq!(s3.messages.len(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shared_notes() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let endpoint = server.ws_endpoint(&name);
+    let mut s1 = ClientSocket::connect(&endpoint, &key, None).await?;
+    let mut s2 = ClientSocket::connect(&endpoint, &key, None).await?;
+    s1.flush().await;
+    assert!(s1.capabilities.has(features::NOTES));
+    assert_eq!(s1.notes, WsNotes::default());
+
+    // Edits are relayed in order, with their authors and base versions.
+    s1.send(WsClient::NotesEdit(0, Bytes::from_static(b"first")))
+        .await;
+    s2.send(WsClient::NotesEdit(0, Bytes::from_static(b"second")))
+        .await;
+    s1.flush().await;
+    s2.flush().await;
+    assert_eq!(s1.notes, s2.notes);
+    assert_eq!(s1.notes.edits.len(), 2);
+    assert!(s1.notes.edits.iter().all(|edit| edit.base == 0));
+
+    // Compacting replaces the earlier edits with the text.
+    s1.send(WsClient::NotesCompact(1, Bytes::from_static(b"text")))
+        .await;
+    let mut s3 = ClientSocket::connect(&endpoint, &key, None).await?;
+    s3.flush().await;
+    assert_eq!(s3.notes.version, 1);
+    assert_eq!(s3.notes.text.as_deref(), Some(&b"text"[..]));
+    assert_eq!(s3.notes.edits, s1.notes.edits[1..]);
+
+    // Edits on top of compacted versions are refused with the latest notes.
+    s2.notes = WsNotes::default();
+    s2.send(WsClient::NotesEdit(0, Bytes::from_static(b"stale")))
+        .await;
+    s2.flush().await;
+    assert_eq!(s2.notes, s3.notes);
+    s2.send(WsClient::NotesEdit(5, Bytes::from_static(b"future")))
+        .await;
+    s2.flush().await;
+    assert_eq!(s2.errors.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unsalted_write_password() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    // Older clients send the zeros of the write password without a salt.
+    let write_zeros = Encrypt::new("hunter2").zeros();
+    let req = OpenRequest {
+        write_password_hash: Some(write_zeros.clone().into()),
+        ..open_request(&Encrypt::new("key"))
+    };
+    let name = client.open(req).await?.into_inner().name;
+
+    // The server salts and hashes them before storing them.
+    let session = server.state().lookup(&name).context("missing session")?;
+    let metadata = session.metadata();
+    assert!(metadata.write_password_salt.is_some());
+    assert_ne!(
+        metadata.write_password_hash.as_deref(),
+        Some(&write_zeros[..])
+    );
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), "key", Some("hunter2")).await?;
+    s.flush().await;
+    assert!(s.users[&s.user_id].can_write);
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), "key", None).await?;
+    s.flush().await;
+    assert!(!s.users[&s.user_id].can_write);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_read_write_permissions() -> Result<()> {
+    let server = TestServer::new().await;
+
+    // create controller with read-only mode enabled
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, true).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    let write_url = controller
+        .write_url()
+        .expect("Should have write URL when enable_readers is true")
+        .to_string();
+
+    tokio::spawn(async move { controller.run().await });
+
+    let write_password = write_url
+        .split(',')
+        .nth(1)
+        .expect("Write URL should contain password");
+
+    // connect with write access
+    let mut writer =
+        ClientSocket::connect(&server.ws_endpoint(&name), &key, Some(write_password)).await?;
+    writer.flush().await;
+
+    // test write permissions
+    writer.send(WsClient::Create(0, 0)).await;
+    writer.flush().await;
+    assert_eq!(
+        writer.shells.len(),
+        1,
+        "Writer should be able to create a shell"
+    );
+    assert!(writer.errors.is_empty(), "Writer should not receive errors");
+
+    // connect with read-only access
+    let mut reader = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    reader.flush().await;
+
+    // test read-only restrictions
+    reader.send(WsClient::Create(0, 0)).await;
+    reader.flush().await;
+    assert!(
+        !reader.errors.is_empty(),
+        "Reader should receive an error when attempting to create shell"
+    );
+    assert_eq!(
+        reader.shells.len(),
+        1,
+        "Reader should still see the existing shell"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ws_message_too_large() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Chat("a".repeat(2 << 20))).await;
+    s.expect_close_eventually(close_codes::MESSAGE_TOO_LARGE)
+        .await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ws_rate_limit() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    for i in 0..250 {
+        s.send(WsClient::Ping(i)).await;
+    }
+    s.expect_close_eventually(close_codes::RATE_LIMITED).await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_notice() -> Result<()> {
+    let server = TestServer::builder().admin_token("hunter2").start().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.flush().await;
+
+    let resp = reqwest::Client::new()
+        .post(format!("{}/api/admin/notice", server.endpoint()))
+        .bearer_auth("hunter2")
+        .header("content-type", "application/json")
+        .body(r#"{"message":"restarting soon"}"#)
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 204);
+
+    s.flush().await;
+    assert_eq!(s.notices, vec!["restarting soon".to_string()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_admin_pin() -> Result<()> {
+    let server = TestServer::builder().admin_token("hunter2").start().await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let http = reqwest::Client::new();
+    let url = format!("{}/api/admin/sessions/{name}/pin", server.endpoint());
+    let session = server.state().lookup(&name).unwrap();
+    assert!(!session.pinned());
+
+    let resp = http.put(&url).bearer_auth("hunter2").send().await?;
+    assert_eq!(resp.status(), 204);
+    assert!(session.pinned());
+
+    let resp = http
+        .get(format!("{}/api/admin/sessions/{name}", server.endpoint()))
+        .bearer_auth("hunter2")
+        .send()
+        .await?;
+    let detail: serde_json::Value = resp.json().await?;
+    assert_eq!(detail["pinned"], true);
+
+    let resp = http.delete(&url).bearer_auth("hunter2").send().await?;
+    assert_eq!(resp.status(), 204);
+    assert!(!session.pinned());
+
+    let url = format!("{}/api/admin/sessions/missing/pin", server.endpoint());
+    let resp = http.put(&url).bearer_auth("hunter2").send().await?;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_banner() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| options.banner = Some("activity is monitored".into()))
+        .start()
+        .await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    assert_eq!(controller.banner(), Some("activity is monitored"));
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.flush().await;
+    assert_eq!(s.banner.as_deref(), Some("activity is monitored"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_input_rate_limit() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| options.input_rate_limit = Some(10))
+        .start()
+        .await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await;
+    s.flush().await;
+    s.send(WsClient::Subscribe(Sid(1), 0, false)).await;
+
+    s.send_input(Sid(1), b"hello!").await;
+    s.send_input(Sid(1), b" 12345").await;
+    s.flush().await;
+    assert_eq!(s.read(Sid(1)), "hello!");
+    assert_eq!(s.throttled.len(), 1);
+    assert_eq!(s.throttled[0].0, Sid(1));
+    assert!(s.throttled[0].1 > 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_keepalive_timeout() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| options.ping_interval = Some(Duration::from_millis(100)))
+        .start()
+        .await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    // A client that keeps reading answers pings and stays connected.
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    for _ in 0..10 {
+        s.flush().await;
+    }
+    s.send(WsClient::Chat("still here".into())).await;
+    s.flush().await;
+    assert_eq!(s.messages.len(), 1);
+    assert_eq!(server.state().metrics().ws_connections.get(), 1);
+
+    // A client that stops reading is disconnected after missing pings.
+    time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(server.state().metrics().ws_connections.get(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_join_links() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let req = OpenRequest {
+        write_password_hash: Some(Encrypt::new("pw").zeros().into()),
+        ..open_request(&Encrypt::new("key"))
+    };
+    let resp = client.open(req).await?.into_inner();
+    let (name, token) = (resp.name, resp.token);
+
+    let http = reqwest::Client::new();
+    let mint = |role: &str| {
+        http.post(format!("{}/api/s/{name}/links", server.endpoint()))
+            .header("content-type", "application/json")
+            .body(format!(r#"{{"role":"{role}","expiresIn":60}}"#))
+    };
+    assert_eq!(mint("write").send().await?.status(), 401);
+
+    let grant_for = |body: String| -> Result<String> {
+        let value: serde_json::Value = serde_json::from_str(&body)?;
+        Ok(value["grant"].as_str().context("missing grant")?.to_owned())
+    };
+    let resp = mint("write").bearer_auth(&token).send().await?;
+    let write_grant = grant_for(resp.text().await?)?;
+    let resp = mint("read").bearer_auth(&token).send().await?;
+    let read_grant = grant_for(resp.text().await?)?;
+
+    // A write link does not replace the write password.
+    let endpoint = format!("{}?grant={write_grant}", server.ws_endpoint(&name));
+    let mut s = ClientSocket::connect(&endpoint, "key", None).await?;
+    s.flush().await;
+    assert!(!s.users[&s.user_id].can_write);
+    let mut writer = ClientSocket::connect(&endpoint, "key", Some("pw")).await?;
+    writer.send(WsClient::Create(0, 0)).await;
+    writer.flush().await;
+    assert!(writer.errors.is_empty());
+
+    // A read link overrides the write password.
+    let endpoint = format!("{}?grant={read_grant}", server.ws_endpoint(&name));
+    let mut reader = ClientSocket::connect(&endpoint, "key", Some("pw")).await?;
+    reader.send(WsClient::Create(0, 0)).await;
+    reader.flush().await;
+    assert!(!reader.errors.is_empty());
+
+    let endpoint = format!("{}?grant=bogus", server.ws_endpoint(&name));
+    let mut s = ClientSocket::connect(&endpoint, "key", None).await?;
+    s.expect_close(close_codes::FORBIDDEN).await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_join_links_required() -> Result<()> {
+    use base64::prelude::{Engine as _, BASE64_STANDARD};
+
+    let server = TestServer::builder()
+        .options(|options| options.require_join_grants = true)
+        .start()
+        .await;
+    let mut client = server.grpc_client().await;
+
+    let req = OpenRequest {
+        write_password_hash: Some(Encrypt::new("pw").zeros().into()),
+        ..open_request(&Encrypt::new("key"))
+    };
+    let resp = client.open(req).await?.into_inner();
+    let (name, token) = (resp.name, resp.token);
+
+    // Users without a link are refused, unless they have the write password.
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), "key", None).await?;
+    s.expect_close_eventually(close_codes::UNAUTHORIZED).await;
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), "key", Some("pw")).await?;
+    s.flush().await;
+    assert!(s.users[&s.user_id].can_write);
+
+    let http = reqwest::Client::new();
+    let resp = http
+        .post(format!("{}/api/s/{name}/links", server.endpoint()))
+        .header("content-type", "application/json")
+        .body(r#"{"role":"read","expiresIn":60}"#)
+        .bearer_auth(&token)
+        .send()
+        .await?;
+    let value: serde_json::Value = serde_json::from_str(&resp.text().await?)?;
+    let grant = value["grant"].as_str().context("missing grant")?;
+
+    let endpoint = format!("{}?grant={grant}", server.ws_endpoint(&name));
+    let mut s = ClientSocket::connect(&endpoint, "key", None).await?;
+    s.flush().await;
+    assert!(!s.users[&s.user_id].can_write);
+
+    // Transcripts need a link too.
+    let zeros = BASE64_STANDARD.encode(Encrypt::new("key").zeros());
+    let url = format!("{}/api/s/{name}/transcript", server.endpoint());
+    let resp = http.get(&url).bearer_auth(&zeros).send().await?;
+    assert_eq!(resp.status(), 403);
+    let url = format!("{url}?grant={grant}");
+    let resp = http.get(&url).bearer_auth(&zeros).send().await?;
+    assert_eq!(resp.status(), 200);
+
+    Ok(())
+}
+
+/// Read the next Server-Sent Event from a response, as an `(event, data)` pair.
+async fn next_event(resp: &mut reqwest::Response, buf: &mut String) -> Result<(String, String)> {
+    loop {
+        if let Some(end) = buf.find("\n\n") {
+            let block: String = buf.drain(..end + 2).collect();
+            let (mut event, mut data) = (String::from("message"), String::new());
+            for line in block.lines() {
+                if let Some(value) = line.strip_prefix("event:") {
+                    event = value.trim().into();
+                } else if let Some(value) = line.strip_prefix("data:") {
+                    data = value.trim().into();
+                }
+            }
+            if !data.is_empty() {
+                return Ok((event, data));
+            }
+            continue;
+        }
+        let chunk = resp.chunk().await?.context("event stream ended")?;
+        buf.push_str(std::str::from_utf8(&chunk)?);
+    }
+}
+
+/// Read the next protocol message from a Server-Sent Events response.
+async fn next_message(resp: &mut reqwest::Response, buf: &mut String) -> Result<WsServer> {
+    use base64::prelude::{Engine as _, BASE64_STANDARD};
+
+    let (_, data) = next_event(resp, buf).await?;
+    Ok(ciborium::de::from_reader(&*BASE64_STANDARD.decode(data)?)?)
+}
+
+#[tokio::test]
+async fn test_event_stream() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+    let req = open_request(&Encrypt::new("key"));
+    let name = client.open(req).await?.into_inner().name;
+
+    let http = reqwest::Client::new();
+    let url = format!("{}/api/s/missing/events", server.endpoint());
+    assert_eq!(http.get(url).send().await?.status(), 404);
+
+    let url = format!("{}/api/s/{name}/events", server.endpoint());
+    let mut resp = http
+        .get(url)
+        .header("accept-encoding", "gzip")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "text/event-stream");
+    assert!(resp.headers().get("content-encoding").is_none());
+
+    let mut buf = String::new();
+    let (event, path) = next_event(&mut resp, &mut buf).await?;
+    assert_eq!(event, "connection");
+    let post = |msg: WsClient| {
+        let mut body = Vec::new();
+        ciborium::ser::into_writer(&msg, &mut body).unwrap();
+        http.post(format!("{}{path}", server.endpoint()))
+            .body(body)
+            .send()
+    };
+
+    assert!(matches!(
+        next_message(&mut resp, &mut buf).await?,
+        WsServer::Hello(..)
+    ));
+    let zeros = Encrypt::new("key").zeros().into();
+    assert_eq!(
+        post(WsClient::Authenticate(zeros, None, None))
+            .await?
+            .status(),
+        204
+    );
+    assert!(matches!(
+        next_message(&mut resp, &mut buf).await?,
+        WsServer::Users(..)
+    ));
+
+    assert_eq!(post(WsClient::Chat("hi".into())).await?.status(), 204);
+    loop {
+        if let WsServer::Hear(_, _, msg) = next_message(&mut resp, &mut buf).await? {
+            assert_eq!(msg, "hi");
+            break;
+        }
+    }
+
+    let bogus = format!("{}/api/s/{name}/events/bogus", server.endpoint());
+    assert_eq!(http.post(bogus).body(vec![]).send().await?.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mesh_event_stream() -> Result<()> {
+    let Some(redis) = test_redis_url() else {
+        return Ok(());
+    };
+    let owner = TestServer::builder().redis(&redis).start().await;
+    let other = TestServer::builder().redis(&redis).start().await;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let req = OpenRequest {
+        session_name: Some(format!("mesh-events-{}", now.as_micros())),
+        ..open_request(&Encrypt::new("key"))
+    };
+    let name = owner.grpc_client().await.open(req).await?.into_inner().name;
+
+    // The event stream and posted messages are proxied to the owner.
+    let http = reqwest::Client::new();
+    let url = format!("{}/api/s/{name}/events", other.endpoint());
+    let mut resp = http.get(url).send().await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "text/event-stream");
+
+    let mut buf = String::new();
+    let (event, path) = next_event(&mut resp, &mut buf).await?;
+    assert_eq!(event, "connection");
+    assert!(matches!(
+        next_message(&mut resp, &mut buf).await?,
+        WsServer::Hello(..)
+    ));
+    let zeros = Encrypt::new("key").zeros().into();
+    let mut body = Vec::new();
+    ciborium::ser::into_writer(&WsClient::Authenticate(zeros, None, None), &mut body)?;
+    let url = format!("{}{path}", other.endpoint());
+    assert_eq!(http.post(url).body(body).send().await?.status(), 204);
+    assert!(matches!(
+        next_message(&mut resp, &mut buf).await?,
+        WsServer::Users(..)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transcript_download() -> Result<()> {
+    use base64::prelude::{Engine as _, BASE64_STANDARD};
+
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+    let encrypt = Encrypt::new("key");
+    let req = open_request(&encrypt);
+    let name = client.open(req).await?.into_inner().name;
+
+    let session = server.state().lookup(&name).context("missing session")?;
+    session.add_shell(Sid(1), (0, 0))?;
+    let stream = 0x100000000 | 1;
+    session.add_data(
+        Sid(1),
+        encrypt.segment(stream, 0, b"hello ").into(),
+        0,
+        1000,
+    )?;
+    session.add_data(Sid(1), encrypt.segment(stream, 6, b"world").into(), 6, 2000)?;
+
+    let http = reqwest::Client::new();
+    let url = format!("{}/api/s/{name}/transcript", server.endpoint());
+    assert_eq!(http.get(&url).send().await?.status(), 401);
+    let wrong = BASE64_STANDARD.encode(Encrypt::new("wrong").zeros());
+    let resp = http.get(&url).bearer_auth(wrong).send().await?;
+    assert_eq!(resp.status(), 401);
+
+    let zeros = BASE64_STANDARD.encode(encrypt.zeros());
+    let resp = http.get(&url).bearer_auth(zeros).send().await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "application/cbor-seq");
+
+    let body = resp.bytes().await?;
+    let mut reader = &*body;
+    let mut records = Vec::new();
+    while !reader.is_empty() {
+        let record: TranscriptRecord = ciborium::de::from_reader(&mut reader)?;
+        records.push(record);
+    }
+    assert_eq!(records.len(), 2);
+    assert!(matches!(&records[0], TranscriptRecord::Header(n, _) if *n == name));
+    let TranscriptRecord::Chunks(id, seqnum, chunks) = &records[1] else {
+        panic!("expected chunks record");
+    };
+    assert_eq!((*id, *seqnum), (Sid(1), 0));
+    let encrypted: Vec<u8> = chunks.concat();
+    assert_eq!(encrypt.segment(stream, 0, &encrypted), b"hello world");
+
+    let zeros = BASE64_STANDARD.encode(encrypt.zeros());
+    let resp = http
+        .get(format!("{url}?timestamps=true"))
+        .bearer_auth(zeros)
+        .send()
+        .await?;
+    let body = resp.bytes().await?;
+    let mut reader = &*body;
+    let _: TranscriptRecord = ciborium::de::from_reader(&mut reader)?;
+    let record: TranscriptRecord = ciborium::de::from_reader(&mut reader)?;
+    let TranscriptRecord::TimedChunks(_, _, chunks, times) = record else {
+        panic!("expected timed chunks record");
+    };
+    assert_eq!(chunks.len(), 2);
+    assert_eq!(times, [1000, 2000]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_output_polling() -> Result<()> {
+    use base64::prelude::{Engine as _, BASE64_STANDARD};
+
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+    let encrypt = Encrypt::new("key");
+    let req = open_request(&encrypt);
+    let name = client.open(req).await?.into_inner().name;
+
+    let session = server.state().lookup(&name).context("missing session")?;
+    session.add_shell(Sid(1), (0, 0))?;
+    let stream = 0x100000000 | 1;
+    session.add_data(Sid(1), encrypt.segment(stream, 0, b"hello ").into(), 0, 0)?;
+    session.add_data(Sid(1), encrypt.segment(stream, 6, b"world").into(), 6, 0)?;
+
+    let http = reqwest::Client::new();
+    let zeros = BASE64_STANDARD.encode(encrypt.zeros());
+    let url = format!("{}/api/s/{name}/shells/1/output", server.endpoint());
+    assert_eq!(http.get(&url).send().await?.status(), 401);
+    let missing = format!("{}/api/s/{name}/shells/2/output", server.endpoint());
+    let resp = http.get(&missing).bearer_auth(&zeros).send().await?;
+    assert_eq!(resp.status(), 404);
+
+    // Output can be read from any offset, decrypting it at that offset.
+    let resp = http
+        .get(format!("{url}?from=3"))
+        .bearer_auth(&zeros)
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["x-sshx-offset"], "3");
+    assert_eq!(resp.headers()["x-sshx-next"], "11");
+    let body = resp.bytes().await?;
+    assert_eq!(encrypt.segment(stream, 3, &body), b"lo world");
+
+    // Polling waits for new output past the offset.
+    let poll = http
+        .get(format!("{url}?from=11&wait=5"))
+        .bearer_auth(&zeros)
+        .send();
+    let write = async {
+        time::sleep(Duration::from_millis(100)).await;
+        session.add_data(Sid(1), encrypt.segment(stream, 11, b"!").into(), 11, 0)
+    };
+    let (resp, written) = tokio::join!(poll, write);
+    written?;
+    let resp = resp?;
+    assert_eq!(resp.headers()["x-sshx-offset"], "11");
+    assert_eq!(resp.headers()["x-sshx-next"], "12");
+    assert_eq!(encrypt.segment(stream, 11, &resp.bytes().await?), b"!");
+
+    // Without a wait, the response is empty if there is no new output.
+    let resp = http
+        .get(format!("{url}?from=12"))
+        .bearer_auth(&zeros)
+        .send()
+        .await?;
+    assert_eq!(resp.headers()["x-sshx-next"], "12");
+    assert!(resp.bytes().await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mesh_transcript_and_output() -> Result<()> {
+    use base64::prelude::{Engine as _, BASE64_STANDARD};
+
+    let Some(redis) = test_redis_url() else {
+        return Ok(());
+    };
+    let owner = TestServer::builder().redis(&redis).start().await;
+    let other = TestServer::builder().redis(&redis).start().await;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let encrypt = Encrypt::new("key");
+    let req = OpenRequest {
+        session_name: Some(format!("mesh-output-{}", now.as_micros())),
+        ..open_request(&encrypt)
+    };
+    let name = owner.grpc_client().await.open(req).await?.into_inner().name;
+
+    let session = owner.state().lookup(&name).context("missing session")?;
+    session.add_shell(Sid(1), (0, 0))?;
+    let stream = 0x100000000 | 1;
+    session.add_data(Sid(1), encrypt.segment(stream, 0, b"hello").into(), 0, 0)?;
+
+    // Requests to the other server are proxied to the owner.
+    let http = reqwest::Client::new();
+    let zeros = BASE64_STANDARD.encode(encrypt.zeros());
+    let url = format!("{}/api/s/{name}/transcript", other.endpoint());
+    assert_eq!(http.get(&url).send().await?.status(), 401);
+    let resp = http.get(&url).bearer_auth(&zeros).send().await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "application/cbor-seq");
+    let record: TranscriptRecord = ciborium::de::from_reader(&*resp.bytes().await?)?;
+    assert!(matches!(record, TranscriptRecord::Header(n, _) if n == name));
+
+    let url = format!("{}/api/s/{name}/shells/1/output", other.endpoint());
+    let resp = http.get(&url).bearer_auth(&zeros).send().await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["x-sshx-next"], "5");
+    assert_eq!(encrypt.segment(stream, 0, &resp.bytes().await?), b"hello");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replay() -> Result<()> {
+    use sshx::replay::{self, PlaybackOptions};
+
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+    let encrypt = Encrypt::new("key");
+    let req = open_request(&encrypt);
+    let name = client.open(req).await?.into_inner().name;
+
+    let session = server.state().lookup(&name).context("missing session")?;
+    session.add_shell(Sid(1), (0, 0))?;
+    let stream = 0x100000000 | 1;
+    session.add_data(Sid(1), encrypt.segment(stream, 0, b"hi ").into(), 0, 1000)?;
+    session.add_data(Sid(1), encrypt.segment(stream, 3, b"there").into(), 3, 1300)?;
+
+    let url = format!("{}/s/{name}#key", server.endpoint());
+    let (data, key) = replay::fetch_transcript(&url).await?;
+    let frames = replay::decrypt_transcript(&data, &key, None)?;
+    assert_eq!(
+        frames,
+        [
+            (Duration::ZERO, b"hi ".to_vec()),
+            (Duration::from_millis(300), b"there".to_vec())
+        ]
+    );
+    assert!(replay::decrypt_transcript(&data, &key, Some(Sid(2))).is_err());
+    assert!(replay::decrypt_transcript(&data, "wrong", None).is_err());
+
+    let cast = "{\"version\": 2, \"width\": 80, \"height\": 24}\n[0.5, \"o\", \"a\"]\n[0.6, \
+                \"i\", \"x\"]\n[5.0, \"o\", \"b\"]\n";
+    let frames = replay::parse_asciicast(cast)?;
+    assert_eq!(frames.len(), 2);
+
+    let options = PlaybackOptions {
+        speed: 10.0,
+        idle_limit: Some(Duration::from_millis(100)),
+    };
+    let mut out = Vec::new();
+    let start = time::Instant::now();
+    replay::play(&frames, options, &mut out).await?;
+    assert_eq!(out, b"ab");
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    Ok(())
+}
+
+/// Read the next JSON text message from a WebSocket.
+async fn next_json(
+    ws: &mut (impl futures_util::Stream<Item = tungstenite::Result<tungstenite::Message>> + Unpin),
+) -> Result<serde_json::Value> {
+    use futures_util::StreamExt;
+    loop {
+        match ws.next().await.context("socket closed")?? {
+            tungstenite::Message::Text(text) => break Ok(serde_json::from_str(&text)?),
+            tungstenite::Message::Binary(_) => panic!("unexpected binary message"),
+            _ => (),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_json_protocol() -> Result<()> {
+    use futures_util::SinkExt;
+    use tungstenite::{client::IntoClientRequest, Message};
+
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+    let req = open_request(&Encrypt::new("key"));
+    let name = client.open(req).await?.into_inner().name;
+
+    let mut request = server.ws_endpoint(&name).into_client_request()?;
+    let protocol = "sshx-json".parse()?;
+    request
+        .headers_mut()
+        .insert("sec-websocket-protocol", protocol);
+    let (mut ws, resp) = tokio_tungstenite::connect_async(request).await?;
+    assert_eq!(resp.headers()["sec-websocket-protocol"], "sshx-json");
+
+    let hello = next_json(&mut ws).await?;
+    assert_eq!(hello["hello"][1], "");
+    assert_eq!(hello["hello"][2], PROTOCOL_VERSION);
+
+    // Clients from before protocol versioning omit the version.
+    let auth = serde_json::json!({ "authenticate": [Encrypt::new("key").zeros(), null] });
+    ws.send(Message::Text(auth.to_string())).await?;
+    let users = next_json(&mut ws).await?;
+    assert_eq!(users["users"][0][1]["canWrite"], true);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_mesh_json_protocol() -> Result<()> {
+    use tungstenite::client::IntoClientRequest;
+
+    let Some(redis) = test_redis_url() else {
+        return Ok(());
+    };
+    let owner = TestServer::builder().redis(&redis).start().await;
+    let other = TestServer::builder().redis(&redis).start().await;
+
+    // A requested name is claimed in the mesh right away, for the first server.
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    let req = OpenRequest {
+        session_name: Some(format!("mesh-json-{}", now.as_micros())),
+        ..open_request(&Encrypt::new("key"))
+    };
+    let name = owner.grpc_client().await.open(req).await?.into_inner().name;
+
+    // Connections to the other server are proxied, and still receive JSON.
+    let mut request = other.ws_endpoint(&name).into_client_request()?;
+    let protocol = "sshx-json".parse()?;
+    request
+        .headers_mut()
+        .insert("sec-websocket-protocol", protocol);
+    let (mut ws, resp) = tokio_tungstenite::connect_async(request).await?;
+    assert_eq!(resp.headers()["sec-websocket-protocol"], "sshx-json");
+    let hello = next_json(&mut ws).await?;
+    assert_eq!(hello["hello"][2], PROTOCOL_VERSION);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_protocol_version_mismatch() -> Result<()> {
+    use futures_util::{SinkExt, StreamExt};
+    use tungstenite::protocol::frame::coding::CloseCode;
+    use tungstenite::Message;
+
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+    let req = open_request(&Encrypt::new("key"));
+    let name = client.open(req).await?.into_inner().name;
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(server.ws_endpoint(&name)).await?;
+    let zeros = Encrypt::new("key").zeros().into();
+    let auth = WsClient::Authenticate(zeros, None, Some(PROTOCOL_VERSION + 1));
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(&auth, &mut buf)?;
+    ws.send(Message::Binary(buf)).await?;
+
+    loop {
+        if let Message::Close(frame) = ws.next().await.context("socket closed")?? {
+            assert_eq!(
+                frame.context("no close frame")?.code,
+                CloseCode::from(close_codes::UPGRADE_REQUIRED)
+            );
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shutdown_notifies_clients() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let req = open_request(&Encrypt::new("key"));
+    let resp = client.open(req).await?.into_inner();
+
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let hello = ClientMessage::Hello(format!("{},{}", resp.name, resp.token));
+    tx.send(ClientUpdate {
+        client_message: Some(hello),
+    })
+    .await?;
+    let mut updates = client
+        .channel(tokio_stream::wrappers::ReceiverStream::new(rx))
+        .await?
+        .into_inner();
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&resp.name), "key", None).await?;
+    s.flush().await;
+
+    server.state().shutdown();
+    s.expect_close_eventually(close_codes::UNAVAILABLE).await;
+    loop {
+        let update = updates.message().await?.context("stream ended early")?;
+        if let Some(ServerMessage::Shutdown(reason)) = update.server_message {
+            assert_eq!(reason, "server restarting");
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_key_signatures() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let key = ClientKey::generate();
+    let req = OpenRequest {
+        client_key: Some(key.public_key().to_vec().into()),
+        ..open_request(&Encrypt::new("key"))
+    };
+    let resp = client.open(req).await?.into_inner();
+
+    let connect = |hello: String| {
+        let mut client = client.clone();
+        async move {
+            let (tx, rx) = tokio::sync::mpsc::channel(16);
+            tx.send(ClientUpdate {
+                client_message: Some(ClientMessage::Hello(hello)),
+            })
+            .await?;
+            let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
+            anyhow::Ok(client.channel(stream).await.map(|_| ()))
+        }
+    };
+    let hello = format!("{},{}", resp.name, resp.token);
+    let status = connect(hello.clone()).await?.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+    // Signatures from another key, or from long ago, are refused.
+    let signed = ClientKey::generate().sign_hello(&resp.name, &resp.token);
+    assert!(connect(format!("{hello},{signed}")).await?.is_err());
+    let signed = key.sign_hello(&resp.name, &resp.token);
+    let (_, signature) = signed.split_once(',').unwrap();
+    assert!(connect(format!("{hello},1,{signature}")).await?.is_err());
+
+    connect(format!("{hello},{signed}")).await??;
+
+    // Each signature is only accepted once.
+    let status = connect(format!("{hello},{signed}")).await?.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    time::sleep(Duration::from_millis(2)).await;
+    let signed = key.sign_hello(&resp.name, &resp.token);
+    connect(format!("{hello},{signed}")).await??;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_command_palette() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    assert!(controller.capabilities().has(features::PALETTE));
+    let items = vec![
+        PaletteItem {
+            label: "List files".into(),
+            command: Some("ls\r".into()),
+            link: None,
+        },
+        PaletteItem {
+            label: "Docs".into(),
+            command: None,
+            link: Some("https://sshx.io".into()),
+        },
+    ];
+    controller.set_palette(&items)?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.flush().await;
+    assert_eq!(s.palette, items);
+
+    // The palette is kept for users who join later.
+    let mut s2 = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s2.flush().await;
+    assert_eq!(s2.palette, items);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_legacy_closed_shell() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let resp = client.open(open_request(&Encrypt::new("key"))).await?;
+    let resp = resp.into_inner();
+    let session = server
+        .state()
+        .lookup(&resp.name)
+        .context("missing session")?;
+    session.add_shell(Sid(1), (0, 0))?;
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&resp.name), "key", None).await?;
+    s.flush().await;
+    assert_eq!(s.shells.len(), 1);
+
+    // Older clients acknowledge closed shells with a bare ID in field 4.
+    let update = ClientUpdate::decode(&[0x20, 0x01][..])?;
+    assert_eq!(update.client_message, Some(ClientMessage::ClosedShell(1)));
+
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let hello = ClientMessage::Hello(format!("{},{}", resp.name, resp.token));
+    tx.send(ClientUpdate {
+        client_message: Some(hello),
+    })
+    .await?;
+    tx.send(update).await?;
+    let _updates = client
+        .channel(tokio_stream::wrappers::ReceiverStream::new(rx))
+        .await?;
+
+    s.flush().await;
+    assert!(s.shells.is_empty());
+    assert_eq!(s.closed, [(Sid(1), Default::default())]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_auxiliary_streams() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let encrypt = Encrypt::new("key");
+    let req = open_request(&encrypt);
+    let resp = client.open(req).await?.into_inner();
+
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    let hello = ClientMessage::Hello(format!("{},{}", resp.name, resp.token));
+    tx.send(ClientUpdate {
+        client_message: Some(hello),
+    })
+    .await?;
+    let mut updates = client
+        .channel(tokio_stream::wrappers::ReceiverStream::new(rx))
+        .await?
+        .into_inner();
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&resp.name), "key", None).await?;
+    s.flush().await;
+
+    let stream = StreamData {
+        id: 3,
+        kind: StreamKind::Clipboard.into(),
+        data: encrypt.segment(0x300000003, 0, b"copied").into(),
+        offset: 0,
+    };
+    tx.send(ClientUpdate {
+        client_message: Some(ClientMessage::Stream(stream)),
+    })
+    .await?;
+    s.flush().await;
+    assert_eq!(
+        s.streams,
+        [(3, WsStreamKind::Clipboard, b"copied".to_vec())]
+    );
+
+    let data = encrypt.segment(0x400000005, 0, b"pasted");
+    s.send(WsClient::Stream(5, WsStreamKind::File, data.into(), 0))
+        .await;
+    loop {
+        let update = updates.message().await?.context("stream ended early")?;
+        if let Some(ServerMessage::Stream(stream)) = update.server_message {
+            assert_eq!(stream.id, 5);
+            assert_eq!(stream.kind(), StreamKind::File);
+            assert_eq!(encrypt.segment(0x400000005, 0, &stream.data), b"pasted");
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ws_per_ip_limit() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| options.max_ws_per_ip = Some(1))
+        .start()
+        .await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.flush().await;
+
+    let err = ClientSocket::connect(&server.ws_endpoint(&name), &key, None)
+        .await
+        .err()
+        .context("second connection should be rejected")?;
+    match err.downcast_ref::<tungstenite::Error>() {
+        Some(tungstenite::Error::Http(resp)) => assert_eq!(resp.status(), 429),
+        _ => panic!("unexpected error: {err:?}"),
+    }
+
+    drop(s);
+    time::sleep(Duration::from_millis(100)).await;
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.flush().await;
+    assert_eq!(s.user_id, Uid(2));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_drain_timeout() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| options.drain_timeout = Some(Duration::from_millis(500)))
+        .start()
+        .await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.flush().await;
+
+    // Open connections keep working while the server drains.
+    server.shutdown();
+    time::sleep(Duration::from_millis(100)).await;
+    s.send(WsClient::Chat("still here".into())).await;
+    s.flush().await;
+    assert_eq!(s.messages.len(), 1);
+
+    time::timeout(
+        Duration::from_secs(2),
+        s.expect_close_eventually(close_codes::UNAVAILABLE),
+    )
+    .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_bandwidth_limit() -> Result<()> {
+    let server = TestServer::builder()
+        .options(|options| options.max_user_bytes = Some(64))
+        .start()
+        .await;
+
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await;
+    s.flush().await;
+    s.send(WsClient::Subscribe(Sid(1), 0, false)).await;
+    s.send_input(Sid(1), b"hello!").await;
+    s.flush().await;
+    assert_eq!(s.read(Sid(1)), "hello!");
+
+    // Input and output both count towards the limit.
+    let session = server.state().lookup(&name).context("missing session")?;
+    let bandwidth = session.user_bandwidth();
+    assert_eq!(bandwidth.len(), 1);
+    assert_eq!(bandwidth[0].1.total(), 12);
+    assert!(session.client_bandwidth().total() >= 12);
+
+    s.send_input(Sid(1), &[b'x'; 64]).await;
+    s.expect_close_eventually(close_codes::BANDWIDTH_EXCEEDED)
+        .await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_duplicate_backend_connection() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let req = open_request(&Encrypt::new("key"));
+    let resp = client.open(req).await?.into_inner();
+
+    let connect = || {
+        let mut client = client.clone();
+        let hello = ClientMessage::Hello(format!("{},{}", resp.name, resp.token));
+        async move {
+            let (tx, rx) = tokio::sync::mpsc::channel(16);
+            tx.send(ClientUpdate {
+                client_message: Some(hello),
+            })
+            .await?;
+            let updates = client
+                .channel(tokio_stream::wrappers::ReceiverStream::new(rx))
+                .await?
+                .into_inner();
+            anyhow::Ok((tx, updates))
+        }
+    };
+    let (_tx1, mut updates1) = connect().await?;
+    let (_tx2, mut updates2) = connect().await?;
+
+    // The stale stream is terminated, and the newer one gets the updates.
+    let status = loop {
+        match updates1.message().await {
+            Ok(Some(_)) => continue,
+            Ok(None) => panic!("stream ended without an error"),
+            Err(status) => break status,
+        }
+    };
+    assert_eq!(status.code(), tonic::Code::Aborted);
+
+    let session = server
+        .state()
+        .lookup(&resp.name)
+        .context("missing session")?;
+    session
+        .update_tx()
+        .send(ServerMessage::CloseShell(1))
+        .await?;
+    loop {
+        let update = updates2.message().await?.context("stream ended early")?;
+        if let Some(ServerMessage::CloseShell(id)) = update.server_message {
+            assert_eq!(id, 1);
+            break;
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,209 @@
+//! Several independent sessions shared from a single process.
+//!
+//! An agent runs a controller for each named session, with its own runner, on
+//! a shared runtime. On Unix, sessions can be managed while the agent is
+//! running by sending commands to a control socket, one per line:
+//!
+//! - `list` returns all open sessions.
+//! - `open <name> [shell]` opens a session, running a shell command if given.
+//! - `close <name>` closes a session.
+//!
+//! Each command is answered with a JSON object on one line, which has an
+//! `error` field if the command failed.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use anyhow::{bail, ensure, Result};
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::controller::Controller;
+use crate::runner::Runner;
+
+/// Information about a session opened by an agent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionInfo {
+    /// Name of the session in the agent, also displayed in its title.
+    pub name: String,
+    /// URL of the session.
+    pub url: String,
+    /// Writable URL of the session, if it has read-only access.
+    pub write_url: Option<String>,
+}
+
+impl SessionInfo {
+    #[cfg(unix)]
+    fn to_json(&self) -> Value {
+        json!({
+            "name": self.name,
+            "url": self.url,
+            "writeUrl": self.write_url,
+        })
+    }
+}
+
+/// A running session, closed when its channel is sent to or dropped.
+struct Running {
+    info: SessionInfo,
+    close_tx: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+/// Manages several sessions on the same server.
+pub struct Agent {
+    server: String,
+    runner: Runner,
+    enable_readers: bool,
+    sessions: Mutex<BTreeMap<String, Running>>,
+}
+
+impl Agent {
+    /// Create an agent for a server, where sessions run `runner` by default.
+    pub fn new(server: &str, runner: Runner, enable_readers: bool) -> Self {
+        Self {
+            server: server.into(),
+            runner,
+            enable_readers,
+            sessions: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Open a new session with a unique name, using the default runner if
+    /// none is given.
+    pub async fn open(&self, name: &str, runner: Option<Runner>) -> Result<SessionInfo> {
+        ensure!(!name.is_empty(), "session name cannot be empty");
+        if self.sessions.lock().unwrap().contains_key(name) {
+            bail!("session {name} is already open");
+        }
+        let runner = runner.unwrap_or_else(|| self.runner.clone());
+        let mut controller =
+            Controller::new(&self.server, name, runner, self.enable_readers).await?;
+        let info = SessionInfo {
+            name: name.into(),
+            url: controller.url().into(),
+            write_url: controller.write_url().map(String::from),
+        };
+
+        let (close_tx, close_rx) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            tokio::select! {
+                _ = controller.run() => unreachable!(),
+                _ = close_rx => (),
+            }
+            if let Err(err) = controller.close().await {
+                warn!(?err, name = controller.name(), "failed to close session");
+            }
+        });
+        let running = Running {
+            info: info.clone(),
+            close_tx,
+            task,
+        };
+
+        // The same name may have been opened while connecting.
+        let duplicate = {
+            let mut sessions = self.sessions.lock().unwrap();
+            if sessions.contains_key(name) {
+                Some(running)
+            } else {
+                sessions.insert(name.into(), running)
+            }
+        };
+        if let Some(duplicate) = duplicate {
+            stop(duplicate).await;
+            bail!("session {name} is already open");
+        }
+        Ok(info)
+    }
+
+    /// Returns the open sessions, ordered by name.
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.values().map(|s| s.info.clone()).collect()
+    }
+
+    /// Close a session by name.
+    pub async fn close(&self, name: &str) -> Result<()> {
+        let running = self.sessions.lock().unwrap().remove(name);
+        let Some(running) = running else {
+            bail!("session {name} is not open");
+        };
+        stop(running).await;
+        Ok(())
+    }
+
+    /// Close all sessions, such as when the agent is shutting down.
+    pub async fn close_all(&self) {
+        let sessions = std::mem::take(&mut *self.sessions.lock().unwrap());
+        futures_util::future::join_all(sessions.into_values().map(stop)).await;
+    }
+
+    /// Serve commands on a control socket, until the listener fails.
+    #[cfg(unix)]
+    pub async fn serve_control(&self, listener: tokio::net::UnixListener) -> Result<()> {
+        let mut connections = futures_util::stream::FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                result = listener.accept() => {
+                    let (stream, _) = result?;
+                    connections.push(self.handle_control(stream));
+                }
+                Some(result) = futures_util::StreamExt::next(&mut connections) => {
+                    if let Err(err) = result {
+                        warn!(?err, "error on control socket connection");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle commands from one connection to the control socket.
+    #[cfg(unix)]
+    async fn handle_control(&self, stream: tokio::net::UnixStream) -> Result<()> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            let resp = match self.command(&line).await {
+                Ok(resp) => resp,
+                Err(err) => json!({ "error": err.to_string() }),
+            };
+            writer.write_all(format!("{resp}\n").as_bytes()).await?;
+        }
+        Ok(())
+    }
+
+    /// Run a command from the control socket.
+    #[cfg(unix)]
+    async fn command(&self, line: &str) -> Result<Value> {
+        let line = line.trim();
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+        let (name, rest) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+        match command {
+            "list" => {
+                let sessions: Vec<_> = self.list().iter().map(SessionInfo::to_json).collect();
+                Ok(json!({ "sessions": sessions }))
+            }
+            "open" => {
+                let shell = rest.trim();
+                let runner = (!shell.is_empty()).then(|| Runner::Shell(shell.into()));
+                let info = self.open(name, runner).await?;
+                Ok(json!({ "session": info.to_json() }))
+            }
+            "close" => {
+                self.close(name).await?;
+                Ok(json!({ "closed": name }))
+            }
+            _ => bail!("unknown command {command:?}, expected list, open or close"),
+        }
+    }
+}
+
+async fn stop(running: Running) {
+    running.close_tx.send(()).ok();
+    running.task.await.ok();
+}
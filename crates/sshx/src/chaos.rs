@@ -0,0 +1,87 @@
+//! Random network faults, for testing how sessions recover from them.
+//!
+//! Faults are injected by the client itself, so the reconnection and resync
+//! paths can be exercised against any server. This is not meant for normal
+//! use, and is only exposed by a hidden command-line flag.
+
+use std::str::FromStr;
+
+use rand::Rng;
+use tokio::time::{self, Duration};
+
+/// Probabilities of injecting each kind of network fault.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Chaos {
+    /// Chance of dropping the gRPC connection after each message from the
+    /// server, as if the network had failed.
+    pub disconnect: f64,
+
+    /// Chance of delaying each message sent to the server or received from a
+    /// WebSocket, by up to `max_delay`.
+    pub delay: f64,
+
+    /// Longest delay of a message.
+    pub max_delay: Duration,
+
+    /// Chance of dropping each frame received from a WebSocket.
+    pub drop: f64,
+}
+
+impl Chaos {
+    /// Returns whether to drop the gRPC connection now.
+    pub fn disconnect(&self) -> bool {
+        roll(self.disconnect)
+    }
+
+    /// Returns whether to drop a WebSocket frame.
+    pub fn drop(&self) -> bool {
+        roll(self.drop)
+    }
+
+    /// Wait for a random delay before a message, if one is injected.
+    pub async fn delay(&self) {
+        if roll(self.delay) && !self.max_delay.is_zero() {
+            let delay = rand::thread_rng().gen_range(Duration::ZERO..self.max_delay);
+            time::sleep(delay).await;
+        }
+    }
+}
+
+fn roll(probability: f64) -> bool {
+    probability > 0.0 && rand::thread_rng().gen_bool(probability.min(1.0))
+}
+
+impl FromStr for Chaos {
+    type Err = String;
+
+    /// Parse a list like `disconnect=0.01,delay=0.1,max_delay=500,drop=0.05`,
+    /// where the delay is in milliseconds. Omitted faults are not injected.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chaos = Chaos::default();
+        for item in s.split(',').filter(|item| !item.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {item:?}"))?;
+            let probability = || match value.parse::<f64>() {
+                Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+                _ => Err(format!("invalid probability for {key}: {value:?}")),
+            };
+            match key {
+                "disconnect" => chaos.disconnect = probability()?,
+                "delay" => chaos.delay = probability()?,
+                "drop" => chaos.drop = probability()?,
+                "max_delay" => {
+                    let ms = value
+                        .parse()
+                        .map_err(|_| format!("invalid delay in milliseconds: {value:?}"))?;
+                    chaos.max_delay = Duration::from_millis(ms);
+                }
+                _ => return Err(format!("unknown kind of fault: {key:?}")),
+            }
+        }
+        if chaos.delay > 0.0 && chaos.max_delay.is_zero() {
+            chaos.max_delay = Duration::from_millis(500);
+        }
+        Ok(chaos)
+    }
+}
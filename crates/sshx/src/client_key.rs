@@ -0,0 +1,78 @@
+//! Key pair that binds a session to the client that opened it.
+//!
+//! The public key is registered when opening a session, and the client signs
+//! the hello of every connection with it afterward. The session token shows up
+//! in commands and logs, but the private key never leaves the client, so a
+//! stolen token is not enough to connect as the backend of the session.
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::prelude::{Engine as _, BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD};
+use ring::signature::{Ed25519KeyPair, KeyPair as _};
+use sshx_core::proto::hello_payload;
+
+/// Ed25519 key pair of a client, which can be exported for `--takeover`.
+pub struct ClientKey {
+    seed: [u8; 32],
+    pair: Ed25519KeyPair,
+}
+
+impl ClientKey {
+    /// Generate a new random key pair.
+    pub fn generate() -> Self {
+        Self::from_seed(rand::random())
+    }
+
+    fn from_seed(seed: [u8; 32]) -> Self {
+        // The seed has the right length, so this cannot fail.
+        let pair = Ed25519KeyPair::from_seed_unchecked(&seed).unwrap();
+        Self { seed, pair }
+    }
+
+    /// Returns the public key, which the server checks signatures with.
+    pub fn public_key(&self) -> &[u8] {
+        self.pair.public_key().as_ref()
+    }
+
+    /// Sign the hello of a connection to a session, returning the
+    /// `timestamp,signature` that follows its name and token.
+    pub fn sign_hello(&self, name: &str, token: &str) -> String {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH);
+        let timestamp = timestamp.unwrap_or_default().as_millis() as u64;
+        let signature = self.pair.sign(&hello_payload(name, token, timestamp));
+        format!("{timestamp},{}", BASE64_STANDARD.encode(signature))
+    }
+}
+
+impl Clone for ClientKey {
+    fn clone(&self) -> Self {
+        Self::from_seed(self.seed)
+    }
+}
+
+impl fmt::Debug for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientKey").finish_non_exhaustive()
+    }
+}
+
+/// Exports the private key, in the same format as it is parsed.
+impl fmt::Display for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&BASE64_URL_SAFE_NO_PAD.encode(self.seed))
+    }
+}
+
+impl FromStr for ClientKey {
+    type Err = String;
+
+    /// Parse a private key exported with `--show-token`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let seed = BASE64_URL_SAFE_NO_PAD.decode(s).ok();
+        let seed = seed.and_then(|seed| seed.try_into().ok());
+        seed.map(Self::from_seed)
+            .ok_or_else(|| "invalid client key".into())
+    }
+}
@@ -1,23 +1,33 @@
 //! Network gRPC client allowing server control of terminals.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::pin::pin;
+use std::sync::{Arc, Mutex};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use sshx_core::proto::{
-    client_update::ClientMessage, server_update::ServerMessage,
-    sshx_service_client::SshxServiceClient, ClientUpdate, CloseRequest, NewShell, OpenRequest,
+    client_features, client_update::ClientMessage, server_update::ServerMessage,
+    sshx_service_client::SshxServiceClient, Capabilities, ClientUpdate, CloseRequest, ClosedShell,
+    InactivityPolicy, NewShell, OpenRequest, OpenResponse, Palette, RosterUser, ServerUpdate,
+    BACKEND_ID_KEY, CLIENT_FEATURES_KEY, TAKEOVER_KEY,
 };
-use sshx_core::{rand_alphanumeric, Sid};
-use tokio::sync::mpsc;
+use sshx_core::protocol::{features, ColorScheme, PaletteItem, ShellEnvironment};
+use sshx_core::{rand_alphanumeric, Sid, Uid};
+use tokio::sync::{mpsc, watch};
 use tokio::task;
 use tokio::time::{self, Duration, Instant, MissedTickBehavior};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
-use tonic::transport::Channel;
-use tracing::{debug, error, warn};
+use tonic::{metadata::MetadataValue, transport::Channel, Code, Streaming};
+use tracing::{debug, error, info, warn};
 
-use crate::encrypt::Encrypt;
-use crate::runner::{Runner, ShellData};
+use crate::chaos::Chaos;
+use crate::client_key::ClientKey;
+use crate::encrypt::{hash_verifier, Encrypt, VERIFIER_SALT_LEN};
+use crate::keys::{check_key, KeyProvider, KeySource};
+use crate::known_servers::KnownServers;
+use crate::notify::{Event, Notifier};
+use crate::proxy::{self, Socks5Proxy};
+use crate::runner::{Runner, ShellData, Watchdog};
 
 /// Interval for sending empty heartbeat messages to the server.
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
@@ -25,6 +35,38 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
 /// Interval to automatically reestablish connections.
 const RECONNECT_INTERVAL: Duration = Duration::from_secs(60);
 
+/// Number of attempts at opening a session when the network fails.
+const OPEN_ATTEMPTS: u32 = 3;
+
+/// Users connected to a session from the web, as reported by the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Viewers {
+    /// Number of users in the session now.
+    pub current: usize,
+    /// Whether any user has joined since the session started.
+    pub any_joined: bool,
+}
+
+/// Options for opening a new session with [`Controller::open`].
+#[derive(Clone, Default)]
+pub struct OpenOptions {
+    /// Give out a read-only link, and a separate link with a write password.
+    pub enable_readers: bool,
+    /// Source of the encryption key. A random key is generated if not given.
+    pub keys: Option<Arc<dyn KeyProvider>>,
+    /// Connect to the server through a SOCKS5 proxy.
+    pub socks5: Option<Socks5Proxy>,
+    /// Color scheme of the terminal, so that users see matching colors.
+    pub color_scheme: Option<ColorScheme>,
+    /// Environment of new shells, shared with users for debugging.
+    pub environment: Option<ShellEnvironment>,
+    /// Pinned identities to check the server against.
+    pub known_servers: Option<KnownServers>,
+    /// Name to open the session under, which appears in its URL. The server
+    /// generates a random one if not given.
+    pub session_name: Option<String>,
+}
+
 /// Handles a single session's communication with the remote server.
 pub struct Controller {
     origin: String,
@@ -36,6 +78,42 @@ pub struct Controller {
     token: String,
     url: String,
     write_url: Option<String>,
+    /// Write password to print separately, if the server asks to leave it out
+    /// of the writable link.
+    write_password: Option<String>,
+    banner: Option<String>,
+    capabilities: Capabilities,
+    chaos: Chaos,
+    notifier: Option<Notifier>,
+    inactivity: Option<InactivityPolicy>,
+    pinned: bool,
+    palette: Option<Palette>,
+    watchdog: Option<Watchdog>,
+    socks5: Option<Socks5Proxy>,
+    /// Key pair that signs connections, if the session is bound to one.
+    client_key: Option<ClientKey>,
+
+    /// Random ID of this client, so that the server can refuse it after
+    /// another client takes over the session.
+    backend_id: String,
+    /// Whether to take over the session on the next connection.
+    takeover: bool,
+    /// Channel opened by [`Controller::reattach`], used by the next connection.
+    /// The stream is not `Sync`, so it is kept in a mutex.
+    reattached: Mutex<Option<(mpsc::Sender<ClientUpdate>, Streaming<ServerUpdate>)>>,
+    /// Set when another client took over the session, so this one stopped.
+    released: watch::Sender<bool>,
+
+    /// Users in the session, reported again by the server on each connection.
+    users: HashSet<Uid>,
+    /// Users who have ever joined, so that notifications are not repeated.
+    seen_users: HashSet<Uid>,
+    viewers: watch::Sender<Viewers>,
+    /// Time of the last message received from the server.
+    last_heard: watch::Sender<Option<Instant>>,
+    /// Users in the session with their join times, as last listed by the
+    /// server.
+    roster: watch::Sender<Vec<RosterUser>>,
 
     /// Channels with backpressure routing messages to each shell task.
     shells_tx: HashMap<Sid, mpsc::Sender<ShellData>>,
@@ -52,51 +130,167 @@ impl Controller {
         name: &str,
         runner: Runner,
         enable_readers: bool,
+    ) -> Result<Self> {
+        let options = OpenOptions {
+            enable_readers,
+            ..Default::default()
+        };
+        Self::open(origin, name, runner, options).await
+    }
+
+    /// Construct a new controller like [`Controller::new`], with more options.
+    pub async fn open(
+        origin: &str,
+        name: &str,
+        runner: Runner,
+        options: OpenOptions,
     ) -> Result<Self> {
         debug!(%origin, "connecting to server");
-        let encryption_key = rand_alphanumeric(14); // 83.3 bits of entropy
+        let keys = options.keys.unwrap_or_else(|| Arc::new(KeySource::Random));
+        let encryption_key = task::spawn_blocking(move || keys.key()).await??;
+        check_key(&encryption_key)?;
 
         let kdf_task = {
             let encryption_key = encryption_key.clone();
             task::spawn_blocking(move || Encrypt::new(&encryption_key))
         };
 
-        let (write_password, kdf_write_password_task) = if enable_readers {
+        // The server stores a salted hash of the write password's zero block.
+        let write_password_salt: [u8; VERIFIER_SALT_LEN] = rand::random();
+        let (write_password, kdf_write_password_task) = if options.enable_readers {
             let write_password = rand_alphanumeric(14); // 83.3 bits of entropy
             let task = {
                 let write_password = write_password.clone();
-                task::spawn_blocking(move || Encrypt::new(&write_password))
+                task::spawn_blocking(move || {
+                    let zeros = Encrypt::new(&write_password).zeros();
+                    hash_verifier(&zeros, &write_password_salt)
+                })
             };
             (Some(write_password), Some(task))
         } else {
             (None, None)
         };
 
-        let mut client = Self::connect(origin).await?;
+        let mut client = proxy::connect(origin, options.socks5.as_ref()).await?;
         let encrypt = kdf_task.await?;
         let write_password_hash = if let Some(task) = kdf_write_password_task {
-            Some(task.await?.zeros().into())
+            Some(task.await?.into())
         } else {
             None
         };
 
+        let client_key = ClientKey::generate();
         let req = OpenRequest {
             origin: origin.into(),
             encrypted_zeros: encrypt.zeros().into(),
             name: name.into(),
+            write_password_salt: write_password_hash
+                .is_some()
+                .then(|| write_password_salt.to_vec().into()),
             write_password_hash,
+            idempotency_key: Some(rand_alphanumeric(22)),
+            color_scheme: match options.color_scheme {
+                Some(colors) => {
+                    let json = serde_json::to_vec(&colors)?;
+                    Some(encrypt.segment(0x700000000, 0, &json).into())
+                }
+                None => None,
+            },
+            environment: match &options.environment {
+                Some(environment) => {
+                    let json = serde_json::to_vec(environment)?;
+                    Some(encrypt.segment(0x800000000, 0, &json).into())
+                }
+                None => None,
+            },
+            client_key: Some(client_key.public_key().to_vec().into()),
+            session_name: options.session_name,
         };
-        let mut resp = client.open(req).await?.into_inner();
+        let mut resp = open_with_retries(&mut client, req).await?;
+        if let Some(known_servers) = &options.known_servers {
+            if let Err(err) = known_servers.verify(origin, &resp.identity) {
+                // Do not leave the session open on a server that is not trusted.
+                let req = CloseRequest {
+                    name: resp.name,
+                    token: resp.token,
+                };
+                client.close(req).await.ok();
+                return Err(err);
+            }
+        }
         resp.url = resp.url + "#" + &encryption_key;
 
-        let write_url = if let Some(write_password) = write_password {
-            Some(resp.url.clone() + "," + &write_password)
-        } else {
-            None
+        let (write_url, write_password) = match write_password {
+            Some(write_password) if resp.omit_write_password => (None, Some(write_password)),
+            Some(write_password) => (Some(resp.url.clone() + "," + &write_password), None),
+            None => (None, None),
         };
 
+        let mut controller =
+            Self::with_session(origin, runner, encrypt, encryption_key, resp, write_url);
+        controller.write_password = write_password;
+        controller.socks5 = options.socks5;
+        // Servers that do not support client keys ignore them.
+        if controller.capabilities.has(features::CLIENT_KEYS) {
+            controller.client_key = Some(client_key);
+        }
+        Ok(controller)
+    }
+
+    /// Take over an existing session from another client, given its URL with
+    /// the encryption key and its token, so that it continues on this machine.
+    ///
+    /// Once connected, the shells of the previous client are closed and new
+    /// shells are started in their place. The optional features of the server
+    /// are not known in this case.
+    pub async fn take_over(origin: &str, url: &str, token: &str, runner: Runner) -> Result<Self> {
+        let (url, secrets) = url
+            .split_once('#')
+            .context("missing encryption key in URL")?;
+        let (encryption_key, write_password) = match secrets.split_once(',') {
+            Some((key, write_password)) => (key, Some(write_password)),
+            None => (secrets, None),
+        };
+        let name = url
+            .rsplit_once("/s/")
+            .map(|(_, name)| name.trim_end_matches('/'))
+            .filter(|name| !name.is_empty() && !name.contains('/'))
+            .context("invalid session URL")?;
+
+        let encrypt = {
+            let encryption_key = encryption_key.to_string();
+            task::spawn_blocking(move || Encrypt::new(&encryption_key)).await?
+        };
+        let resp = OpenResponse {
+            name: name.into(),
+            token: token.into(),
+            url: format!("{url}#{encryption_key}"),
+            ..Default::default()
+        };
+        let write_url = write_password.map(|_| format!("{url}#{secrets}"));
+        let mut controller = Self::with_session(
+            origin,
+            runner,
+            encrypt,
+            encryption_key.into(),
+            resp,
+            write_url,
+        );
+        controller.takeover = true;
+        Ok(controller)
+    }
+
+    /// Construct a controller for a session opened on the server.
+    fn with_session(
+        origin: &str,
+        runner: Runner,
+        encrypt: Encrypt,
+        encryption_key: String,
+        resp: OpenResponse,
+        write_url: Option<String>,
+    ) -> Self {
         let (output_tx, output_rx) = mpsc::channel(64);
-        Ok(Self {
+        Self {
             origin: origin.into(),
             runner,
             encrypt,
@@ -105,10 +299,30 @@ impl Controller {
             token: resp.token,
             url: resp.url,
             write_url,
+            write_password: None,
+            banner: resp.banner,
+            capabilities: resp.capabilities.unwrap_or_default(),
+            chaos: Chaos::default(),
+            notifier: None,
+            inactivity: None,
+            pinned: false,
+            palette: None,
+            watchdog: None,
+            socks5: None,
+            client_key: None,
+            backend_id: rand_alphanumeric(16),
+            takeover: false,
+            reattached: Mutex::new(None),
+            released: watch::Sender::new(false),
+            users: HashSet::new(),
+            seen_users: HashSet::new(),
+            viewers: watch::Sender::new(Viewers::default()),
+            last_heard: watch::Sender::new(None),
+            roster: watch::Sender::new(Vec::new()),
             shells_tx: HashMap::new(),
             output_tx,
             output_rx,
-        })
+        }
     }
 
     /// Create a new gRPC client to the HTTP(S) origin.
@@ -116,8 +330,8 @@ impl Controller {
     /// This is used on reconnection to the server, since some replicas may be
     /// gracefully shutting down, which means connected clients need to start a
     /// new TCP handshake.
-    async fn connect(origin: &str) -> Result<SshxServiceClient<Channel>, tonic::transport::Error> {
-        SshxServiceClient::connect(String::from(origin)).await
+    async fn connect(&self) -> Result<SshxServiceClient<Channel>, tonic::transport::Error> {
+        proxy::connect(&self.origin, self.socks5.as_ref()).await
     }
 
     /// Returns the name of the session.
@@ -135,17 +349,148 @@ impl Controller {
         self.write_url.as_deref()
     }
 
+    /// Returns the write password, if it is not part of the write URL.
+    pub fn write_password(&self) -> Option<&str> {
+        self.write_password.as_deref()
+    }
+
+    /// Returns the message from the server operator, if any.
+    pub fn banner(&self) -> Option<&str> {
+        self.banner.as_deref()
+    }
+
+    /// Returns the optional features and limits of the server.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
     /// Returns the encryption key for this session, hidden from the server.
     pub fn encryption_key(&self) -> &str {
         &self.encryption_key
     }
 
+    /// Returns the secret token of this session, which lets another client
+    /// take over the session with [`Controller::take_over`].
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Subscribe to whether another client took over the session, after which
+    /// this client stops its shells and no longer connects to the server.
+    pub fn released(&self) -> watch::Receiver<bool> {
+        self.released.subscribe()
+    }
+
+    /// Subscribe to the users connected to the session.
+    ///
+    /// This is only updated if the server supports
+    /// [`features::USER_PRESENCE`](sshx_core::protocol::features::USER_PRESENCE).
+    pub fn viewers(&self) -> watch::Receiver<Viewers> {
+        self.viewers.subscribe()
+    }
+
+    /// Subscribe to the time that a message was last received from the server,
+    /// which shows whether the connection is healthy.
+    pub fn last_heard(&self) -> watch::Receiver<Option<Instant>> {
+        self.last_heard.subscribe()
+    }
+
+    /// Subscribe to the users in the session and the times that they joined,
+    /// which the server lists periodically for auditing.
+    ///
+    /// Each listed user is also logged at the info level.
+    pub fn roster(&self) -> watch::Receiver<Vec<RosterUser>> {
+        self.roster.subscribe()
+    }
+
+    /// Inject random network faults into the connection, for testing.
+    pub fn set_chaos(&mut self, chaos: Chaos) {
+        self.chaos = chaos;
+    }
+
+    /// Post notifications about this session to a webhook, starting with its
+    /// link right away. The read-only link is posted if there is one.
+    pub fn set_notifier(&mut self, notifier: Notifier) {
+        self.notifier = Some(notifier);
+        self.notify(Event::Started(self.url.clone()));
+    }
+
+    /// Set how long the server keeps the session if this client disconnects
+    /// without closing it, instead of the server's default.
+    pub fn set_inactivity(&mut self, policy: InactivityPolicy) {
+        self.inactivity = Some(policy);
+    }
+
+    /// Pin the session, so that the server keeps it however long this client
+    /// is disconnected, like for a machine that reboots nightly.
+    pub fn pin(&mut self) {
+        self.pinned = true;
+    }
+
+    /// Connect to the server through a SOCKS5 proxy from now on.
+    pub fn set_socks5(&mut self, proxy: Socks5Proxy) {
+        self.socks5 = Some(proxy);
+    }
+
+    /// Returns the key pair that the session is bound to, if any. It is needed
+    /// to take over the session from another machine.
+    pub fn client_key(&self) -> Option<&ClientKey> {
+        self.client_key.as_ref()
+    }
+
+    /// Sign connections with the key pair that the session is bound to, when
+    /// taking it over from another client.
+    pub fn set_client_key(&mut self, key: ClientKey) {
+        self.client_key = Some(key);
+    }
+
+    /// Report shells that stop responding to input to users, and optionally
+    /// restart them.
+    pub fn set_watchdog(&mut self, watchdog: Watchdog) {
+        self.watchdog = Some(watchdog);
+    }
+
+    /// Suggest commands and links to users, who can paste the commands into a
+    /// shell or open the links with a click. This replaces earlier suggestions.
+    pub fn set_palette(&mut self, items: &[PaletteItem]) -> Result<()> {
+        let json = serde_json::to_vec(items)?;
+        // Each list is encrypted at a new offset, so the keystream is not reused.
+        let offset = (self.palette.as_ref()).map_or(0, |p| p.offset + p.data.len() as u64);
+        let data = self.encrypt.segment(0x600000000, offset, &json);
+        self.palette = Some(Palette {
+            data: data.into(),
+            offset,
+        });
+        Ok(())
+    }
+
+    /// Post a notification in the background, if a webhook is set.
+    fn notify(&self, event: Event) {
+        if let Some(notifier) = self.notifier.clone() {
+            let name = self.name.clone();
+            tokio::spawn(async move {
+                if let Err(err) = notifier.notify(&name, &event).await {
+                    warn!(?err, "failed to post notification");
+                }
+            });
+        }
+    }
+
     /// Run the controller forever, listening for requests from the server.
     pub async fn run(&mut self) -> ! {
         let mut last_retry = Instant::now();
         let mut retries = 0;
         loop {
             if let Err(err) = self.try_channel().await {
+                let status = err.downcast_ref::<tonic::Status>();
+                if status.is_some_and(|status| status.code() == Code::FailedPrecondition) {
+                    warn!("session was taken over by another client, stopping");
+                    self.shells_tx.clear(); // Shuts down the shell tasks.
+                    self.released.send_replace(true);
+                    loop {
+                        std::future::pending::<()>().await;
+                    }
+                }
                 if last_retry.elapsed() >= Duration::from_secs(10) {
                     retries = 0;
                 }
@@ -158,17 +503,40 @@ impl Controller {
         }
     }
 
+    /// Connect once to a session that is being taken over, so that it is taken
+    /// over right away. Returns `false` if the session no longer exists, or if
+    /// it refuses this client, as when a different session was opened under its
+    /// name since or the signature of the client key is rejected.
+    pub async fn reattach(&mut self) -> Result<bool> {
+        match self.open_channel().await {
+            Ok(channel) => {
+                // Shells are started in place of the previous client's on this
+                // channel, so it is kept.
+                *self.reattached.get_mut().unwrap() = Some(channel);
+                Ok(true)
+            }
+            Err(err) => match err.downcast_ref::<tonic::Status>().map(|s| s.code()) {
+                Some(
+                    Code::NotFound
+                    | Code::PermissionDenied
+                    | Code::Unauthenticated
+                    | Code::FailedPrecondition,
+                ) => Ok(false),
+                _ => Err(err),
+            },
+        }
+    }
+
     /// Helper function used by `run()` that can return errors.
     async fn try_channel(&mut self) -> Result<()> {
-        let (tx, rx) = mpsc::channel(16);
-
-        let hello = ClientMessage::Hello(format!("{},{}", self.name, self.token));
-        send_msg(&tx, hello).await?;
-
-        let mut client = Self::connect(&self.origin).await?;
-        let resp = client.channel(ReceiverStream::new(rx)).await?;
-        let mut messages = resp.into_inner(); // A stream of server messages.
+        let (tx, mut messages) = match self.reattached.get_mut().unwrap().take() {
+            Some(channel) => channel,
+            None => self.open_channel().await?,
+        };
 
+        // The server reports the users in the session again on this connection.
+        self.users.clear();
+        self.update_viewers();
         let mut interval = time::interval(HEARTBEAT_INTERVAL);
         interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
         let mut reconnect = pin!(time::sleep(RECONNECT_INTERVAL));
@@ -180,6 +548,7 @@ impl Controller {
                 }
                 msg = self.output_rx.recv() => {
                     let msg = msg.context("unreachable: output_tx was closed?")?;
+                    self.chaos.delay().await;
                     send_msg(&tx, msg).await?;
                     continue;
                 }
@@ -192,11 +561,12 @@ impl Controller {
                     return Ok(()); // Reconnect to the server.
                 }
             };
+            self.last_heard.send_replace(Some(Instant::now()));
 
             match message {
                 ServerMessage::Input(input) => {
                     let data = self.encrypt.segment(0x200000000, input.offset, &input.data);
-                    if let Some(sender) = self.shells_tx.get(&Sid(input.id)) {
+                    if let Some(sender) = self.shells_tx.get(&input.sid()) {
                         // This line applies backpressure if the shell task is overloaded.
                         sender.send(ShellData::Data(data)).await.ok();
                     } else {
@@ -204,8 +574,7 @@ impl Controller {
                     }
                 }
                 ServerMessage::CreateShell(new_shell) => {
-                    let id = Sid(new_shell.id);
-                    let center = (new_shell.x, new_shell.y);
+                    let (id, center) = (new_shell.sid(), new_shell.center());
                     if !self.shells_tx.contains_key(&id) {
                         self.spawn_shell_task(id, center);
                     } else {
@@ -214,26 +583,65 @@ impl Controller {
                 }
                 ServerMessage::CloseShell(id) => {
                     // Closes the channel when it is dropped, notifying the task to shut down.
-                    self.shells_tx.remove(&Sid(id));
-                    send_msg(&tx, ClientMessage::ClosedShell(id)).await?;
+                    let id = Sid::from(id);
+                    self.shells_tx.remove(&id);
+                    let closed = ClosedShell::new(id, "closed by a user");
+                    send_msg(&tx, closed.into_message(&self.capabilities)).await?;
                 }
                 ServerMessage::Sync(seqnums) => {
-                    for (id, seq) in seqnums.map {
-                        if let Some(sender) = self.shells_tx.get(&Sid(id)) {
-                            sender.send(ShellData::Sync(seq)).await.ok();
-                        } else {
-                            warn!(%id, "received sequence number for non-existing shell");
-                            send_msg(&tx, ClientMessage::ClosedShell(id)).await?;
+                    for (id, seq) in seqnums.iter() {
+                        match self.shells_tx.get(&id) {
+                            // The task of a shell may have ended without reporting it.
+                            Some(sender) if !sender.is_closed() => {
+                                sender.send(ShellData::Sync(seq)).await.ok();
+                            }
+                            _ => {
+                                warn!(%id, "received sequence number for non-existing shell");
+                                self.shells_tx.remove(&id);
+                                let closed = ClosedShell::new(id, "shell is not running");
+                                send_msg(&tx, closed.into_message(&self.capabilities)).await?;
+                            }
                         }
                     }
                 }
                 ServerMessage::Resize(msg) => {
-                    if let Some(sender) = self.shells_tx.get(&Sid(msg.id)) {
+                    if let Some(sender) = self.shells_tx.get(&msg.sid()) {
                         sender.send(ShellData::Size(msg.rows, msg.cols)).await.ok();
                     } else {
                         warn!(%msg.id, "received resize for non-existing shell");
                     }
                 }
+                ServerMessage::Shutdown(reason) => {
+                    bail!("server is shutting down: {reason}");
+                }
+                ServerMessage::Stream(stream) => {
+                    warn!(%stream.id, "received data for unsupported stream");
+                }
+                ServerMessage::UserJoined(user) => {
+                    self.users.insert(user.uid());
+                    let first_time = self.seen_users.insert(user.uid());
+                    self.update_viewers();
+                    if first_time {
+                        self.notify(Event::UserJoined(user.name, user.can_write));
+                    }
+                }
+                ServerMessage::UserLeft(id) => {
+                    self.users.remove(&Uid(id));
+                    self.update_viewers();
+                }
+                ServerMessage::Roster(roster) => {
+                    for user in &roster.users {
+                        info!(
+                            session = %self.name,
+                            id = user.id,
+                            name = %user.name,
+                            can_write = user.can_write,
+                            joined_at = user.joined_at,
+                            "user in session roster",
+                        );
+                    }
+                    self.roster.send_replace(roster.users);
+                }
                 ServerMessage::Ping(ts) => {
                     // Echo back the timestamp, for stateless latency measurement.
                     send_msg(&tx, ClientMessage::Pong(ts)).await?;
@@ -242,7 +650,64 @@ impl Controller {
                     error!(?err, "error received from server");
                 }
             }
+
+            if self.chaos.disconnect() {
+                bail!("injected a network fault");
+            }
+        }
+    }
+
+    /// Open a channel to the server and introduce this client on it, returning
+    /// the sender of client messages and a stream of server messages.
+    async fn open_channel(
+        &mut self,
+    ) -> Result<(mpsc::Sender<ClientUpdate>, Streaming<ServerUpdate>)> {
+        let (tx, rx) = mpsc::channel(16);
+        let hello = match &self.client_key {
+            Some(key) => {
+                let signed = key.sign_hello(&self.name, &self.token);
+                format!("{},{},{signed}", self.name, self.token)
+            }
+            None => format!("{},{}", self.name, self.token),
+        };
+        let hello = ClientMessage::Hello(hello);
+        send_msg(&tx, hello).await?;
+        if let Some(policy) = self.inactivity.clone() {
+            // Sent on every connection, in case the session moved to another server.
+            send_msg(&tx, ClientMessage::Inactivity(policy)).await?;
+        }
+        if self.pinned {
+            send_msg(&tx, ClientMessage::Pin(true)).await?;
+        }
+        if let Some(palette) = self.palette.clone() {
+            send_msg(&tx, ClientMessage::Palette(palette)).await?;
         }
+
+        let mut client = self.connect().await?;
+        let mut req = tonic::Request::new(ReceiverStream::new(rx));
+        let features = [
+            client_features::USER_JOINED,
+            client_features::USER_PRESENCE,
+            client_features::ROSTER,
+        ];
+        let features = features.join(",").parse()?;
+        req.metadata_mut().insert(CLIENT_FEATURES_KEY, features);
+        req.metadata_mut()
+            .insert(BACKEND_ID_KEY, self.backend_id.parse()?);
+        if self.takeover {
+            let zeros = MetadataValue::from_bytes(&self.encrypt.zeros());
+            req.metadata_mut().insert_bin(TAKEOVER_KEY, zeros);
+        }
+        let resp = client.channel(req).await?;
+        self.takeover = false; // The server has replaced the previous client.
+        Ok((tx, resp.into_inner()))
+    }
+
+    fn update_viewers(&self) {
+        self.viewers.send_replace(Viewers {
+            current: self.users.len(),
+            any_joined: !self.seen_users.is_empty(),
+        });
     }
 
     /// Entry point to start a new terminal task on the client.
@@ -254,22 +719,24 @@ impl Controller {
         let runner = self.runner.clone();
         let encrypt = self.encrypt.clone();
         let output_tx = self.output_tx.clone();
+        let watchdog = self.watchdog;
+        let capabilities = self.capabilities.clone();
         tokio::spawn(async move {
             debug!(%id, "spawning new shell");
-            let new_shell = NewShell {
-                id: id.0,
-                x: center.0,
-                y: center.1,
-            };
+            let new_shell = NewShell::new(id, center);
             if let Err(err) = output_tx.send(ClientMessage::CreatedShell(new_shell)).await {
                 error!(%id, ?err, "failed to send shell creation message");
                 return;
             }
-            if let Err(err) = runner.run(id, encrypt, shell_rx, output_tx.clone()).await {
-                let err = ClientMessage::Error(err.to_string());
-                output_tx.send(err).await.ok();
-            }
-            output_tx.send(ClientMessage::ClosedShell(id.0)).await.ok();
+            let run = runner.run(id, encrypt, shell_rx, output_tx.clone(), watchdog);
+            let closed = match run.await {
+                Ok(()) => ClosedShell::new(id, "shell exited"),
+                Err(err) => ClosedShell::new(id, format!("shell failed: {err}")),
+            };
+            output_tx
+                .send(closed.into_message(&capabilities))
+                .await
+                .ok();
         });
     }
 
@@ -280,12 +747,45 @@ impl Controller {
             name: self.name.clone(),
             token: self.token.clone(),
         };
-        let mut client = Self::connect(&self.origin).await?;
+        let mut client = self.connect().await?;
         client.close(req).await?;
+        if let Some(notifier) = &self.notifier {
+            if let Err(err) = notifier.notify(&self.name, &Event::Ended).await {
+                warn!(?err, "failed to post notification");
+            }
+        }
         Ok(())
     }
 }
 
+/// Open a session, retrying if the network fails before there is a response.
+///
+/// The session may have been opened even if the response was lost, so retries
+/// send the same idempotency key to get it back instead of opening another.
+async fn open_with_retries(
+    client: &mut SshxServiceClient<Channel>,
+    req: OpenRequest,
+) -> Result<OpenResponse> {
+    let mut attempt = 1;
+    loop {
+        match client.open(req.clone()).await {
+            Ok(resp) => return Ok(resp.into_inner()),
+            Err(status)
+                if attempt < OPEN_ATTEMPTS
+                    && matches!(
+                        status.code(),
+                        Code::Unavailable | Code::Unknown | Code::DeadlineExceeded
+                    ) =>
+            {
+                warn!(%status, "failed to open session, retrying");
+                time::sleep(Duration::from_millis(500) * attempt).await;
+                attempt += 1;
+            }
+            Err(status) => return Err(status.into()),
+        }
+    }
+}
+
 /// Attempt to send a client message over an update channel.
 async fn send_msg(tx: &mpsc::Sender<ClientUpdate>, message: ClientMessage) -> Result<()> {
     let update = ClientUpdate {
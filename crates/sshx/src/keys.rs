@@ -0,0 +1,87 @@
+//! Sources of the encryption keys of new sessions.
+//!
+//! By default, every session gets a freshly generated key. Organizations with
+//! policies around key custody can keep keys in an environment variable, the
+//! OS keychain or a hardware token instead, by implementing [`KeyProvider`].
+
+use std::env;
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use sshx_core::rand_alphanumeric;
+
+/// Source of the encryption key for a new session.
+pub trait KeyProvider: Send + Sync {
+    /// Returns the encryption key for a new session. This may block, such as
+    /// while waiting for the user to touch a hardware token.
+    fn key(&self) -> Result<String>;
+}
+
+/// Built-in sources of encryption keys, as chosen on the command line.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum KeySource {
+    /// Generate a random key for every session.
+    #[default]
+    Random,
+
+    /// Read the key from an environment variable.
+    Env(String),
+
+    /// Run a command with the shell and read the key from the first line of
+    /// its output, like `security find-generic-password -s sshx -w` for the
+    /// macOS keychain, or the tool of a hardware token.
+    Command(String),
+}
+
+impl KeyProvider for KeySource {
+    fn key(&self) -> Result<String> {
+        match self {
+            KeySource::Random => Ok(rand_alphanumeric(14)), // 83.3 bits of entropy
+            KeySource::Env(var) => env::var(var).with_context(|| format!("failed to read ${var}")),
+            KeySource::Command(command) => {
+                let output = if cfg!(windows) {
+                    Command::new("cmd").args(["/C", command]).output()
+                } else {
+                    Command::new("sh").args(["-c", command]).output()
+                };
+                let output = output.with_context(|| format!("failed to run {command:?}"))?;
+                if !output.status.success() {
+                    bail!("key command {command:?} failed with {}", output.status);
+                }
+                let stdout = String::from_utf8(output.stdout).context("key is not UTF-8")?;
+                Ok(stdout.lines().next().unwrap_or_default().to_string())
+            }
+        }
+    }
+}
+
+impl FromStr for KeySource {
+    type Err = String;
+
+    /// Parse `random`, `env:VAR` or `command:COMMAND`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            _ if s == "random" => Ok(KeySource::Random),
+            Some(("env", var)) if !var.is_empty() => Ok(KeySource::Env(var.into())),
+            Some(("command", command)) if !command.is_empty() => {
+                Ok(KeySource::Command(command.into()))
+            }
+            _ => Err(format!(
+                "expected random, env:VAR or command:COMMAND, got {s:?}"
+            )),
+        }
+    }
+}
+
+/// Check that a key can be put in the fragment of a session link, where it is
+/// followed by a comma and the write password.
+pub fn check_key(key: &str) -> Result<()> {
+    if key.is_empty() {
+        bail!("encryption key is empty");
+    }
+    if key.contains([',', '#']) || key.contains(char::is_whitespace) {
+        bail!("encryption key cannot contain commas, '#' or whitespace");
+    }
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! Identities of servers, pinned on first use like SSH known hosts.
+//!
+//! Servers report an identity with each session, which is a fingerprint of
+//! their token-signing key. The first time the client opens a session on a
+//! server, it stores the identity. If it changes later, the client may have
+//! been silently redirected to a different backend.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use tracing::{info, warn};
+
+/// What to do when the identity of a server changes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdentityCheck {
+    /// Refuse to share the session.
+    #[default]
+    Strict,
+
+    /// Print a warning and continue.
+    Warn,
+
+    /// Do not check or store identities.
+    Off,
+}
+
+impl FromStr for IdentityCheck {
+    type Err = String;
+
+    /// Parse `strict`, `warn` or `off`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(IdentityCheck::Strict),
+            "warn" => Ok(IdentityCheck::Warn),
+            "off" => Ok(IdentityCheck::Off),
+            _ => Err(format!("expected strict, warn or off, got {s:?}")),
+        }
+    }
+}
+
+/// File of pinned server identities, with one `origin identity` per line.
+#[derive(Debug, Clone)]
+pub struct KnownServers {
+    path: PathBuf,
+    check: IdentityCheck,
+}
+
+impl KnownServers {
+    /// Use the file at this path, which is created if it does not exist.
+    pub fn new(path: impl Into<PathBuf>, check: IdentityCheck) -> Self {
+        Self {
+            path: path.into(),
+            check,
+        }
+    }
+
+    /// Returns the default file in the user's config directory, if any.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("sshx").join("known_servers"))
+    }
+
+    /// Check the identity reported by a server, pinning it if the server has
+    /// not been seen before.
+    pub fn verify(&self, origin: &str, identity: &str) -> Result<()> {
+        if self.check == IdentityCheck::Off {
+            return Ok(());
+        }
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {:?}", self.path));
+            }
+        };
+        let pinned = contents
+            .lines()
+            .filter_map(|line| line.trim().split_once(' '))
+            .find(|&(known, _)| known == origin)
+            .map(|(_, pinned)| pinned.trim());
+
+        match pinned {
+            Some(pinned) if pinned == identity => Ok(()),
+            Some(pinned) => {
+                let message = format!(
+                    "identity of {origin} changed from {pinned} to {:?}, so it may not be the \
+                     server you expect; if the change is expected, remove its line from {:?}",
+                    identity, self.path,
+                );
+                if self.check == IdentityCheck::Strict {
+                    bail!(message);
+                }
+                warn!("{message}");
+                Ok(())
+            }
+            // Servers from before identities do not report one.
+            None if identity.is_empty() => Ok(()),
+            None => {
+                // Failing to pin the identity should not stop the session.
+                match self.pin(origin, identity) {
+                    Ok(()) => info!(%origin, %identity, "pinned new server identity"),
+                    Err(err) => warn!(?err, "failed to pin server identity"),
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Append the identity of a new server to the file.
+    fn pin(&self, origin: &str, identity: &str) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{origin} {identity}")?;
+        Ok(())
+    }
+}
@@ -6,7 +6,20 @@
 #![deny(unsafe_code)]
 #![warn(missing_docs)]
 
+pub mod agent;
+pub mod chaos;
+pub mod client_key;
 pub mod controller;
-pub mod encrypt;
+pub use sshx_crypto as encrypt;
+pub mod keys;
+pub mod known_servers;
+pub mod notify;
+pub mod proxy;
+pub mod record;
+pub mod replay;
+pub mod resume;
 pub mod runner;
+#[cfg(unix)]
+pub mod systemd;
 pub mod terminal;
+pub mod viewer;
@@ -1,11 +1,34 @@
+use std::io::{IsTerminal, Read};
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use ansi_term::Color::{Cyan, Fixed, Green};
-use anyhow::Result;
-use clap::Parser;
-use sshx::{controller::Controller, runner::Runner, terminal::get_default_shell};
-use tokio::signal;
-use tracing::error;
+use ansi_term::Color::{Cyan, Fixed, Green, Yellow};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use sshx::agent::Agent;
+use sshx::client_key::ClientKey;
+use sshx::controller::{OpenOptions, Viewers};
+use sshx::keys::KeySource;
+use sshx::known_servers::{IdentityCheck, KnownServers};
+use sshx::notify::Notifier;
+use sshx::proxy::Socks5Proxy;
+use sshx::record::{self, Recorder, UploadConfig};
+use sshx::replay::{self, PlaybackOptions};
+use sshx::resume::SavedSession;
+use sshx::runner::{Runner, Watchdog};
+use sshx::terminal::{get_default_shell, local_winsize, shell_environment, Terminal};
+use sshx::viewer::WebClient;
+use sshx::{chaos::Chaos, controller::Controller};
+use sshx_core::logfile::{LogFile, LogFileOptions, Rotation};
+use sshx_core::proto::InactivityPolicy;
+use sshx_core::protocol::{features, ColorScheme, PaletteItem};
+use sshx_core::Sid;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, watch};
+use tokio::{signal, task, time};
+use tracing::{error, info};
 
 /// A secure web-based, collaborative terminal.
 #[derive(Parser, Debug)]
@@ -15,6 +38,24 @@ struct Args {
     #[clap(long, default_value = "https://sshx.io", env = "SSHX_SERVER")]
     server: String,
 
+    /// Connect to the server through a SOCKS5 proxy, like `127.0.0.1:9050`
+    /// for Tor. Host names are resolved by the proxy.
+    #[clap(long, value_name = "HOST:PORT", env = "SSHX_SOCKS5")]
+    socks5: Option<Socks5Proxy>,
+
+    /// Username for the SOCKS5 proxy.
+    #[clap(long, requires_all = ["socks5", "socks5_password"], env = "SSHX_SOCKS5_USER")]
+    socks5_user: Option<String>,
+
+    /// Password for the SOCKS5 proxy.
+    #[clap(
+        long,
+        requires = "socks5_user",
+        env = "SSHX_SOCKS5_PASSWORD",
+        hide_env_values = true
+    )]
+    socks5_password: Option<String>,
+
     /// Local shell command to run in the terminal.
     #[clap(long)]
     shell: Option<String>,
@@ -27,10 +68,296 @@ struct Args {
     #[clap(long)]
     name: Option<String>,
 
+    /// Name of the session in its URL, like `team-demo-day` for a stable link,
+    /// with at least 10 characters (defaults to a random name).
+    #[clap(long, value_name = "NAME", conflicts_with = "takeover")]
+    session_name: Option<String>,
+
     /// Enable read-only access mode - generates separate URLs for viewers and
     /// editors.
     #[clap(long)]
     enable_readers: bool,
+
+    /// Continue a session shared by another sshx client, like one on another
+    /// machine, given its link. The other client stops sharing.
+    #[clap(long, value_name = "URL", requires = "token")]
+    takeover: Option<String>,
+
+    /// Secret token of the session for `--takeover`, printed by the other
+    /// client with `--show-token`.
+    #[clap(long, env = "SSHX_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Private key that the session is bound to, for `--takeover`, printed by
+    /// the other client with `--show-token`.
+    #[clap(
+        long,
+        requires = "takeover",
+        env = "SSHX_CLIENT_KEY",
+        hide_env_values = true
+    )]
+    client_key: Option<ClientKey>,
+
+    /// Print the command to continue this session from another machine,
+    /// which includes its secret token.
+    #[clap(long)]
+    show_token: bool,
+
+    /// Save the session to a state file, and resume it with the same link if
+    /// the client is restarted before the server closes it.
+    #[clap(long, conflicts_with = "takeover")]
+    resume: bool,
+
+    /// State file for `--resume`, which can only hold one session. Defaults to
+    /// a file in `sshx/sessions` in the user's state directory, for each server
+    /// and working directory.
+    #[clap(long, value_name = "FILE", requires = "resume")]
+    state_file: Option<PathBuf>,
+
+    /// Write logs to this file instead of stderr.
+    #[clap(long)]
+    log_file: Option<PathBuf>,
+
+    /// Rotate the log file when it would grow past this many megabytes.
+    #[clap(long, requires = "log_file")]
+    log_max_size: Option<u64>,
+
+    /// Rotate the log file periodically: never, hourly or daily.
+    #[clap(long, default_value = "never")]
+    log_rotation: Rotation,
+
+    /// Number of compressed, rotated log files to keep.
+    #[clap(long, default_value_t = 7)]
+    log_keep: usize,
+
+    /// Inject random network faults, like `disconnect=0.01,delay=0.1`, to
+    /// test recovery from them.
+    #[clap(long, hide = true)]
+    chaos: Option<Chaos>,
+
+    /// Post to this Slack, Discord or other webhook URL when the session
+    /// starts, when someone joins, and when it ends.
+    #[clap(long, env = "SSHX_NOTIFY_WEBHOOK", value_name = "URL")]
+    notify: Option<String>,
+
+    /// CI debugging mode: only prints the URL, closes the session once the
+    /// last viewer has left, and fails if nobody joined before the timeout.
+    #[clap(long)]
+    ci: bool,
+
+    /// Close the session after this long, like `90s`, `30m` or `2h`. Defaults
+    /// to 30 minutes in CI mode.
+    #[clap(long, value_parser = parse_duration)]
+    timeout: Option<Duration>,
+
+    /// How long to wait in CI mode for someone to rejoin after the last viewer
+    /// leaves.
+    #[clap(long, default_value = "1m", value_parser = parse_duration)]
+    grace_period: Duration,
+
+    /// How long the server keeps the session if this client disconnects
+    /// without closing it, like `0s` to close it right away, `30m`, or
+    /// `forever`. Defaults to the server's setting.
+    #[clap(long, value_name = "DURATION", value_parser = parse_keep_alive)]
+    keep_alive: Option<InactivityPolicy>,
+
+    /// Pin the session, so that the server keeps it and its URL however long
+    /// this client is disconnected, like on a machine that reboots nightly.
+    #[clap(long)]
+    pin: bool,
+
+    /// JSON file with commands and links to suggest to users, like
+    /// `[{"label": "Build", "command": "make"}, {"label": "Docs", "link":
+    /// "https://example.com"}]`. Users with write access can paste the
+    /// commands into a shell with a click.
+    #[clap(long, value_name = "FILE")]
+    palette: Option<PathBuf>,
+
+    /// Tell users when a shell has not printed anything for this long after
+    /// receiving input, like `5m`, since it may have stopped responding.
+    #[clap(long, value_name = "DURATION", value_parser = parse_duration)]
+    watchdog: Option<Duration>,
+
+    /// Restart shells that stop responding, as detected by `--watchdog`.
+    #[clap(long, requires = "watchdog")]
+    watchdog_restart: bool,
+
+    /// Where the encryption key of the session comes from: `random`,
+    /// `env:VAR`, or `command:COMMAND` to print it with a keychain or
+    /// hardware token tool.
+    #[clap(long, value_name = "SOURCE", default_value = "random")]
+    key: KeySource,
+
+    /// Color scheme of this terminal, `dark` or `light`, so that users see
+    /// matching colors. Detected from `COLORFGBG` if not given.
+    #[clap(long, value_name = "SCHEME", env = "SSHX_COLOR_SCHEME")]
+    color_scheme: Option<ColorScheme>,
+
+    /// Share the environment variables and `TERM` of new shells with users,
+    /// to debug why a prompt looks different. Values of variables that look
+    /// like secrets are redacted.
+    #[clap(long)]
+    share_env: bool,
+
+    /// What to do when the identity of the server, which is pinned the first
+    /// time a session is opened on it, changes: `strict` to refuse to share,
+    /// `warn`, or `off`.
+    #[clap(long, value_name = "CHECK", default_value = "strict")]
+    server_identity: IdentityCheck,
+
+    /// File of pinned server identities. Defaults to `sshx/known_servers` in
+    /// the user's config directory.
+    #[clap(long, value_name = "FILE", env = "SSHX_KNOWN_SERVERS")]
+    known_servers: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// Subcommands of the client, instead of sharing a terminal.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Play back a recorded session in this terminal.
+    Replay {
+        /// Asciicast file, transcript file downloaded from the server, or
+        /// session URL with its encryption key.
+        source: String,
+
+        /// Encryption key of a transcript file, from its session URL.
+        #[clap(long)]
+        key: Option<String>,
+
+        /// ID of the shell to play back from a transcript, instead of the
+        /// first one.
+        #[clap(long)]
+        shell: Option<u32>,
+
+        /// Multiplier for the playback speed.
+        #[clap(long, default_value_t = 1.0)]
+        speed: f64,
+
+        /// Longest pause between outputs, in seconds.
+        #[clap(long, default_value_t = 2.0)]
+        idle_limit: f64,
+    },
+
+    /// Record a shell in this terminal to an asciicast file, without sharing
+    /// it. The recording ends when the shell exits.
+    Record {
+        /// Asciicast file to write the recording to.
+        file: PathBuf,
+
+        /// Upload the finished recording to the asciinema server set in the
+        /// `[asciinema]` table of the client's config file.
+        #[clap(long)]
+        upload: bool,
+
+        /// Config file with the upload server. Defaults to `sshx/config.toml`
+        /// in the user's config directory.
+        #[clap(long, value_name = "FILE", requires = "upload")]
+        config: Option<PathBuf>,
+    },
+
+    /// Join a session in this terminal, without a browser.
+    View {
+        /// Session URL with its encryption key, and write password if any.
+        url: String,
+
+        /// ID of the shell to view, instead of the first one.
+        #[clap(long)]
+        shell: Option<u32>,
+
+        /// Only view the shell, without sending any input to it.
+        #[clap(long)]
+        read_only: bool,
+    },
+
+    /// Share several sessions from this process, like one for each project.
+    Agent {
+        /// Session to open, with an optional shell command instead of the
+        /// default shell, like `api` or `api=/bin/zsh`. May be repeated.
+        #[clap(long = "session", value_name = "NAME[=SHELL]")]
+        sessions: Vec<String>,
+
+        /// Unix socket for listing, opening and closing sessions while the
+        /// agent is running.
+        #[clap(long, value_name = "PATH")]
+        control: Option<PathBuf>,
+    },
+}
+
+/// Byte of Ctrl+], which detaches from a session in `sshx view`.
+const DETACH_KEY: u8 = 0x1d;
+
+/// Timeout of a session in CI mode, if none is given.
+const CI_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Parse a duration like `90s`, `30m` or `2h`, in seconds if there is no unit.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "s"),
+    };
+    let secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("expected a unit of s, m or h, got {unit:?}")),
+    };
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("expected a duration like 30m, got {s:?}"))?;
+    Ok(Duration::from_secs(value * secs))
+}
+
+/// Parse a duration for `--keep-alive`, or `forever`.
+fn parse_keep_alive(s: &str) -> Result<InactivityPolicy, String> {
+    match s {
+        "forever" => Ok(InactivityPolicy::keep(None)),
+        _ => Ok(InactivityPolicy::keep(Some(parse_duration(s)?))),
+    }
+}
+
+/// Detect the color scheme of the terminal that the client is running in.
+fn detect_color_scheme() -> Option<ColorScheme> {
+    ColorScheme::from_colorfgbg(&std::env::var("COLORFGBG").ok()?)
+}
+
+/// Read and check the commands and links for `--palette`.
+fn read_palette(path: &Path) -> Result<Vec<PaletteItem>> {
+    let data = std::fs::read(path).with_context(|| format!("failed to read {path:?}"))?;
+    let items: Vec<PaletteItem> =
+        serde_json::from_slice(&data).with_context(|| format!("invalid palette in {path:?}"))?;
+    for item in &items {
+        match (&item.command, &item.link) {
+            (Some(_), None) => (),
+            (None, Some(link)) if link.starts_with("https://") || link.starts_with("http://") => (),
+            (None, Some(link)) => bail!("palette link {link:?} is not an HTTP(S) URL"),
+            _ => bail!(
+                "palette item {:?} needs either a command or a link",
+                item.label
+            ),
+        }
+    }
+    Ok(items)
+}
+
+/// Wait until the last viewer has left a session and nobody rejoined within
+/// the grace period.
+async fn wait_for_viewers_to_leave(mut viewers: watch::Receiver<Viewers>, grace_period: Duration) {
+    loop {
+        if viewers
+            .wait_for(|v| v.any_joined && v.current == 0)
+            .await
+            .is_err()
+        {
+            return std::future::pending().await;
+        }
+        let rejoin = viewers.wait_for(|v| v.current > 0);
+        if time::timeout(grace_period, rejoin).await.is_err() {
+            return;
+        }
+    }
 }
 
 fn print_greeting(shell: &str, controller: &Controller) {
@@ -54,6 +381,22 @@ fn print_greeting(shell: &str, controller: &Controller) {
             link_e = Cyan.underline().paint(write_url),
             shell_v = Fixed(8).paint(shell),
         );
+    } else if let Some(write_password) = controller.write_password() {
+        println!(
+            r#"
+  {sshx} {version}
+
+  {arr}  Link:           {link_v}
+  {arr}  Write password: {password}
+  {arr}  Shell:          {shell_v}
+"#,
+            sshx = Green.bold().paint("sshx"),
+            version = Green.paint(&version_str),
+            arr = Green.paint("➜"),
+            link_v = Cyan.underline().paint(controller.url()),
+            password = Cyan.paint(write_password),
+            shell_v = Fixed(8).paint(shell),
+        );
     } else {
         println!(
             r#"
@@ -69,15 +412,301 @@ fn print_greeting(shell: &str, controller: &Controller) {
             shell_v = Fixed(8).paint(shell),
         );
     }
+    if let Some(banner) = controller.banner() {
+        println!("  {}\n", Yellow.paint(banner));
+    }
+}
+
+/// Play back a recording from a file or session URL.
+async fn replay(
+    source: String,
+    key: Option<String>,
+    shell: Option<u32>,
+    options: PlaybackOptions,
+) -> Result<()> {
+    let frames = if source.starts_with("http://") || source.starts_with("https://") {
+        let (data, key) = replay::fetch_transcript(&source).await?;
+        let shell = shell.map(Sid);
+        task::spawn_blocking(move || replay::decrypt_transcript(&data, &key, shell)).await??
+    } else {
+        let data = tokio::fs::read(&source)
+            .await
+            .with_context(|| format!("failed to read {source}"))?;
+        match key {
+            Some(key) => {
+                let shell = shell.map(Sid);
+                task::spawn_blocking(move || replay::decrypt_transcript(&data, &key, shell))
+                    .await??
+            }
+            None => replay::parse_asciicast(&String::from_utf8(data)?)
+                .context("not an asciicast file, pass --key to play a transcript")?,
+        }
+    };
+    replay::play(&frames, options, tokio::io::stdout()).await
+}
+
+/// Record a local shell to an asciicast file until it exits, then upload the
+/// recording if there is a config for it.
+async fn record(shell: String, path: PathBuf, upload: Option<UploadConfig>) -> Result<()> {
+    let (rows, cols) = local_winsize().unwrap_or((24, 80));
+    let mut terminal = Terminal::new(&shell).await?;
+    terminal.set_winsize(rows, cols)?;
+    let file =
+        std::fs::File::create(&path).with_context(|| format!("failed to create {path:?}"))?;
+    let mut recorder = Recorder::new(std::io::BufWriter::new(file), rows, cols)?;
+
+    let interactive = std::io::stdin().is_terminal();
+    if interactive {
+        eprint!(
+            "Recording to {}, exit the shell to finish.\r\n",
+            path.display()
+        );
+    }
+    let (tx, mut input) = mpsc::channel::<Vec<u8>>(16);
+    std::thread::spawn(move || {
+        let mut buf = [0; 1024];
+        while let Ok(n @ 1..) = std::io::stdin().read(&mut buf) {
+            if tx.blocking_send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    let raw_mode = if interactive {
+        Some(raw_mode::enable()?)
+    } else {
+        None
+    };
+
+    #[cfg(unix)]
+    let mut resized = signal::unix::signal(signal::unix::SignalKind::window_change())?;
+
+    let mut stdout = tokio::io::stdout();
+    let mut buf = [0; 4096];
+    loop {
+        tokio::select! {
+            // Reads fail once the shell exits and its PTY is closed.
+            result = terminal.read(&mut buf) => match result {
+                Ok(n @ 1..) => {
+                    recorder.output(&buf[..n])?;
+                    stdout.write_all(&buf[..n]).await?;
+                    stdout.flush().await?;
+                }
+                _ => break,
+            },
+            Some(data) = input.recv() => terminal.write_all(&data).await?,
+            _ = async {
+                #[cfg(unix)]
+                resized.recv().await;
+                #[cfg(not(unix))]
+                std::future::pending::<()>().await;
+            } => {
+                if let Some((rows, cols)) = local_winsize() {
+                    terminal.set_winsize(rows, cols)?;
+                    recorder.resize(rows, cols)?;
+                }
+            }
+        }
+    }
+    drop(raw_mode);
+    recorder.finish()?;
+    eprintln!("Saved the recording to {}.", path.display());
+
+    if let Some(config) = upload {
+        let url = record::upload(&path, &config).await?;
+        println!("{url}");
+    }
+    Ok(())
+}
+
+/// Follow a shell of a session, sending keystrokes to it unless read-only.
+async fn view(url: String, shell: Option<u32>, read_only: bool) -> Result<()> {
+    let mut client = WebClient::connect(&url).await?;
+    let interactive = !read_only && std::io::stdin().is_terminal();
+    if interactive {
+        eprint!(
+            "Connected to {}, press Ctrl+] to detach.\r\n",
+            client.name()
+        );
+    } else {
+        eprintln!("Connected to {}, press Ctrl+C to detach.", client.name());
+    }
+
+    let input = interactive.then(|| {
+        let (tx, rx) = mpsc::channel(16);
+        std::thread::spawn(move || {
+            let mut buf = [0; 1024];
+            while let Ok(n @ 1..) = std::io::stdin().read(&mut buf) {
+                let data = &buf[..n];
+                let end = data.iter().position(|&b| b == DETACH_KEY);
+                let data = data[..end.unwrap_or(n)].to_vec();
+                if (!data.is_empty() && tx.blocking_send(data).is_err()) || end.is_some() {
+                    break;
+                }
+            }
+        });
+        rx
+    });
+    let _raw_mode = if interactive {
+        Some(raw_mode::enable()?)
+    } else {
+        None
+    };
+
+    let follow = client.follow(shell.map(Sid), input, tokio::io::stdout());
+    tokio::select! {
+        result = follow => result?,
+        Ok(()) = signal::ctrl_c() => (),
+    }
+    client.close().await
+}
+
+/// Run several sessions until interrupted, managed over a control socket.
+async fn agent(
+    agent: Agent,
+    sessions: Vec<String>,
+    control: Option<PathBuf>,
+    quiet: bool,
+) -> Result<()> {
+    #[cfg(unix)]
+    let listener = match &control {
+        Some(path) => {
+            use std::os::unix::fs::FileTypeExt;
+            if std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            let listener = tokio::net::UnixListener::bind(path)
+                .with_context(|| format!("failed to bind {}", path.display()))?;
+            Some(listener)
+        }
+        None => None,
+    };
+    #[cfg(not(unix))]
+    if control.is_some() {
+        bail!("control sockets are only supported on Unix");
+    }
+
+    let result = async {
+        for session in &sessions {
+            let (name, runner) = match session.split_once('=') {
+                Some((name, shell)) => (name, Some(Runner::Shell(shell.into()))),
+                None => (session.as_str(), None),
+            };
+            let info = agent.open(name, runner).await?;
+            match (quiet, &info.write_url) {
+                (true, _) => println!("{}\t{}", info.name, info.url),
+                (false, None) => println!("  {}  {}", Green.paint(&info.name), info.url),
+                (false, Some(write_url)) => println!(
+                    "  {}  {} (writable: {write_url})",
+                    Green.paint(&info.name),
+                    info.url
+                ),
+            }
+        }
+        let serve_control = async {
+            #[cfg(unix)]
+            if let Some(listener) = listener {
+                return agent.serve_control(listener).await;
+            }
+            std::future::pending().await
+        };
+        tokio::select! {
+            result = serve_control => result,
+            Ok(()) = signal::ctrl_c() => Ok(()),
+        }
+    }
+    .await;
+
+    agent.close_all().await;
+    if let Some(path) = &control {
+        std::fs::remove_file(path).ok();
+    }
+    result
+}
+
+/// Raw mode for the local terminal, so keystrokes are passed through as-is.
+#[cfg(unix)]
+mod raw_mode {
+    use anyhow::Result;
+    use nix::sys::termios::{self, SetArg, Termios};
+
+    /// Restores the original terminal settings when dropped.
+    pub struct RawMode(Termios);
+
+    pub fn enable() -> Result<RawMode> {
+        let stdin = std::io::stdin();
+        let original = termios::tcgetattr(&stdin)?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(&stdin, SetArg::TCSANOW, &raw)?;
+        Ok(RawMode(original))
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            _ = termios::tcsetattr(std::io::stdin(), SetArg::TCSANOW, &self.0);
+        }
+    }
+}
+
+/// Terminals are left in their usual mode on other platforms.
+#[cfg(not(unix))]
+mod raw_mode {
+    pub fn enable() -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 #[tokio::main]
 async fn start(args: Args) -> Result<()> {
+    match args.command {
+        Some(Command::Replay {
+            source,
+            key,
+            shell,
+            speed,
+            idle_limit,
+        }) => {
+            let idle_limit =
+                Duration::try_from_secs_f64(idle_limit).context("invalid idle limit")?;
+            let options = PlaybackOptions {
+                speed,
+                idle_limit: Some(idle_limit),
+            };
+            return replay(source, key, shell, options).await;
+        }
+        Some(Command::View {
+            url,
+            shell,
+            read_only,
+        }) => return view(url, shell, read_only).await,
+        Some(Command::Agent { .. } | Command::Record { .. }) | None => (),
+    }
+
     let shell = match args.shell {
         Some(shell) => shell,
         None => get_default_shell().await,
     };
 
+    if let Some(Command::Record {
+        file,
+        upload,
+        config,
+    }) = args.command
+    {
+        let upload = match (upload, config.or_else(UploadConfig::default_path)) {
+            (false, _) => None,
+            (true, Some(path)) => Some(UploadConfig::load(&path)?),
+            (true, None) => bail!("no config directory for --upload, use --config"),
+        };
+        return record(shell, file, upload).await;
+    }
+
+    if let Some(Command::Agent { sessions, control }) = args.command {
+        let runner = Runner::Shell(shell);
+        let manager = Agent::new(&args.server, runner, args.enable_readers);
+        return agent(manager, sessions, control, args.quiet).await;
+    }
+
     let name = args.name.unwrap_or_else(|| {
         let mut name = whoami::username();
         if let Ok(host) = whoami::fallible::hostname() {
@@ -89,34 +718,227 @@ async fn start(args: Args) -> Result<()> {
         name
     });
 
+    let notifier = args.notify.as_deref().map(Notifier::new).transpose()?;
+    let palette = args.palette.as_deref().map(read_palette).transpose()?;
     let runner = Runner::Shell(shell.clone());
-    let mut controller = Controller::new(&args.server, &name, runner, args.enable_readers).await?;
-    if args.quiet {
+    let socks5 = args.socks5.map(|proxy| Socks5Proxy {
+        auth: args.socks5_user.zip(args.socks5_password),
+        ..proxy
+    });
+    let state_file = if args.resume {
+        let path = (args.state_file).or_else(|| SavedSession::default_path(&args.server));
+        Some(path.context("no state directory for --resume, use --state-file")?)
+    } else {
+        None
+    };
+    let saved = match &state_file {
+        Some(path) => SavedSession::load(path)?.filter(|saved| saved.server == args.server),
+        None => None,
+    };
+    let resumed = match saved {
+        Some(saved) => resume(&args.server, saved, runner.clone(), socks5.clone()).await?,
+        None => None,
+    };
+    // The optional features of the server are only known for new sessions.
+    let taken_over = args.takeover.is_some() || resumed.is_some();
+    let mut controller = match (&args.takeover, &args.token, resumed) {
+        (_, _, Some(controller)) => controller,
+        (Some(url), Some(token), None) => {
+            let mut controller = Controller::take_over(&args.server, url, token, runner).await?;
+            if let Some(proxy) = socks5 {
+                controller.set_socks5(proxy);
+            }
+            if let Some(key) = args.client_key {
+                controller.set_client_key(key);
+            }
+            controller
+        }
+        _ => {
+            let options = OpenOptions {
+                enable_readers: args.enable_readers,
+                keys: Some(Arc::new(args.key)),
+                socks5,
+                color_scheme: args.color_scheme.or_else(detect_color_scheme),
+                environment: args.share_env.then(|| shell_environment(&shell)),
+                known_servers: (args.known_servers.or_else(KnownServers::default_path))
+                    .map(|path| KnownServers::new(path, args.server_identity)),
+                session_name: args.session_name,
+            };
+            Controller::open(&args.server, &name, runner, options).await?
+        }
+    };
+    if let Some(chaos) = args.chaos {
+        controller.set_chaos(chaos);
+    }
+    if let Some(notifier) = notifier {
+        controller.set_notifier(notifier);
+    }
+    if let Some(policy) = args.keep_alive {
+        controller.set_inactivity(policy);
+    }
+    if args.pin {
+        controller.pin();
+    }
+    if let Some(timeout) = args.watchdog {
+        controller.set_watchdog(Watchdog {
+            timeout,
+            restart: args.watchdog_restart,
+        });
+    }
+    if let Some(palette) = palette {
+        if !taken_over && !controller.capabilities().has(features::PALETTE) {
+            eprintln!("warning: server does not support --palette, so users will not see it");
+        }
+        controller.set_palette(&palette)?;
+    }
+    if args.color_scheme.is_some()
+        && !taken_over
+        && !controller.capabilities().has(features::COLOR_SCHEME)
+    {
+        eprintln!("warning: server does not support --color-scheme, so users will not see it");
+    }
+    if args.share_env && !taken_over && !controller.capabilities().has(features::ENVIRONMENT) {
+        eprintln!("warning: server does not support --share-env, so users will not see it");
+    }
+    if args.quiet || args.ci {
         println!("{}", controller.url());
+        if let Some(banner) = controller.banner() {
+            eprintln!("{banner}");
+        }
     } else {
         print_greeting(&shell, &controller);
     }
+    if let Some(path) = &state_file {
+        SavedSession::new(&args.server, &controller).save(path)?;
+    }
+    if args.show_token {
+        let url = controller.write_url().unwrap_or(controller.url());
+        eprintln!("To continue this session on another machine, run:");
+        let client_key = (controller.client_key())
+            .map(|key| format!(" --client-key {key}"))
+            .unwrap_or_default();
+        eprintln!(
+            "  sshx --server {} --takeover '{url}' --token {}{client_key}\n",
+            args.server,
+            controller.token()
+        );
+    }
+
+    // Viewers are only tracked in CI mode if the server reports them.
+    let track_viewers = args.ci && controller.capabilities().has(features::USER_PRESENCE);
+    if args.ci && !track_viewers {
+        eprintln!("warning: server does not report viewers, closing only after the timeout");
+    }
+    let viewers = controller.viewers();
+    let mut released = controller.released();
+    let timeout = args.timeout.or(args.ci.then_some(CI_TIMEOUT));
+    let timed_out = async {
+        match timeout {
+            Some(timeout) => time::sleep(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    let viewers_left = async {
+        if track_viewers {
+            wait_for_viewers_to_leave(viewers.clone(), args.grace_period).await;
+        } else {
+            std::future::pending().await
+        }
+    };
+
+    #[cfg(unix)]
+    if let Some(systemd) = sshx::systemd::Systemd::from_env()? {
+        let last_heard = controller.last_heard();
+        tokio::spawn(async move {
+            if let Err(err) = systemd.supervise(last_heard).await {
+                error!(?err, "failed to notify systemd");
+            }
+        });
+    }
 
     let exit_signal = signal::ctrl_c();
     tokio::pin!(exit_signal);
     tokio::select! {
         _ = controller.run() => unreachable!(),
         Ok(()) = &mut exit_signal => (),
+        _ = timed_out => info!("session timed out, closing"),
+        _ = viewers_left => info!("all viewers left, closing"),
+        _ = released.wait_for(|&released| released) => {
+            // The session continues with the other client, so it is not closed.
+            eprintln!("This session was taken over by another client.");
+            if let Some(path) = &state_file {
+                SavedSession::remove(path)?;
+            }
+            return Ok(());
+        }
     };
     controller.close().await?;
+    if let Some(path) = &state_file {
+        SavedSession::remove(path)?;
+    }
 
+    if track_viewers && !viewers.borrow().any_joined {
+        bail!("nobody joined the session");
+    }
     Ok(())
 }
 
+/// Take over the session saved by an earlier run of the client, if the server
+/// still has it open.
+async fn resume(
+    server: &str,
+    saved: SavedSession,
+    runner: Runner,
+    socks5: Option<Socks5Proxy>,
+) -> Result<Option<Controller>> {
+    let mut controller = Controller::take_over(server, &saved.url, &saved.token, runner).await?;
+    if let Some(proxy) = socks5 {
+        controller.set_socks5(proxy);
+    }
+    if let Some(key) = saved.client_key {
+        controller.set_client_key(key);
+    }
+    if controller.reattach().await? {
+        info!(session = %controller.name(), "resumed saved session");
+        Ok(Some(controller))
+    } else {
+        eprintln!("warning: saved session can no longer be resumed, so a new one is opened");
+        Ok(None)
+    }
+}
+
 fn main() -> ExitCode {
     let args = Args::parse();
 
-    let default_level = if args.quiet { "error" } else { "info" };
+    let default_level = if args.quiet || args.ci {
+        "error"
+    } else {
+        "info"
+    };
 
-    tracing_subscriber::fmt()
-        .with_env_filter(std::env::var("RUST_LOG").unwrap_or(default_level.into()))
-        .with_writer(std::io::stderr)
-        .init();
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(std::env::var("RUST_LOG").unwrap_or(default_level.into()));
+    match &args.log_file {
+        Some(path) => {
+            let options = LogFileOptions {
+                path: path.clone(),
+                max_size: args.log_max_size.map(|size| size * 1024 * 1024),
+                rotation: args.log_rotation,
+                keep: args.log_keep,
+            };
+            match LogFile::open(options) {
+                Ok(file) => builder
+                    .with_ansi(false)
+                    .with_writer(Mutex::new(file))
+                    .init(),
+                Err(err) => {
+                    eprintln!("failed to open log file {}: {err}", path.display());
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        None => builder.with_writer(std::io::stderr).init(),
+    }
 
     match start(args) {
         Ok(()) => ExitCode::SUCCESS,
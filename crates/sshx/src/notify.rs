@@ -0,0 +1,110 @@
+//! Notifications about a session, posted to a chat webhook.
+//!
+//! Incoming webhooks of Slack and Discord are detected from their URLs. Any
+//! other URL receives a JSON object with the kind of event and a message.
+
+use anyhow::{bail, Result};
+use serde_json::{json, Value};
+use tokio::time::Duration;
+
+/// Timeout for posting a notification.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something that happened in a session.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// The session started, with its link.
+    Started(String),
+    /// A user joined from the web, with their name and whether they can write.
+    UserJoined(String, bool),
+    /// The session ended.
+    Ended,
+}
+
+impl Event {
+    fn kind(&self) -> &'static str {
+        match self {
+            Event::Started(_) => "started",
+            Event::UserJoined(..) => "userJoined",
+            Event::Ended => "ended",
+        }
+    }
+
+    fn message(&self, session: &str) -> String {
+        match self {
+            Event::Started(url) => format!("sshx session {session} started: {url}"),
+            Event::UserJoined(name, true) => format!("{name} joined sshx session {session}"),
+            Event::UserJoined(name, false) => {
+                format!("{name} joined sshx session {session} as a viewer")
+            }
+            Event::Ended => format!("sshx session {session} ended"),
+        }
+    }
+}
+
+/// Format of the webhook that notifications are posted to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Webhook {
+    Slack,
+    Discord,
+    Json,
+}
+
+/// Posts notifications about a session to a webhook.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    client: reqwest::Client,
+    url: String,
+    webhook: Webhook,
+}
+
+impl Notifier {
+    /// Create a notifier for a webhook URL.
+    pub fn new(url: &str) -> Result<Self> {
+        let Some((scheme, rest)) = url.split_once("://") else {
+            bail!("invalid webhook URL: {url}");
+        };
+        if scheme != "https" && scheme != "http" {
+            bail!("webhook URL must use http or https");
+        }
+        let host = rest.split('/').next().unwrap_or_default();
+        let webhook = match host {
+            "hooks.slack.com" => Webhook::Slack,
+            "discord.com" | "discordapp.com" => Webhook::Discord,
+            _ => Webhook::Json,
+        };
+        let client = reqwest::Client::builder().timeout(NOTIFY_TIMEOUT).build()?;
+        Ok(Self {
+            client,
+            url: url.into(),
+            webhook,
+        })
+    }
+
+    /// Post a notification about an event in a session.
+    pub async fn notify(&self, session: &str, event: &Event) -> Result<()> {
+        let resp = self
+            .client
+            .post(&self.url)
+            .json(&self.body(session, event))
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            bail!("webhook responded with {}", resp.status());
+        }
+        Ok(())
+    }
+
+    fn body(&self, session: &str, event: &Event) -> Value {
+        let message = event.message(session);
+        match self.webhook {
+            Webhook::Slack => json!({ "text": message }),
+            Webhook::Discord => json!({ "content": message }),
+            Webhook::Json => json!({
+                "event": event.kind(),
+                "session": session,
+                "text": message,
+            }),
+        }
+    }
+}
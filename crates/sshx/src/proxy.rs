@@ -0,0 +1,75 @@
+//! Connections to the server through a SOCKS5 proxy, like Tor or a bastion.
+
+use std::str::FromStr;
+
+use sshx_core::proto::sshx_service_client::SshxServiceClient;
+use tokio::net::TcpStream;
+use tokio_socks::tcp::Socks5Stream;
+use tonic::transport::{Channel, Endpoint, Uri};
+use tower::service_fn;
+
+/// SOCKS5 proxy that connections to the server are made through.
+///
+/// Host names are resolved by the proxy, so that `.onion` addresses work and
+/// no DNS queries are made locally.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    /// Address of the proxy, like `127.0.0.1:9050`.
+    pub addr: String,
+    /// Username and password, if the proxy requires them.
+    pub auth: Option<(String, String)>,
+}
+
+impl FromStr for Socks5Proxy {
+    type Err = String;
+
+    /// Parse an address like `127.0.0.1:9050`, without authentication.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(Self {
+                addr: s.into(),
+                auth: None,
+            }),
+            _ => Err(format!(
+                "expected a proxy address like host:port, got {s:?}"
+            )),
+        }
+    }
+}
+
+impl Socks5Proxy {
+    /// Open a TCP connection to the host and port of a URI through the proxy.
+    async fn connect(self, uri: Uri) -> Result<Socks5Stream<TcpStream>, tokio_socks::Error> {
+        let host = uri.host().unwrap_or_default();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let default_port = if uri.scheme_str() == Some("https") {
+            443
+        } else {
+            80
+        };
+        let target = (host, uri.port_u16().unwrap_or(default_port));
+        match &self.auth {
+            Some((user, password)) => {
+                Socks5Stream::connect_with_password(&*self.addr, target, user, password).await
+            }
+            None => Socks5Stream::connect(&*self.addr, target).await,
+        }
+    }
+}
+
+/// Connect to the gRPC service of the server, through a proxy if given.
+pub(crate) async fn connect(
+    origin: &str,
+    socks5: Option<&Socks5Proxy>,
+) -> Result<SshxServiceClient<Channel>, tonic::transport::Error> {
+    let endpoint = Endpoint::from_shared(String::from(origin))?;
+    let channel = match socks5 {
+        Some(proxy) => {
+            let proxy = proxy.clone();
+            let connector = service_fn(move |uri| proxy.clone().connect(uri));
+            endpoint.connect_with_connector(connector).await?
+        }
+        None => endpoint.connect().await?,
+    };
+    Ok(SshxServiceClient::new(channel))
+}
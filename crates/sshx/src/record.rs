@@ -0,0 +1,265 @@
+//! Local recordings of a shell as asciicast v2 files, and uploads of them.
+//!
+//! Recordings can be played back with any asciicast player, or pushed to a
+//! server that speaks the asciinema upload API, such as to archive pairing
+//! sessions.
+//! The upload server is set in the `[asciinema]` table of the client's config
+//! file:
+//!
+//! ```toml
+//! [asciinema]
+//! server = "https://asciinema.example.com"
+//! install_id = "..."
+//! ```
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use encoding_rs::{Decoder, UTF_8};
+use reqwest::multipart::{Form, Part};
+use serde_json::{json, Value};
+
+/// Writer of the output of a shell to an asciicast v2 file.
+pub struct Recorder<W: Write> {
+    out: W,
+    start: Instant,
+    /// Decodes output that ends partway through a UTF-8 character.
+    decoder: Decoder,
+}
+
+impl<W: Write> Recorder<W> {
+    /// Start a recording of a terminal with the given size.
+    pub fn new(mut out: W, rows: u16, cols: u16) -> Result<Self> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let header = json!({
+            "version": 2,
+            "width": cols,
+            "height": rows,
+            "timestamp": timestamp,
+        });
+        writeln!(out, "{header}")?;
+        Ok(Self {
+            out,
+            start: Instant::now(),
+            decoder: UTF_8.new_decoder(),
+        })
+    }
+
+    /// Record output of the shell at the current time.
+    pub fn output(&mut self, data: &[u8]) -> Result<()> {
+        let mut text = String::with_capacity(data.len() + 4);
+        _ = self.decoder.decode_to_string(data, &mut text, false);
+        self.event("o", &text)
+    }
+
+    /// Record that the terminal was resized.
+    pub fn resize(&mut self, rows: u16, cols: u16) -> Result<()> {
+        self.event("r", &format!("{cols}x{rows}"))
+    }
+
+    /// End the recording, returning the underlying writer.
+    pub fn finish(mut self) -> Result<W> {
+        let mut text = String::with_capacity(4);
+        _ = self.decoder.decode_to_string(&[], &mut text, true);
+        self.event("o", &text)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn event(&mut self, code: &str, data: &str) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let time = self.start.elapsed().as_secs_f64();
+        writeln!(self.out, "{}", json!([time, code, data]))?;
+        Ok(())
+    }
+}
+
+/// Server that recordings are uploaded to, using the asciinema API.
+#[derive(Clone, Debug)]
+pub struct UploadConfig {
+    /// Base URL of the server, like `https://asciinema.org`.
+    pub server: String,
+    /// Install ID that authenticates the uploader with the server.
+    pub install_id: String,
+}
+
+impl UploadConfig {
+    /// Returns the default config file of the client, `sshx/config.toml` in
+    /// the user's config directory.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("sshx").join("config.toml"))
+    }
+
+    /// Read the upload server from the `[asciinema]` table of a config file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("failed to read {path:?}"))?;
+        let config: toml::Table = text
+            .parse()
+            .with_context(|| format!("invalid config file {path:?}"))?;
+        let get = |key: &str| {
+            let value = config.get("asciinema").and_then(|table| table.get(key));
+            value
+                .and_then(|value| value.as_str())
+                .map(String::from)
+                .with_context(|| format!("set asciinema.{key} in {path:?} to upload recordings"))
+        };
+        Ok(Self {
+            server: get("server")?.trim_end_matches('/').into(),
+            install_id: get("install_id")?,
+        })
+    }
+}
+
+/// Upload a finished recording, returning the URL where it can be watched.
+pub async fn upload(path: &Path, config: &UploadConfig) -> Result<String> {
+    let data = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("failed to read {path:?}"))?;
+    let part = Part::bytes(data)
+        .file_name("ascii.cast")
+        .mime_str("application/x-asciicast")?;
+    let url = format!("{}/api/asciicasts", config.server);
+    let resp = reqwest::Client::new()
+        .post(&url)
+        .basic_auth(whoami::username(), Some(&config.install_id))
+        .header("accept", "application/json")
+        .multipart(Form::new().part("asciicast", part))
+        .send()
+        .await
+        .with_context(|| format!("failed to reach {url}"))?;
+    let status = resp.status();
+    if !status.is_success() {
+        let message = resp.text().await.unwrap_or_default();
+        bail!("failed to upload recording: {status} {}", message.trim());
+    }
+    let body: Value = resp.json().await.context("invalid response from server")?;
+    match body["url"].as_str() {
+        Some(url) => Ok(url.into()),
+        None => bail!("server did not return the URL of the recording"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::{upload, Recorder, UploadConfig};
+
+    #[test]
+    fn records_events() -> Result<()> {
+        let mut recorder = Recorder::new(Vec::new(), 24, 80)?;
+        recorder.output(b"caf\xc3")?; // Split in the middle of a character.
+        recorder.output(b"\xa9 ")?;
+        recorder.resize(30, 100)?;
+        recorder.output(b"ok")?;
+        let cast = String::from_utf8(recorder.finish()?)?;
+
+        let mut lines = cast.lines().map(serde_json::from_str::<Value>);
+        let header = lines.next().unwrap()?;
+        assert_eq!(header["version"], 2);
+        assert_eq!(
+            (&header["width"], &header["height"]),
+            (&json!(80), &json!(24))
+        );
+        let events: Vec<_> = lines
+            .map(|event| {
+                let event = event?;
+                Ok((event[1].clone(), event[2].clone()))
+            })
+            .collect::<Result<_>>()?;
+        assert_eq!(
+            events,
+            [
+                (json!("o"), json!("caf")),
+                (json!("o"), json!("é ")),
+                (json!("r"), json!("100x30")),
+                (json!("o"), json!("ok")),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn loads_config() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "[asciinema]\nserver = \"https://example.com/\"\n")?;
+        let err = UploadConfig::load(&path).unwrap_err();
+        assert!(err.to_string().contains("asciinema.install_id"));
+
+        std::fs::write(
+            &path,
+            "[asciinema]\nserver = \"https://example.com/\"\ninstall_id = \"abc\"\n",
+        )?;
+        let config = UploadConfig::load(&path)?;
+        assert_eq!(config.server, "https://example.com");
+        assert_eq!(config.install_id, "abc");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn uploads_recording() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let config = UploadConfig {
+            server: format!("http://{}", listener.local_addr()?),
+            install_id: "abc".into(),
+        };
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // Read the headers, then the rest of the body by its length.
+            let body_len = loop {
+                let n = stream.read(&mut buf).await?;
+                anyhow::ensure!(n > 0, "request ended early");
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_lowercase();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text.split("content-length: ").nth(1).unwrap_or("0");
+                    let length: usize = length.lines().next().unwrap().trim().parse()?;
+                    break end + 4 + length;
+                }
+            };
+            while request.len() < body_len {
+                let n = stream.read(&mut buf).await?;
+                anyhow::ensure!(n > 0, "request ended early");
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body = json!({ "url": "https://example.com/a/1" }).to_string();
+            let response = format!(
+                "HTTP/1.1 201 Created\r\ncontent-type: application/json\r\ncontent-length: \
+                 {}\r\nconnection: close\r\n\r\n{body}",
+                body.len(),
+            );
+            stream.write_all(response.as_bytes()).await?;
+            Ok(String::from_utf8(request)?)
+        });
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("session.cast");
+        let cast = "{\"version\": 2, \"width\": 80, \"height\": 24}\n[0.5, \"o\", \"hi\"]\n";
+        std::fs::write(&path, cast)?;
+        let url = upload(&path, &config).await?;
+        assert_eq!(url, "https://example.com/a/1");
+
+        let request = server.await??;
+        assert!(request.starts_with("POST /api/asciicasts HTTP/1.1\r\n"));
+        let expected = reqwest::Client::new()
+            .post(&config.server)
+            .basic_auth(whoami::username(), Some("abc"))
+            .build()?;
+        let auth = expected.headers()["authorization"].to_str()?;
+        assert!(request.contains(&format!("authorization: {auth}\r\n")));
+        assert!(request.contains("name=\"asciicast\"; filename=\"ascii.cast\""));
+        assert!(request.contains(cast));
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! Playback of recorded sessions in the local terminal.
+//!
+//! Recordings are either asciicast v2 files, or encrypted transcripts of a
+//! shell downloaded from the server, which are decrypted with the session key.
+
+use anyhow::{bail, ensure, Context, Result};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use serde_json::Value;
+use sshx_core::protocol::TranscriptRecord;
+use sshx_core::Sid;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::{self, Duration};
+
+use crate::encrypt::Encrypt;
+
+/// Output of a recording, each with its time since the start.
+pub type Frames = Vec<(Duration, Vec<u8>)>;
+
+/// Options for playing back a recording.
+#[derive(Clone, Copy, Debug)]
+pub struct PlaybackOptions {
+    /// Multiplier for the speed of playback.
+    pub speed: f64,
+    /// Longest pause between two frames, before the speed is applied.
+    pub idle_limit: Option<Duration>,
+}
+
+/// Parse the output events of an asciicast v2 file.
+pub fn parse_asciicast(text: &str) -> Result<Frames> {
+    let mut lines = text.lines();
+    let header: Value = serde_json::from_str(lines.next().unwrap_or_default())
+        .context("missing asciicast header")?;
+    ensure!(
+        header["version"] == 2,
+        "only asciicast version 2 is supported"
+    );
+
+    let mut frames = Vec::new();
+    for line in lines.filter(|line| !line.trim().is_empty()) {
+        let (time, code, data): (f64, String, String) =
+            serde_json::from_str(line).with_context(|| format!("invalid event: {line}"))?;
+        if code == "o" {
+            let time = Duration::try_from_secs_f64(time).context("invalid event time")?;
+            frames.push((time, data.into_bytes()));
+        }
+    }
+    Ok(frames)
+}
+
+/// Decrypt the data of one shell in a transcript, or the first shell if not
+/// given. Chunks without a recorded time are played back without pauses.
+pub fn decrypt_transcript(data: &[u8], key: &str, shell: Option<Sid>) -> Result<Frames> {
+    let mut reader = data;
+    let encrypt = Encrypt::new(key);
+    let mut frames = None;
+    while !reader.is_empty() {
+        let record = ciborium::de::from_reader(&mut reader).context("invalid transcript")?;
+        let (id, seqnum, chunks, times) = match record {
+            TranscriptRecord::Header(_, zeros) => {
+                ensure!(encrypt.zeros()[..] == zeros[..], "wrong encryption key");
+                continue;
+            }
+            TranscriptRecord::Chunks(id, seqnum, chunks) => (id, seqnum, chunks, Vec::new()),
+            TranscriptRecord::TimedChunks(id, seqnum, chunks, times) => (id, seqnum, chunks, times),
+        };
+        if shell.is_some_and(|shell| shell != id) || frames.is_some() {
+            continue;
+        }
+        let start = times.first().copied().unwrap_or_default();
+        let mut offset = seqnum;
+        let decrypted = chunks.iter().enumerate().map(|(i, chunk)| {
+            let data = encrypt.segment(0x100000000 | u64::from(id.0), offset, chunk);
+            offset += chunk.len() as u64;
+            let time = times.get(i).map_or(0, |&time| time.saturating_sub(start));
+            (Duration::from_millis(time), data)
+        });
+        frames = Some(decrypted.collect());
+    }
+    match (frames, shell) {
+        (Some(frames), _) => Ok(frames),
+        (None, Some(shell)) => bail!("shell {shell} is not in the transcript"),
+        (None, None) => bail!("the transcript has no shells"),
+    }
+}
+
+/// Download the transcript of a session from its URL, returning it with the
+/// encryption key in the URL.
+pub async fn fetch_transcript(url: &str) -> Result<(Vec<u8>, String)> {
+    let (url, secrets) = url
+        .split_once('#')
+        .context("session URL is missing the encryption key")?;
+    let key = secrets.split(',').next().unwrap_or_default();
+    let Some(index) = url.rfind("/s/") else {
+        bail!("session URL should look like https://sshx.io/s/<name>");
+    };
+    let url = format!(
+        "{}/api{}/transcript?timestamps=true",
+        &url[..index],
+        &url[index..]
+    );
+
+    let zeros = {
+        let key = key.to_owned();
+        tokio::task::spawn_blocking(move || Encrypt::new(&key).zeros()).await?
+    };
+    let resp = reqwest::Client::new()
+        .get(&url)
+        .bearer_auth(BASE64_STANDARD.encode(zeros))
+        .send()
+        .await
+        .with_context(|| format!("failed to reach {url}"))?;
+    if !resp.status().is_success() {
+        bail!("failed to download transcript: {}", resp.status());
+    }
+    Ok((resp.bytes().await?.to_vec(), key.to_owned()))
+}
+
+/// Write frames to an output with their original pacing.
+pub async fn play(
+    frames: &Frames,
+    options: PlaybackOptions,
+    mut out: impl AsyncWrite + Unpin,
+) -> Result<()> {
+    ensure!(options.speed > 0.0, "playback speed must be positive");
+    let mut last = Duration::ZERO;
+    for (time, data) in frames {
+        let mut pause = time.saturating_sub(last);
+        if let Some(limit) = options.idle_limit {
+            pause = pause.min(limit);
+        }
+        time::sleep(pause.div_f64(options.speed)).await;
+        last = *time;
+        out.write_all(data).await?;
+        out.flush().await?;
+    }
+    Ok(())
+}
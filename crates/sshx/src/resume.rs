@@ -0,0 +1,114 @@
+//! Secrets of a session saved to a state file, so that it can be resumed.
+//!
+//! With `--resume`, the client saves the link, token and client key of its
+//! session. If the client is restarted while the server still keeps the
+//! session open, it takes over the session from its earlier run instead of
+//! opening a new one with a different link. The file is removed when the
+//! session is closed.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use ring::digest::{digest, SHA256};
+
+use crate::client_key::ClientKey;
+use crate::controller::Controller;
+
+/// Session saved by an earlier run of the client.
+#[derive(Debug, Clone)]
+pub struct SavedSession {
+    /// Address of the server that the session is on.
+    pub server: String,
+    /// Link to the session with its encryption key, and write password if any.
+    pub url: String,
+    /// Secret token of the session.
+    pub token: String,
+    /// Key pair that the session is bound to, if any.
+    pub client_key: Option<ClientKey>,
+}
+
+impl SavedSession {
+    /// Save the session of a controller connected to this server.
+    pub fn new(server: &str, controller: &Controller) -> Self {
+        Self {
+            server: server.into(),
+            url: controller.write_url().unwrap_or(controller.url()).into(),
+            token: controller.token().into(),
+            client_key: controller.client_key().cloned(),
+        }
+    }
+
+    /// Returns the default file in the user's state directory, if any, for
+    /// sessions on this server that are started from the current directory.
+    /// Clients started elsewhere do not resume each other's sessions.
+    pub fn default_path(server: &str) -> Option<PathBuf> {
+        let dir = dirs::state_dir().or_else(dirs::data_local_dir)?;
+        let cwd = env::current_dir().ok()?;
+        let key = format!("{server}\0{}", cwd.display());
+        let hash = digest(&SHA256, key.as_bytes());
+        let file = BASE64_URL_SAFE_NO_PAD.encode(&hash.as_ref()[..12]);
+        Some(dir.join("sshx").join("sessions").join(file))
+    }
+
+    /// Read a saved session, if the file exists.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).with_context(|| format!("failed to read {path:?}")),
+        };
+        let get = |key: &str| {
+            contents
+                .lines()
+                .filter_map(|line| line.trim().split_once(' '))
+                .find(|&(k, _)| k == key)
+                .map(|(_, value)| value.trim())
+        };
+        let missing = || format!("saved session in {path:?} is incomplete");
+        let client_key = get("client_key").map(str::parse).transpose();
+        Ok(Some(Self {
+            server: get("server").with_context(missing)?.into(),
+            url: get("url").with_context(missing)?.into(),
+            token: get("token").with_context(missing)?.into(),
+            client_key: client_key.map_err(anyhow::Error::msg)?,
+        }))
+    }
+
+    /// Write the session to a file, which only the user can read.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(path)
+            .with_context(|| format!("failed to write {path:?}"))?;
+        // The mode above only applies to new files, not ones written before.
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+        writeln!(file, "server {}", self.server)?;
+        writeln!(file, "url {}", self.url)?;
+        writeln!(file, "token {}", self.token)?;
+        if let Some(key) = &self.client_key {
+            writeln!(file, "client_key {key}")?;
+        }
+        Ok(())
+    }
+
+    /// Remove the saved session, after it was closed.
+    pub fn remove(path: &Path) -> Result<()> {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(err).with_context(|| format!("failed to remove {path:?}"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -1,13 +1,20 @@
 //! Defines tasks that control the behavior of a single shell in the client.
 
+use std::collections::HashMap;
+use std::future::{self, Future};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use anyhow::Result;
 use encoding_rs::{CoderResult, UTF_8};
-use sshx_core::proto::{client_update::ClientMessage, TerminalData};
+use sshx_core::proto::{client_update::ClientMessage, ShellHealth, TerminalData};
 use sshx_core::Sid;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     sync::mpsc,
+    time::{self, Instant},
 };
+use tracing::warn;
 
 use crate::encrypt::Encrypt;
 use crate::terminal::Terminal;
@@ -24,6 +31,85 @@ pub enum Runner {
 
     /// Mock runner that only echos its input, useful for testing.
     Echo,
+
+    /// Mock runner that plays back a programmed script, useful for testing.
+    Script(Script),
+}
+
+/// Detects shells that stop responding to input, so that users are told.
+#[derive(Debug, Clone, Copy)]
+pub struct Watchdog {
+    /// How long input may be pending without any output from the shell, before
+    /// it is reported as stalled.
+    pub timeout: Duration,
+    /// Restart the shell process in the same terminal when it stalls.
+    pub restart: bool,
+}
+
+/// Tracks pending input of a single shell for its [`Watchdog`].
+struct WatchdogState {
+    watchdog: Option<Watchdog>,
+    pending_since: Option<Instant>, // first input since the last output
+    stalled: bool,
+}
+
+impl WatchdogState {
+    fn new(watchdog: Option<Watchdog>) -> Self {
+        Self {
+            watchdog,
+            pending_since: None,
+            stalled: false,
+        }
+    }
+
+    /// Record that input was sent to the shell.
+    fn input(&mut self) {
+        self.pending_since.get_or_insert_with(Instant::now);
+    }
+
+    /// Record output from the shell, returning whether it recovered.
+    fn output(&mut self) -> bool {
+        self.pending_since = None;
+        std::mem::take(&mut self.stalled)
+    }
+
+    /// Record that the shell stalled, returning whether to restart it.
+    fn stall(&mut self) -> bool {
+        self.pending_since = None;
+        self.stalled = true;
+        self.watchdog.is_some_and(|w| w.restart)
+    }
+
+    /// Time when pending input makes the shell stalled, if it is not already.
+    fn deadline(&self) -> Option<Instant> {
+        let watchdog = self.watchdog?;
+        Some(self.pending_since? + watchdog.timeout).filter(|_| !self.stalled)
+    }
+
+    /// Wait until the shell is stalled, or forever if there is no deadline.
+    async fn expired(&self) {
+        match self.deadline() {
+            Some(deadline) => time::sleep_until(deadline).await,
+            None => future::pending().await,
+        }
+    }
+
+    /// Run a future until it finishes, or until the shell is stalled.
+    async fn limit<F: Future>(&self, future: F) -> Option<F::Output> {
+        match self.deadline() {
+            Some(deadline) => time::timeout_at(deadline, future).await.ok(),
+            None => Some(future.await),
+        }
+    }
+}
+
+/// Message reporting the health of a shell to the server.
+fn health(id: Sid, stalled: bool, restarted: bool) -> ClientMessage {
+    ClientMessage::ShellHealth(ShellHealth {
+        id: id.0,
+        stalled,
+        restarted,
+    })
 }
 
 /// Internal message routed to shell runners.
@@ -38,16 +124,26 @@ pub enum ShellData {
 
 impl Runner {
     /// Asynchronous task to run a single shell with process I/O.
+    ///
+    /// The echo runner always responds, so it ignores the watchdog, and the
+    /// script runner reports stalls but cannot be restarted.
     pub async fn run(
         &self,
         id: Sid,
         encrypt: Encrypt,
         shell_rx: mpsc::Receiver<ShellData>,
         output_tx: mpsc::Sender<ClientMessage>,
+        watchdog: Option<Watchdog>,
     ) -> Result<()> {
+        let watchdog = WatchdogState::new(watchdog);
         match self {
-            Self::Shell(shell) => shell_task(id, encrypt, shell, shell_rx, output_tx).await,
+            Self::Shell(shell) => {
+                shell_task(id, encrypt, shell, shell_rx, output_tx, watchdog).await
+            }
             Self::Echo => echo_task(id, encrypt, shell_rx, output_tx).await,
+            Self::Script(script) => {
+                script_task(id, encrypt, script, shell_rx, output_tx, watchdog).await
+            }
         }
     }
 }
@@ -59,9 +155,11 @@ async fn shell_task(
     shell: &str,
     mut shell_rx: mpsc::Receiver<ShellData>,
     output_tx: mpsc::Sender<ClientMessage>,
+    mut watchdog: WatchdogState,
 ) -> Result<()> {
     let mut term = Terminal::new(shell).await?;
-    term.set_winsize(24, 80)?;
+    let mut winsize = (24, 80);
+    term.set_winsize(winsize.0, winsize.1)?;
 
     let mut content = String::new(); // content from the terminal
     let mut content_offset = 0; // bytes before the first character of `content`
@@ -72,6 +170,7 @@ async fn shell_task(
     let mut finished = false; // set when this is done
 
     while !finished {
+        let mut stalled = false; // set when the watchdog expires
         tokio::select! {
             result = term.read(&mut buf) => {
                 let n = result?;
@@ -81,12 +180,20 @@ async fn shell_task(
                     content.reserve(decoder.max_utf8_buffer_length(n).unwrap());
                     let (result, _, _) = decoder.decode_to_string(&buf[..n], &mut content, false);
                     debug_assert!(result == CoderResult::InputEmpty);
+                    if watchdog.output() {
+                        output_tx.send(health(id, false, false)).await?;
+                    }
                 }
             }
             item = shell_rx.recv() => {
                 match item {
                     Some(ShellData::Data(data)) => {
-                        term.write_all(&data).await?;
+                        watchdog.input();
+                        // Writing waits while the shell is not reading its input.
+                        match watchdog.limit(term.write_all(&data)).await {
+                            Some(result) => result?,
+                            None => stalled = true,
+                        }
                     }
                     Some(ShellData::Sync(seq2)) => {
                         if seq2 < seq as u64 {
@@ -97,11 +204,26 @@ async fn shell_task(
                         }
                     }
                     Some(ShellData::Size(rows, cols)) => {
-                        term.set_winsize(rows as u16, cols as u16)?;
+                        winsize = (rows as u16, cols as u16);
+                        term.set_winsize(winsize.0, winsize.1)?;
                     }
                     None => finished = true, // Server closed this shell.
                 }
             }
+            _ = watchdog.expired() => stalled = true,
+        }
+
+        if stalled {
+            output_tx.send(health(id, true, false)).await?;
+            if watchdog.stall() {
+                warn!(%id, "restarting shell that stopped responding to input");
+                term = Terminal::new(shell).await?; // The old process is killed.
+                term.set_winsize(winsize.0, winsize.1)?;
+                decoder = UTF_8.new_decoder();
+                content.push_str("\r\n[sshx: restarted the shell after it stopped responding]\r\n");
+                watchdog.output();
+                output_tx.send(health(id, false, true)).await?;
+            }
         }
 
         if finished {
@@ -117,12 +239,13 @@ async fn shell_task(
             let data = encrypt.segment(
                 0x100000000 | id.0 as u64, // stream number
                 (content_offset + start) as u64,
-                content[start..end].as_bytes(),
+                &content.as_bytes()[start..end],
             );
             let data = TerminalData {
-                id: id.0,
+                id: id.into(),
                 data: data.into(),
                 seq: (content_offset + start) as u64,
+                time: Some(unix_time_ms()),
             };
             output_tx.send(ClientMessage::Data(data)).await?;
             seq = content_offset + end;
@@ -139,6 +262,14 @@ async fn shell_task(
     Ok(())
 }
 
+/// Returns the current time in milliseconds since the Unix epoch.
+fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
 /// Find the last char boundary before an index in O(1) time.
 fn prev_char_boundary(s: &str, i: usize) -> usize {
     (0..=i)
@@ -159,11 +290,12 @@ async fn echo_task(
             ShellData::Data(data) => {
                 let msg = String::from_utf8_lossy(&data);
                 let term_data = TerminalData {
-                    id: id.0,
+                    id: id.into(),
                     data: encrypt
                         .segment(0x100000000 | id.0 as u64, seq, msg.as_bytes())
                         .into(),
                     seq,
+                    time: Some(unix_time_ms()),
                 };
                 output_tx.send(ClientMessage::Data(term_data)).await?;
                 seq += msg.len() as u64;
@@ -174,3 +306,179 @@ async fn echo_task(
     }
     Ok(())
 }
+
+/// Programmed behavior of a [`Runner::Script`] shell, for deterministic tests.
+///
+/// Every shell plays the steps back in order, then waits until it is closed.
+/// Input and window sizes received by each shell are recorded, and can be
+/// read from any clone of the script.
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    steps: Vec<Step>,
+    log: Arc<Mutex<ScriptLog>>,
+}
+
+#[derive(Debug, Clone)]
+enum Step {
+    Output(Vec<u8>),
+    Sleep(Duration),
+    WaitFor(Vec<u8>),
+    Exit,
+}
+
+#[derive(Debug, Default)]
+struct ScriptLog {
+    input: HashMap<Sid, Vec<u8>>,
+    sizes: HashMap<Sid, Vec<(u32, u32)>>,
+}
+
+impl Script {
+    /// Create an empty script, for a shell that never prints anything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Print output, which is sent to the server as a separate chunk.
+    pub fn output(mut self, data: impl Into<Vec<u8>>) -> Self {
+        self.steps.push(Step::Output(data.into()));
+        self
+    }
+
+    /// Pause for some time before the next step.
+    pub fn sleep(mut self, duration: Duration) -> Self {
+        self.steps.push(Step::Sleep(duration));
+        self
+    }
+
+    /// Wait until the input received since the last wait contains a pattern.
+    pub fn wait_for(mut self, pattern: impl Into<Vec<u8>>) -> Self {
+        self.steps.push(Step::WaitFor(pattern.into()));
+        self
+    }
+
+    /// Exit the shell, as if its process had ended.
+    pub fn exit(mut self) -> Self {
+        self.steps.push(Step::Exit);
+        self
+    }
+
+    /// Returns all input received by a shell.
+    pub fn input(&self, id: Sid) -> Vec<u8> {
+        let log = self.log.lock().unwrap();
+        log.input.get(&id).cloned().unwrap_or_default()
+    }
+
+    /// Returns the window sizes that a shell was resized to, as rows and
+    /// columns.
+    pub fn sizes(&self, id: Sid) -> Vec<(u32, u32)> {
+        let log = self.log.lock().unwrap();
+        log.sizes.get(&id).cloned().unwrap_or_default()
+    }
+}
+
+async fn script_task(
+    id: Sid,
+    encrypt: Encrypt,
+    script: &Script,
+    mut shell_rx: mpsc::Receiver<ShellData>,
+    output_tx: mpsc::Sender<ClientMessage>,
+    mut watchdog: WatchdogState,
+) -> Result<()> {
+    let mut content = Vec::new(); // all output printed so far
+    let mut seq = 0; // our log of the server's sequence number
+    let mut seq_outdated = 0; // number of times seq has been outdated
+    let mut input = Vec::new(); // input received since the last wait
+    let mut steps = script.steps.iter();
+    let mut step = steps.next();
+    let mut deadline = None; // end of the current sleep step
+
+    loop {
+        let mut stalled = false; // set when the watchdog expires
+        let item = match step {
+            Some(Step::Output(data)) => {
+                content.extend_from_slice(data);
+                step = steps.next();
+                if watchdog.output() {
+                    output_tx.send(health(id, false, false)).await?;
+                }
+                None
+            }
+            Some(Step::Sleep(duration)) => {
+                let until = *deadline.get_or_insert_with(|| Instant::now() + *duration);
+                tokio::select! {
+                    _ = time::sleep_until(until) => {
+                        deadline = None;
+                        step = steps.next();
+                        None
+                    }
+                    item = shell_rx.recv() => Some(item),
+                    _ = watchdog.expired() => {
+                        stalled = true;
+                        None
+                    }
+                }
+            }
+            Some(Step::WaitFor(pattern)) if contains(&input, pattern) => {
+                input.clear();
+                step = steps.next();
+                None
+            }
+            Some(Step::Exit) => return Ok(()),
+            Some(Step::WaitFor(_)) | None => tokio::select! {
+                item = shell_rx.recv() => Some(item),
+                _ = watchdog.expired() => {
+                    stalled = true;
+                    None
+                }
+            },
+        };
+
+        match item {
+            Some(Some(ShellData::Data(data))) => {
+                let mut log = script.log.lock().unwrap();
+                log.input.entry(id).or_default().extend_from_slice(&data);
+                input.extend_from_slice(&data);
+                watchdog.input();
+            }
+            Some(Some(ShellData::Sync(seq2))) if seq2 < seq as u64 => {
+                seq_outdated += 1;
+                if seq_outdated >= 3 {
+                    seq = seq2 as usize;
+                }
+            }
+            Some(Some(ShellData::Size(rows, cols))) => {
+                let mut log = script.log.lock().unwrap();
+                log.sizes.entry(id).or_default().push((rows, cols));
+            }
+            Some(None) => return Ok(()), // Server closed this shell.
+            Some(Some(ShellData::Sync(_))) | None => (),
+        }
+
+        if stalled {
+            watchdog.stall(); // Scripts are not restarted.
+            output_tx.send(health(id, true, false)).await?;
+        }
+
+        // Send data if the server has fallen behind.
+        if content.len() > seq {
+            let end = content.len().min(seq + CONTENT_CHUNK_SIZE);
+            let data = encrypt.segment(0x100000000 | id.0 as u64, seq as u64, &content[seq..end]);
+            let data = TerminalData {
+                id: id.into(),
+                data: data.into(),
+                seq: seq as u64,
+                time: Some(unix_time_ms()),
+            };
+            output_tx.send(ClientMessage::Data(data)).await?;
+            seq = end;
+            seq_outdated = 0;
+        }
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty()
+        || haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+}
@@ -0,0 +1,99 @@
+//! Readiness and watchdog notifications for running as a systemd service.
+//!
+//! With `Type=notify`, systemd passes a datagram socket in `NOTIFY_SOCKET`, and
+//! with `WatchdogSec=` it also passes the watchdog timeout in `WATCHDOG_USEC`.
+//! The service is marked ready once the session is connected, and watchdog
+//! pings are only sent while messages arrive from the server, so systemd
+//! restarts the client if its connection gets stuck.
+
+use std::env;
+use std::io;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+use tokio::sync::watch;
+use tokio::time::{self, Duration, Instant};
+
+/// Connection to the service manager that started this process.
+#[derive(Debug)]
+pub struct Systemd {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    watchdog: Option<Duration>,
+}
+
+impl Systemd {
+    /// Connect to the service manager from the environment, if this process
+    /// runs as a `Type=notify` service.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let Ok(path) = env::var("NOTIFY_SOCKET") else {
+            return Ok(None);
+        };
+        // The watchdog may be meant for another process, like a wrapper script.
+        let watched = match env::var("WATCHDOG_PID") {
+            Ok(pid) => pid == std::process::id().to_string(),
+            Err(_) => true,
+        };
+        let watchdog = env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| watched)
+            .map(Duration::from_micros);
+        Self::new(&path, watchdog).map(Some)
+    }
+
+    /// Connect to a notification socket, where a path starting with `@` is in
+    /// the abstract namespace, with an optional watchdog timeout.
+    pub fn new(path: &str, watchdog: Option<Duration>) -> io::Result<Self> {
+        let addr = match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)?
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => {
+                let msg = "abstract sockets are only supported on Linux";
+                return Err(io::Error::new(io::ErrorKind::Unsupported, msg));
+            }
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            watchdog,
+        })
+    }
+
+    /// Send a state change to the service manager, like `READY=1`.
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+
+    /// Report that the service is ready once a message is first received from
+    /// the server, then send watchdog pings while messages keep arriving.
+    ///
+    /// This takes the time of the last message, from
+    /// [`Controller::last_heard`](crate::controller::Controller::last_heard).
+    pub async fn supervise(
+        &self,
+        mut last_heard: watch::Receiver<Option<Instant>>,
+    ) -> io::Result<()> {
+        if last_heard.wait_for(Option::is_some).await.is_err() {
+            return Ok(());
+        }
+        self.notify("READY=1")?;
+
+        let Some(timeout) = self.watchdog else {
+            return Ok(());
+        };
+        let mut interval = time::interval(timeout / 2);
+        loop {
+            interval.tick().await;
+            let healthy = last_heard.borrow().is_some_and(|t| t.elapsed() < timeout);
+            if healthy {
+                self.notify("WATCHDOG=1")?;
+            }
+        }
+    }
+}
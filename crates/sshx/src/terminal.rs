@@ -2,23 +2,73 @@
 
 #![allow(unsafe_code)]
 
+use std::env;
+
+use sshx_core::protocol::ShellEnvironment;
+
 cfg_if::cfg_if! {
     if #[cfg(unix)] {
         mod unix;
-        pub use unix::{get_default_shell, Terminal};
+        pub use unix::{get_default_shell, local_winsize, Terminal};
     } else if #[cfg(windows)] {
         mod windows;
-        pub use windows::{get_default_shell, Terminal};
+        pub use windows::{get_default_shell, local_winsize, Terminal};
     } else {
         compile_error!("unsupported platform for terminal driver");
     }
 }
 
+/// Environment variables that are set for shells, replacing those of the
+/// client.
+const SHELL_ENV: [(&str, &str); 3] = [
+    ("TERM", "xterm-256color"),
+    ("COLORTERM", "truecolor"),
+    ("TERM_PROGRAM", "sshx"),
+];
+
+/// Environment variables of the client that are removed for shells.
+const SHELL_ENV_REMOVED: [&str; 1] = ["TERM_PROGRAM_VERSION"];
+
+/// Parts of variable names whose values are redacted from a snapshot of the
+/// environment, since they are likely to be secrets.
+const SECRET_NAMES: [&str; 6] = ["KEY", "TOKEN", "SECRET", "PASS", "AUTH", "CREDENTIAL"];
+
+/// Take a snapshot of the environment that new shells start with, so that
+/// users can debug differences from the client's own terminal.
+pub fn shell_environment(shell: &str) -> ShellEnvironment {
+    let overridden =
+        |name: &str| SHELL_ENV_REMOVED.contains(&name) || SHELL_ENV.iter().any(|&(n, _)| n == name);
+    let mut vars: Vec<(String, String)> = env::vars_os()
+        .map(|(name, value)| {
+            (
+                name.to_string_lossy().into_owned(),
+                value.to_string_lossy().into_owned(),
+            )
+        })
+        .filter(|(name, _)| !overridden(name))
+        .chain(SHELL_ENV.map(|(name, value)| (name.to_owned(), value.to_owned())))
+        .map(|(name, value)| {
+            let upper = name.to_uppercase();
+            if SECRET_NAMES.iter().any(|part| upper.contains(part)) {
+                (name, "<redacted>".into())
+            } else {
+                (name, value)
+            }
+        })
+        .collect();
+    vars.sort();
+    ShellEnvironment {
+        shell: shell.into(),
+        term: SHELL_ENV[0].1.into(),
+        vars,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
 
-    use super::Terminal;
+    use super::{shell_environment, Terminal};
 
     #[tokio::test]
     async fn winsize() -> Result<()> {
@@ -29,4 +79,21 @@ mod tests {
         assert_eq!(terminal.get_winsize()?, (120, 72));
         Ok(())
     }
+
+    #[test]
+    fn environment() {
+        std::env::set_var("SSHX_TEST_API_TOKEN", "hunter2");
+        let environment = shell_environment("/bin/sh");
+        assert_eq!(environment.shell, "/bin/sh");
+        assert_eq!(environment.term, "xterm-256color");
+
+        let get = |name: &str| {
+            let var = environment.vars.iter().find(|(n, _)| n == name);
+            var.map(|(_, value)| value.as_str())
+        };
+        assert_eq!(get("TERM"), Some("xterm-256color"));
+        assert_eq!(get("TERM_PROGRAM"), Some("sshx"));
+        assert_eq!(get("SSHX_TEST_API_TOKEN"), Some("<redacted>"));
+        assert!(environment.vars.windows(2).all(|w| w[0] <= w[1]));
+    }
 }
@@ -18,6 +18,8 @@ use tokio::fs::{self, File};
 use tokio::io::{self, AsyncRead, AsyncWrite};
 use tracing::{instrument, trace};
 
+use super::{SHELL_ENV, SHELL_ENV_REMOVED};
+
 /// Returns the default shell on this system.
 pub async fn get_default_shell() -> String {
     if let Ok(shell) = env::var("SHELL") {
@@ -38,6 +40,17 @@ pub async fn get_default_shell() -> String {
     String::from("sh")
 }
 
+/// Returns the window size of the terminal that the client is running in, if
+/// its standard output is one.
+pub fn local_winsize() -> Option<(u16, u16)> {
+    nix::ioctl_read_bad!(ioctl_get_winsize, TIOCGWINSZ, Winsize);
+    let mut winsize = make_winsize(0, 0);
+    // Safety: The ioctl only writes to the winsize, and fails if standard output
+    // is not a terminal.
+    unsafe { ioctl_get_winsize(std::io::stdout().as_raw_fd(), &mut winsize) }.ok()?;
+    (winsize.ws_row > 0 && winsize.ws_col > 0).then_some((winsize.ws_row, winsize.ws_col))
+}
+
 /// An object that stores the state for a terminal session.
 #[pin_project(PinnedDrop)]
 pub struct Terminal {
@@ -96,10 +109,12 @@ impl Terminal {
         unsafe { CloseFdsBuilder::new().closefrom(3) };
 
         // Set terminal environment variables appropriately.
-        env::set_var("TERM", "xterm-256color");
-        env::set_var("COLORTERM", "truecolor");
-        env::set_var("TERM_PROGRAM", "sshx");
-        env::remove_var("TERM_PROGRAM_VERSION");
+        for (name, value) in SHELL_ENV {
+            env::set_var(name, value);
+        }
+        for name in SHELL_ENV_REMOVED {
+            env::remove_var(name);
+        }
 
         // Start the process.
         execvp(shell, &[shell])
@@ -9,6 +9,8 @@ use tokio::fs::{self, File};
 use tokio::io::{self, AsyncRead, AsyncWrite};
 use tracing::instrument;
 
+use super::{SHELL_ENV, SHELL_ENV_REMOVED};
+
 /// Returns the default shell on this system.
 ///
 /// For Windows, this is implemented currently to just look for shells at a
@@ -29,6 +31,13 @@ pub async fn get_default_shell() -> String {
     String::from("cmd.exe")
 }
 
+/// Returns the window size of the terminal that the client is running in.
+///
+/// This is not detected on Windows, so a default size is used instead.
+pub fn local_winsize() -> Option<(u16, u16)> {
+    None
+}
+
 /// An object that stores the state for a terminal session.
 #[pin_project(PinnedDrop)]
 pub struct Terminal {
@@ -47,10 +56,10 @@ impl Terminal {
         let mut command = Command::new(shell);
 
         // Set terminal environment variables appropriately.
-        command.env("TERM", "xterm-256color");
-        command.env("COLORTERM", "truecolor");
-        command.env("TERM_PROGRAM", "sshx");
-        command.env_remove("TERM_PROGRAM_VERSION");
+        command.envs(SHELL_ENV);
+        for name in SHELL_ENV_REMOVED {
+            command.env_remove(name);
+        }
 
         let mut child =
             tokio::task::spawn_blocking(move || conpty::Process::spawn(command)).await??;
@@ -0,0 +1,307 @@
+//! Headless client for viewing and controlling sessions over WebSocket.
+//!
+//! This speaks the same real-time protocol as the web app, so it can be used to
+//! build bots or terminal-based viewers for sessions.
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use sshx_core::protocol::{close_codes, WsCapabilities, WsClient, WsServer, PROTOCOL_VERSION};
+use sshx_core::{Sid, Uid};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::chaos::Chaos;
+use crate::encrypt::Encrypt;
+
+/// A user connected to a session over WebSocket, like a browser tab.
+///
+/// Terminal data received from the server is decrypted, and input sent to the
+/// server is encrypted, with the key from the session URL.
+pub struct WebClient {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    encrypt: Encrypt,
+    user_id: Uid,
+    name: String,
+    capabilities: WsCapabilities,
+    chaos: Chaos,
+    /// Offset of the next input in the encrypted input stream.
+    input_offset: u64,
+    /// First message after authentication, received before it is requested.
+    pending: Option<WsServer>,
+}
+
+impl WebClient {
+    /// Connect to a session by its URL, as printed by the command-line client.
+    ///
+    /// The URL looks like `https://sshx.io/s/<name>#<key>`, with an optional
+    /// write password after the key, like `#<key>,<password>`.
+    pub async fn connect(url: &str) -> Result<Self> {
+        let (url, secrets) = url
+            .split_once('#')
+            .context("session URL is missing the encryption key")?;
+        let (key, write_password) = match secrets.split_once(',') {
+            Some((key, write_password)) => (key, Some(write_password)),
+            None => (secrets, None),
+        };
+        Self::connect_with_key(&websocket_url(url)?, key, write_password).await
+    }
+
+    /// Connect to a session's WebSocket endpoint, like
+    /// `wss://sshx.io/api/s/<name>`, with its encryption key.
+    pub async fn connect_with_key(
+        ws_url: &str,
+        key: &str,
+        write_password: Option<&str>,
+    ) -> Result<Self> {
+        let (stream, _) = tokio_tungstenite::connect_async(ws_url).await?;
+        let mut this = Self {
+            stream,
+            encrypt: Encrypt::new(key),
+            user_id: Uid(0),
+            name: String::new(),
+            capabilities: WsCapabilities::default(),
+            chaos: Chaos::default(),
+            input_offset: rand::random(),
+            pending: None,
+        };
+
+        match this.recv_raw().await? {
+            Some(WsServer::Hello(user_id, name, _, capabilities)) => {
+                this.user_id = user_id;
+                this.name = name;
+                this.capabilities = capabilities;
+            }
+            _ => bail!("expected a hello message from the server"),
+        }
+        let write_zeros = write_password.map(|password| Encrypt::new(password).zeros().into());
+        let zeros = this.encrypt.zeros().into();
+        this.send(WsClient::Authenticate(
+            zeros,
+            write_zeros,
+            Some(PROTOCOL_VERSION),
+        ))
+        .await?;
+        match this.recv_raw().await? {
+            Some(WsServer::InvalidAuth()) => bail!("invalid encryption key or write password"),
+            Some(msg) => this.pending = Some(msg),
+            None => bail!("connection closed during authentication"),
+        }
+        Ok(this)
+    }
+
+    /// Returns the ID of this user in the session.
+    pub fn user_id(&self) -> Uid {
+        self.user_id
+    }
+
+    /// Returns the name of the session, like `user@hostname`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the optional features and limits of the server.
+    pub fn capabilities(&self) -> &WsCapabilities {
+        &self.capabilities
+    }
+
+    /// Inject random network faults into received messages, for testing.
+    pub fn set_chaos(&mut self, chaos: Chaos) {
+        self.chaos = chaos;
+    }
+
+    /// Send a message to the server.
+    pub async fn send(&mut self, msg: WsClient) -> Result<()> {
+        let mut buf = Vec::new();
+        ciborium::ser::into_writer(&msg, &mut buf)?;
+        self.stream.send(Message::Binary(buf)).await?;
+        Ok(())
+    }
+
+    /// Start receiving the data of a shell, from a chunk index.
+    pub async fn subscribe(&mut self, id: Sid, chunknum: u64) -> Result<()> {
+        self.send(WsClient::Subscribe(id, chunknum, false)).await
+    }
+
+    /// Encrypt input and send it to a shell, which requires write access.
+    pub async fn send_input(&mut self, id: Sid, data: &[u8]) -> Result<()> {
+        let offset = self.input_offset;
+        self.input_offset = self.input_offset.wrapping_add(data.len() as u64);
+        let data = self.encrypt.segment(0x200000000, offset, data);
+        self.send(WsClient::Data(id, data.into(), offset)).await
+    }
+
+    /// Receive the next message from the server, or `None` once the connection
+    /// is closed.
+    ///
+    /// Terminal data in [`WsServer::Chunks`] and [`WsServer::TimedChunks`], and
+    /// auxiliary stream data in [`WsServer::Stream`], are returned decrypted.
+    pub async fn recv(&mut self) -> Result<Option<WsServer>> {
+        let msg = match self.pending.take() {
+            Some(msg) => msg,
+            None => match self.recv_raw().await? {
+                Some(msg) => msg,
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(match msg {
+            WsServer::Chunks(id, seqnum, chunks) => {
+                WsServer::Chunks(id, seqnum, self.decrypt_chunks(id, seqnum, chunks))
+            }
+            WsServer::TimedChunks(id, seqnum, chunks, times) => {
+                let chunks = self.decrypt_chunks(id, seqnum, chunks);
+                WsServer::TimedChunks(id, seqnum, chunks, times)
+            }
+            WsServer::Stream(id, kind, data, offset) => {
+                let data = self
+                    .encrypt
+                    .segment(0x300000000 | u64::from(id), offset, &data);
+                WsServer::Stream(id, kind, data.into(), offset)
+            }
+            msg => msg,
+        }))
+    }
+
+    /// Follow the output of a shell, writing it to `out` as it arrives, until
+    /// the shell is closed.
+    ///
+    /// This follows the first shell in the session if none is given, waiting
+    /// for one to be opened. Input received on `input` is sent to the shell if
+    /// this user can write, and following stops when the channel is closed.
+    pub async fn follow(
+        &mut self,
+        shell: Option<Sid>,
+        mut input: Option<mpsc::Receiver<Vec<u8>>>,
+        mut out: impl AsyncWrite + Unpin,
+    ) -> Result<()> {
+        let mut id = None;
+        let mut can_write = false;
+        let mut written: u64 = 0; // bytes of output already written
+        loop {
+            // Input is left in the channel until there is a shell to send it to.
+            let recv_input = async {
+                match (&mut input, id) {
+                    (Some(input), Some(_)) => input.recv().await,
+                    _ => std::future::pending().await,
+                }
+            };
+            let msg = tokio::select! {
+                msg = self.recv() => msg?,
+                data = recv_input => match (data, id) {
+                    (Some(data), Some(id)) if can_write => {
+                        self.send_input(id, &data).await?;
+                        continue;
+                    }
+                    (Some(_), _) => continue,
+                    (None, _) => return Ok(()),
+                },
+            };
+            match msg {
+                None => return Ok(()),
+                Some(WsServer::Users(users)) => {
+                    if let Some((_, user)) = users.iter().find(|(uid, _)| *uid == self.user_id) {
+                        can_write = user.can_write;
+                    }
+                }
+                Some(WsServer::UserDiff(uid, Some(user))) if uid == self.user_id => {
+                    can_write = user.can_write;
+                }
+                Some(WsServer::Shells(shells)) => match id {
+                    Some(id) if !shells.iter().any(|(sid, _)| *sid == id) => return Ok(()),
+                    Some(_) => (),
+                    None => {
+                        let found = match shell {
+                            Some(shell) => shells.iter().find(|(sid, _)| *sid == shell),
+                            None => shells.first(),
+                        };
+                        if let Some(&(sid, _)) = found {
+                            id = Some(sid);
+                            self.send(WsClient::SetFocus(Some(sid))).await?;
+                            self.subscribe(sid, 0).await?;
+                        }
+                    }
+                },
+                Some(WsServer::Chunks(sid, seqnum, chunks)) if Some(sid) == id => {
+                    let mut offset = seqnum;
+                    for chunk in chunks {
+                        // Skip any output that was already written.
+                        let skip = written.saturating_sub(offset).min(chunk.len() as u64);
+                        out.write_all(&chunk[skip as usize..]).await?;
+                        offset += chunk.len() as u64;
+                        written = written.max(offset);
+                    }
+                    out.flush().await?;
+                }
+                Some(WsServer::Error(err)) => bail!("error from server: {err}"),
+                Some(_) => (),
+            }
+        }
+    }
+
+    /// Close the connection gracefully.
+    pub async fn close(mut self) -> Result<()> {
+        self.stream.close(None).await?;
+        Ok(())
+    }
+
+    async fn recv_raw(&mut self) -> Result<Option<WsServer>> {
+        while let Some(msg) = self.stream.next().await.transpose()? {
+            match msg {
+                Message::Binary(_) if self.chaos.drop() => (),
+                Message::Binary(msg) => {
+                    self.chaos.delay().await;
+                    return Ok(Some(ciborium::de::from_reader(&*msg)?));
+                }
+                Message::Close(Some(frame)) if frame.code != 1000.into() => {
+                    match u16::from(frame.code) {
+                        close_codes::UPGRADE_REQUIRED => {
+                            bail!("this version of sshx is too old for the server, please upgrade")
+                        }
+                        close_codes::UNAVAILABLE => {
+                            bail!("the server is restarting, please try again shortly")
+                        }
+                        close_codes::EXPIRED => bail!("the join link has expired"),
+                        code => bail!("server closed the connection: {} ({code})", frame.reason),
+                    }
+                }
+                Message::Close(_) => return Ok(None),
+                _ => (), // Ignore pings and other messages.
+            }
+        }
+        Ok(None)
+    }
+
+    fn decrypt_chunks(&self, id: Sid, seqnum: u64, chunks: Vec<Bytes>) -> Vec<Bytes> {
+        let stream_num = 0x100000000 | u64::from(u32::from(id));
+        let mut offset = seqnum;
+        chunks
+            .into_iter()
+            .map(|chunk| {
+                let data = self.encrypt.segment(stream_num, offset, &chunk);
+                offset += chunk.len() as u64;
+                data.into()
+            })
+            .collect()
+    }
+}
+
+/// Convert the web URL of a session into the URL of its WebSocket endpoint.
+fn websocket_url(url: &str) -> Result<String> {
+    let (scheme, rest) = url.split_once("://").context("invalid session URL")?;
+    let scheme = match scheme {
+        "https" => "wss",
+        "http" => "ws",
+        _ => bail!("session URL must use http or https"),
+    };
+    let Some(index) = rest.rfind("/s/") else {
+        bail!("session URL should look like https://sshx.io/s/<name>");
+    };
+    Ok(format!(
+        "{scheme}://{}/api{}",
+        &rest[..index],
+        &rest[index..]
+    ))
+}
@@ -0,0 +1,22 @@
+#!/bin/bash
+
+# Builds the WebAssembly encryption module of the web app into src/lib/wasm.
+#
+# This needs the wasm32-unknown-unknown target, and the wasm-bindgen CLI at the
+# same version as the wasm-bindgen crate in Cargo.lock.
+
+set -euo pipefail
+cd "$(dirname "$0")/.."
+
+# The crate is only built as a cdylib here, not for native targets.
+cargo rustc -p sshx-crypto --release --features wasm \
+  --target wasm32-unknown-unknown --crate-type cdylib
+
+version=$(cargo pkgid wasm-bindgen | sed 's/.*[@#]//')
+if [[ "$(wasm-bindgen --version)" != "wasm-bindgen $version" ]]; then
+  echo "error: install wasm-bindgen-cli $version to build the web app" >&2
+  exit 1
+fi
+
+wasm-bindgen target/wasm32-unknown-unknown/release/sshx_crypto.wasm \
+  --target web --out-dir src/lib/wasm
@@ -2,46 +2,29 @@
  * @file Encryption of byte streams based on a random key.
  *
  * This is used for end-to-end encryption between the terminal source and its
- * client. Keep this file consistent with the Rust implementation.
+ * client. It runs the Rust implementation from the `sshx-crypto` crate, built
+ * to WebAssembly by `scripts/build-wasm.sh`.
  */
 
-const SALT: string =
-  "This is a non-random salt for sshx.io, since we want to stretch the security of 83-bit keys!";
+import init, { Encrypt as WasmEncrypt } from "./wasm/sshx_crypto";
+
+let loaded: Promise<unknown> | null = null;
 
 export class Encrypt {
-  private constructor(private aesKey: CryptoKey) {}
+  private constructor(private inner: WasmEncrypt) {}
 
   static async new(key: string): Promise<Encrypt> {
-    const { Argon2, Argon2Mode } = await import(
-      "https://esm.sh/@sphereon/isomorphic-argon2@1.0.1" as any
-    );
-    const result = await Argon2.hash(key, SALT, {
-      mode: Argon2Mode.Argon2id,
-      memory: 19 * 1024,
-      iterations: 2,
-      parallelism: 1,
-      hashLength: 16,
+    // Retry loading the module on the next call if it failed.
+    loaded ??= init().catch((error) => {
+      loaded = null;
+      throw error;
     });
-    const aesKey = await crypto.subtle.importKey(
-      "raw",
-      Uint8Array.from(
-        result.hex.match(/.{1,2}/g).map((byte: string) => parseInt(byte, 16)),
-      ),
-      { name: "AES-CTR" },
-      false,
-      ["encrypt"],
-    );
-    return new Encrypt(aesKey);
+    await loaded;
+    return new Encrypt(new WasmEncrypt(key));
   }
 
   async zeros(): Promise<Uint8Array> {
-    const zeros = new Uint8Array(16);
-    const cipher = await crypto.subtle.encrypt(
-      { name: "AES-CTR", counter: zeros, length: 64 },
-      this.aesKey,
-      zeros,
-    );
-    return new Uint8Array(cipher);
+    return this.inner.zeros();
   }
 
   async segment(
@@ -49,26 +32,6 @@ export class Encrypt {
     offset: bigint,
     data: Uint8Array,
   ): Promise<Uint8Array> {
-    if (streamNum === 0n) throw new Error("stream number must be nonzero"); // security check)
-
-    const blockNum = offset >> 4n;
-    const iv = new Uint8Array(16);
-    new DataView(iv.buffer).setBigUint64(0, streamNum);
-    new DataView(iv.buffer).setBigUint64(8, blockNum);
-
-    const padBytes = Number(offset % 16n);
-    const paddedData = new Uint8Array(padBytes + data.length);
-    paddedData.set(data, padBytes);
-
-    const encryptedData = await crypto.subtle.encrypt(
-      {
-        name: "AES-CTR",
-        counter: iv,
-        length: 64,
-      },
-      this.aesKey,
-      paddedData,
-    );
-    return new Uint8Array(encryptedData, padBytes, data.length);
+    return this.inner.segment(streamNum, offset, data);
   }
 }
@@ -0,0 +1,234 @@
+/**
+ * @file Shared notes of a session, which all users can edit together.
+ *
+ * The server keeps an ordered log of encrypted edits that it cannot read, so
+ * clients resolve concurrent edits with light-weight operational
+ * transformation. Each edit replaces a range of the text, on top of the
+ * version of the log that its author had seen. Every client transforms an
+ * edit against the edits added to the log after that version, in the same
+ * order, so they all agree on the text. Clients only send one edit at a time,
+ * and wait for it to be added to the log before sending the next one.
+ *
+ * Keep the format of edits consistent with the docs of the Rust version.
+ */
+
+import type { Encrypt } from "./encrypt";
+import { createLock } from "./lock";
+import type { WsClient, WsNotes, WsNotesEdit } from "./protocol";
+
+/** Stream number for encrypting the notes. */
+const NOTES_STREAM = 0x500000000n;
+
+/** Number of edits in the log before the author of the next one compacts it. */
+const COMPACT_THRESHOLD = 256;
+
+/** Number of recent edits kept when compacting, to transform late edits. */
+const COMPACT_KEEP = 64;
+
+/** Replace `del` characters at `pos` with `ins`, counted in UTF-16 units. */
+export type NotesEdit = { pos: number; del: number; ins: string };
+
+/** Apply an edit to a text. */
+export function applyEdit(text: string, edit: NotesEdit): string {
+  return text.slice(0, edit.pos) + edit.ins + text.slice(edit.pos + edit.del);
+}
+
+/** Returns a single edit that changes one text into another, if they differ. */
+export function diffText(from: string, to: string): NotesEdit | null {
+  if (from === to) return null;
+  const shortest = Math.min(from.length, to.length);
+  let start = 0;
+  while (start < shortest && from[start] === to[start]) start++;
+  let end = 0;
+  while (
+    end < shortest - start &&
+    from[from.length - 1 - end] === to[to.length - 1 - end]
+  ) {
+    end++;
+  }
+  return {
+    pos: start,
+    del: from.length - start - end,
+    ins: to.slice(start, to.length - end),
+  };
+}
+
+/** Transform an edit to apply after a prior edit from earlier in the log. */
+export function transformEdit(edit: NotesEdit, prior: NotesEdit): NotesEdit {
+  const end = edit.pos + edit.del;
+  const priorEnd = prior.pos + prior.del;
+  const shift = prior.ins.length - prior.del;
+  if (edit.pos < prior.pos && end <= prior.pos) {
+    return edit;
+  }
+  if (edit.pos >= priorEnd) {
+    return { ...edit, pos: edit.pos + shift };
+  }
+  if (edit.pos < prior.pos) {
+    if (end <= priorEnd) {
+      return { ...edit, del: prior.pos - edit.pos };
+    }
+    // The prior edit is inside the replaced range, so keep what it inserted.
+    return {
+      pos: edit.pos,
+      del: end + shift - edit.pos,
+      ins: prior.ins + edit.ins,
+    };
+  }
+  const pos = prior.pos + prior.ins.length;
+  return { pos, del: Math.max(0, end + shift - pos), ins: edit.ins };
+}
+
+/** Shared notes of a session, kept in sync with the server's log of edits. */
+export class SharedNotes {
+  /** Text of the notes for this user, including changes not in the log yet. */
+  local = "";
+
+  #lock = createLock();
+  #counter: bigint;
+  #baseVersion = 0; // Version of the compacted text.
+  #baseText = "";
+  #edits: NotesEdit[] = []; // Transformed edits after the compacted text.
+  #text = ""; // Text after all edits in the log.
+  #pending = false; // Whether an edit of this user is not in the log yet.
+  #listeners = new Set<() => void>();
+
+  constructor(
+    private encrypt: Encrypt,
+    private send: (message: WsClient) => void,
+  ) {
+    // Start from a random offset, like for terminal input.
+    const array = new Uint8Array(8);
+    crypto.getRandomValues(array);
+    this.#counter = new DataView(array.buffer).getBigUint64(0);
+  }
+
+  /** Number of edits in the log. */
+  get version(): number {
+    return this.#baseVersion + this.#edits.length;
+  }
+
+  /** Listen for changes to the local text caused by other users. */
+  onChange(callback: () => void): () => void {
+    this.#listeners.add(callback);
+    return () => this.#listeners.delete(callback);
+  }
+
+  /** Replace the log, after joining or when this user's edit was too old. */
+  load(notes: WsNotes) {
+    return this.#lock(async () => {
+      const prevText = this.#text;
+      this.#baseVersion = notes.version;
+      this.#baseText = notes.text ? await this.#open(notes.text) : "";
+      this.#edits = [];
+      this.#text = this.#baseText;
+      for (const edit of notes.edits) {
+        await this.#add(edit);
+      }
+      this.#pending = false;
+      this.#rebase(prevText, diffText(prevText, this.#text));
+      await this.#flush();
+    });
+  }
+
+  /** Add an edit to the log, with the version of the notes after it. */
+  receive(version: number, edit: WsNotesEdit, userId: number) {
+    return this.#lock(async () => {
+      // Edits may be sent again around the time that the log was loaded.
+      if (version !== this.version + 1) return;
+      const prevText = this.#text;
+      const transformed = await this.#add(edit);
+      if (edit.user === userId) {
+        this.#pending = false;
+        await this.#compact();
+      } else {
+        this.#rebase(prevText, transformed);
+      }
+      await this.#flush();
+    });
+  }
+
+  /** Change the local text, sending the change as soon as possible. */
+  update(local: string) {
+    this.local = local;
+    return this.#lock(() => this.#flush());
+  }
+
+  async #add(edit: WsNotesEdit): Promise<NotesEdit> {
+    let result: NotesEdit = { pos: 0, del: 0, ins: "" };
+    try {
+      const { pos, del, ins } = JSON.parse(await this.#open(edit.data));
+      result = { pos: Number(pos), del: Number(del), ins: String(ins) };
+    } catch (error) {
+      console.warn("Ignoring invalid edit to notes", error);
+    }
+    const concurrent = this.#edits.slice(
+      Math.max(0, edit.base - this.#baseVersion),
+    );
+    for (const prior of concurrent) {
+      result = transformEdit(result, prior);
+    }
+    result.pos = Math.max(0, Math.min(result.pos, this.#text.length));
+    result.del = Math.max(
+      0,
+      Math.min(result.del, this.#text.length - result.pos),
+    );
+    this.#edits.push(result);
+    this.#text = applyEdit(this.#text, result);
+    return result;
+  }
+
+  /** Move changes of this user onto the text after an edit from the log. */
+  #rebase(prevText: string, edit: NotesEdit | null) {
+    if (!edit) return;
+    const change = diffText(prevText, this.local);
+    this.local = change
+      ? applyEdit(this.#text, transformEdit(change, edit))
+      : this.#text;
+    for (const callback of this.#listeners) callback();
+  }
+
+  async #flush() {
+    const edit = diffText(this.#text, this.local);
+    if (this.#pending || !edit) return;
+    this.#pending = true;
+    const version = this.version;
+    const data = await this.#seal(JSON.stringify(edit));
+    this.send({ notesEdit: [version, data] });
+  }
+
+  async #compact() {
+    if (this.#edits.length < COMPACT_THRESHOLD) return;
+    const count = this.#edits.length - COMPACT_KEEP;
+    for (const edit of this.#edits.slice(0, count)) {
+      this.#baseText = applyEdit(this.#baseText, edit);
+    }
+    this.#baseVersion += count;
+    this.#edits = this.#edits.slice(count);
+    const text = await this.#seal(this.#baseText);
+    this.send({ notesCompact: [this.#baseVersion, text] });
+  }
+
+  async #seal(plaintext: string): Promise<Uint8Array> {
+    const data = new TextEncoder().encode(plaintext);
+    const offset = this.#counter;
+    this.#counter += BigInt(data.length); // Must increment before the `await`.
+    const encrypted = await this.encrypt.segment(NOTES_STREAM, offset, data);
+    const buf = new Uint8Array(8 + encrypted.length);
+    new DataView(buf.buffer).setBigUint64(0, offset);
+    buf.set(encrypted, 8);
+    return buf;
+  }
+
+  async #open(data: Uint8Array): Promise<string> {
+    if (data.length < 8) return "";
+    const view = new DataView(data.buffer, data.byteOffset, data.byteLength);
+    const offset = view.getBigUint64(0);
+    const buf = await this.encrypt.segment(
+      NOTES_STREAM,
+      offset,
+      data.subarray(8),
+    );
+    return new TextDecoder().decode(buf);
+  }
+}
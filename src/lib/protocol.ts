@@ -1,6 +1,24 @@
 type Sid = number; // u32
 type Uid = number; // u32
 
+/** Version of the real-time protocol, see the Rust version. */
+export const PROTOCOL_VERSION = 1;
+
+/** Codes that the server closes connections with, see the Rust version. */
+export const CloseCode = {
+  MESSAGE_TOO_LARGE: 1009,
+  UNAUTHORIZED: 4401,
+  FORBIDDEN: 4403,
+  NOT_FOUND: 4404,
+  TIMEOUT: 4408,
+  EXPIRED: 4410,
+  UPGRADE_REQUIRED: 4426,
+  RATE_LIMITED: 4429,
+  INTERNAL_ERROR: 4500,
+  UNAVAILABLE: 4503,
+  BANDWIDTH_EXCEEDED: 4509,
+} as const;
+
 /** Position and size of a window, see the Rust version. */
 export type WsWinsize = {
   x: number;
@@ -15,33 +33,120 @@ export type WsUser = {
   cursor: [number, number] | null;
   focus: number | null;
   canWrite: boolean;
+  color?: number;
+  avatar?: string | null;
+  viewport?: WsViewport | null;
+};
+
+/** Part of the canvas that a user sees, see the Rust version. */
+export type WsViewport = {
+  x: number;
+  y: number;
+  zoom: number;
+};
+
+/** Profile of a user, see the Rust version. */
+export type WsProfile = {
+  name: string;
+  avatar: string | null;
+};
+
+/** Reason that a shell was closed, see the Rust version. */
+export type WsShellClosed = {
+  exitCode: number | null;
+  signal: number | null;
+  reason: string;
+};
+
+/** Whether a shell responds to input, see the Rust version. */
+export type WsShellHealth = {
+  stalled: boolean;
+  restarted: boolean;
+};
+
+/** Encrypted shared notes of a session, see the Rust version. */
+export type WsNotes = {
+  version: number;
+  text: Uint8Array | null;
+  edits: WsNotesEdit[];
+};
+
+/** Encrypted edit to the shared notes, see the Rust version. */
+export type WsNotesEdit = {
+  user: Uid;
+  base: number;
+  data: Uint8Array;
+};
+
+/** Command or link suggested by the client, see the Rust version. */
+export type PaletteItem = {
+  label: string;
+  command?: string;
+  link?: string;
+};
+
+/** Color scheme of the client's terminal, see the Rust version. */
+export type ColorScheme = "dark" | "light";
+
+/** Environment of the client's new shells, see the Rust version. */
+export type ShellEnvironment = {
+  shell: string;
+  term: string;
+  vars: [string, string][];
+};
+
+/** Kind of data carried by an auxiliary stream, see the Rust version. */
+export type WsStreamKind = "file" | "port" | "clipboard" | "metrics";
+
+/** Optional features and limits of the server, see the Rust version. */
+export type WsCapabilities = {
+  features: string[];
+  maxMessageSize: number;
 };
 
 /** Server message type, see the Rust version. */
 export type WsServer = {
-  hello?: [Uid, string];
+  hello?: [Uid, string, number, WsCapabilities?];
   invalidAuth?: [];
   users?: [Uid, WsUser][];
   userDiff?: [Uid, WsUser | null];
   shells?: [Sid, WsWinsize][];
   chunks?: [Sid, number, Uint8Array[]];
+  timedChunks?: [Sid, number, Uint8Array[], number[]];
   hear?: [Uid, string, string];
   shellLatency?: number | bigint;
   pong?: number | bigint;
   error?: string;
+  notice?: string;
+  banner?: string;
+  shellClosed?: [Sid, WsShellClosed];
+  throttled?: [Sid, number];
+  stream?: [number, WsStreamKind, Uint8Array, number | bigint];
+  throughput?: [Sid, number][];
+  notes?: WsNotes;
+  notesEdit?: [number, WsNotesEdit];
+  palette?: [Uint8Array, number | bigint];
+  shellHealth?: [Sid, WsShellHealth];
+  colorScheme?: Uint8Array;
+  environment?: Uint8Array;
 };
 
 /** Client message type, see the Rust version. */
 export type WsClient = {
-  authenticate?: [Uint8Array, Uint8Array | null];
+  authenticate?: [Uint8Array, Uint8Array | null, number];
   setName?: string;
+  setProfile?: WsProfile;
   setCursor?: [number, number] | null;
   setFocus?: number | null;
+  setViewport?: WsViewport | null;
   create?: [number, number];
   close?: Sid;
   move?: [Sid, WsWinsize | null];
   data?: [Sid, Uint8Array, bigint];
-  subscribe?: [Sid, number];
+  subscribe?: [Sid, number] | [Sid, number, boolean];
   chat?: string;
   ping?: bigint;
+  stream?: [number, WsStreamKind, Uint8Array, bigint];
+  notesEdit?: [number, Uint8Array];
+  notesCompact?: [number, Uint8Array];
 };
@@ -1,6 +1,6 @@
 import { persisted } from "svelte-persisted-store";
 import themes, { type ThemeName, defaultTheme } from "./ui/themes";
-import { derived, type Readable } from "svelte/store";
+import { derived, writable, type Readable } from "svelte/store";
 
 export type Settings = {
   name: string;
@@ -10,16 +10,19 @@ export type Settings = {
 
 const storedSettings = persisted<Partial<Settings>>("sshx-settings-store", {});
 
+/** Theme matching the terminal of the session's client, if it shared one. */
+export const clientTheme = writable<ThemeName | null>(null);
+
 /** A persisted store for settings of the current user. */
 export const settings: Readable<Settings> = derived(
-  storedSettings,
-  ($storedSettings) => {
+  [storedSettings, clientTheme],
+  ([$storedSettings, $clientTheme]) => {
     // Do some validation on all of the stored settings.
     const name = $storedSettings.name ?? "";
 
     let theme = $storedSettings.theme;
     if (!theme || !Object.hasOwn(themes, theme)) {
-      theme = defaultTheme;
+      theme = $clientTheme ?? defaultTheme;
     }
 
     let scrollback = $storedSettings.scrollback;
@@ -13,6 +13,9 @@ const RECONNECT_DELAY = 500;
 /** Number of messages to queue while disconnected. */
 const BUFFER_SIZE = 64;
 
+/** Failed WebSocket connections before switching to Server-Sent Events. */
+const FALLBACK_ATTEMPTS = 3;
+
 export type SrocketOptions<T> = {
   /** Handle a message received from the server. */
   onMessage(message: T): void;
@@ -25,14 +28,37 @@ export type SrocketOptions<T> = {
 
   /** Called when an incoming or existing connection is closed. */
   onClose?(event: CloseEvent): void;
+
+  /**
+   * Server-Sent Events URL to fall back to if WebSockets cannot connect, such
+   * as behind some proxies. Messages are sent by POST in this mode.
+   */
+  fallbackUrl?: string;
+
+  /**
+   * WebTransport URL to try before WebSockets, in browsers that support it.
+   * Messages are framed by their length on one stream that the server opens.
+   * If it cannot connect, WebSockets are used from then on.
+   */
+  webTransportUrl?: string;
 };
 
-/** A reconnecting WebSocket client for real-time communication. */
+/**
+ * A reconnecting WebSocket client for real-time communication, with optional
+ * WebTransport and a fallback to Server-Sent Events.
+ */
 export class Srocket<T, U> {
   #url: string;
   #options: SrocketOptions<T>;
 
   #ws: WebSocket | null;
+  #wt: WebTransport | null;
+  #wtWriter: WritableStreamDefaultWriter<Uint8Array> | null;
+  #wtFailed: boolean;
+  #events: EventSource | null;
+  #inputUrl: string | null;
+  #posting: Promise<unknown>;
+  #failures: number;
   #connected: boolean;
   #buffer: Uint8Array[];
   #disposed: boolean;
@@ -49,6 +75,13 @@ export class Srocket<T, U> {
     this.#options = options;
 
     this.#ws = null;
+    this.#wt = null;
+    this.#wtWriter = null;
+    this.#wtFailed = typeof WebTransport === "undefined";
+    this.#events = null;
+    this.#inputUrl = null;
+    this.#posting = Promise.resolve();
+    this.#failures = 0;
     this.#connected = false;
     this.#buffer = [];
     this.#disposed = false;
@@ -65,8 +98,8 @@ export class Srocket<T, U> {
     // See: https://github.com/kriszyp/cbor-x/issues/120
     const data = <Uint8Array>(encode(message) as unknown);
 
-    if (this.#connected && this.#ws) {
-      this.#ws.send(data);
+    if (this.#connected) {
+      this.#transmit(data);
     } else {
       if (this.#buffer.length < BUFFER_SIZE) {
         this.#buffer.push(data);
@@ -79,19 +112,33 @@ export class Srocket<T, U> {
     this.#stateChange(false);
     this.#disposed = true;
     this.#ws?.close();
+    this.#wt?.close();
+    this.#events?.close();
   }
 
   #reconnect() {
     if (this.#disposed) return;
-    if (this.#ws !== null) {
+    if (this.#ws !== null || this.#wt !== null || this.#events !== null) {
       throw new Error("invariant violation: reconnecting while connected");
     }
+    if (this.#options.webTransportUrl && !this.#wtFailed) {
+      this.#reconnectWebTransport(this.#options.webTransportUrl);
+      return;
+    }
+    if (this.#options.fallbackUrl && this.#failures >= FALLBACK_ATTEMPTS) {
+      this.#reconnectEvents(this.#options.fallbackUrl);
+      return;
+    }
+    let opened = false;
     this.#ws = new WebSocket(this.#url);
     this.#ws.binaryType = "arraybuffer";
     this.#ws.onopen = () => {
+      opened = true;
+      this.#failures = 0;
       this.#stateChange(true);
     };
     this.#ws.onclose = (event) => {
+      if (!opened) this.#failures++;
       this.#options.onClose?.(event);
       this.#ws = null;
       this.#stateChange(false);
@@ -107,18 +154,126 @@ export class Srocket<T, U> {
     };
   }
 
+  #reconnectWebTransport(url: string) {
+    let opened = false;
+    const wt = new WebTransport(url);
+    this.#wt = wt;
+    const close = (info: WebTransportCloseInfo | null) => {
+      if (this.#wt !== wt) return;
+      this.#wt = null;
+      this.#wtWriter = null;
+      if (!opened) {
+        // Refused or unreachable, so the server only takes WebSockets.
+        this.#wtFailed = true;
+      } else if (info) {
+        this.#options.onClose?.(
+          new CloseEvent("close", {
+            code: info.closeCode,
+            reason: info.reason,
+          }),
+        );
+      }
+      this.#stateChange(false);
+      setTimeout(() => this.#reconnect(), opened ? RECONNECT_DELAY : 0);
+    };
+    wt.closed.then(close, () => close(null));
+
+    const receive = async () => {
+      const streams = wt.incomingBidirectionalStreams.getReader();
+      const { value: stream } = await streams.read();
+      streams.releaseLock();
+      if (!stream || this.#wt !== wt) return;
+      this.#wtWriter = stream.writable.getWriter();
+      opened = true;
+      this.#failures = 0;
+      this.#stateChange(true);
+
+      // Each message is prefixed by its length, as a 32-bit big-endian integer.
+      const reader = stream.readable.getReader();
+      let buf = new Uint8Array(0);
+      for (;;) {
+        const { value, done } = await reader.read();
+        if (done) break;
+        const joined = new Uint8Array(buf.length + value.length);
+        joined.set(buf);
+        joined.set(value, buf.length);
+        buf = joined;
+        while (buf.length >= 4) {
+          const len = new DataView(buf.buffer, buf.byteOffset).getUint32(0);
+          if (buf.length < 4 + len) break;
+          const message: T = decode(buf.subarray(4, 4 + len));
+          buf = buf.subarray(4 + len);
+          this.#options.onMessage(message);
+        }
+      }
+    };
+    receive().catch(() => wt.close());
+  }
+
+  #reconnectEvents(url: string) {
+    const events = new EventSource(url);
+    this.#events = events;
+    const close = () => {
+      if (this.#events !== events) return;
+      events.close();
+      this.#events = null;
+      this.#inputUrl = null;
+      this.#stateChange(false);
+      setTimeout(() => this.#reconnect(), RECONNECT_DELAY);
+    };
+    events.addEventListener("connection", (event) => {
+      this.#inputUrl = event.data;
+      this.#stateChange(true);
+    });
+    events.addEventListener("close", (event) => {
+      const [code, ...reason] = event.data.split(" ");
+      this.#options.onClose?.(
+        new CloseEvent("close", { code: Number(code), reason: reason.join(" ") }),
+      );
+      close();
+    });
+    events.onmessage = (event) => {
+      const data = Uint8Array.from(atob(event.data), (c) => c.charCodeAt(0));
+      const message: T = decode(data);
+      this.#options.onMessage(message);
+    };
+    events.onerror = close;
+  }
+
+  /** Send an encoded message over the current connection. */
+  #transmit(data: Uint8Array) {
+    if (this.#ws) {
+      this.#ws.send(data);
+    } else if (this.#wtWriter) {
+      const frame = new Uint8Array(4 + data.length);
+      new DataView(frame.buffer).setUint32(0, data.length);
+      frame.set(data, 4);
+      this.#wtWriter.write(frame).catch(() => {});
+    } else if (this.#inputUrl) {
+      // Chain requests so that messages arrive in order.
+      const url = this.#inputUrl;
+      this.#posting = this.#posting.then(() =>
+        fetch(url, {
+          method: "POST",
+          headers: { "Content-Type": "application/cbor" },
+          body: data,
+        }).catch(() => {}),
+      );
+    }
+  }
+
   #stateChange(connected: boolean) {
     if (!this.#disposed && connected !== this.#connected) {
       this.#connected = connected;
       if (connected) {
         this.#options.onConnect?.();
 
-        if (!this.#ws) {
+        if (!this.#ws && !this.#wtWriter && !this.#inputUrl) {
           throw new Error("invariant violation: connected but ws is null");
         }
         // Send any queued messages.
         for (const message of this.#buffer) {
-          this.#ws.send(message);
+          this.#transmit(message);
         }
         this.#buffer = [];
       } else {
@@ -202,6 +202,29 @@ const tokyoNight: ITheme = {
   brightWhite: "#acb0d0",
 };
 
+const githubLight: ITheme = {
+  foreground: "#24292e",
+  background: "#ffffff",
+  cursor: "#24292e",
+  selectionBackground: "#0366d640",
+  black: "#24292e",
+  red: "#d73a49",
+  green: "#28a745",
+  yellow: "#dbab09",
+  blue: "#0366d6",
+  magenta: "#5a32a3",
+  cyan: "#1b7c83",
+  white: "#6a737d",
+  brightBlack: "#959da5",
+  brightRed: "#cb2431",
+  brightGreen: "#22863a",
+  brightYellow: "#b08800",
+  brightBlue: "#005cc5",
+  brightMagenta: "#5a32a3",
+  brightCyan: "#3192aa",
+  brightWhite: "#d1d5da",
+};
+
 const themes = {
   "VS Code Dark": defaultDark,
   Hybrid: hybrid,
@@ -212,10 +235,14 @@ const themes = {
   "Gruvbox Dark": gruvboxDark,
   "Solarized Dark": solarizedDark,
   "Tokyo Night": tokyoNight,
+  "GitHub Light": githubLight,
 };
 
 export type ThemeName = keyof typeof themes;
 
 export const defaultTheme: ThemeName = "VS Code Dark";
 
+/** Default theme when the client's terminal has a light background. */
+export const defaultLightTheme: ThemeName = "GitHub Light";
+
 export default themes;
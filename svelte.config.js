@@ -12,6 +12,10 @@ const config = {
   ],
 
   kit: {
+    // Set when the server is run with a matching `--base-path`.
+    paths: {
+      base: process.env.BASE_PATH ?? "",
+    },
     adapter: adapter({
       fallback: "spa.html", // SPA mode
       precompress: true,
@@ -1,10 +1,18 @@
 import { execSync } from "node:child_process";
+import { existsSync } from "node:fs";
 
 import { defineConfig } from "vite";
 import { sveltekit } from "@sveltejs/kit/vite";
 
 const commitHash = execSync("git rev-parse --short HEAD").toString().trim();
 
+// The encryption module is built from Rust, separately from the web app.
+if (!existsSync("src/lib/wasm/sshx_crypto.js")) {
+  throw new Error(
+    "src/lib/wasm is missing, run `npm run build:wasm` to build it first",
+  );
+}
+
 export default defineConfig({
   define: {
     __APP_VERSION__: JSON.stringify("0.2.4-" + commitHash),